@@ -0,0 +1,823 @@
+use nine_sdk::attestation::{Attestor, MAX_NONCE_SIZE};
+use nine_sdk::framing::{FrameCodec, FramingError};
+use nine_sdk::protocol::{
+    ACCOUNT_MAC_PROTOCOL_VERSION, CLIENT_AUTH_PROTOCOL_VERSION, ErrorCode, HD_WALLET_PROTOCOL_VERSION,
+    KDF_UPGRADE_PROTOCOL_VERSION, LOCKOUT_PROTOCOL_VERSION, PROTOCOL_VERSION, RequestEnvelope, ResponseEnvelope,
+    negotiate_version,
+};
+use nine_sdk::secure_channel::{ChannelError, ClientPublicKey, EnclaveIdentity, SecureChannel};
+use nine_sdk::transport::TransportStream;
+use nine_sdk::{
+    EnclaveRequest, EnclaveResponse, EncryptedKeyConfig, KeyManager, KeyManagerError, SealedWallet,
+    SecretPassword, SecretPhrase,
+};
+use std::pin::Pin;
+use std::sync::Arc;
+use wallet::WalletStore;
+
+#[cfg(feature = "nsm")]
+pub mod nsm;
+pub mod wallet;
+
+/// Serves protocol envelopes on a single connection until the peer disconnects
+///
+/// Requests carry the caller's config, so one `KeyManager` can be shared by every connection.
+/// Unlocked wallets live in `wallets`, so a session opened on one connection can sign on another.
+/// The connection opens with the secure channel handshake against `identity`, after which
+/// every frame is encrypted. The first request must be a `Hello`; everything else is refused
+/// until a version has been negotiated. Oversized frames are answered with an error and the
+/// connection is closed, since the unread payload leaves the stream out of sync.
+///
+/// `Attest` requests are answered by `attestor`, binding the document to `identity`'s public
+/// key; without an attestor they are refused. `StorageKey` requests are answered with keys
+/// derived from `identity`, but only for clients that authenticated in the handshake with
+/// one of `trusted_clients`.
+pub async fn handle_connection(
+    stream: Pin<Box<dyn TransportStream>>,
+    key_manager: Arc<KeyManager>,
+    wallets: Arc<WalletStore>,
+    identity: Arc<EnclaveIdentity>,
+    trusted_clients: Arc<[ClientPublicKey]>,
+    attestor: Option<Arc<dyn Attestor>>,
+    codec: FrameCodec,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut channel = SecureChannel::accept(stream, codec, &identity).await?;
+    let mut negotiated_version: Option<u16> = None;
+
+    loop {
+        let buffer = match channel.recv().await {
+            Ok(Some(buffer)) => buffer,
+            Ok(None) => {
+                log::info!("Client disconnected");
+                break;
+            }
+            Err(ChannelError::Framing(e @ FramingError::FrameTooLarge { .. })) => {
+                log::warn!("Rejecting frame: {}", e);
+                let reply = ResponseEnvelope {
+                    version: negotiated_version.unwrap_or(PROTOCOL_VERSION),
+                    request_id: 0,
+                    response: EnclaveResponse::Error {
+                        code: ErrorCode::MalformedRequest,
+                        message: e.to_string(),
+                    },
+                };
+                channel.send(&serde_json::to_vec(&reply)?).await?;
+                return Err(e.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Process request
+        let (request_id, response) = match serde_json::from_slice::<RequestEnvelope>(&buffer) {
+            Ok(envelope) => {
+                log::info!("Received request {}: {:?}", envelope.request_id, envelope.request);
+                let session = Session {
+                    key_manager: &key_manager,
+                    wallets: &wallets,
+                    identity: &identity,
+                    trusted_clients: &trusted_clients,
+                    client_key: channel.client_key().copied(),
+                    attestor: attestor.as_deref(),
+                };
+                let response = dispatch(envelope.version, envelope.request, &mut negotiated_version, &session).await;
+                (envelope.request_id, response)
+            }
+            Err(e) => {
+                log::warn!("Malformed request: {}", e);
+                let response = EnclaveResponse::Error {
+                    code: ErrorCode::MalformedRequest,
+                    message: e.to_string(),
+                };
+                (0, response)
+            }
+        };
+        let reply = ResponseEnvelope {
+            version: negotiated_version.unwrap_or(PROTOCOL_VERSION),
+            request_id,
+            response,
+        };
+
+        // Send response
+        channel.send(&serde_json::to_vec(&reply)?).await?;
+
+        log::info!("Sent response {}", request_id);
+    }
+
+    Ok(())
+}
+
+/// Per-connection view of the enclave's services
+struct Session<'a> {
+    key_manager: &'a KeyManager,
+    wallets: &'a WalletStore,
+    identity: &'a EnclaveIdentity,
+    trusted_clients: &'a [ClientPublicKey],
+    /// Static key the client proved in the handshake, if any
+    client_key: Option<ClientPublicKey>,
+    attestor: Option<&'a dyn Attestor>,
+}
+
+/// Applies the handshake rules before handing a request to the key manager
+async fn dispatch(
+    envelope_version: u16,
+    request: EnclaveRequest,
+    negotiated_version: &mut Option<u16>,
+    session: &Session<'_>,
+) -> EnclaveResponse {
+    match (request, *negotiated_version) {
+        (EnclaveRequest::Hello { version, min_version }, _) => {
+            match negotiate_version(version, min_version) {
+                Some(version) => {
+                    *negotiated_version = Some(version);
+                    EnclaveResponse::Version { version }
+                }
+                None => EnclaveResponse::Error {
+                    code: ErrorCode::UnsupportedVersion,
+                    message: format!(
+                        "peer supports versions {}..={}, enclave speaks {}",
+                        min_version, version, PROTOCOL_VERSION
+                    ),
+                },
+            }
+        }
+        (_, None) => EnclaveResponse::Error {
+            code: ErrorCode::HandshakeRequired,
+            message: "Hello must be sent before any other request".to_string(),
+        },
+        (_, Some(version)) if version != envelope_version => EnclaveResponse::Error {
+            code: ErrorCode::UnsupportedVersion,
+            message: format!(
+                "request uses version {}, connection negotiated {}",
+                envelope_version, version
+            ),
+        },
+        (request, Some(version)) if request.min_version() > version => EnclaveResponse::Error {
+            code: ErrorCode::UnsupportedVersion,
+            message: format!(
+                "request needs version {}, connection negotiated {}",
+                request.min_version(),
+                version
+            ),
+        },
+        (EnclaveRequest::Attest { nonce }, Some(_)) => attest(&nonce, session),
+        (EnclaveRequest::StorageKey { key_id }, Some(version)) => storage_key(&key_id, version, session),
+        (request, Some(version)) => {
+            let response = process_request(request, version, session.key_manager, session.wallets).await;
+            match response {
+                // Older peers can't parse the lockout code, but still refuse the attempt
+                EnclaveResponse::Error {
+                    code: ErrorCode::LockedOut { .. },
+                    message,
+                } if version < LOCKOUT_PROTOCOL_VERSION => EnclaveResponse::Error {
+                    code: ErrorCode::AuthenticationFailed,
+                    message,
+                },
+                response => response,
+            }
+        }
+    }
+}
+
+/// Hands out the storage key for `key_id` if the client is one the enclave trusts with it
+fn storage_key(key_id: &str, version: u16, session: &Session<'_>) -> EnclaveResponse {
+    if session.client_key.is_some_and(|client_key| session.trusted_clients.contains(&client_key)) {
+        return EnclaveResponse::StorageKey {
+            key: session.identity.derive_storage_key(key_id),
+        };
+    }
+    log::warn!("Refusing storage key to untrusted client {:?}", session.client_key);
+    EnclaveResponse::Error {
+        // Older peers can't parse the code, but are refused all the same
+        code: if version < CLIENT_AUTH_PROTOCOL_VERSION {
+            ErrorCode::AuthenticationFailed
+        } else {
+            ErrorCode::Unauthorized
+        },
+        message: KeyManagerError::Unauthorized.to_string(),
+    }
+}
+
+/// Produces an attestation document binding `nonce` to this connection's channel key
+fn attest(nonce: &[u8], session: &Session<'_>) -> EnclaveResponse {
+    let Some(attestor) = session.attestor else {
+        return EnclaveResponse::Error {
+            code: ErrorCode::AttestationUnavailable,
+            message: "enclave is running without an attestation source".to_string(),
+        };
+    };
+    if nonce.len() > MAX_NONCE_SIZE {
+        return EnclaveResponse::Error {
+            code: ErrorCode::MalformedRequest,
+            message: format!("nonce exceeds {} bytes", MAX_NONCE_SIZE),
+        };
+    }
+
+    match attestor.attest(nonce, session.identity.public_key().as_bytes()) {
+        Ok(document) => EnclaveResponse::Attestation { document },
+        Err(e) => EnclaveResponse::Error {
+            code: ErrorCode::AttestationUnavailable,
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Dispatches a single request to the key manager or the wallet store
+///
+/// `version` is the protocol version negotiated with the peer.
+pub async fn process_request(
+    request: EnclaveRequest,
+    version: u16,
+    key_manager: &KeyManager,
+    wallets: &WalletStore,
+) -> EnclaveResponse {
+    match request {
+        EnclaveRequest::Hello { .. } | EnclaveRequest::Attest { .. } | EnclaveRequest::StorageKey { .. } => {
+            EnclaveResponse::Error {
+                code: ErrorCode::MalformedRequest,
+                message: "request is handled per connection".to_string(),
+            }
+        }
+        EnclaveRequest::SetupConfig { password } => {
+            let config = key_manager
+                .create_config(&password)
+                .await
+                .and_then(|config| Ok(serde_json::to_string_pretty(&config)?));
+            match config {
+                Ok(config) => EnclaveResponse::ConfigSetup { config },
+                Err(e) => EnclaveResponse::error(&e),
+            }
+        }
+        EnclaveRequest::VerifyAndDeriveKeys { config, password } => {
+            match key_manager.verify_and_derive_keys_for(&config, &password).await {
+                Ok((key1, key2)) => EnclaveResponse::Keys { key1, key2 },
+                Err(e) => EnclaveResponse::error(&e),
+            }
+        }
+        EnclaveRequest::CreateWallet { config, password, context } => {
+            // Older peers would drop the sealed recovery phrase, and with it the record's MAC
+            let with_mnemonic = version >= HD_WALLET_PROTOCOL_VERSION;
+            let wallet = key_manager
+                .verify_and_derive_keys_for(&config, &password)
+                .await
+                .and_then(|(key1, key2)| {
+                    if with_mnemonic {
+                        let (wallet, mnemonic) = wallets.create_with_mnemonic(&key1, &key2, &context)?;
+                        Ok((wallet, Some(SecretPhrase::from(mnemonic.as_str()))))
+                    } else {
+                        Ok((wallets.create(&key1, &key2, &context)?, None))
+                    }
+                });
+            match wallet {
+                Ok((wallet, mnemonic)) => EnclaveResponse::WalletCreated { wallet, mnemonic },
+                Err(e) => EnclaveResponse::error(&e),
+            }
+        }
+        EnclaveRequest::ImportWallet {
+            config,
+            password,
+            context,
+            secret,
+        } => {
+            let wallet = key_manager
+                .verify_and_derive_keys_for(&config, &password)
+                .await
+                .and_then(|(key1, key2)| wallets.import(secret.expose(), &key1, &key2, &context));
+            match wallet {
+                Ok(wallet) => EnclaveResponse::WalletCreated { wallet, mnemonic: None },
+                Err(e) => EnclaveResponse::error(&e),
+            }
+        }
+        EnclaveRequest::UnlockWallet { config, password, wallet, context } => {
+            // Older peers would store the resealed wallet without its new config
+            let upgrade_config = version >= KDF_UPGRADE_PROTOCOL_VERSION;
+            unlock_wallet(key_manager, wallets, &config, &password, &wallet, &context, upgrade_config)
+                .await
+                .unwrap_or_else(|e| EnclaveResponse::error(&e))
+        }
+        EnclaveRequest::ChangePassword {
+            config,
+            old_password,
+            new_password,
+            wallet,
+            context,
+        } => change_password(key_manager, wallets, &config, &old_password, &new_password, &wallet, &context)
+            .await
+            .unwrap_or_else(|e| EnclaveResponse::error(&e)),
+        EnclaveRequest::LockWallet { session } => {
+            wallets.lock(&session);
+            EnclaveResponse::WalletLocked
+        }
+        EnclaveRequest::SelectAccount { session, index } => match wallets.select_account(&session, index) {
+            // Older peers don't send the selection back with the wallet, so keep its MAC
+            Ok((address, mac)) => EnclaveResponse::AccountSelected {
+                address,
+                mac: (version >= ACCOUNT_MAC_PROTOCOL_VERSION).then_some(mac),
+            },
+            Err(e) => EnclaveResponse::error(&e),
+        },
+        EnclaveRequest::SignMessage { session, message } => {
+            signature(wallets.sign_message(&session, &message))
+        }
+        EnclaveRequest::SignTypedData { session, typed_data } => {
+            signature(wallets.sign_typed_data(&session, &typed_data))
+        }
+        EnclaveRequest::SignTransaction { session, transaction } => {
+            match wallets.sign_transaction(&session, &transaction) {
+                Ok(raw) => EnclaveResponse::SignedTransaction { raw },
+                Err(e) => EnclaveResponse::error(&e),
+            }
+        }
+    }
+}
+
+/// Unlocks `wallet`, moving it to a freshly derived config if `config` was derived with
+/// parameters other than the key manager's and `upgrade_config` is set
+async fn unlock_wallet(
+    key_manager: &KeyManager,
+    wallets: &WalletStore,
+    config: &EncryptedKeyConfig,
+    password: &SecretPassword,
+    wallet: &SealedWallet,
+    context: &str,
+    upgrade_config: bool,
+) -> Result<EnclaveResponse, KeyManagerError> {
+    let (key1, key2) = key_manager.verify_and_derive_keys_for(config, password).await?;
+    let (session, address, upgraded) = wallets.unlock(&key1, &key2, wallet, context)?;
+    if !upgrade_config || key_manager.is_current(config) {
+        return Ok(EnclaveResponse::WalletUnlocked {
+            session,
+            address,
+            upgraded,
+            config: None,
+        });
+    }
+
+    match reseal_under_new_config(key_manager, wallets, &session, password, context).await {
+        Ok((config, wallet)) => Ok(EnclaveResponse::WalletUnlocked {
+            session,
+            address,
+            upgraded: Some(wallet),
+            config: Some(config),
+        }),
+        Err(e) => {
+            wallets.lock(&session);
+            Err(e)
+        }
+    }
+}
+
+/// Opens `wallet` with the keys for `old_password` and reseals it under a fresh config
+/// for `new_password`
+///
+/// The wallet is only held for the duration of the request; any signing session the
+/// caller already has stays open.
+async fn change_password(
+    key_manager: &KeyManager,
+    wallets: &WalletStore,
+    config: &EncryptedKeyConfig,
+    old_password: &SecretPassword,
+    new_password: &SecretPassword,
+    wallet: &SealedWallet,
+    context: &str,
+) -> Result<EnclaveResponse, KeyManagerError> {
+    let (key1, key2) = key_manager.verify_and_derive_keys_for(config, old_password).await?;
+    let (session, _, _) = wallets.unlock(&key1, &key2, wallet, context)?;
+
+    let resealed = reseal_under_new_config(key_manager, wallets, &session, new_password, context).await;
+    wallets.lock(&session);
+    let (config, wallet) = resealed?;
+    Ok(EnclaveResponse::PasswordChanged { config, wallet })
+}
+
+/// Creates a config for `password` with the key manager's parameters and reseals the
+/// wallet unlocked as `session` under its keys
+async fn reseal_under_new_config(
+    key_manager: &KeyManager,
+    wallets: &WalletStore,
+    session: &str,
+    password: &SecretPassword,
+    context: &str,
+) -> Result<(EncryptedKeyConfig, SealedWallet), KeyManagerError> {
+    let config = key_manager.create_config(password).await?;
+    let (key1, key2) = key_manager.verify_and_derive_keys_for(&config, password).await?;
+    let wallet = wallets.reseal(session, &key1, &key2, context)?;
+    Ok((config, wallet))
+}
+
+fn signature(result: Result<Vec<u8>, KeyManagerError>) -> EnclaveResponse {
+    match result {
+        Ok(signature) => EnclaveResponse::Signature { signature },
+        Err(e) => EnclaveResponse::error(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nine_sdk::protocol::{MIN_PROTOCOL_VERSION, WALLET_PROTOCOL_VERSION};
+    use nine_sdk::attestation::AttestationVerifier;
+    use nine_sdk::attestation::fake::FakeNsm;
+    use nine_sdk::secure_channel::ClientHandshake;
+    use nine_sdk::test_util::captured_logs;
+    use nine_sdk::{ClientConfig, ClientIdentity, EnclaveClient, Transport};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn session<'a>(key_manager: &'a KeyManager, wallets: &'a WalletStore, identity: &'a EnclaveIdentity) -> Session<'a> {
+        Session {
+            key_manager,
+            wallets,
+            identity,
+            trusted_clients: &[],
+            client_key: None,
+            attestor: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_before_hello_are_refused() {
+        let key_manager = KeyManager::new();
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let mut negotiated = None;
+        let request = EnclaveRequest::SetupConfig {
+            password: "pw".into(),
+        };
+
+        let response = dispatch(PROTOCOL_VERSION, request, &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::Error { code: ErrorCode::HandshakeRequired, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hello_negotiates_version() {
+        let key_manager = KeyManager::new();
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let mut negotiated = None;
+
+        let response = dispatch(PROTOCOL_VERSION, EnclaveRequest::hello(), &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(response, EnclaveResponse::Version { version } if version == PROTOCOL_VERSION));
+        assert_eq!(negotiated, Some(PROTOCOL_VERSION));
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_hello_is_rejected() {
+        let key_manager = KeyManager::new();
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let mut negotiated = None;
+        let hello = EnclaveRequest::Hello {
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+        };
+
+        let response = dispatch(PROTOCOL_VERSION, hello, &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::Error { code: ErrorCode::UnsupportedVersion, .. }
+        ));
+        assert_eq!(negotiated, None);
+    }
+
+    #[tokio::test]
+    async fn test_lockout_is_reported_as_a_failed_login_to_older_peers() {
+        let key_manager = KeyManager::new().with_lockout(nine_sdk::LockoutPolicy {
+            free_attempts: 0,
+            ..Default::default()
+        });
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let config = key_manager.create_config(&"pw".into()).await.unwrap();
+        let verify = || EnclaveRequest::VerifyAndDeriveKeys {
+            config: config.clone(),
+            password: "guess".into(),
+        };
+
+        let mut negotiated = Some(PROTOCOL_VERSION);
+        dispatch(PROTOCOL_VERSION, verify(), &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        let response = dispatch(PROTOCOL_VERSION, verify(), &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::Error { code: ErrorCode::LockedOut { .. }, .. }
+        ));
+
+        let old_version = LOCKOUT_PROTOCOL_VERSION - 1;
+        let mut negotiated = Some(old_version);
+        let response = dispatch(old_version, verify(), &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::Error { code: ErrorCode::AuthenticationFailed, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_storage_keys_go_only_to_trusted_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let transport = Transport::Tcp(listener.local_addr().unwrap());
+        let identity = Arc::new(EnclaveIdentity::generate());
+        let enclave_key = identity.public_key();
+        let bot = ClientIdentity::generate();
+        let trusted_clients: Arc<[ClientPublicKey]> = Arc::new([bot.public_key()]);
+        let enclave_identity = Arc::clone(&identity);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_connection(
+                    Box::pin(stream),
+                    Arc::new(KeyManager::new()),
+                    Arc::new(WalletStore::default()),
+                    Arc::clone(&enclave_identity),
+                    Arc::clone(&trusted_clients),
+                    None,
+                    FrameCodec::default(),
+                ));
+            }
+        });
+        let client = |identity: Option<ClientIdentity>| {
+            let config = ClientConfig {
+                identity,
+                ..ClientConfig::default()
+            };
+            EnclaveClient::with_config(transport.clone(), enclave_key, config)
+        };
+
+        let key = client(Some(bot)).storage_key("k1").await.unwrap();
+        assert_eq!(key, identity.derive_storage_key("k1"));
+
+        // Clients that don't authenticate, or aren't trusted, are refused
+        for untrusted in [None, Some(ClientIdentity::generate())] {
+            assert!(matches!(
+                client(untrusted).storage_key("k1").await,
+                Err(KeyManagerError::Unauthorized)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_older_peers_are_refused_storage_keys_with_a_code_they_know() {
+        let key_manager = KeyManager::new();
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let old_version = CLIENT_AUTH_PROTOCOL_VERSION - 1;
+        let mut negotiated = Some(old_version);
+        let request = EnclaveRequest::StorageKey {
+            key_id: "k1".to_string(),
+        };
+
+        let response = dispatch(old_version, request, &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(response, EnclaveResponse::Error { code: ErrorCode::AuthenticationFailed, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_version_mismatch_after_handshake() {
+        let key_manager = KeyManager::new();
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let mut negotiated = Some(MIN_PROTOCOL_VERSION);
+        let request = EnclaveRequest::SetupConfig {
+            password: "pw".into(),
+        };
+
+        let response = dispatch(PROTOCOL_VERSION + 1, request, &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::Error { code: ErrorCode::UnsupportedVersion, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_requests_newer_than_the_negotiated_version_are_refused() {
+        let key_manager = KeyManager::new();
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let config = key_manager.create_config(&"pw".into()).await.unwrap();
+        let create = || EnclaveRequest::CreateWallet {
+            config: config.clone(),
+            password: "pw".into(),
+            context: "user:1".to_string(),
+        };
+        let storage_key = || EnclaveRequest::StorageKey {
+            key_id: "k1".to_string(),
+        };
+
+        // A peer that negotiated the first version can't reach wallets or storage keys
+        let mut negotiated = Some(MIN_PROTOCOL_VERSION);
+        for request in [create(), storage_key()] {
+            let response = dispatch(MIN_PROTOCOL_VERSION, request, &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+            assert!(matches!(response, EnclaveResponse::Error { code: ErrorCode::UnsupportedVersion, .. }));
+        }
+
+        let version = WALLET_PROTOCOL_VERSION;
+        let mut negotiated = Some(version);
+        let response = dispatch(version, create(), &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(response, EnclaveResponse::WalletCreated { .. }));
+        let response = dispatch(version, storage_key(), &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(response, EnclaveResponse::Error { code: ErrorCode::UnsupportedVersion, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_outdated_config_is_rederived_on_unlock() {
+        let old = KeyManager::with_params(nine_sdk::KdfParams {
+            m_cost: 1024,
+            t_cost: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let config = old.create_config(&"pw".into()).await.unwrap();
+        let (key1, key2) = old.verify_and_derive_keys_for(&config, &"pw".into()).await.unwrap();
+        let wallets = WalletStore::default();
+        let wallet = wallets.create(&key1, &key2, "user:1").unwrap();
+        let unlock = || EnclaveRequest::UnlockWallet {
+            config: config.clone(),
+            password: "pw".into(),
+            wallet: wallet.clone(),
+            context: "user:1".to_string(),
+        };
+
+        let key_manager = KeyManager::new();
+        // Peers that predate config upgrades are left on their parameters
+        let response = process_request(unlock(), KDF_UPGRADE_PROTOCOL_VERSION - 1, &key_manager, &wallets).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::WalletUnlocked { upgraded: None, config: None, .. }
+        ));
+
+        let response = process_request(unlock(), KDF_UPGRADE_PROTOCOL_VERSION, &key_manager, &wallets).await;
+        let EnclaveResponse::WalletUnlocked {
+            address,
+            upgraded: Some(upgraded),
+            config: Some(rederived),
+            ..
+        } = response
+        else {
+            panic!("unexpected response: {:?}", response);
+        };
+        assert!(key_manager.is_current(&rederived));
+        assert_eq!(upgraded.address, address);
+
+        let (key1, key2) = key_manager.verify_and_derive_keys_for(&rederived, &"pw".into()).await.unwrap();
+        let (_, reopened, _) = wallets.unlock(&key1, &key2, &upgraded, "user:1").unwrap();
+        assert_eq!(reopened, address);
+    }
+
+    #[tokio::test]
+    async fn test_change_password_keeps_the_wallet() {
+        let key_manager = KeyManager::new();
+        let wallets = WalletStore::default();
+        let config = key_manager.create_config(&"old".into()).await.unwrap();
+        let (key1, key2) = key_manager.verify_and_derive_keys_for(&config, &"old".into()).await.unwrap();
+        let wallet = wallets.create(&key1, &key2, "user:1").unwrap();
+        let change = |old_password: &str| EnclaveRequest::ChangePassword {
+            config: config.clone(),
+            old_password: old_password.into(),
+            new_password: "new".into(),
+            wallet: wallet.clone(),
+            context: "user:1".to_string(),
+        };
+
+        let response = process_request(change("wrong"), PROTOCOL_VERSION, &key_manager, &wallets).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::Error { code: ErrorCode::AuthenticationFailed, .. }
+        ));
+
+        let response = process_request(change("old"), PROTOCOL_VERSION, &key_manager, &wallets).await;
+        let EnclaveResponse::PasswordChanged { config: changed, wallet: resealed } = response else {
+            panic!("unexpected response: {:?}", response);
+        };
+        assert_eq!(resealed.address, wallet.address);
+        assert!(key_manager.verify_and_derive_keys_for(&changed, &"old".into()).await.is_err());
+        let (key1, key2) = key_manager.verify_and_derive_keys_for(&changed, &"new".into()).await.unwrap();
+        let (_, address, _) = wallets.unlock(&key1, &key2, &resealed, "user:1").unwrap();
+        assert_eq!(address, wallet.address);
+    }
+
+    #[tokio::test]
+    async fn test_only_current_peers_get_recovery_phrases() {
+        let key_manager = KeyManager::new();
+        let wallets = WalletStore::default();
+        let config = key_manager.create_config(&"pw".into()).await.unwrap();
+        let create = || EnclaveRequest::CreateWallet {
+            config: config.clone(),
+            password: "pw".into(),
+            context: "user:1".to_string(),
+        };
+
+        let response = process_request(create(), HD_WALLET_PROTOCOL_VERSION - 1, &key_manager, &wallets).await;
+        let EnclaveResponse::WalletCreated { wallet, mnemonic: None } = response else {
+            panic!("unexpected response: {:?}", response);
+        };
+        assert_eq!(wallet.encrypted_mnemonic, None);
+
+        let response = process_request(create(), PROTOCOL_VERSION, &key_manager, &wallets).await;
+        let EnclaveResponse::WalletCreated { wallet, mnemonic: Some(_) } = response else {
+            panic!("unexpected response: {:?}", response);
+        };
+        assert!(wallet.encrypted_mnemonic.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_attest_binds_channel_key() {
+        let key_manager = KeyManager::new();
+        let identity = EnclaveIdentity::generate();
+        let wallets = WalletStore::default();
+        let mut negotiated = Some(PROTOCOL_VERSION);
+        let attest = || EnclaveRequest::Attest {
+            nonce: b"nonce".to_vec(),
+        };
+
+        let response = dispatch(PROTOCOL_VERSION, attest(), &mut negotiated, &session(&key_manager, &wallets, &identity)).await;
+        assert!(matches!(
+            response,
+            EnclaveResponse::Error { code: ErrorCode::AttestationUnavailable, .. }
+        ));
+
+        let nsm = FakeNsm::new(Default::default()).unwrap();
+        let session = Session {
+            attestor: Some(&nsm),
+            ..session(&key_manager, &wallets, &identity)
+        };
+        let document = match dispatch(PROTOCOL_VERSION, attest(), &mut negotiated, &session).await {
+            EnclaveResponse::Attestation { document } => document,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let doc = AttestationVerifier::new(nsm.root_certificate().to_vec())
+            .verify(&document, b"nonce")
+            .unwrap();
+        assert_eq!(
+            doc.public_key.as_deref().map(Vec::as_slice),
+            Some(identity.public_key().as_bytes().as_slice())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_logged_without_credentials() {
+        captured_logs();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let identity = Arc::new(EnclaveIdentity::generate());
+        let client = EnclaveClient::new(Transport::Tcp(listener.local_addr().unwrap()), identity.public_key());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(
+                Box::pin(stream),
+                Arc::new(KeyManager::new()),
+                Arc::new(WalletStore::default()),
+                identity,
+                Arc::new([]),
+                None,
+                FrameCodec::default(),
+            )
+            .await
+        });
+
+        let password = SecretPassword::from("log-leak-canary");
+        let config: EncryptedKeyConfig = serde_json::from_str(&client.setup_config(&password).await.unwrap()).unwrap();
+        client.verify_and_derive_keys(&config, &password).await.unwrap();
+
+        let logs = captured_logs();
+        assert!(logs.iter().any(|line| line.contains("VerifyAndDeriveKeys")));
+        for line in &logs {
+            assert!(!line.contains("log-leak-canary"), "password logged: {}", line);
+            assert!(!line.contains(&config.password_hash), "password hash logged: {}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let identity = Arc::new(EnclaveIdentity::generate());
+        let codec = FrameCodec::new(512, Duration::from_secs(1));
+        let server = tokio::spawn(handle_connection(
+            Box::pin(server),
+            Arc::new(KeyManager::new()),
+            Arc::new(WalletStore::default()),
+            Arc::clone(&identity),
+            Arc::new([]),
+            None,
+            codec,
+        ));
+
+        // Complete the handshake by hand so the raw stream stays available
+        let (handshake, hello) = ClientHandshake::start(&identity.public_key());
+        codec.write_frame(&mut client, &hello).await.unwrap();
+        let reply = codec.read_frame(&mut client).await.unwrap().unwrap();
+        let mut ciphers = handshake.finish(&reply).unwrap();
+
+        client.write_all(&4096u32.to_be_bytes()).await.unwrap();
+
+        let reply = codec.read_frame(&mut client).await.unwrap().unwrap();
+        let reply: ResponseEnvelope = serde_json::from_slice(&ciphers.open(&reply).unwrap()).unwrap();
+        assert!(matches!(
+            reply.response,
+            EnclaveResponse::Error { code: ErrorCode::MalformedRequest, .. }
+        ));
+        assert!(server.await.unwrap().is_err());
+    }
+}
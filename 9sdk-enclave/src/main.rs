@@ -1,14 +1,20 @@
-use nine_sdk::{KeyManager, EnclaveRequest, EnclaveResponse, Transport, listen};
+use nine_sdk::protocol::{ErrorCode, PROTOCOL_VERSION, RequestEnvelope, ResponseEnvelope, negotiate_version};
+use nine_sdk::framing::{DEFAULT_FRAME_TIMEOUT, DEFAULT_MAX_FRAME_SIZE};
+use nine_sdk::attestation::Attestor;
+use nine_sdk::secure_channel::{self, ChannelError, ClientPublicKey, EnclaveIdentity};
+use nine_sdk::transport::DEFAULT_UNIX_SOCKET_MODE;
+use nine_sdk::{KeyManager, KdfParams, LockoutPolicy, DerivedKey, SecretPassword, EncryptedKeyConfig, EnclaveRequest, EnclaveResponse, FrameCodec, FramingError, Transport, TransportListener};
+use nine_sdk_enclave::handle_connection;
+use nine_sdk_enclave::wallet::{DEFAULT_SESSION_TTL, WalletStore};
 use std::env;
+use std::net::TcpListener;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
-use std::pin::Pin;
+use std::time::Duration;
 use argon2::Argon2;
 use password_hash::{PasswordHash, SaltString, PasswordHasher, PasswordVerifier};
 use rand_core::RngCore;
-use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use zeroize::Zeroizing;
 
 #[derive(Error, Debug)]
 pub enum EnclaveError {
@@ -22,44 +28,46 @@ pub enum EnclaveError {
     SocketError(String),
     #[error("Serialization error: {0}")]
     SerializationError(#[from] serde_json::Error),
+    #[error("Framing error: {0}")]
+    FramingError(#[from] FramingError),
+    #[error("Secure channel error: {0}")]
+    ChannelError(#[from] ChannelError),
 }
 
-/// Configuration for the enclave
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct EnclaveConfig {
-    password_hash: String,
-    salt1: String,
-    salt2: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum EnclaveRequest {
-    SetupConfig { password: String },
-    VerifyAndDeriveKeys { password: String },
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum EnclaveResponse {
-    ConfigSetup { config: String },
-    Keys { key1: Vec<u8>, key2: Vec<u8> },
-    Error { message: String },
+impl EnclaveError {
+    /// Wire error code reported to the client for this error
+    fn code(&self) -> ErrorCode {
+        match self {
+            EnclaveError::AuthenticationFailed => ErrorCode::AuthenticationFailed,
+            EnclaveError::KeyGenerationError(_) => ErrorCode::KeyGeneration,
+            EnclaveError::InvalidConfig => ErrorCode::InvalidConfig,
+            EnclaveError::SerializationError(_) => ErrorCode::MalformedRequest,
+            EnclaveError::SocketError(_)
+            | EnclaveError::FramingError(_)
+            | EnclaveError::ChannelError(_) => ErrorCode::Internal,
+        }
+    }
 }
 
 /// Manager for handling sensitive operations in the enclave
 pub struct EnclaveManager {
-    config: Option<EnclaveConfig>,
+    config: Option<EncryptedKeyConfig>,
     listener: TcpListener,
+    codec: FrameCodec,
+    identity: EnclaveIdentity,
 }
 
 impl EnclaveManager {
     /// Creates a new instance of the enclave manager
-    pub fn new() -> Result<Self, EnclaveError> {
+    pub fn new(identity: EnclaveIdentity) -> Result<Self, EnclaveError> {
         let listener = TcpListener::bind("0.0.0.0:8080")
             .map_err(|e| EnclaveError::SocketError(e.to_string()))?;
 
         Ok(Self {
             config: None,
             listener,
+            codec: FrameCodec::default(),
+            identity,
         })
     }
 
@@ -71,23 +79,36 @@ impl EnclaveManager {
                 .accept()
                 .map_err(|e| EnclaveError::SocketError(e.to_string()))?
                 .0;
+            stream
+                .set_read_timeout(Some(self.codec.frame_timeout()))
+                .map_err(|e| EnclaveError::SocketError(e.to_string()))?;
 
-            // Read request length first
-            let mut length_buf = [0u8; 4];
-            if let Err(e) = stream.read_exact(&mut length_buf) {
-                log::warn!("Error reading message length: {}", e);
-                continue; // Connection closed or error, try next connection
-            }
-            let length = u32::from_be_bytes(length_buf) as usize;
+            let mut ciphers = match self.handshake(&mut stream) {
+                Ok(Some(ciphers)) => ciphers,
+                Ok(None) => continue, // Connection closed before the handshake
+                Err(e) => {
+                    log::warn!("Secure channel handshake failed: {}", e);
+                    continue;
+                }
+            };
 
-            // Then read the exact amount of bytes
-            let mut buffer = vec![0u8; length];
-            if let Err(e) = stream.read_exact(&mut buffer) {
-                log::warn!("Error reading message body: {}", e);
-                continue; // Error reading request
-            }
+            let buffer = match self.codec.read_frame_blocking(&mut stream) {
+                Ok(Some(buffer)) => buffer,
+                Ok(None) => continue, // Connection closed before a request
+                Err(e) => {
+                    log::warn!("Error reading request frame: {}", e);
+                    continue; // Error reading request, try next connection
+                }
+            };
+            let buffer = match ciphers.open(&buffer) {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    log::warn!("Rejecting request frame: {}", e);
+                    continue;
+                }
+            };
 
-            let request: EnclaveRequest = match serde_json::from_slice(&buffer) {
+            let envelope: RequestEnvelope = match serde_json::from_slice(&buffer) {
                 Ok(req) => req,
                 Err(e) => {
                     log::error!("Error deserializing request: {}", e);
@@ -96,48 +117,82 @@ impl EnclaveManager {
             };
 
             // Process request
-            let response = self.handle_request(request)?;
-
-            // Send response with length prefix
-            let response_bytes = serde_json::to_vec(&response)
-                .map_err(|e| EnclaveError::SocketError(e.to_string()))?;
-            let length = (response_bytes.len() as u32).to_be_bytes();
+            let response = ResponseEnvelope {
+                version: PROTOCOL_VERSION,
+                request_id: envelope.request_id,
+                response: self.handle_request(envelope.request)?,
+            };
 
-            // Write length prefix first
-            stream
-                .write_all(&length)
-                .map_err(|e| EnclaveError::SocketError(e.to_string()))?;
-            // Then write the actual response
-            stream
-                .write_all(&response_bytes)
-                .map_err(|e| EnclaveError::SocketError(e.to_string()))?;
+            let response_bytes = ciphers.seal(&serde_json::to_vec(&response)?)?;
+            self.codec.write_frame_blocking(&mut stream, &response_bytes)?;
         }
     }
 
+    /// Answers the client's secure channel handshake on a blocking stream
+    fn handshake(&self, stream: &mut std::net::TcpStream) -> Result<Option<secure_channel::CipherPair>, EnclaveError> {
+        let hello = match self.codec.read_frame_blocking(stream)? {
+            Some(hello) => hello,
+            None => return Ok(None),
+        };
+        // The legacy server derives no storage keys, so the client's identity doesn't matter
+        let (reply, ciphers, _) = secure_channel::respond(&self.identity, &hello)?;
+        self.codec.write_frame_blocking(stream, &reply)?;
+        Ok(Some(ciphers))
+    }
+
     /// Handles an incoming request
     fn handle_request(&mut self, request: EnclaveRequest) -> Result<EnclaveResponse, EnclaveError> {
         match request {
+            EnclaveRequest::Hello { version, min_version } => {
+                match negotiate_version(version, min_version) {
+                    Some(version) => Ok(EnclaveResponse::Version { version }),
+                    None => Ok(EnclaveResponse::Error {
+                        code: ErrorCode::UnsupportedVersion,
+                        message: format!("enclave speaks version {}", PROTOCOL_VERSION),
+                    }),
+                }
+            }
             EnclaveRequest::SetupConfig { password } => {
                 let config = self.setup_config(&password)?;
                 Ok(EnclaveResponse::ConfigSetup { config })
             }
-            EnclaveRequest::VerifyAndDeriveKeys { password } => {
+            EnclaveRequest::VerifyAndDeriveKeys { config, password } => {
+                self.config = Some(config);
                 match self.verify_and_derive_keys(&password) {
-                    Ok((key1, key2)) => Ok(EnclaveResponse::Keys {
-                        key1: key1.to_vec(),
-                        key2: key2.to_vec(),
-                    }),
+                    Ok((key1, key2)) => Ok(EnclaveResponse::Keys { key1, key2 }),
                     Err(e) => Ok(EnclaveResponse::Error {
+                        code: e.code(),
                         message: e.to_string(),
                     }),
                 }
             }
+            EnclaveRequest::Attest { .. } => Ok(EnclaveResponse::Error {
+                code: ErrorCode::AttestationUnavailable,
+                message: "legacy server does not support attestation".to_string(),
+            }),
+            EnclaveRequest::StorageKey { .. } => Ok(EnclaveResponse::Error {
+                code: ErrorCode::MalformedRequest,
+                message: "legacy server does not derive storage keys".to_string(),
+            }),
+            EnclaveRequest::CreateWallet { .. }
+            | EnclaveRequest::ImportWallet { .. }
+            | EnclaveRequest::UnlockWallet { .. }
+            | EnclaveRequest::LockWallet { .. }
+            | EnclaveRequest::ChangePassword { .. }
+            | EnclaveRequest::SelectAccount { .. }
+            | EnclaveRequest::SignMessage { .. }
+            | EnclaveRequest::SignTypedData { .. }
+            | EnclaveRequest::SignTransaction { .. } => Ok(EnclaveResponse::Error {
+                code: ErrorCode::MalformedRequest,
+                message: "legacy server does not hold wallets".to_string(),
+            }),
         }
     }
 
     /// Sets up the enclave configuration with a password
-    pub fn setup_config(&mut self, password: &str) -> Result<String, EnclaveError> {
-        let password_hash = hash_password(password)?;
+    pub fn setup_config(&mut self, password: &SecretPassword) -> Result<String, EnclaveError> {
+        let kdf = KdfParams::default();
+        let password_hash = hash_password(&kdf_argon2(&kdf)?, password.expose())?;
 
         let mut salt1 = [0u8; 16];
         let mut salt2 = [0u8; 16];
@@ -145,10 +200,11 @@ impl EnclaveManager {
         rng.fill_bytes(&mut salt1);
         rng.fill_bytes(&mut salt2);
 
-        let config = EnclaveConfig {
+        let config = EncryptedKeyConfig {
             password_hash,
             salt1: hex::encode(&salt1),
             salt2: hex::encode(&salt2),
+            kdf,
         };
 
         self.config = Some(config.clone());
@@ -158,12 +214,14 @@ impl EnclaveManager {
     /// Verifies a password and derives encryption keys
     pub fn verify_and_derive_keys(
         &self,
-        password: &str,
-    ) -> Result<([u8; 32], [u8; 32]), EnclaveError> {
+        password: &SecretPassword,
+    ) -> Result<(DerivedKey, DerivedKey), EnclaveError> {
         let cfg = self.config.as_ref().ok_or(EnclaveError::InvalidConfig)?;
+        let argon2 = kdf_argon2(&cfg.kdf)?;
+        let password = password.expose();
 
         // Verify password
-        if !verify_password(password, &cfg.password_hash) {
+        if !verify_password(&argon2, password, &cfg.password_hash) {
             return Err(EnclaveError::AuthenticationFailed);
         }
 
@@ -174,18 +232,22 @@ impl EnclaveManager {
             hex::decode(&cfg.salt2).map_err(|e| EnclaveError::KeyGenerationError(e.to_string()))?;
 
         // Derive keys
-        let key1 = derive_key(password, &salt1)?;
-        let key2 = derive_key(password, &salt2)?;
+        let key1 = derive_key(&argon2, password, &salt1)?;
+        let key2 = derive_key(&argon2, password, &salt2)?;
 
         Ok((key1, key2))
     }
 }
 
+/// Argon2 instance for the parameters a config was derived with
+fn kdf_argon2(kdf: &KdfParams) -> Result<Argon2<'static>, EnclaveError> {
+    kdf.argon2().map_err(|e| EnclaveError::KeyGenerationError(e.to_string()))
+}
+
 /// Hashes a password using Argon2
-fn hash_password(password: &str) -> Result<String, EnclaveError> {
+fn hash_password(argon2: &Argon2, password: &str) -> Result<String, EnclaveError> {
     let mut rng = rand::thread_rng();
     let salt = SaltString::generate(&mut rng);
-    let argon2 = Argon2::default();
 
     Ok(argon2
         .hash_password(password.as_bytes(), &salt)
@@ -194,12 +256,11 @@ fn hash_password(password: &str) -> Result<String, EnclaveError> {
 }
 
 /// Verifies a password against a stored hash
-fn verify_password(password: &str, stored_hash: &str) -> bool {
+fn verify_password(argon2: &Argon2, password: &str, stored_hash: &str) -> bool {
     let parsed_hash = match PasswordHash::new(stored_hash) {
         Ok(hash) => hash,
         Err(_) => return false,
     };
-    let argon2 = Argon2::default();
 
     argon2
         .verify_password(password.as_bytes(), &parsed_hash)
@@ -207,15 +268,65 @@ fn verify_password(password: &str, stored_hash: &str) -> bool {
 }
 
 /// Derives a 256-bit key from a password and salt using Argon2
-fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], EnclaveError> {
-    let mut key = [0u8; 32];
-    let argon2 = Argon2::default();
+fn derive_key(argon2: &Argon2, password: &str, salt: &[u8]) -> Result<DerivedKey, EnclaveError> {
+    let mut key = Zeroizing::new([0u8; 32]);
 
     argon2
-        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .hash_password_into(password.as_bytes(), salt, key.as_mut())
         .map_err(|e| EnclaveError::KeyGenerationError(e.to_string()))?;
 
-    Ok(key)
+    Ok(DerivedKey::from(*key))
+}
+
+/// Reads a numeric setting from the environment, falling back to `default`
+fn env_u32(name: &str, default: u32) -> u32 {
+    env::var(name)
+        .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {}", name)))
+        .unwrap_or(default)
+}
+
+/// Reads the clients trusted with storage keys from `TRUSTED_CLIENT_KEYS`, a comma-separated
+/// list of hex public keys
+fn trusted_clients() -> Result<Arc<[ClientPublicKey]>, ChannelError> {
+    let keys = env::var("TRUSTED_CLIENT_KEYS").unwrap_or_default();
+    let keys = keys
+        .split(',')
+        .filter(|key| !key.trim().is_empty())
+        .map(ClientPublicKey::from_hex)
+        .collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+        log::warn!("TRUSTED_CLIENT_KEYS not set, storage keys will be refused to every client");
+    }
+    Ok(keys.into())
+}
+
+/// Picks the attestation source from `ATTESTATION`: `nsm`, `fake`, or unset for none
+fn create_attestor() -> Result<Option<Arc<dyn Attestor>>, Box<dyn std::error::Error>> {
+    match env::var("ATTESTATION").as_deref() {
+        #[cfg(feature = "nsm")]
+        Ok("nsm") => {
+            log::info!("Attesting through the Nitro Security Module");
+            Ok(Some(Arc::new(nine_sdk_enclave::nsm::NsmAttestor::open()?)))
+        }
+        #[cfg(feature = "fake-nsm")]
+        Ok("fake") => {
+            use nine_sdk::attestation::fake::FakeNsm;
+            // Debug-mode enclaves report all-zero PCRs; the fake does the same
+            let pcrs = (0..3).map(|index| (index, vec![0u8; 48])).collect();
+            let nsm = FakeNsm::new(pcrs)?;
+            log::warn!("Using the fake NSM; attestation documents prove nothing");
+            if let Ok(path) = env::var("FAKE_NSM_ROOT_CERT") {
+                std::fs::write(&path, nsm.root_certificate())?;
+                log::info!("Wrote fake NSM root certificate to {}", path);
+            }
+            Ok(Some(Arc::new(nsm)))
+        }
+        Ok(other) if !other.is_empty() => Err(format!("Unsupported ATTESTATION source: {}", other).into()),
+        _ => {
+            log::info!("Attestation disabled");
+            Ok(None)
+        }
+    }
 }
 
 #[tokio::main]
@@ -223,7 +334,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     pretty_env_logger::init();
     log::info!("9SDK Enclave starting...");
 
-    let key_manager = Arc::new(Mutex::new(KeyManager::new()));
+    // New and outdated configs are derived with these; existing ones keep working
+    let defaults = KdfParams::default();
+    let kdf = KdfParams {
+        m_cost: env_u32("ARGON2_M_COST", defaults.m_cost),
+        t_cost: env_u32("ARGON2_T_COST", defaults.t_cost),
+        parallelism: env_u32("ARGON2_PARALLELISM", defaults.parallelism),
+        ..defaults
+    };
+    log::info!(
+        "Deriving keys with Argon2id m={} t={} p={}",
+        kdf.m_cost, kdf.t_cost, kdf.parallelism
+    );
+    // Password guesses against a config are throttled here, whatever the bot does
+    let lockout_defaults = LockoutPolicy::default();
+    let lockout = LockoutPolicy {
+        free_attempts: env_u32("LOCKOUT_FREE_ATTEMPTS", lockout_defaults.free_attempts),
+        base_delay: Duration::from_secs(
+            env_u32("LOCKOUT_BASE_DELAY_SECS", lockout_defaults.base_delay.as_secs() as u32).into(),
+        ),
+        max_delay: Duration::from_secs(
+            env_u32("LOCKOUT_MAX_DELAY_SECS", lockout_defaults.max_delay.as_secs() as u32).into(),
+        ),
+        ..lockout_defaults
+    };
+    let key_manager = Arc::new(KeyManager::with_params(kdf)?.with_lockout(lockout));
+
+    // Clients pin the public half of this key; it must stay stable across restarts
+    let identity = match env::var("ENCLAVE_STATIC_KEY") {
+        Ok(secret) => EnclaveIdentity::from_hex(&secret)?,
+        Err(_) => {
+            log::warn!("ENCLAVE_STATIC_KEY not set, generating an ephemeral identity");
+            EnclaveIdentity::generate()
+        }
+    };
+    log::info!("Enclave public key: {}", identity.public_key().to_hex());
+    let identity = Arc::new(identity);
+    let trusted_clients = trusted_clients()?;
+    let attestor = create_attestor()?;
+
+    // Unlocked wallets are dropped after this long without signing
+    let session_ttl = env::var("WALLET_SESSION_TTL_SECS")
+        .map(|v| Duration::from_secs(v.parse().expect("Invalid WALLET_SESSION_TTL_SECS")))
+        .unwrap_or(DEFAULT_SESSION_TTL);
+    let wallets = Arc::new(WalletStore::new(session_ttl));
+
+    let max_frame_size = env::var("MAX_FRAME_SIZE")
+        .map(|v| v.parse::<usize>().expect("Invalid MAX_FRAME_SIZE"))
+        .unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+    let frame_timeout = env::var("FRAME_TIMEOUT_SECS")
+        .map(|v| Duration::from_secs(v.parse().expect("Invalid FRAME_TIMEOUT_SECS")))
+        .unwrap_or(DEFAULT_FRAME_TIMEOUT);
+    let codec = FrameCodec::new(max_frame_size, frame_timeout);
 
     // Determine transport based on environment
     let transport = if env::var("USE_VSOCK").as_deref() == Ok("true") {
@@ -242,13 +404,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         
         #[cfg(feature = "vsock")]
         {
-            Transport::Vsock(cid, port)
+            Transport::Vsock { cid, port }
         }
         #[cfg(not(feature = "vsock"))]
         {
             log::error!("vsock feature not enabled!");
             return Err("vsock feature not enabled".into());
         }
+    } else if let Ok(path) = env::var("UNIX_SOCKET") {
+        log::info!("Using Unix socket transport: {}", path);
+        Transport::Unix(path.into())
     } else {
         let addr = env::var("TCP_ADDRESS")
             .unwrap_or_else(|_| "0.0.0.0:5005".to_string());
@@ -256,15 +421,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Transport::Tcp(addr.parse()?)
     };
 
+    let listener = match transport {
+        Transport::Unix(path) => {
+            let mode = env::var("UNIX_SOCKET_MODE")
+                .map(|v| u32::from_str_radix(&v, 8).expect("Invalid UNIX_SOCKET_MODE"))
+                .unwrap_or(DEFAULT_UNIX_SOCKET_MODE);
+            TransportListener::bind_unix(path, mode)?
+        }
+        transport => TransportListener::bind(transport).await?,
+    };
+
     loop {
         log::info!("Waiting for connection...");
-        match listen(transport.clone()).await {
-            Ok(mut stream) => {
+        match listener.accept().await {
+            Ok(stream) => {
                 log::info!("Connection established");
                 let key_manager = Arc::clone(&key_manager);
+                let wallets = Arc::clone(&wallets);
+                let identity = Arc::clone(&identity);
+                let trusted_clients = Arc::clone(&trusted_clients);
+                let attestor = attestor.clone();
                 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream, key_manager).await {
+                    if let Err(e) = handle_connection(stream, key_manager, wallets, identity, trusted_clients, attestor, codec).await {
                         log::error!("Error handling connection: {}", e);
                     }
                 });
@@ -276,73 +455,3 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 }
-
-async fn handle_connection(
-    mut stream: Pin<Box<dyn nine_sdk::transport::TransportStream>>,
-    key_manager: Arc<Mutex<KeyManager>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-        // Read message length
-        let mut length_buf = [0u8; 4];
-        match stream.read_exact(&mut length_buf).await {
-            Ok(_) => {},
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                log::info!("Client disconnected");
-                break;
-            }
-            Err(e) => return Err(e.into()),
-        }
-        
-        let length = u32::from_be_bytes(length_buf) as usize;
-        
-        // Read message body
-        let mut buffer = vec![0u8; length];
-        stream.read_exact(&mut buffer).await?;
-        
-        // Process request
-        let request: EnclaveRequest = serde_json::from_slice(&buffer)?;
-        log::info!("Received request: {:?}", request);
-        
-        let response = process_request(request, &key_manager).await;
-        
-        // Send response
-        let response_bytes = serde_json::to_vec(&response)?;
-        let length_bytes = (response_bytes.len() as u32).to_be_bytes();
-        stream.write_all(&length_bytes).await?;
-        stream.write_all(&response_bytes).await?;
-        stream.flush().await?;
-        
-        log::info!("Sent response");
-    }
-    
-    Ok(())
-}
-
-async fn process_request(
-    request: EnclaveRequest,
-    key_manager: &Arc<Mutex<KeyManager>>,
-) -> EnclaveResponse {
-    match request {
-        EnclaveRequest::SetupConfig { password } => {
-            let mut km = key_manager.lock().await;
-            match km.setup_config(&password).await {
-                Ok(config) => EnclaveResponse::ConfigSetup { config },
-                Err(e) => EnclaveResponse::Error {
-                    message: e.to_string(),
-                },
-            }
-        }
-        EnclaveRequest::VerifyAndDeriveKeys { password } => {
-            let km = key_manager.lock().await;
-            match km.verify_and_derive_keys(&password).await {
-                Ok((key1, key2)) => EnclaveResponse::Keys {
-                    key1: key1.to_vec(),
-                    key2: key2.to_vec(),
-                },
-                Err(e) => EnclaveResponse::Error {
-                    message: e.to_string(),
-                },
-            }
-        }
-    }
-}
@@ -0,0 +1,43 @@
+//! Attestation through the Nitro Security Module device
+
+use aws_nitro_enclaves_nsm_api::api::{Request, Response};
+use aws_nitro_enclaves_nsm_api::driver::{nsm_exit, nsm_init, nsm_process_request};
+use nine_sdk::attestation::{AttestationError, Attestor};
+use serde_bytes::ByteBuf;
+
+/// Requests attestation documents from `/dev/nsm`
+pub struct NsmAttestor {
+    fd: i32,
+}
+
+impl NsmAttestor {
+    /// Opens the NSM device; fails outside a Nitro enclave
+    pub fn open() -> Result<Self, AttestationError> {
+        let fd = nsm_init();
+        if fd < 0 {
+            return Err(AttestationError::Unavailable("cannot open /dev/nsm".to_string()));
+        }
+        Ok(Self { fd })
+    }
+}
+
+impl Attestor for NsmAttestor {
+    fn attest(&self, nonce: &[u8], public_key: &[u8]) -> Result<Vec<u8>, AttestationError> {
+        let request = Request::Attestation {
+            user_data: None,
+            nonce: Some(ByteBuf::from(nonce)),
+            public_key: Some(ByteBuf::from(public_key)),
+        };
+        match nsm_process_request(self.fd, request) {
+            Response::Attestation { document } => Ok(document),
+            Response::Error(code) => Err(AttestationError::Unavailable(format!("NSM error: {:?}", code))),
+            other => Err(AttestationError::Unavailable(format!("unexpected NSM response: {:?}", other))),
+        }
+    }
+}
+
+impl Drop for NsmAttestor {
+    fn drop(&mut self) {
+        nsm_exit(self.fd);
+    }
+}
@@ -0,0 +1,744 @@
+//! Ethereum wallets that never leave the enclave
+//!
+//! Private keys are generated here and sealed under the user's first derived key, as a
+//! [`SealedBlob`] bound to a context naming the owner, before being handed to the bot for
+//! storage with a MAC under the second derived key over the whole record. Unlocking a
+//! sealed wallet keeps its signer in a [`WalletStore`] behind a random session token,
+//! which the bot presents to sign.
+//!
+//! New wallets come from a BIP-39 recovery phrase, sealed alongside the key of its first
+//! BIP-44 account. An unlocked session can switch to any other account of the phrase; the
+//! selection is part of the record, so switching returns a new MAC for it.
+//! Existing wallets can be imported from either a private key or a recovery phrase.
+
+use alloy_consensus::{SignableTransaction, TypedTransaction};
+use alloy_dyn_abi::TypedData;
+use alloy_eips::eip2718::Encodable2718;
+use alloy_network::TxSignerSync;
+use alloy_signer::SignerSync;
+use alloy_signer_local::coins_bip39::{English, Mnemonic};
+use alloy_signer_local::{MnemonicBuilder, PrivateKeySigner};
+use hmac::{Hmac, Mac};
+use nine_sdk::{DerivedKey, KeyManagerError, SealedBlob, SealedBlobError, SealedWallet, SecretBytes, decrypt_chacha20};
+use rand::{RngCore, thread_rng};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// How long an unlocked wallet stays usable without being signed with
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Domain separation for [`wallet_mac`]
+const MAC_CONTEXT: &[u8] = b"nine-sdk wallet mac v1";
+
+/// Words in a generated recovery phrase
+const MNEMONIC_WORDS: usize = 12;
+
+/// Appended to a wallet's context to bind its sealed recovery phrase
+const MNEMONIC_CONTEXT_SUFFIX: &str = "/mnemonic";
+
+/// Largest BIP-44 address index; higher ones would be hardened
+const MAX_ACCOUNT_INDEX: u32 = 0x7fff_ffff;
+
+struct Unlocked {
+    /// Signer for the sealed key, which is resealed when the wallet moves to new keys
+    signer: PrivateKeySigner,
+    mnemonic: Option<Zeroizing<String>>,
+    /// Account selected from `mnemonic`, if other than the sealed one
+    account: Option<PrivateKeySigner>,
+    /// The record as the bot stores it, and the key its MAC is under, for re-authenticating
+    /// it when another account is selected
+    wallet: SealedWallet,
+    mac_key: DerivedKey,
+    last_used: Instant,
+}
+
+/// Unlocked signers, keyed by session token
+///
+/// Sessions idle for longer than the TTL are dropped, so a bot that restarts without
+/// locking its users' wallets doesn't leave their keys resident forever.
+pub struct WalletStore {
+    sessions: Mutex<HashMap<String, Unlocked>>,
+    ttl: Duration,
+}
+
+impl Default for WalletStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_TTL)
+    }
+}
+
+impl WalletStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Generates a wallet, sealing its private key under `key` for `context` and
+    /// authenticating it under `mac_key`
+    pub fn create(&self, key: &DerivedKey, mac_key: &DerivedKey, context: &str) -> Result<SealedWallet, KeyManagerError> {
+        seal(&PrivateKeySigner::random(), None, 0, key, mac_key, context)
+    }
+
+    /// Generates a wallet from a fresh recovery phrase, sealing the phrase and its first
+    /// account's key like [`create`](Self::create), and returns the phrase for backup
+    pub fn create_with_mnemonic(
+        &self,
+        key: &DerivedKey,
+        mac_key: &DerivedKey,
+        context: &str,
+    ) -> Result<(SealedWallet, Zeroizing<String>), KeyManagerError> {
+        let mnemonic = Mnemonic::<English>::new_with_count(&mut thread_rng(), MNEMONIC_WORDS)
+            .map(|mnemonic| Zeroizing::new(mnemonic.to_phrase()))
+            .map_err(|e| KeyManagerError::KeyGenerationError(e.to_string()))?;
+        let signer = derive_account(&mnemonic, 0)?;
+        let wallet = seal(&signer, Some(&mnemonic), 0, key, mac_key, context)?;
+        Ok((wallet, mnemonic))
+    }
+
+    /// Seals an existing wallet like [`create`](Self::create) or
+    /// [`create_with_mnemonic`](Self::create_with_mnemonic) would a generated one
+    ///
+    /// `secret` is either a hex private key, with or without `0x`, or a recovery phrase.
+    /// Errors never say what was wrong with it, so the secret can't end up in a log.
+    pub fn import(
+        &self,
+        secret: &str,
+        key: &DerivedKey,
+        mac_key: &DerivedKey,
+        context: &str,
+    ) -> Result<SealedWallet, KeyManagerError> {
+        let secret = secret.trim();
+        if secret.contains(char::is_whitespace) {
+            let lowercase = Zeroizing::new(secret.to_lowercase());
+            let phrase = Zeroizing::new(lowercase.split_whitespace().collect::<Vec<_>>().join(" "));
+            let signer = derive_account(&phrase, 0).map_err(|_| KeyManagerError::InvalidImport)?;
+            seal(&signer, Some(&phrase), 0, key, mac_key, context)
+        } else {
+            let private_key = hex::decode(secret.strip_prefix("0x").unwrap_or(secret))
+                .map(SecretBytes::new)
+                .map_err(|_| KeyManagerError::InvalidImport)?;
+            let signer = PrivateKeySigner::from_slice(&private_key).map_err(|_| KeyManagerError::InvalidImport)?;
+            seal(&signer, None, 0, key, mac_key, context)
+        }
+    }
+
+    /// Checks `wallet`'s MAC, opens it with `key` and returns a session token for signing
+    /// with it, its address and, for wallets in an older format, a resealed replacement
+    ///
+    /// Legacy wallets without a MAC or a context binding are accepted once, so they can be
+    /// replaced with the returned one. Only the legacy ciphertext layout may lack a MAC;
+    /// sealed blobs always had one, so a sealed blob without it is refused.
+    pub fn unlock(
+        &self,
+        key: &DerivedKey,
+        mac_key: &DerivedKey,
+        wallet: &SealedWallet,
+        context: &str,
+    ) -> Result<(String, String, Option<SealedWallet>), KeyManagerError> {
+        let is_legacy = !wallet.nonce.is_empty();
+        match &wallet.mac {
+            Some(mac) => {
+                let mac = hex::decode(mac).map_err(|_| KeyManagerError::IntegrityCheckFailed)?;
+                wallet_mac(mac_key, wallet)
+                    .verify_slice(&mac)
+                    .map_err(|_| KeyManagerError::IntegrityCheckFailed)?;
+            }
+            None if is_legacy => {}
+            // Only legacy records predate the MAC, so a missing one means it was stripped
+            None => return Err(KeyManagerError::IntegrityCheckFailed),
+        }
+
+        let signer = if is_legacy {
+            unseal_legacy(wallet, key)?
+        } else {
+            signer_from_bytes(&unseal(&wallet.encrypted_private_key, key, context)?)?
+        };
+        let address = signer.address().to_checksum(None);
+        // The stored address is what the user sees, so it must belong to the sealed key
+        if !address.eq_ignore_ascii_case(&wallet.address) {
+            return Err(KeyManagerError::InvalidConfig);
+        }
+        let mnemonic = match &wallet.encrypted_mnemonic {
+            Some(blob) => {
+                let phrase = unseal(blob, key, &mnemonic_context(context))?;
+                let phrase = std::str::from_utf8(&phrase)
+                    .map(|phrase| Zeroizing::new(phrase.to_string()))
+                    .map_err(|e| KeyManagerError::DecryptionError(e.to_string()))?;
+                // The sealed key must be the phrase's first account
+                if derive_account(&phrase, 0)?.address() != signer.address() {
+                    return Err(KeyManagerError::InvalidConfig);
+                }
+                Some(phrase)
+            }
+            None => None,
+        };
+        let upgraded = if is_legacy || wallet.mac.is_none() {
+            let mnemonic = mnemonic.as_deref().map(String::as_str);
+            Some(seal(&signer, mnemonic, wallet.account_index, key, mac_key, context)?)
+        } else {
+            None
+        };
+
+        let mut token = [0u8; 32];
+        thread_rng().fill_bytes(&mut token);
+        let session = hex::encode(token);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, unlocked| unlocked.last_used.elapsed() < self.ttl);
+        sessions.insert(
+            session.clone(),
+            Unlocked {
+                signer,
+                mnemonic,
+                account: None,
+                wallet: upgraded.clone().unwrap_or_else(|| wallet.clone()),
+                mac_key: mac_key.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok((session, address, upgraded))
+    }
+
+    /// Seals the wallet unlocked as `session` afresh under `key` and `mac_key`, for moving
+    /// it to keys derived with different parameters or from a new password
+    ///
+    /// The session carries on with the resealed record, keeping its selected account.
+    pub fn reseal(
+        &self,
+        session: &str,
+        key: &DerivedKey,
+        mac_key: &DerivedKey,
+        context: &str,
+    ) -> Result<SealedWallet, KeyManagerError> {
+        self.with_session(session, |unlocked| {
+            let mnemonic = unlocked.mnemonic.as_deref().map(String::as_str);
+            let wallet = seal(&unlocked.signer, mnemonic, unlocked.wallet.account_index, key, mac_key, context)?;
+            unlocked.wallet = wallet.clone();
+            unlocked.mac_key = mac_key.clone();
+            Ok(wallet)
+        })
+    }
+
+    /// Signs with account `index` of the recovery phrase from now on, returning its address
+    /// and the wallet's MAC with `index` selected, to be stored along with it
+    ///
+    /// Index 0 is the wallet's own key.
+    pub fn select_account(&self, session: &str, index: u32) -> Result<(String, String), KeyManagerError> {
+        self.with_session(session, |unlocked| {
+            let mnemonic = unlocked.mnemonic.as_ref().ok_or(KeyManagerError::NotHdWallet)?;
+            let account = derive_account(mnemonic, index)?;
+            let address = account.address().to_checksum(None);
+            unlocked.account = (index != 0).then_some(account);
+            unlocked.wallet.account_index = index;
+            let mac = hex::encode(wallet_mac(&unlocked.mac_key, &unlocked.wallet).finalize().into_bytes());
+            unlocked.wallet.mac = Some(mac.clone());
+            Ok((address, mac))
+        })
+    }
+
+    pub fn lock(&self, session: &str) {
+        self.sessions.lock().unwrap().remove(session);
+    }
+
+    /// Signs `message` as an EIP-191 personal message
+    pub fn sign_message(&self, session: &str, message: &[u8]) -> Result<Vec<u8>, KeyManagerError> {
+        self.with_signer(session, |signer| {
+            let signature = signer
+                .sign_message_sync(message)
+                .map_err(|e| KeyManagerError::SigningError(e.to_string()))?;
+            Ok(signature.as_bytes().to_vec())
+        })
+    }
+
+    /// Signs an EIP-712 payload given as `TypedData` JSON
+    pub fn sign_typed_data(&self, session: &str, typed_data: &str) -> Result<Vec<u8>, KeyManagerError> {
+        let typed_data: TypedData = serde_json::from_str(typed_data)?;
+        self.with_signer(session, |signer| {
+            let signature = signer
+                .sign_dynamic_typed_data_sync(&typed_data)
+                .map_err(|e| KeyManagerError::SigningError(e.to_string()))?;
+            Ok(signature.as_bytes().to_vec())
+        })
+    }
+
+    /// Signs a `TypedTransaction` given as JSON and returns its EIP-2718 encoding
+    pub fn sign_transaction(&self, session: &str, transaction: &str) -> Result<Vec<u8>, KeyManagerError> {
+        let mut transaction: TypedTransaction = serde_json::from_str(transaction)?;
+        self.with_signer(session, |signer| {
+            let signature = signer
+                .sign_transaction_sync(&mut transaction)
+                .map_err(|e| KeyManagerError::SigningError(e.to_string()))?;
+            Ok(transaction.clone().into_signed(signature).encoded_2718())
+        })
+    }
+
+    fn with_signer<T>(
+        &self,
+        session: &str,
+        sign: impl FnOnce(&PrivateKeySigner) -> Result<T, KeyManagerError>,
+    ) -> Result<T, KeyManagerError> {
+        self.with_session(session, |unlocked| sign(unlocked.account.as_ref().unwrap_or(&unlocked.signer)))
+    }
+
+    fn with_session<T>(
+        &self,
+        session: &str,
+        f: impl FnOnce(&mut Unlocked) -> Result<T, KeyManagerError>,
+    ) -> Result<T, KeyManagerError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let unlocked = match sessions.get_mut(session) {
+            Some(unlocked) if unlocked.last_used.elapsed() < self.ttl => unlocked,
+            Some(_) => {
+                sessions.remove(session);
+                return Err(KeyManagerError::WalletLocked);
+            }
+            None => return Err(KeyManagerError::WalletLocked),
+        };
+        unlocked.last_used = Instant::now();
+        f(unlocked)
+    }
+}
+
+/// Seals `signer`'s private key, and the recovery phrase it came from if any, under `key`
+/// as blobs bound to `context`, with account `account_index` of the phrase selected
+fn seal(
+    signer: &PrivateKeySigner,
+    mnemonic: Option<&str>,
+    account_index: u32,
+    key: &DerivedKey,
+    mac_key: &DerivedKey,
+    context: &str,
+) -> Result<SealedWallet, KeyManagerError> {
+    let seal_blob = |plaintext: &[u8], context: &str| {
+        SealedBlob::seal(key.expose(), plaintext, context.as_bytes())
+            .map(|blob| hex::encode(blob.to_bytes()))
+            .map_err(|e| KeyManagerError::EncryptionError(e.to_string()))
+    };
+    let private_key = Zeroizing::new(signer.to_bytes().0);
+    let encrypted_mnemonic = mnemonic
+        .map(|phrase| seal_blob(phrase.as_bytes(), &mnemonic_context(context)))
+        .transpose()?;
+
+    let public_key = signer.credential().verifying_key().to_encoded_point(false);
+    let mut wallet = SealedWallet {
+        address: signer.address().to_checksum(None),
+        public_key: hex::encode(public_key.as_bytes()),
+        encrypted_private_key: seal_blob(private_key.as_slice(), context)?,
+        nonce: String::new(),
+        mac: None,
+        encrypted_mnemonic,
+        account_index,
+    };
+    wallet.mac = Some(hex::encode(wallet_mac(mac_key, &wallet).finalize().into_bytes()));
+    Ok(wallet)
+}
+
+/// HMAC over every field of `wallet` but the MAC itself, each prefixed with its length
+///
+/// The recovery phrase and the selected account are only covered when present, so MACs of
+/// older wallets still match.
+fn wallet_mac(mac_key: &DerivedKey, wallet: &SealedWallet) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key.expose()).expect("HMAC accepts any key length");
+    mac.update(MAC_CONTEXT);
+    // Tagged, so it can't pass for a hex sealed phrase
+    let account_index = (wallet.account_index != 0).then(|| format!("account:{}", wallet.account_index));
+    let fields = [&wallet.address, &wallet.public_key, &wallet.nonce, &wallet.encrypted_private_key];
+    for field in fields.into_iter().chain(&wallet.encrypted_mnemonic).chain(&account_index) {
+        mac.update(&(field.len() as u32).to_be_bytes());
+        mac.update(field.as_bytes());
+    }
+    mac
+}
+
+/// Opens a hex sealed blob bound to `context`
+fn unseal(blob: &str, key: &DerivedKey, context: &str) -> Result<SecretBytes, KeyManagerError> {
+    let blob = hex::decode(blob).map_err(|e| KeyManagerError::DecryptionError(e.to_string()))?;
+    SealedBlob::from_bytes(&blob)
+        .and_then(|blob| blob.open(key.expose(), context.as_bytes()))
+        .map_err(|e| match e {
+            // Another user's wallet copied into this record
+            SealedBlobError::ContextMismatch => KeyManagerError::IntegrityCheckFailed,
+            other => KeyManagerError::DecryptionError(other.to_string()),
+        })
+}
+
+fn mnemonic_context(context: &str) -> String {
+    format!("{}{}", context, MNEMONIC_CONTEXT_SUFFIX)
+}
+
+/// Derives the signer at `m/44'/60'/0'/0/{index}` from a recovery phrase
+fn derive_account(phrase: &str, index: u32) -> Result<PrivateKeySigner, KeyManagerError> {
+    if index > MAX_ACCOUNT_INDEX {
+        return Err(KeyManagerError::KeyGenerationError(format!("account index {} is out of range", index)));
+    }
+    MnemonicBuilder::<English>::default()
+        .phrase(phrase)
+        .index(index)
+        .and_then(|builder| builder.build())
+        .map_err(|e| KeyManagerError::KeyGenerationError(e.to_string()))
+}
+
+/// Opens a wallet stored before sealed blobs: a bare ciphertext of the hex private key
+fn unseal_legacy(wallet: &SealedWallet, key: &DerivedKey) -> Result<PrivateKeySigner, KeyManagerError> {
+    let decode = |field: &str| hex::decode(field).map_err(|e| KeyManagerError::DecryptionError(e.to_string()));
+    let nonce: [u8; 12] = decode(&wallet.nonce)?
+        .try_into()
+        .map_err(|_| KeyManagerError::DecryptionError("invalid nonce length".to_string()))?;
+    let private_key = decrypt_chacha20(key.expose(), &decode(&wallet.encrypted_private_key)?, &nonce)?;
+
+    let private_key = std::str::from_utf8(&private_key)
+        .map_err(|e| KeyManagerError::DecryptionError(e.to_string()))
+        .and_then(decode)
+        .map(SecretBytes::new)?;
+    signer_from_bytes(&private_key)
+}
+
+fn signer_from_bytes(private_key: &[u8]) -> Result<PrivateKeySigner, KeyManagerError> {
+    PrivateKeySigner::from_slice(private_key).map_err(|e| KeyManagerError::DecryptionError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_consensus::TxEnvelope;
+    use alloy_eips::eip2718::Decodable2718;
+    use alloy_primitives::Signature;
+
+    const CONTEXT: &str = "user:1";
+
+    fn key(byte: u8) -> DerivedKey {
+        DerivedKey::from([byte; 32])
+    }
+
+    fn unlocked(store: &WalletStore) -> (String, String) {
+        let wallet = store.create(&key(7), &key(9), CONTEXT).unwrap();
+        let (session, address, _) = store.unlock(&key(7), &key(9), &wallet, CONTEXT).unwrap();
+        (session, address)
+    }
+
+    /// A wallet as the bot stored it before sealed blobs and MACs
+    fn legacy_wallet(signer: &PrivateKeySigner) -> SealedWallet {
+        let nonce = [5u8; 12];
+        let private_key = hex::encode(signer.to_bytes());
+        let ciphertext = nine_sdk::encrypt_chacha20(key(7).expose(), private_key.as_bytes(), &nonce).unwrap();
+        SealedWallet {
+            address: signer.address().to_checksum(None),
+            public_key: hex::encode(signer.address()),
+            encrypted_private_key: hex::encode(ciphertext),
+            nonce: hex::encode(nonce),
+            mac: None,
+            encrypted_mnemonic: None,
+            account_index: 0,
+        }
+    }
+
+    #[test]
+    fn test_unlock_requires_the_sealing_key() {
+        let store = WalletStore::default();
+        let wallet = store.create(&key(7), &key(9), CONTEXT).unwrap();
+
+        assert!(matches!(
+            store.unlock(&key(8), &key(9), &wallet, CONTEXT),
+            Err(KeyManagerError::DecryptionError(_))
+        ));
+
+        // A sealed key paired with someone else's address is refused
+        let other = store.create(&key(7), &key(9), CONTEXT).unwrap();
+        let mut swapped = SealedWallet {
+            address: other.address,
+            ..wallet.clone()
+        };
+        swapped.mac = Some(hex::encode(wallet_mac(&key(9), &swapped).finalize().into_bytes()));
+        assert!(matches!(
+            store.unlock(&key(7), &key(9), &swapped, CONTEXT),
+            Err(KeyManagerError::InvalidConfig)
+        ));
+
+        let (_, address, upgraded) = store.unlock(&key(7), &key(9), &wallet, CONTEXT).unwrap();
+        assert_eq!(address, wallet.address);
+        assert_eq!(upgraded, None);
+    }
+
+    #[test]
+    fn test_wallet_is_bound_to_its_context() {
+        let store = WalletStore::default();
+        let wallet = store.create(&key(7), &key(9), CONTEXT).unwrap();
+
+        assert!(matches!(
+            store.unlock(&key(7), &key(9), &wallet, "user:2"),
+            Err(KeyManagerError::IntegrityCheckFailed)
+        ));
+    }
+
+    #[test]
+    fn test_legacy_wallet_is_resealed() {
+        let store = WalletStore::default();
+        let signer = PrivateKeySigner::random();
+        let legacy = legacy_wallet(&signer);
+
+        let (_, address, upgraded) = store.unlock(&key(7), &key(9), &legacy, CONTEXT).unwrap();
+        let upgraded = upgraded.unwrap();
+        assert_eq!(address, legacy.address);
+        assert_eq!(upgraded.address, legacy.address);
+        assert!(upgraded.nonce.is_empty());
+        assert!(upgraded.mac.is_some());
+
+        // The replacement is bound to the context and opens without further upgrades
+        assert!(store.unlock(&key(7), &key(9), &upgraded, "user:2").is_err());
+        let (_, _, again) = store.unlock(&key(7), &key(9), &upgraded, CONTEXT).unwrap();
+        assert_eq!(again, None);
+    }
+
+    #[test]
+    fn test_resealed_wallet_opens_under_the_new_keys() {
+        let store = WalletStore::default();
+        let (session, address) = unlocked(&store);
+
+        let resealed = store.reseal(&session, &key(1), &key(2), CONTEXT).unwrap();
+        assert_eq!(resealed.address, address);
+        assert!(store.unlock(&key(7), &key(9), &resealed, CONTEXT).is_err());
+        let (_, reopened, upgraded) = store.unlock(&key(1), &key(2), &resealed, CONTEXT).unwrap();
+        assert_eq!(reopened, address);
+        assert_eq!(upgraded, None);
+
+        store.lock(&session);
+        assert!(matches!(
+            store.reseal(&session, &key(1), &key(2), CONTEXT),
+            Err(KeyManagerError::WalletLocked)
+        ));
+    }
+
+    #[test]
+    fn test_known_phrases_derive_standard_addresses() {
+        let phrase = "test test test test test test test test test test test junk";
+        let addresses: Vec<String> = (0..2)
+            .map(|index| derive_account(phrase, index).unwrap().address().to_checksum(None))
+            .collect();
+        assert_eq!(
+            addresses,
+            ["0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266", "0x70997970C51812dc3A010C7d01b50e0d17dc79C8"]
+        );
+
+        let phrase = "fire evolve buddy tenant talent favorite ankle stem regret myth dream fresh";
+        assert_eq!(
+            derive_account(phrase, 2).unwrap().address().to_checksum(None),
+            "0x1D86AD5eBb2380dAdEAF52f61f4F428C485460E9"
+        );
+        assert!(derive_account(phrase, MAX_ACCOUNT_INDEX + 1).is_err());
+    }
+
+    #[test]
+    fn test_mnemonic_wallet_selects_accounts() {
+        let store = WalletStore::default();
+        let (wallet, mnemonic) = store.create_with_mnemonic(&key(7), &key(9), CONTEXT).unwrap();
+        assert_eq!(mnemonic.split_whitespace().count(), MNEMONIC_WORDS);
+        assert_eq!(wallet.address, derive_account(&mnemonic, 0).unwrap().address().to_checksum(None));
+
+        // The phrase is bound to the wallet's context like its key
+        assert!(matches!(
+            store.unlock(&key(7), &key(9), &wallet, "user:2"),
+            Err(KeyManagerError::IntegrityCheckFailed)
+        ));
+
+        let (session, address, upgraded) = store.unlock(&key(7), &key(9), &wallet, CONTEXT).unwrap();
+        assert_eq!(address, wallet.address);
+        assert_eq!(upgraded, None);
+
+        let (second, _) = store.select_account(&session, 1).unwrap();
+        assert_eq!(second, derive_account(&mnemonic, 1).unwrap().address().to_checksum(None));
+        assert_ne!(second, address);
+        let signature = store.sign_message(&session, b"hello").unwrap();
+        let signature = Signature::try_from(signature.as_slice()).unwrap();
+        assert_eq!(signature.recover_address_from_msg(b"hello").unwrap().to_checksum(None), second);
+
+        // Resealing keeps the phrase and the first account, along with the selection
+        let resealed = store.reseal(&session, &key(1), &key(2), CONTEXT).unwrap();
+        assert_eq!(resealed.address, address);
+        assert_eq!(resealed.account_index, 1);
+        let (session, _, _) = store.unlock(&key(1), &key(2), &resealed, CONTEXT).unwrap();
+        assert_eq!(store.select_account(&session, 1).unwrap().0, second);
+        assert_eq!(store.select_account(&session, 0).unwrap().0, address);
+
+        let (plain, _) = unlocked(&store);
+        assert!(matches!(store.select_account(&plain, 1), Err(KeyManagerError::NotHdWallet)));
+    }
+
+    #[test]
+    fn test_selected_account_is_covered_by_the_mac() {
+        let store = WalletStore::default();
+        let (wallet, _) = store.create_with_mnemonic(&key(7), &key(9), CONTEXT).unwrap();
+        let (session, _, _) = store.unlock(&key(7), &key(9), &wallet, CONTEXT).unwrap();
+
+        let (_, mac) = store.select_account(&session, 2).unwrap();
+        let selected = SealedWallet {
+            account_index: 2,
+            mac: Some(mac),
+            ..wallet.clone()
+        };
+        let (_, _, upgraded) = store.unlock(&key(7), &key(9), &selected, CONTEXT).unwrap();
+        assert_eq!(upgraded, None);
+
+        // Neither the old MAC nor the new one vouches for another selection
+        for tampered in [
+            SealedWallet { account_index: 2, ..wallet.clone() },
+            SealedWallet { account_index: 3, ..selected.clone() },
+            SealedWallet { account_index: 0, ..selected },
+        ] {
+            assert!(matches!(
+                store.unlock(&key(7), &key(9), &tampered, CONTEXT),
+                Err(KeyManagerError::IntegrityCheckFailed)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_import_accepts_keys_and_phrases() {
+        let store = WalletStore::default();
+        let signer = PrivateKeySigner::random();
+        let private_key = hex::encode(signer.to_bytes());
+        let address = signer.address().to_checksum(None);
+
+        for secret in [private_key.clone(), format!("  0x{}\n", private_key)] {
+            let wallet = store.import(&secret, &key(7), &key(9), CONTEXT).unwrap();
+            assert_eq!(wallet.address, address);
+            assert_eq!(wallet.encrypted_mnemonic, None);
+            let (_, unlocked_address, _) = store.unlock(&key(7), &key(9), &wallet, CONTEXT).unwrap();
+            assert_eq!(unlocked_address, address);
+        }
+
+        // Phrases are normalised, so the sealed one derives the same accounts
+        let (generated, mnemonic) = store.create_with_mnemonic(&key(7), &key(9), CONTEXT).unwrap();
+        let messy = format!(" {} ", mnemonic.to_uppercase().replace(' ', "  \n"));
+        let wallet = store.import(&messy, &key(7), &key(9), CONTEXT).unwrap();
+        assert_eq!(wallet.address, generated.address);
+        let (session, _, _) = store.unlock(&key(7), &key(9), &wallet, CONTEXT).unwrap();
+        assert_eq!(
+            store.select_account(&session, 1).unwrap().0,
+            derive_account(&mnemonic, 1).unwrap().address().to_checksum(None)
+        );
+
+        // Valid words with a bad checksum; the last word would have to be "about"
+        let bad_checksum = ["abandon"; 12].join(" ");
+        let invalid = [
+            String::new(),
+            "0x1234".to_string(),
+            "zz".repeat(32),
+            "00".repeat(32),
+            bad_checksum,
+            mnemonic[..mnemonic.rfind(' ').unwrap()].to_string(),
+        ];
+        for secret in invalid {
+            assert!(matches!(
+                store.import(&secret, &key(7), &key(9), CONTEXT),
+                Err(KeyManagerError::InvalidImport)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_tampered_wallet_fails_integrity_check() {
+        let store = WalletStore::default();
+        let wallet = store.create(&key(7), &key(9), CONTEXT).unwrap();
+        let other = store.create(&key(7), &key(9), CONTEXT).unwrap();
+
+        let tampered = [
+            SealedWallet { address: other.address.clone(), ..wallet.clone() },
+            SealedWallet { public_key: other.public_key.clone(), ..wallet.clone() },
+            SealedWallet { nonce: hex::encode([0u8; 12]), ..wallet.clone() },
+            SealedWallet { encrypted_private_key: other.encrypted_private_key.clone(), ..wallet.clone() },
+            SealedWallet { mac: Some("not hex".to_string()), ..wallet.clone() },
+            SealedWallet { encrypted_mnemonic: Some(other.encrypted_private_key.clone()), ..wallet.clone() },
+        ];
+        for tampered in tampered {
+            assert!(matches!(
+                store.unlock(&key(7), &key(9), &tampered, CONTEXT),
+                Err(KeyManagerError::IntegrityCheckFailed)
+            ));
+        }
+        assert!(matches!(
+            store.unlock(&key(7), &key(1), &wallet, CONTEXT),
+            Err(KeyManagerError::IntegrityCheckFailed)
+        ));
+    }
+
+    #[test]
+    fn test_sealed_wallet_without_mac_is_refused() {
+        let store = WalletStore::default();
+        let wallet = store.create(&key(7), &key(9), CONTEXT).unwrap();
+
+        let stripped = SealedWallet { mac: None, ..wallet };
+        assert!(matches!(
+            store.unlock(&key(7), &key(9), &stripped, CONTEXT),
+            Err(KeyManagerError::IntegrityCheckFailed)
+        ));
+    }
+
+    #[test]
+    fn test_signatures_recover_to_wallet_address() {
+        let store = WalletStore::default();
+        let (session, address) = unlocked(&store);
+
+        let signature = store.sign_message(&session, b"hello").unwrap();
+        let signature = Signature::try_from(signature.as_slice()).unwrap();
+        let signer = signature.recover_address_from_msg(b"hello").unwrap();
+        assert_eq!(signer.to_checksum(None), address);
+
+        let typed_data = serde_json::json!({
+            "types": {
+                "EIP712Domain": [{ "name": "name", "type": "string" }],
+                "Mail": [{ "name": "contents", "type": "string" }]
+            },
+            "primaryType": "Mail",
+            "domain": { "name": "meow" },
+            "message": { "contents": "hi" }
+        });
+        let signature = store.sign_typed_data(&session, &typed_data.to_string()).unwrap();
+        let typed_data: TypedData = serde_json::from_value(typed_data).unwrap();
+        let hash = typed_data.eip712_signing_hash().unwrap();
+        let signature = Signature::try_from(signature.as_slice()).unwrap();
+        assert_eq!(signature.recover_address_from_prehash(&hash).unwrap().to_checksum(None), address);
+    }
+
+    #[test]
+    fn test_signed_transaction_is_broadcastable() {
+        let store = WalletStore::default();
+        let (session, address) = unlocked(&store);
+        let transaction = serde_json::json!({
+            "type": "0x2",
+            "chainId": "0x1",
+            "nonce": "0x0",
+            "gas": "0x5208",
+            "maxFeePerGas": "0x3b9aca00",
+            "maxPriorityFeePerGas": "0x3b9aca00",
+            "to": "0x0000000000000000000000000000000000000001",
+            "value": "0x1",
+            "accessList": [],
+            "input": "0x"
+        });
+
+        let raw = store.sign_transaction(&session, &transaction.to_string()).unwrap();
+        let envelope = TxEnvelope::decode_2718(&mut raw.as_slice()).unwrap();
+        let signer = envelope
+            .signature()
+            .recover_address_from_prehash(&envelope.signature_hash())
+            .unwrap();
+        assert_eq!(signer.to_checksum(None), address);
+    }
+
+    #[test]
+    fn test_locked_and_expired_sessions_cannot_sign() {
+        let store = WalletStore::default();
+        let (session, _) = unlocked(&store);
+        store.lock(&session);
+        assert!(matches!(store.sign_message(&session, b"hi"), Err(KeyManagerError::WalletLocked)));
+
+        let store = WalletStore::new(Duration::ZERO);
+        let (session, _) = unlocked(&store);
+        assert!(matches!(store.sign_message(&session, b"hi"), Err(KeyManagerError::WalletLocked)));
+    }
+}
@@ -0,0 +1,509 @@
+//! Nitro Enclaves attestation documents
+//!
+//! An attestation document is a `COSE_Sign1` structure signed with ES384 by the Nitro
+//! Security Module (NSM). Its payload is a CBOR map holding the enclave's PCRs, the
+//! caller's nonce, an optional public key, and the certificate chain of the signing key.
+//! [`AttestationVerifier`] checks the chain against a pinned root, the signature, the
+//! expected PCR values and the nonce.
+//!
+//! [`Attestor`] abstracts the NSM so the enclave can run outside Nitro; with the
+//! `fake-nsm` feature, [`fake::FakeNsm`] signs documents with a locally generated chain.
+
+use ciborium::Value;
+use p384::ecdsa::signature::Verifier;
+use p384::ecdsa::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use thiserror::Error;
+use x509_cert::Certificate;
+use x509_cert::der::{Decode, DecodePem, Encode, oid::ObjectIdentifier};
+
+/// Largest nonce the NSM accepts
+pub const MAX_NONCE_SIZE: usize = 512;
+
+/// COSE algorithm identifier for ECDSA with SHA-384
+const COSE_ALG_ES384: i64 = -35;
+const COSE_HEADER_ALG: i64 = 1;
+const COSE_SIGN1_TAG: u64 = 18;
+const ECDSA_WITH_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+#[derive(Error, Debug)]
+pub enum AttestationError {
+    #[error("Malformed attestation document: {0}")]
+    Malformed(String),
+    #[error("Untrusted certificate chain: {0}")]
+    Certificate(String),
+    #[error("Attestation signature is invalid")]
+    Signature,
+    #[error("PCR{index} does not match the expected value")]
+    PcrMismatch { index: usize },
+    #[error("Attestation nonce does not match")]
+    NonceMismatch,
+    #[error("Attested public key does not match the pinned enclave key")]
+    PublicKeyMismatch,
+    #[error("Attestation unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// Payload of an attestation document, as produced by the NSM
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttestationDocument {
+    pub module_id: String,
+    pub digest: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub pcrs: BTreeMap<usize, ByteBuf>,
+    pub certificate: ByteBuf,
+    /// Root first, ending with the issuer of `certificate`
+    pub cabundle: Vec<ByteBuf>,
+    pub public_key: Option<ByteBuf>,
+    pub user_data: Option<ByteBuf>,
+    pub nonce: Option<ByteBuf>,
+}
+
+/// Source of attestation documents inside the enclave
+pub trait Attestor: Send + Sync {
+    /// Returns a signed document binding `nonce` and `public_key` to this enclave
+    fn attest(&self, nonce: &[u8], public_key: &[u8]) -> Result<Vec<u8>, AttestationError>;
+}
+
+/// Checks attestation documents against a pinned root and expected PCRs
+#[derive(Debug, Clone)]
+pub struct AttestationVerifier {
+    root_certificate: Vec<u8>,
+    expected_pcrs: BTreeMap<usize, Vec<u8>>,
+}
+
+impl AttestationVerifier {
+    /// Trusts chains rooted at the DER-encoded `root_certificate`
+    pub fn new(root_certificate: Vec<u8>) -> Self {
+        Self {
+            root_certificate,
+            expected_pcrs: BTreeMap::new(),
+        }
+    }
+
+    /// Trusts chains rooted at a PEM-encoded certificate, e.g. the AWS Nitro root
+    pub fn from_pem(pem: &str) -> Result<Self, AttestationError> {
+        let root = Certificate::from_pem(pem).map_err(|e| AttestationError::Certificate(e.to_string()))?;
+        let der = root.to_der().map_err(|e| AttestationError::Certificate(e.to_string()))?;
+        Ok(Self::new(der))
+    }
+
+    /// Requires PCR `index` to equal `value`
+    pub fn expect_pcr(mut self, index: usize, value: Vec<u8>) -> Self {
+        self.expected_pcrs.insert(index, value);
+        self
+    }
+
+    /// Verifies `document` was produced for `nonce` by an enclave with the expected PCRs
+    pub fn verify(&self, document: &[u8], nonce: &[u8]) -> Result<AttestationDocument, AttestationError> {
+        self.verify_at(document, nonce, SystemTime::now())
+    }
+
+    /// Like [`AttestationVerifier::verify`], checking certificate validity at `now`
+    pub fn verify_at(
+        &self,
+        document: &[u8],
+        nonce: &[u8],
+        now: SystemTime,
+    ) -> Result<AttestationDocument, AttestationError> {
+        let sign1 = CoseSign1::decode(document)?;
+        let doc: AttestationDocument = ciborium::de::from_reader(sign1.payload.as_slice())
+            .map_err(|e| AttestationError::Malformed(e.to_string()))?;
+        if doc.digest != "SHA384" {
+            return Err(AttestationError::Malformed(format!("unsupported digest {}", doc.digest)));
+        }
+
+        let leaf = self.verify_chain(&doc, now)?;
+        let key = verifying_key(&leaf)?;
+        let signature = Signature::from_slice(&sign1.signature).map_err(|_| AttestationError::Signature)?;
+        key.verify(&sign1.signed_bytes()?, &signature)
+            .map_err(|_| AttestationError::Signature)?;
+
+        for (&index, expected) in &self.expected_pcrs {
+            if doc.pcrs.get(&index).map(|pcr| pcr.as_slice()) != Some(expected.as_slice()) {
+                return Err(AttestationError::PcrMismatch { index });
+            }
+        }
+        if doc.nonce.as_deref().map(Vec::as_slice) != Some(nonce) {
+            return Err(AttestationError::NonceMismatch);
+        }
+        Ok(doc)
+    }
+
+    /// Walks the bundle from the pinned root down to the signing certificate
+    fn verify_chain(&self, doc: &AttestationDocument, now: SystemTime) -> Result<Certificate, AttestationError> {
+        let (root, intermediates) = doc
+            .cabundle
+            .split_first()
+            .ok_or_else(|| AttestationError::Certificate("empty CA bundle".to_string()))?;
+        if root.as_slice() != self.root_certificate.as_slice() {
+            return Err(AttestationError::Certificate("unknown root certificate".to_string()));
+        }
+
+        let mut issuer = parse_certificate(root)?;
+        check_validity(&issuer, now)?;
+        for der in intermediates.iter().chain(std::iter::once(&doc.certificate)) {
+            let cert = parse_certificate(der)?;
+            check_validity(&cert, now)?;
+            check_signed_by(&cert, &issuer)?;
+            issuer = cert;
+        }
+        Ok(issuer)
+    }
+}
+
+/// The parts of a `COSE_Sign1` structure needed to check its signature
+struct CoseSign1 {
+    protected: Vec<u8>,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl CoseSign1 {
+    fn decode(bytes: &[u8]) -> Result<Self, AttestationError> {
+        let malformed = |reason: &str| AttestationError::Malformed(reason.to_string());
+
+        let value: Value = ciborium::de::from_reader(bytes).map_err(|e| AttestationError::Malformed(e.to_string()))?;
+        // The tag is optional when the context already implies COSE_Sign1
+        let value = match value {
+            Value::Tag(COSE_SIGN1_TAG, inner) => *inner,
+            value => value,
+        };
+        let items = value.into_array().map_err(|_| malformed("expected a COSE_Sign1 array"))?;
+        let [protected, _unprotected, payload, signature]: [Value; 4] =
+            items.try_into().map_err(|_| malformed("COSE_Sign1 must have four elements"))?;
+
+        let sign1 = Self {
+            protected: protected.into_bytes().map_err(|_| malformed("protected header must be bytes"))?,
+            payload: payload.into_bytes().map_err(|_| malformed("payload must be bytes"))?,
+            signature: signature.into_bytes().map_err(|_| malformed("signature must be bytes"))?,
+        };
+
+        let header: Value = ciborium::de::from_reader(sign1.protected.as_slice())
+            .map_err(|e| AttestationError::Malformed(e.to_string()))?;
+        let alg = header
+            .as_map()
+            .and_then(|map| map.iter().find(|(k, _)| *k == Value::from(COSE_HEADER_ALG)))
+            .map(|(_, v)| v);
+        if alg != Some(&Value::from(COSE_ALG_ES384)) {
+            return Err(malformed("unsupported signature algorithm"));
+        }
+        Ok(sign1)
+    }
+
+    /// Encodes the `Sig_structure` covered by the signature
+    fn signed_bytes(&self) -> Result<Vec<u8>, AttestationError> {
+        sig_structure(&self.protected, &self.payload)
+    }
+}
+
+fn sig_structure(protected: &[u8], payload: &[u8]) -> Result<Vec<u8>, AttestationError> {
+    let structure = Value::Array(vec![
+        Value::Text("Signature1".to_string()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(payload.to_vec()),
+    ]);
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(&structure, &mut bytes).map_err(|e| AttestationError::Malformed(e.to_string()))?;
+    Ok(bytes)
+}
+
+fn parse_certificate(der: &[u8]) -> Result<Certificate, AttestationError> {
+    Certificate::from_der(der).map_err(|e| AttestationError::Certificate(e.to_string()))
+}
+
+fn verifying_key(cert: &Certificate) -> Result<VerifyingKey, AttestationError> {
+    let key = cert.tbs_certificate.subject_public_key_info.subject_public_key.raw_bytes();
+    VerifyingKey::from_sec1_bytes(key).map_err(|_| AttestationError::Certificate("expected a P-384 key".to_string()))
+}
+
+fn check_validity(cert: &Certificate, now: SystemTime) -> Result<(), AttestationError> {
+    let validity = &cert.tbs_certificate.validity;
+    if now < validity.not_before.to_system_time() || now > validity.not_after.to_system_time() {
+        return Err(AttestationError::Certificate(format!(
+            "{} is outside its validity period",
+            cert.tbs_certificate.subject
+        )));
+    }
+    Ok(())
+}
+
+fn check_signed_by(cert: &Certificate, issuer: &Certificate) -> Result<(), AttestationError> {
+    let untrusted = |reason: &str| {
+        AttestationError::Certificate(format!("{}: {}", cert.tbs_certificate.subject, reason))
+    };
+
+    if cert.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+        return Err(untrusted("issuer does not match the chain"));
+    }
+    if cert.signature_algorithm.oid != ECDSA_WITH_SHA384 {
+        return Err(untrusted("unsupported signature algorithm"));
+    }
+    let tbs = cert
+        .tbs_certificate
+        .to_der()
+        .map_err(|e| AttestationError::Certificate(e.to_string()))?;
+    let signature = cert
+        .signature
+        .as_bytes()
+        .and_then(|der| Signature::from_der(der).ok())
+        .ok_or_else(|| untrusted("malformed signature"))?;
+    verifying_key(issuer)?
+        .verify(&tbs, &signature)
+        .map_err(|_| untrusted("bad signature"))
+}
+
+/// Local stand-in for the NSM, for running and testing outside Nitro
+#[cfg(any(test, feature = "fake-nsm"))]
+pub mod fake {
+    use super::*;
+    use p384::ecdsa::signature::Signer;
+    use p384::ecdsa::{DerSignature, SigningKey};
+    use rand::thread_rng;
+    use std::str::FromStr;
+    use std::time::{Duration, UNIX_EPOCH};
+    use x509_cert::builder::{Builder, CertificateBuilder, Profile};
+    use x509_cert::name::Name;
+    use x509_cert::serial_number::SerialNumber;
+    use x509_cert::spki::SubjectPublicKeyInfoOwned;
+    use x509_cert::time::Validity;
+
+    const CERT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Signs attestation documents with a freshly generated root, intermediate and leaf
+    pub struct FakeNsm {
+        pcrs: BTreeMap<usize, Vec<u8>>,
+        cabundle: Vec<Vec<u8>>,
+        certificate: Vec<u8>,
+        signing_key: SigningKey,
+    }
+
+    impl FakeNsm {
+        /// Creates a fake NSM reporting `pcrs`
+        pub fn new(pcrs: BTreeMap<usize, Vec<u8>>) -> Result<Self, AttestationError> {
+            let root_key = SigningKey::random(&mut thread_rng());
+            let root_name = name("CN=fake-nsm-root")?;
+            let root = issue(Profile::Root, 1, root_name.clone(), &root_key, &root_key)?;
+
+            let intermediate_key = SigningKey::random(&mut thread_rng());
+            let intermediate_name = name("CN=fake-nsm-intermediate")?;
+            let profile = Profile::SubCA {
+                issuer: root_name,
+                path_len_constraint: Some(0),
+            };
+            let intermediate = issue(profile, 2, intermediate_name.clone(), &intermediate_key, &root_key)?;
+
+            let signing_key = SigningKey::random(&mut thread_rng());
+            let profile = Profile::Leaf {
+                issuer: intermediate_name,
+                enable_key_agreement: false,
+                enable_key_encipherment: false,
+            };
+            let certificate = issue(profile, 3, name("CN=fake-nsm")?, &signing_key, &intermediate_key)?;
+
+            Ok(Self {
+                pcrs,
+                cabundle: vec![root, intermediate],
+                certificate,
+                signing_key,
+            })
+        }
+
+        /// DER-encoded root to pin in an [`AttestationVerifier`]
+        pub fn root_certificate(&self) -> &[u8] {
+            &self.cabundle[0]
+        }
+
+        /// Builds the document this NSM would sign for `nonce` and `public_key`
+        pub fn document(&self, nonce: &[u8], public_key: &[u8]) -> AttestationDocument {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            AttestationDocument {
+                module_id: "fake-nsm".to_string(),
+                digest: "SHA384".to_string(),
+                timestamp,
+                pcrs: self
+                    .pcrs
+                    .iter()
+                    .map(|(&index, value)| (index, ByteBuf::from(value.clone())))
+                    .collect(),
+                certificate: ByteBuf::from(self.certificate.clone()),
+                cabundle: self.cabundle.iter().cloned().map(ByteBuf::from).collect(),
+                public_key: Some(ByteBuf::from(public_key.to_vec())),
+                user_data: None,
+                nonce: Some(ByteBuf::from(nonce.to_vec())),
+            }
+        }
+
+        /// Encodes and signs an arbitrary document as a tagged `COSE_Sign1`
+        pub fn sign(&self, document: &AttestationDocument) -> Result<Vec<u8>, AttestationError> {
+            let mut payload = Vec::new();
+            ciborium::ser::into_writer(document, &mut payload)
+                .map_err(|e| AttestationError::Malformed(e.to_string()))?;
+            let header = Value::Map(vec![(Value::from(COSE_HEADER_ALG), Value::from(COSE_ALG_ES384))]);
+            let mut protected = Vec::new();
+            ciborium::ser::into_writer(&header, &mut protected)
+                .map_err(|e| AttestationError::Malformed(e.to_string()))?;
+
+            let signature: Signature = self.signing_key.sign(&sig_structure(&protected, &payload)?);
+            let sign1 = Value::Tag(
+                COSE_SIGN1_TAG,
+                Box::new(Value::Array(vec![
+                    Value::Bytes(protected),
+                    Value::Map(Vec::new()),
+                    Value::Bytes(payload),
+                    Value::Bytes(signature.to_bytes().to_vec()),
+                ])),
+            );
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&sign1, &mut bytes)
+                .map_err(|e| AttestationError::Malformed(e.to_string()))?;
+            Ok(bytes)
+        }
+    }
+
+    impl Attestor for FakeNsm {
+        fn attest(&self, nonce: &[u8], public_key: &[u8]) -> Result<Vec<u8>, AttestationError> {
+            self.sign(&self.document(nonce, public_key))
+        }
+    }
+
+    fn name(name: &str) -> Result<Name, AttestationError> {
+        Name::from_str(name).map_err(|e| AttestationError::Certificate(e.to_string()))
+    }
+
+    fn issue(
+        profile: Profile,
+        serial: u32,
+        subject: Name,
+        subject_key: &SigningKey,
+        issuer_key: &SigningKey,
+    ) -> Result<Vec<u8>, AttestationError> {
+        let error = |e: &dyn std::fmt::Display| AttestationError::Certificate(e.to_string());
+        let validity = Validity::from_now(CERT_LIFETIME).map_err(|e| error(&e))?;
+        let spki = SubjectPublicKeyInfoOwned::from_key(*subject_key.verifying_key()).map_err(|e| error(&e))?;
+        let builder = CertificateBuilder::new(profile, SerialNumber::from(serial), validity, subject, spki, issuer_key)
+            .map_err(|e| error(&e))?;
+        let certificate = builder.build::<DerSignature>().map_err(|e| error(&e))?;
+        certificate.to_der().map_err(|e| error(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::FakeNsm;
+    use super::*;
+    use std::time::Duration;
+
+    const NONCE: &[u8] = b"nonce-0123456789";
+
+    fn pcrs() -> BTreeMap<usize, Vec<u8>> {
+        (0..3).map(|index| (index, vec![index as u8; 48])).collect()
+    }
+
+    fn verifier(nsm: &FakeNsm) -> AttestationVerifier {
+        pcrs()
+            .into_iter()
+            .fold(AttestationVerifier::new(nsm.root_certificate().to_vec()), |verifier, (index, value)| {
+                verifier.expect_pcr(index, value)
+            })
+    }
+
+    #[test]
+    fn test_valid_document_is_accepted() {
+        let nsm = FakeNsm::new(pcrs()).unwrap();
+        let document = nsm.attest(NONCE, b"channel key").unwrap();
+
+        let doc = verifier(&nsm).verify(&document, NONCE).unwrap();
+        assert_eq!(doc.public_key.as_deref().map(Vec::as_slice), Some(&b"channel key"[..]));
+        assert_eq!(doc.pcrs[&0].as_slice(), &[0u8; 48][..]);
+    }
+
+    #[test]
+    fn test_wrong_nonce_and_pcrs_are_rejected() {
+        let nsm = FakeNsm::new(pcrs()).unwrap();
+        let document = nsm.attest(NONCE, b"key").unwrap();
+
+        // A replayed document answers someone else's nonce
+        assert!(matches!(
+            verifier(&nsm).verify(&document, b"other nonce"),
+            Err(AttestationError::NonceMismatch)
+        ));
+
+        // A different enclave image measures differently
+        let verifier = verifier(&nsm).expect_pcr(2, vec![9; 48]);
+        assert!(matches!(
+            verifier.verify(&document, NONCE),
+            Err(AttestationError::PcrMismatch { index: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_untrusted_root_is_rejected() {
+        let nsm = FakeNsm::new(pcrs()).unwrap();
+        let other = FakeNsm::new(pcrs()).unwrap();
+        let document = nsm.attest(NONCE, b"key").unwrap();
+
+        assert!(matches!(
+            verifier(&other).verify(&document, NONCE),
+            Err(AttestationError::Certificate(_))
+        ));
+
+        // Claiming the trusted root while chaining to another one breaks the signature chain
+        let mut doc = nsm.document(NONCE, b"key");
+        doc.cabundle[0] = ByteBuf::from(other.root_certificate().to_vec());
+        let document = nsm.sign(&doc).unwrap();
+        assert!(matches!(
+            verifier(&other).verify(&document, NONCE),
+            Err(AttestationError::Certificate(_))
+        ));
+    }
+
+    #[test]
+    fn test_tampered_payload_fails_signature() {
+        let nsm = FakeNsm::new(pcrs()).unwrap();
+        let document = nsm.attest(NONCE, b"key").unwrap();
+
+        // Flip one byte of PCR0 inside the signed payload
+        let position = document
+            .windows(48)
+            .position(|window| window == [0u8; 48])
+            .unwrap();
+        let mut tampered = document.clone();
+        tampered[position] ^= 1;
+        let verifier = AttestationVerifier::new(nsm.root_certificate().to_vec());
+        assert!(matches!(
+            verifier.verify(&tampered, NONCE),
+            Err(AttestationError::Signature)
+        ));
+    }
+
+    #[test]
+    fn test_expired_chain_is_rejected() {
+        let nsm = FakeNsm::new(pcrs()).unwrap();
+        let document = nsm.attest(NONCE, b"key").unwrap();
+
+        let later = SystemTime::now() + Duration::from_secs(2 * 24 * 60 * 60);
+        assert!(matches!(
+            verifier(&nsm).verify_at(&document, NONCE, later),
+            Err(AttestationError::Certificate(_))
+        ));
+    }
+
+    #[test]
+    fn test_malformed_documents() {
+        let verifier = AttestationVerifier::new(Vec::new());
+        assert!(matches!(verifier.verify(b"", NONCE), Err(AttestationError::Malformed(_))));
+
+        let mut not_cose = Vec::new();
+        ciborium::ser::into_writer(&Value::Array(vec![Value::from(1)]), &mut not_cose).unwrap();
+        assert!(matches!(verifier.verify(&not_cose, NONCE), Err(AttestationError::Malformed(_))));
+    }
+}
@@ -0,0 +1,1116 @@
+use crate::attestation::{AttestationError, AttestationVerifier};
+use crate::framing::{FrameCodec, FramingError};
+use crate::protocol::{
+    ATTESTATION_PROTOCOL_VERSION, EnclaveRequest, EnclaveResponse, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, RequestEnvelope, ResponseEnvelope, SealedWallet,
+};
+use crate::secure_channel::{ChannelError, ClientIdentity, EnclavePublicKey, SecureChannel};
+use crate::transport::{Transport, connect};
+use crate::{DerivedKey, EncryptedKeyConfig, KeyManagerError, SecretPassword, SecretPhrase};
+use rand::{RngCore, thread_rng};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+/// Tuning for [`EnclaveClient`]'s connection pool
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Maximum number of open connections, and so of requests in flight
+    pub max_connections: usize,
+    /// Deadline for a whole request, including waiting for a connection
+    pub request_timeout: Duration,
+    /// Deadline for connecting and completing the handshake
+    pub connect_timeout: Duration,
+    /// Idle connections older than this are pinged before being reused
+    pub health_check_interval: Duration,
+    /// Delay before retrying after the first failed connect
+    pub initial_backoff: Duration,
+    /// Upper bound on the reconnect delay
+    pub max_backoff: Duration,
+    /// Frame limits used on every connection
+    pub codec: FrameCodec,
+    /// When set, every new connection must present a valid attestation document
+    pub attestation: Option<AttestationVerifier>,
+    /// When set, every connection proves this key to the enclave, which answers requests
+    /// such as [`EnclaveClient::storage_key`] only for clients it trusts
+    pub identity: Option<ClientIdentity>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 4,
+            request_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(5),
+            health_check_interval: Duration::from_secs(30),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            codec: FrameCodec::default(),
+            attestation: None,
+            identity: None,
+        }
+    }
+}
+
+/// Wallet generated by [`EnclaveClient::create_wallet`]
+#[derive(Debug, Clone)]
+pub struct CreatedWallet {
+    pub wallet: SealedWallet,
+    /// Recovery phrase to show the user once; `None` from enclaves that predate them
+    pub mnemonic: Option<SecretPhrase>,
+}
+
+/// Wallet opened by [`EnclaveClient::unlock_wallet`]
+#[derive(Debug, Clone)]
+pub struct UnlockedWallet {
+    /// Token to sign with until the wallet is locked
+    pub session: String,
+    pub address: String,
+    /// Resealed wallet to store in place of the one passed in
+    pub upgraded: Option<SealedWallet>,
+    /// Re-derived config to store alongside `upgraded`
+    pub config: Option<EncryptedKeyConfig>,
+}
+
+/// Account switched to by [`EnclaveClient::select_account`]
+#[derive(Debug, Clone)]
+pub struct SelectedAccount {
+    pub address: String,
+    /// Wallet MAC covering the selection, to store along with it; `None` from enclaves
+    /// whose MACs don't cover it
+    pub mac: Option<String>,
+}
+
+/// Pooled client for the enclave's versioned wire protocol
+///
+/// Connections are opened on demand, up to `max_connections`, and returned to an idle
+/// pool after each successful request. Failed connects back off exponentially, shared
+/// across all callers, so a restarting enclave isn't hammered. Transport failures and
+/// missed deadlines surface as [`KeyManagerError::Unavailable`].
+///
+/// Every connection runs over a [`SecureChannel`] authenticated against the pinned
+/// enclave key, so requests and responses are encrypted on any transport.
+pub struct EnclaveClient {
+    transport: Transport,
+    enclave_key: EnclavePublicKey,
+    config: ClientConfig,
+    permits: Semaphore,
+    idle: Mutex<Vec<Connection>>,
+    backoff: Mutex<Backoff>,
+    next_request_id: AtomicU64,
+}
+
+/// An established channel and the protocol version negotiated on it
+struct Connection {
+    channel: SecureChannel,
+    version: u16,
+    last_used: Instant,
+}
+
+/// Reconnect schedule shared by every caller
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    fn remaining(&self) -> Duration {
+        self.retry_at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+            .unwrap_or_default()
+    }
+
+    fn record_failure(&mut self, config: &ClientConfig) {
+        let exponent = self.failures.min(16);
+        let delay = config
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(config.max_backoff);
+        self.failures += 1;
+        self.retry_at = Some(Instant::now() + delay);
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl EnclaveClient {
+    /// Creates a client that will connect over `transport` to the enclave owning `enclave_key`
+    pub fn new(transport: Transport, enclave_key: EnclavePublicKey) -> Self {
+        Self::with_config(transport, enclave_key, ClientConfig::default())
+    }
+
+    /// Creates a client with custom pool settings
+    pub fn with_config(transport: Transport, enclave_key: EnclavePublicKey, config: ClientConfig) -> Self {
+        Self {
+            transport,
+            enclave_key,
+            permits: Semaphore::new(config.max_connections.max(1)),
+            config,
+            idle: Mutex::new(Vec::new()),
+            backoff: Mutex::new(Backoff::default()),
+            next_request_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Replaces the default frame limits used on the connection
+    pub fn with_codec(mut self, codec: FrameCodec) -> Self {
+        self.config.codec = codec;
+        self
+    }
+
+    /// Creates a client and eagerly establishes the first connection
+    pub async fn connect(transport: Transport, enclave_key: EnclavePublicKey) -> Result<Self, KeyManagerError> {
+        let client = Self::new(transport, enclave_key);
+        let connection = tokio::time::timeout(client.config.connect_timeout, client.open_connection())
+            .await
+            .map_err(|_| KeyManagerError::Unavailable("connect timed out".to_string()))?
+            .map_err(into_unavailable)?;
+        client.checkin(connection);
+        Ok(client)
+    }
+
+    /// Returns the protocol version negotiated on a pooled connection, if any
+    pub async fn protocol_version(&self) -> Option<u16> {
+        self.idle.lock().unwrap().last().map(|c| c.version)
+    }
+
+    /// Sends a request and waits for the matching response within the configured deadline
+    pub async fn request(
+        &self,
+        request: EnclaveRequest,
+    ) -> Result<EnclaveResponse, KeyManagerError> {
+        self.request_with_timeout(request, self.config.request_timeout).await
+    }
+
+    /// Sends a request with an explicit deadline
+    pub async fn request_with_timeout(
+        &self,
+        request: EnclaveRequest,
+        timeout: Duration,
+    ) -> Result<EnclaveResponse, KeyManagerError> {
+        let deadline = Instant::now() + timeout;
+        // A request cut off mid-exchange drops its connection rather than returning it
+        tokio::time::timeout_at(deadline, self.dispatch(request, deadline))
+            .await
+            .map_err(|_| KeyManagerError::Unavailable("request deadline exceeded".to_string()))?
+    }
+
+    /// Asks the enclave to create a new key configuration for `password`
+    pub async fn setup_config(&self, password: &SecretPassword) -> Result<String, KeyManagerError> {
+        let request = EnclaveRequest::SetupConfig {
+            password: password.clone(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::ConfigSetup { config } => Ok(config),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Verifies `password` against `config` inside the enclave and returns the derived keys
+    pub async fn verify_and_derive_keys(
+        &self,
+        config: &EncryptedKeyConfig,
+        password: &SecretPassword,
+    ) -> Result<(DerivedKey, DerivedKey), KeyManagerError> {
+        let request = EnclaveRequest::VerifyAndDeriveKeys {
+            config: config.clone(),
+            password: password.clone(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::Keys { key1, key2 } => Ok((key1, key2)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Fetches the key for encrypting stored records under master key `key_id`
+    ///
+    /// The enclave derives it from its identity, so the same `key_id` always yields the
+    /// same key from the same enclave image and static key. It is only handed to clients
+    /// whose [`ClientConfig::identity`] the enclave trusts.
+    pub async fn storage_key(&self, key_id: &str) -> Result<DerivedKey, KeyManagerError> {
+        let request = EnclaveRequest::StorageKey {
+            key_id: key_id.to_string(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::StorageKey { key } => Ok(key),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Generates a wallet inside the enclave, sealed under the key derived from `password`
+    /// and bound to `context`
+    pub async fn create_wallet(
+        &self,
+        config: &EncryptedKeyConfig,
+        password: &SecretPassword,
+        context: &str,
+    ) -> Result<CreatedWallet, KeyManagerError> {
+        let request = EnclaveRequest::CreateWallet {
+            config: config.clone(),
+            password: password.clone(),
+            context: context.to_string(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::WalletCreated { wallet, mnemonic } => Ok(CreatedWallet { wallet, mnemonic }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Seals an existing wallet, given as a hex private key or a recovery phrase, like
+    /// [`create_wallet`](Self::create_wallet) does a generated one
+    pub async fn import_wallet(
+        &self,
+        config: &EncryptedKeyConfig,
+        password: &SecretPassword,
+        context: &str,
+        secret: &SecretPhrase,
+    ) -> Result<SealedWallet, KeyManagerError> {
+        let request = EnclaveRequest::ImportWallet {
+            config: config.clone(),
+            password: password.clone(),
+            context: context.to_string(),
+            secret: secret.clone(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::WalletCreated { wallet, .. } => Ok(wallet),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Unlocks `wallet` inside the enclave, returning a signing session and any
+    /// replacement records the caller should store
+    pub async fn unlock_wallet(
+        &self,
+        config: &EncryptedKeyConfig,
+        password: &SecretPassword,
+        wallet: &SealedWallet,
+        context: &str,
+    ) -> Result<UnlockedWallet, KeyManagerError> {
+        let request = EnclaveRequest::UnlockWallet {
+            config: config.clone(),
+            password: password.clone(),
+            wallet: wallet.clone(),
+            context: context.to_string(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::WalletUnlocked {
+                session,
+                address,
+                upgraded,
+                config,
+            } => Ok(UnlockedWallet {
+                session,
+                address,
+                upgraded,
+                config,
+            }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Reseals `wallet` under a fresh config for `new_password`, returning both
+    ///
+    /// Fails with [`KeyManagerError::AuthenticationFailed`] if `old_password` doesn't
+    /// match `config`.
+    pub async fn change_password(
+        &self,
+        config: &EncryptedKeyConfig,
+        old_password: &SecretPassword,
+        new_password: &SecretPassword,
+        wallet: &SealedWallet,
+        context: &str,
+    ) -> Result<(EncryptedKeyConfig, SealedWallet), KeyManagerError> {
+        let request = EnclaveRequest::ChangePassword {
+            config: config.clone(),
+            old_password: old_password.clone(),
+            new_password: new_password.clone(),
+            wallet: wallet.clone(),
+            context: context.to_string(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::PasswordChanged { config, wallet } => Ok((config, wallet)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Drops the enclave's signer for `session`
+    pub async fn lock_wallet(&self, session: &str) -> Result<(), KeyManagerError> {
+        let request = EnclaveRequest::LockWallet {
+            session: session.to_string(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::WalletLocked => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Switches `session` to account `index` of its wallet's recovery phrase
+    pub async fn select_account(&self, session: &str, index: u32) -> Result<SelectedAccount, KeyManagerError> {
+        let request = EnclaveRequest::SelectAccount {
+            session: session.to_string(),
+            index,
+        };
+        match self.request(request).await? {
+            EnclaveResponse::AccountSelected { address, mac } => Ok(SelectedAccount { address, mac }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Signs `message` as an EIP-191 personal message, returning the 65-byte signature
+    pub async fn sign_message(&self, session: &str, message: &[u8]) -> Result<Vec<u8>, KeyManagerError> {
+        let request = EnclaveRequest::SignMessage {
+            session: session.to_string(),
+            message: message.to_vec(),
+        };
+        self.signature(request).await
+    }
+
+    /// Signs an EIP-712 payload given as `TypedData` JSON
+    pub async fn sign_typed_data(&self, session: &str, typed_data: &str) -> Result<Vec<u8>, KeyManagerError> {
+        let request = EnclaveRequest::SignTypedData {
+            session: session.to_string(),
+            typed_data: typed_data.to_string(),
+        };
+        self.signature(request).await
+    }
+
+    /// Signs a `TypedTransaction` given as JSON, returning the raw signed transaction
+    pub async fn sign_transaction(&self, session: &str, transaction: &str) -> Result<Vec<u8>, KeyManagerError> {
+        let request = EnclaveRequest::SignTransaction {
+            session: session.to_string(),
+            transaction: transaction.to_string(),
+        };
+        match self.request(request).await? {
+            EnclaveResponse::SignedTransaction { raw } => Ok(raw),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn signature(&self, request: EnclaveRequest) -> Result<Vec<u8>, KeyManagerError> {
+        match self.request(request).await? {
+            EnclaveResponse::Signature { signature } => Ok(signature),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        request: EnclaveRequest,
+        deadline: Instant,
+    ) -> Result<EnclaveResponse, KeyManagerError> {
+        let _permit = self.permits.acquire().await.expect("pool semaphore is never closed");
+
+        let (mut connection, reused) = match self.checkout().await {
+            Some(connection) => (connection, true),
+            None => (self.reconnect(deadline).await?, false),
+        };
+        let mut result = self.exchange(&mut connection, &request).await;
+
+        // A pooled connection goes stale when the enclave restarts; retry once on a fresh one,
+        // unless the request may already have been carried out
+        if reused && request.is_idempotent() && matches!(&result, Err(e) if is_connection_failure(e)) {
+            connection = self.reconnect(deadline).await?;
+            result = self.exchange(&mut connection, &request).await;
+        }
+
+        match result {
+            Ok(response) => {
+                self.checkin(connection);
+                Ok(response)
+            }
+            // The stream may be mid-frame, so the connection is dropped here
+            Err(e) => Err(into_unavailable(e)),
+        }
+    }
+
+    /// Takes an idle connection, health-checking it if it has been idle for a while
+    async fn checkout(&self) -> Option<Connection> {
+        loop {
+            let mut connection = self.idle.lock().unwrap().pop()?;
+            if connection.last_used.elapsed() < self.config.health_check_interval {
+                return Some(connection);
+            }
+            let ping = EnclaveRequest::hello();
+            if let Ok(EnclaveResponse::Version { .. }) = self.exchange(&mut connection, &ping).await {
+                return Some(connection);
+            }
+        }
+    }
+
+    fn checkin(&self, mut connection: Connection) {
+        connection.last_used = Instant::now();
+        self.idle.lock().unwrap().push(connection);
+    }
+
+    /// Opens a new connection, waiting out the shared backoff while the deadline allows
+    async fn reconnect(&self, deadline: Instant) -> Result<Connection, KeyManagerError> {
+        let mut last_error = None;
+        loop {
+            let wait = self.backoff.lock().unwrap().remaining();
+            if Instant::now() + wait >= deadline {
+                let reason = last_error.map_or_else(
+                    || "waiting to reconnect".to_string(),
+                    |e: KeyManagerError| e.to_string(),
+                );
+                return Err(KeyManagerError::Unavailable(reason));
+            }
+            tokio::time::sleep(wait).await;
+
+            match tokio::time::timeout(self.config.connect_timeout, self.open_connection()).await {
+                Ok(Ok(connection)) => {
+                    self.backoff.lock().unwrap().reset();
+                    return Ok(connection);
+                }
+                Ok(Err(e)) if !is_connection_failure(&e) => return Err(e),
+                Ok(Err(e)) => last_error = Some(e),
+                Err(_) => last_error = Some(KeyManagerError::SocketError("connect timed out".to_string())),
+            }
+            self.backoff.lock().unwrap().record_failure(&self.config);
+        }
+    }
+
+    /// Connects, authenticates the enclave and performs the version handshake,
+    /// followed by attestation if configured
+    async fn open_connection(&self) -> Result<Connection, KeyManagerError> {
+        let stream = connect(self.transport.clone())
+            .await
+            .map_err(|e| KeyManagerError::SocketError(e.to_string()))?;
+        let mut channel = match &self.config.identity {
+            Some(identity) => SecureChannel::connect_authenticated(stream, self.config.codec, &self.enclave_key, identity).await?,
+            None => SecureChannel::connect(stream, self.config.codec, &self.enclave_key).await?,
+        };
+
+        let hello = RequestEnvelope {
+            version: PROTOCOL_VERSION,
+            request_id: self.next_request_id(),
+            request: EnclaveRequest::hello(),
+        };
+        let version = match send(&mut channel, &hello).await? {
+            EnclaveResponse::Version { version }
+                if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) =>
+            {
+                version
+            }
+            EnclaveResponse::Version { version } => {
+                return Err(KeyManagerError::IncompatibleVersion {
+                    local: PROTOCOL_VERSION,
+                    remote: version,
+                });
+            }
+            other => return Err(unexpected(other)),
+        };
+
+        let mut connection = Connection {
+            channel,
+            version,
+            last_used: Instant::now(),
+        };
+        if let Some(verifier) = &self.config.attestation {
+            self.attest(&mut connection, verifier).await?;
+        }
+        Ok(connection)
+    }
+
+    /// Checks that the enclave runs the expected image and owns the pinned channel key
+    async fn attest(
+        &self,
+        connection: &mut Connection,
+        verifier: &AttestationVerifier,
+    ) -> Result<(), KeyManagerError> {
+        if connection.version < ATTESTATION_PROTOCOL_VERSION {
+            return Err(AttestationError::Unavailable(format!(
+                "enclave speaks protocol version {}",
+                connection.version
+            ))
+            .into());
+        }
+
+        let mut nonce = vec![0u8; 32];
+        thread_rng().fill_bytes(&mut nonce);
+        let request = EnclaveRequest::Attest {
+            nonce: nonce.clone(),
+        };
+        let document = match self.exchange(connection, &request).await? {
+            EnclaveResponse::Attestation { document } => document,
+            other => return Err(unexpected(other)),
+        };
+
+        let document = verifier.verify(&document, &nonce)?;
+        // Without this binding, a genuine enclave's document could vouch for an impostor's channel
+        if document.public_key.as_deref().map(Vec::as_slice) != Some(self.enclave_key.as_bytes().as_slice()) {
+            return Err(AttestationError::PublicKeyMismatch.into());
+        }
+        Ok(())
+    }
+
+    async fn exchange(
+        &self,
+        connection: &mut Connection,
+        request: &EnclaveRequest,
+    ) -> Result<EnclaveResponse, KeyManagerError> {
+        let envelope = RequestEnvelope {
+            version: connection.version,
+            request_id: self.next_request_id(),
+            request: request.clone(),
+        };
+        send(&mut connection.channel, &envelope).await
+    }
+
+    fn next_request_id(&self) -> u64 {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Sends one envelope and returns the response carried by the matching reply
+async fn send(
+    channel: &mut SecureChannel,
+    envelope: &RequestEnvelope,
+) -> Result<EnclaveResponse, KeyManagerError> {
+    let request_bytes = serde_json::to_vec(envelope)?;
+    channel.send(&request_bytes).await?;
+    let response_bytes = channel.recv().await?.ok_or_else(|| {
+        KeyManagerError::SocketError("enclave closed the connection".to_string())
+    })?;
+    let reply: ResponseEnvelope = serde_json::from_slice(&response_bytes)?;
+
+    if reply.request_id != envelope.request_id {
+        return Err(KeyManagerError::ProtocolError(format!(
+            "response id {} does not match request id {}",
+            reply.request_id, envelope.request_id
+        )));
+    }
+    Ok(reply.response)
+}
+
+/// Whether an error means the connection itself is broken
+fn is_connection_failure(error: &KeyManagerError) -> bool {
+    matches!(
+        error,
+        KeyManagerError::SocketError(_)
+            | KeyManagerError::FramingError(
+                FramingError::Io(_) | FramingError::Truncated | FramingError::Timeout
+            )
+            | KeyManagerError::SecureChannel(ChannelError::Closed)
+    )
+}
+
+fn into_unavailable(error: KeyManagerError) -> KeyManagerError {
+    if is_connection_failure(&error) {
+        KeyManagerError::Unavailable(error.to_string())
+    } else {
+        error
+    }
+}
+
+/// Maps a response that doesn't match the request to an error
+fn unexpected(response: EnclaveResponse) -> KeyManagerError {
+    match response {
+        EnclaveResponse::Error { code, message } => code.into_error(message),
+        _ => KeyManagerError::UnexpectedResponse,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::FramingError;
+    use crate::attestation::Attestor;
+    use crate::attestation::fake::FakeNsm;
+    use crate::protocol::ErrorCode;
+    use crate::secure_channel::EnclaveIdentity;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn test_config() -> EncryptedKeyConfig {
+        EncryptedKeyConfig {
+            password_hash: "hash".to_string(),
+            salt1: "aa".to_string(),
+            salt2: "bb".to_string(),
+            kdf: Default::default(),
+        }
+    }
+
+    fn identity() -> EnclaveIdentity {
+        EnclaveIdentity::from_bytes([42; 32])
+    }
+
+    fn enclave_key() -> EnclavePublicKey {
+        identity().public_key()
+    }
+
+    async fn read_request(channel: &mut SecureChannel) -> RequestEnvelope {
+        let buffer = channel.recv().await.unwrap().unwrap();
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    async fn write_response(channel: &mut SecureChannel, request_id: u64, response: EnclaveResponse) {
+        let envelope = ResponseEnvelope {
+            version: PROTOCOL_VERSION,
+            request_id,
+            response,
+        };
+        let bytes = serde_json::to_vec(&envelope).unwrap();
+        channel.send(&bytes).await.unwrap();
+    }
+
+    async fn accept_secure(stream: TcpStream) -> SecureChannel {
+        SecureChannel::accept(Box::pin(stream), FrameCodec::default(), &identity())
+            .await
+            .unwrap()
+    }
+
+    // Accepts a connection and answers the client's secure and version handshakes
+    async fn accept_and_handshake(listener: &TcpListener) -> SecureChannel {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = accept_secure(stream).await;
+        let hello = read_request(&mut stream).await;
+        assert!(matches!(hello.request, EnclaveRequest::Hello { .. }));
+        let response = EnclaveResponse::Version {
+            version: PROTOCOL_VERSION,
+        };
+        write_response(&mut stream, hello.request_id, response).await;
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_verify_and_derive_keys_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            assert_eq!(envelope.version, PROTOCOL_VERSION);
+            match envelope.request {
+                EnclaveRequest::VerifyAndDeriveKeys { config, password } => {
+                    assert_eq!(config.salt1, "aa");
+                    assert_eq!(password.expose(), "pw");
+                }
+                other => panic!("unexpected request: {:?}", other),
+            }
+            let response = EnclaveResponse::Keys {
+                key1: [1; 32].into(),
+                key2: [2; 32].into(),
+            };
+            write_response(&mut stream, envelope.request_id, response).await;
+        });
+
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key());
+        let config = test_config();
+        let (key1, key2) = client.verify_and_derive_keys(&config, &"pw".into()).await.unwrap();
+        assert_eq!(key1.expose(), &[1; 32]);
+        assert_eq!(key2.expose(), &[2; 32]);
+        assert_eq!(client.protocol_version().await, Some(PROTOCOL_VERSION));
+    }
+
+    #[tokio::test]
+    async fn test_authentication_failure_is_typed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            let response = EnclaveResponse::Error {
+                code: ErrorCode::AuthenticationFailed,
+                message: "Authentication failed".to_string(),
+            };
+            write_response(&mut stream, envelope.request_id, response).await;
+        });
+
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key());
+        let result = client.verify_and_derive_keys(&test_config(), &"wrong".into()).await;
+        assert!(matches!(result, Err(KeyManagerError::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_incompatible_enclave() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = accept_secure(stream).await;
+            let hello = read_request(&mut stream).await;
+            let response = EnclaveResponse::Version {
+                version: PROTOCOL_VERSION + 1,
+            };
+            write_response(&mut stream, hello.request_id, response).await;
+        });
+
+        let result = EnclaveClient::connect(Transport::Tcp(addr), enclave_key()).await;
+        assert!(matches!(
+            result,
+            Err(KeyManagerError::IncompatibleVersion { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_enclave_with_unexpected_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let impostor = EnclaveIdentity::generate();
+            let _ = SecureChannel::accept(Box::pin(stream), FrameCodec::default(), &impostor).await;
+        });
+
+        let result = EnclaveClient::connect(Transport::Tcp(addr), enclave_key()).await;
+        assert!(matches!(
+            result,
+            Err(KeyManagerError::SecureChannel(ChannelError::Handshake(_)))
+        ));
+    }
+
+    // Serves one connection that answers an attestation request for `attested_key`
+    async fn serve_attestation(listener: TcpListener, nsm: Arc<FakeNsm>, attested_key: EnclavePublicKey) {
+        let mut stream = accept_and_handshake(&listener).await;
+        let envelope = read_request(&mut stream).await;
+        let nonce = match envelope.request {
+            EnclaveRequest::Attest { nonce } => nonce,
+            other => panic!("unexpected request: {:?}", other),
+        };
+        let document = nsm.attest(&nonce, attested_key.as_bytes()).unwrap();
+        write_response(&mut stream, envelope.request_id, EnclaveResponse::Attestation { document }).await;
+    }
+
+    #[tokio::test]
+    async fn test_attestation_is_verified_on_connect() {
+        let nsm = Arc::new(FakeNsm::new([(0, vec![0; 48])].into()).unwrap());
+        let config = ClientConfig {
+            attestation: Some(
+                AttestationVerifier::new(nsm.root_certificate().to_vec()).expect_pcr(0, vec![0; 48]),
+            ),
+            ..ClientConfig::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_attestation(listener, Arc::clone(&nsm), enclave_key()));
+        let client = EnclaveClient::with_config(Transport::Tcp(addr), enclave_key(), config.clone());
+        assert!(client.open_connection().await.is_ok());
+
+        // A genuine document vouching for a different channel key is refused
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let other_key = EnclaveIdentity::generate().public_key();
+        tokio::spawn(serve_attestation(listener, Arc::clone(&nsm), other_key));
+        let client = EnclaveClient::with_config(Transport::Tcp(addr), enclave_key(), config);
+        assert!(matches!(
+            client.open_connection().await,
+            Err(KeyManagerError::AttestationFailed(AttestationError::PublicKeyMismatch))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_mismatched_request_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            let response = EnclaveResponse::ConfigSetup {
+                config: "{}".to_string(),
+            };
+            write_response(&mut stream, envelope.request_id + 1, response).await;
+        });
+
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key());
+        let result = client.setup_config(&"pw".into()).await;
+        assert!(matches!(result, Err(KeyManagerError::ProtocolError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_connection_loss() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            // First connection is dropped after the handshake without answering
+            let stream = accept_and_handshake(&listener).await;
+            drop(stream);
+
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            let response = EnclaveResponse::ConfigSetup {
+                config: "{}".to_string(),
+            };
+            write_response(&mut stream, envelope.request_id, response).await;
+        });
+
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key());
+        assert!(matches!(
+            client.setup_config(&"pw".into()).await,
+            Err(KeyManagerError::Unavailable(_))
+        ));
+        assert!(client.setup_config(&"pw".into()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_unavailable_enclave() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let result = EnclaveClient::connect(Transport::Tcp(addr), enclave_key()).await;
+        assert!(matches!(result, Err(KeyManagerError::Unavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            let response = EnclaveResponse::ConfigSetup {
+                config: "x".repeat(1024),
+            };
+            write_response(&mut stream, envelope.request_id, response).await;
+        });
+
+        let codec = FrameCodec::new(512, Duration::from_secs(1));
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key()).with_codec(codec);
+        let result = client.setup_config(&"pw".into()).await;
+        assert!(matches!(
+            result,
+            Err(KeyManagerError::FramingError(FramingError::FrameTooLarge { max: 512, .. }))
+        ));
+    }
+
+    // Answers every request on a connection with a canned config, tracking open connections
+    async fn serve_setup(stream: TcpStream, open: Arc<AtomicUsize>, peak: Arc<AtomicUsize>) {
+        let mut stream = accept_secure(stream).await;
+        let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+        peak.fetch_max(now, Ordering::SeqCst);
+        loop {
+            let buffer = match stream.recv().await {
+                Ok(Some(buffer)) => buffer,
+                _ => break,
+            };
+            let envelope: RequestEnvelope = serde_json::from_slice(&buffer).unwrap();
+            let response = match envelope.request {
+                EnclaveRequest::Hello { .. } => EnclaveResponse::Version {
+                    version: PROTOCOL_VERSION,
+                },
+                _ => {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    EnclaveResponse::ConfigSetup {
+                        config: "{}".to_string(),
+                    }
+                }
+            };
+            write_response(&mut stream, envelope.request_id, response).await;
+        }
+        open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    async fn spawn_server() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let open = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let accepted_peak = Arc::clone(&peak);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_setup(stream, Arc::clone(&open), Arc::clone(&accepted_peak)));
+            }
+        });
+        (addr, peak)
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuses_connections_and_bounds_concurrency() {
+        let (addr, peak) = spawn_server().await;
+        let config = ClientConfig {
+            max_connections: 2,
+            ..ClientConfig::default()
+        };
+        let client = Arc::new(EnclaveClient::with_config(Transport::Tcp(addr), enclave_key(), config));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let client = Arc::clone(&client);
+                tokio::spawn(async move { client.setup_config(&"pw".into()).await })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap().is_ok());
+        }
+
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert!(client.idle.lock().unwrap().len() <= 2);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_health_checked() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut stream = accept_and_handshake(&listener).await;
+            let mut kinds = Vec::new();
+            for _ in 0..3 {
+                let envelope = read_request(&mut stream).await;
+                let response = match envelope.request {
+                    EnclaveRequest::Hello { .. } => {
+                        kinds.push("hello");
+                        EnclaveResponse::Version {
+                            version: PROTOCOL_VERSION,
+                        }
+                    }
+                    _ => {
+                        kinds.push("setup");
+                        EnclaveResponse::ConfigSetup {
+                            config: "{}".to_string(),
+                        }
+                    }
+                };
+                write_response(&mut stream, envelope.request_id, response).await;
+            }
+            kinds
+        });
+
+        let config = ClientConfig {
+            health_check_interval: Duration::ZERO,
+            ..ClientConfig::default()
+        };
+        let client = EnclaveClient::with_config(Transport::Tcp(addr), enclave_key(), config);
+        client.setup_config(&"pw".into()).await.unwrap();
+        client.setup_config(&"pw".into()).await.unwrap();
+
+        // The second request pings the pooled connection before using it
+        assert_eq!(server.await.unwrap(), vec!["setup", "hello", "setup"]);
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut stream = accept_and_handshake(&listener).await;
+            // Read the request but never answer it
+            let _ = read_request(&mut stream).await;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key());
+        let started = std::time::Instant::now();
+        let result = client
+            .request_with_timeout(EnclaveRequest::hello(), Duration::from_millis(200))
+            .await;
+        assert!(matches!(result, Err(KeyManagerError::Unavailable(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_backoff_fails_fast_then_recovers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let config = ClientConfig {
+            request_timeout: Duration::from_millis(300),
+            initial_backoff: Duration::from_millis(500),
+            ..ClientConfig::default()
+        };
+        let client = EnclaveClient::with_config(Transport::Tcp(addr), enclave_key(), config);
+
+        assert!(matches!(
+            client.setup_config(&"pw".into()).await,
+            Err(KeyManagerError::Unavailable(_))
+        ));
+
+        // While backing off, callers get an answer without another connect attempt
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            client.setup_config(&"pw".into()).await,
+            Err(KeyManagerError::Unavailable(_))
+        ));
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // The enclave comes back on the same address
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let open = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_setup(stream, open, peak).await;
+        });
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(client.setup_config(&"pw".into()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stale_pooled_connection_is_replaced() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            // The first connection serves one request and then the enclave "restarts"
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            write_response(&mut stream, envelope.request_id, EnclaveResponse::WalletLocked).await;
+            drop(stream);
+
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            assert!(matches!(envelope.request, EnclaveRequest::LockWallet { .. }));
+            write_response(&mut stream, envelope.request_id, EnclaveResponse::WalletLocked).await;
+        });
+
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key());
+        client.lock_wallet("session").await.unwrap();
+        // The pooled connection is dead; the request transparently retries on a new one
+        client.lock_wallet("session").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_non_idempotent_request_is_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let seen = requests.clone();
+
+        tokio::spawn(async move {
+            let mut stream = accept_and_handshake(&listener).await;
+            let envelope = read_request(&mut stream).await;
+            let response = EnclaveResponse::ConfigSetup {
+                config: "first".to_string(),
+            };
+            write_response(&mut stream, envelope.request_id, response).await;
+            // The enclave reads the next request and dies before answering it
+            read_request(&mut stream).await;
+            seen.fetch_add(1, Ordering::SeqCst);
+            drop(stream);
+
+            loop {
+                let mut stream = accept_and_handshake(&listener).await;
+                if stream.recv().await.unwrap().is_some() {
+                    seen.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        let client = EnclaveClient::new(Transport::Tcp(addr), enclave_key());
+        assert_eq!(client.setup_config(&"pw".into()).await.unwrap(), "first");
+        assert!(matches!(
+            client.setup_config(&"pw".into()).await,
+            Err(KeyManagerError::Unavailable(_))
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
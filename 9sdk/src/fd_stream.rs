@@ -0,0 +1,284 @@
+//! Readiness-driven async adapters for socket types tokio doesn't wrap natively
+//!
+//! Sockets are switched to non-blocking mode and registered with the runtime through
+//! [`AsyncFd`], so a `WouldBlock` parks the task until the reactor reports readiness
+//! instead of returning `Pending` with no waker. vsock uses these directly; any other
+//! socket that owns its descriptor (e.g. a Unix socket in tests) can stand in for it.
+
+use crate::transport::TransportStream;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsFd, AsRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Connected socket that can be driven by [`AsyncFdStream`]
+///
+/// `AsFd` means the socket owns its descriptor, which is what makes registering it sound.
+pub trait FdSocket: Read + Write + AsFd + AsRawFd {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+/// Listening socket that can be driven by [`AsyncFdListener`]
+pub trait FdListener: AsFd + AsRawFd {
+    type Stream: FdSocket;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+}
+
+impl FdSocket for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl FdListener for UnixListener {
+    type Stream = UnixStream;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+#[cfg(feature = "vsock")]
+impl FdSocket for vsock::VsockStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        vsock::VsockStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(feature = "vsock")]
+impl FdListener for vsock::VsockListener {
+    type Stream = vsock::VsockStream;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        vsock::VsockListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn accept_stream(&self) -> io::Result<vsock::VsockStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+/// Registers an owned socket with the reactor
+fn register<T: AsFd + AsRawFd>(inner: T) -> io::Result<AsyncFd<T>> {
+    // SAFETY: `T` owns its descriptor (`AsFd`), so it stays open and refers to the same
+    // socket for as long as the returned `AsyncFd` owns `inner`, and the std and vsock
+    // sockets used here always report that same descriptor from `as_raw_fd`.
+    Ok(unsafe { AsyncFd::register(inner) }?)
+}
+
+/// Async stream over a non-blocking socket registered with the tokio reactor
+pub struct AsyncFdStream<S: FdSocket> {
+    inner: AsyncFd<S>,
+}
+
+impl<S: FdSocket> AsyncFdStream<S> {
+    /// Switches the socket to non-blocking mode and registers it with the reactor
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new(stream: S) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            inner: register(stream)?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+}
+
+impl<S: FdSocket + Unpin> AsyncRead for AsyncFdStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = ready!(this.inner.poll_read_ready_mut(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_mut().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // Readiness was stale; try_io cleared it, so poll again to re-arm the waker
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl<S: FdSocket + Unpin> AsyncWrite for AsyncFdStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = ready!(this.inner.poll_write_ready_mut(cx))?;
+            match guard.try_io(|inner| inner.get_mut().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Stream sockets have no userspace buffer to flush
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = match self.inner.get_ref().shutdown_write() {
+            // The peer may already have gone away; the write half is closed either way
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result,
+        };
+        Poll::Ready(result)
+    }
+}
+
+impl<S: FdSocket + Send + Unpin> TransportStream for AsyncFdStream<S> {}
+
+/// Async listener over a non-blocking listening socket
+pub struct AsyncFdListener<L: FdListener> {
+    inner: AsyncFd<L>,
+}
+
+impl<L: FdListener> AsyncFdListener<L> {
+    /// Switches the listener to non-blocking mode and registers it with the reactor
+    pub fn new(listener: L) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            inner: register(listener)?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &L {
+        self.inner.get_ref()
+    }
+
+    /// Waits until a connection is pending without blocking the runtime thread
+    pub async fn accept(&self) -> io::Result<AsyncFdStream<L::Stream>> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().accept_stream()) {
+                Ok(stream) => return AsyncFdStream::new(stream?),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    fn pair() -> (AsyncFdStream<UnixStream>, AsyncFdStream<UnixStream>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (AsyncFdStream::new(a).unwrap(), AsyncFdStream::new(b).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_read_wakes_when_data_arrives() {
+        let (mut reader, mut writer) = pair();
+
+        // The read is pending first and must be woken by the reactor, not by polling
+        let read = tokio::spawn(async move {
+            let mut buf = [0u8; 5];
+            reader.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        writer.write_all(b"hello").await.unwrap();
+
+        let buf = timeout(Duration::from_secs(1), read).await.unwrap().unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_write_waits_for_buffer_space() {
+        let (mut reader, mut writer) = pair();
+        // Larger than the default socket buffer, so the writer has to wait for the reader
+        let payload = vec![7u8; 4 * 1024 * 1024];
+        let expected = payload.clone();
+
+        let write = tokio::spawn(async move {
+            writer.write_all(&payload).await.unwrap();
+        });
+
+        let mut received = vec![0u8; expected.len()];
+        timeout(Duration::from_secs(5), reader.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, expected);
+        write.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_signals_eof() {
+        let (mut reader, mut writer) = pair();
+
+        writer.write_all(b"bye").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(1), reader.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[tokio::test]
+    async fn test_listener_accepts_without_blocking_runtime() {
+        let dir = std::env::temp_dir().join(format!("nine-sdk-fd-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let listener = AsyncFdListener::new(UnixListener::bind(&dir).unwrap()).unwrap();
+
+        // On a current-thread runtime a blocking accept would starve the connecting task
+        let path = dir.clone();
+        let client = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut stream = AsyncFdStream::new(UnixStream::connect(path).unwrap()).unwrap();
+            stream.write_all(b"ping").await.unwrap();
+        });
+
+        let mut stream = timeout(Duration::from_secs(1), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        client.await.unwrap();
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! Length-prefixed framing shared by both ends of a transport
+//!
+//! Each frame is a big-endian `u32` length followed by that many payload bytes.
+//! Lengths above the configured maximum are rejected before any buffer is
+//! allocated, and once the first byte of a frame has arrived the rest of it must
+//! follow within the frame timeout.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest frame accepted by default (1 MiB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// Default time allowed to receive the remainder of a started frame
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum FramingError {
+    #[error("Frame of {size} bytes exceeds maximum of {max} bytes")]
+    FrameTooLarge { size: usize, max: usize },
+    #[error("Connection closed in the middle of a frame")]
+    Truncated,
+    #[error("Timed out waiting for the rest of a frame")]
+    Timeout,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Reads and writes length-prefixed frames
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+    frame_timeout: Duration,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE, DEFAULT_FRAME_TIMEOUT)
+    }
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize, frame_timeout: Duration) -> Self {
+        Self {
+            max_frame_size: max_frame_size.min(u32::MAX as usize),
+            frame_timeout,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    pub fn frame_timeout(&self) -> Duration {
+        self.frame_timeout
+    }
+
+    /// Reads one frame, returning `None` if the peer closed the stream between frames
+    pub async fn read_frame<R>(&self, reader: &mut R) -> Result<Option<Vec<u8>>, FramingError>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut header = [0u8; LENGTH_PREFIX_SIZE];
+
+        // Waiting for the first byte is idle time, not a partial frame
+        if reader.read(&mut header[..1]).await? == 0 {
+            return Ok(None);
+        }
+
+        let frame = async {
+            read_exact_async(reader, &mut header[1..]).await?;
+            let length = self.check_size(u32::from_be_bytes(header) as usize)?;
+
+            let mut payload = vec![0u8; length];
+            read_exact_async(reader, &mut payload).await?;
+            Ok(payload)
+        };
+
+        tokio::time::timeout(self.frame_timeout, frame)
+            .await
+            .map_err(|_| FramingError::Timeout)?
+            .map(Some)
+    }
+
+    /// Writes one frame and flushes the writer
+    pub async fn write_frame<W>(&self, writer: &mut W, payload: &[u8]) -> Result<(), FramingError>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let length = self.check_size(payload.len())?;
+        writer.write_all(&(length as u32).to_be_bytes()).await?;
+        writer.write_all(payload).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Blocking variant of [`FrameCodec::read_frame`]
+    ///
+    /// The frame timeout is enforced by the caller through the socket's read timeout;
+    /// an expired read surfaces as [`FramingError::Timeout`].
+    pub fn read_frame_blocking<R: Read>(&self, reader: &mut R) -> Result<Option<Vec<u8>>, FramingError> {
+        let mut header = [0u8; LENGTH_PREFIX_SIZE];
+
+        match reader.read(&mut header[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) => return Err(map_blocking_error(e)),
+        }
+
+        reader
+            .read_exact(&mut header[1..])
+            .map_err(map_blocking_error)?;
+        let length = self.check_size(u32::from_be_bytes(header) as usize)?;
+
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload).map_err(map_blocking_error)?;
+        Ok(Some(payload))
+    }
+
+    /// Blocking variant of [`FrameCodec::write_frame`]
+    pub fn write_frame_blocking<W: Write>(&self, writer: &mut W, payload: &[u8]) -> Result<(), FramingError> {
+        let length = self.check_size(payload.len())?;
+        writer.write_all(&(length as u32).to_be_bytes())?;
+        writer.write_all(payload)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn check_size(&self, size: usize) -> Result<usize, FramingError> {
+        if size > self.max_frame_size {
+            Err(FramingError::FrameTooLarge {
+                size,
+                max: self.max_frame_size,
+            })
+        } else {
+            Ok(size)
+        }
+    }
+}
+
+async fn read_exact_async<R>(reader: &mut R, buf: &mut [u8]) -> Result<(), FramingError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(FramingError::Truncated),
+        Err(e) => Err(FramingError::Io(e)),
+    }
+}
+
+fn map_blocking_error(error: io::Error) -> FramingError {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => FramingError::Truncated,
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => FramingError::Timeout,
+        _ => FramingError::Io(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let codec = FrameCodec::default();
+        let (mut client, mut server) = duplex(64);
+
+        let writer = tokio::spawn(async move {
+            codec.write_frame(&mut client, b"hello").await.unwrap();
+            codec.write_frame(&mut client, b"").await.unwrap();
+        });
+
+        assert_eq!(codec.read_frame(&mut server).await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(codec.read_frame(&mut server).await.unwrap(), Some(Vec::new()));
+        writer.await.unwrap();
+
+        // Writer dropped between frames: clean end of stream
+        assert_eq!(codec.read_frame(&mut server).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected_before_allocation() {
+        let codec = FrameCodec::new(16, DEFAULT_FRAME_TIMEOUT);
+        let (mut client, mut server) = duplex(64);
+
+        // Advertise a 4 GiB frame without sending any payload
+        client.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+
+        let result = codec.read_frame(&mut server).await;
+        assert!(matches!(
+            result,
+            Err(FramingError::FrameTooLarge { size, max: 16 }) if size == u32::MAX as usize
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_write_is_rejected() {
+        let codec = FrameCodec::new(4, DEFAULT_FRAME_TIMEOUT);
+        let (mut client, _server) = duplex(64);
+
+        let result = codec.write_frame(&mut client, b"too long").await;
+        assert!(matches!(result, Err(FramingError::FrameTooLarge { size: 8, max: 4 })));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame() {
+        let codec = FrameCodec::default();
+        let (mut client, mut server) = duplex(64);
+
+        client.write_all(&10u32.to_be_bytes()).await.unwrap();
+        client.write_all(b"abc").await.unwrap();
+        drop(client);
+
+        let result = codec.read_frame(&mut server).await;
+        assert!(matches!(result, Err(FramingError::Truncated)));
+    }
+
+    #[tokio::test]
+    async fn test_partial_frame_times_out() {
+        let codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE, Duration::from_millis(50));
+        let (mut client, mut server) = duplex(64);
+
+        // Half a length prefix, then silence
+        client.write_all(&[0, 0]).await.unwrap();
+
+        let result = codec.read_frame(&mut server).await;
+        assert!(matches!(result, Err(FramingError::Timeout)));
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_does_not_time_out() {
+        let codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE, Duration::from_millis(50));
+        let (mut client, mut server) = duplex(64);
+
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            codec.write_frame(&mut client, b"late").await.unwrap();
+        });
+
+        assert_eq!(codec.read_frame(&mut server).await.unwrap(), Some(b"late".to_vec()));
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn test_blocking_roundtrip_and_limits() {
+        let codec = FrameCodec::new(8, DEFAULT_FRAME_TIMEOUT);
+
+        let mut buffer = Vec::new();
+        codec.write_frame_blocking(&mut buffer, b"ping").unwrap();
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(codec.read_frame_blocking(&mut reader).unwrap(), Some(b"ping".to_vec()));
+        assert_eq!(codec.read_frame_blocking(&mut reader).unwrap(), None);
+
+        let mut reader = Cursor::new(64u32.to_be_bytes().to_vec());
+        assert!(matches!(
+            codec.read_frame_blocking(&mut reader),
+            Err(FramingError::FrameTooLarge { size: 64, max: 8 })
+        ));
+
+        let mut reader = Cursor::new(vec![0, 0, 0, 4, b'p']);
+        assert!(matches!(
+            codec.read_frame_blocking(&mut reader),
+            Err(FramingError::Truncated)
+        ));
+    }
+}
@@ -1,16 +1,39 @@
 use argon2::{
     Argon2,
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, Salt, SaltString},
 };
 use rand::{RngCore, thread_rng};
 use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 use thiserror::Error;
-use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
 
+pub mod attestation;
+pub mod client;
+#[cfg(unix)]
+pub mod fd_stream;
+pub mod framing;
+pub mod lockout;
+pub mod protocol;
+pub mod sealed;
+pub mod secret;
+pub mod secure_channel;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 pub mod transport;
 
-pub use transport::{Transport, connect, listen};
+pub use attestation::{AttestationError, AttestationVerifier, Attestor};
+pub use client::{ClientConfig, CreatedWallet, EnclaveClient, SelectedAccount, UnlockedWallet};
+pub use framing::{FrameCodec, FramingError};
+pub use lockout::{AttemptTracker, LockoutPolicy};
+pub use protocol::{EnclaveRequest, EnclaveResponse, ErrorCode, SealedWallet};
+pub use sealed::{SealedBlob, SealedBlobError};
+pub use secret::{DerivedKey, SecretBytes, SecretPassword, SecretPhrase};
+pub use secure_channel::{ChannelError, ClientIdentity, ClientPublicKey, EnclaveIdentity, EnclavePublicKey, SecureChannel};
+pub use transport::{Transport, TransportListener, connect, listen};
 
 #[derive(Error, Debug)]
 pub enum KeyManagerError {
@@ -28,6 +51,106 @@ pub enum KeyManagerError {
     EncryptionError(String),
     #[error("Decryption error: {0}")]
     DecryptionError(String),
+    #[error("Enclave error: {0}")]
+    EnclaveError(String),
+    #[error("Unexpected enclave response")]
+    UnexpectedResponse,
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+    #[error("Incompatible protocol version: local {local}, remote {remote}")]
+    IncompatibleVersion { local: u16, remote: u16 },
+    #[error("Framing error: {0}")]
+    FramingError(#[from] FramingError),
+    #[error("Enclave unavailable: {0}")]
+    Unavailable(String),
+    #[error("Secure channel error: {0}")]
+    SecureChannel(ChannelError),
+    #[error("Attestation failed: {0}")]
+    AttestationFailed(#[from] AttestationError),
+    #[error("Wallet is locked")]
+    WalletLocked,
+    #[error("Signing error: {0}")]
+    SigningError(String),
+    #[error("Stored wallet failed its integrity check")]
+    IntegrityCheckFailed,
+    #[error("Wallet has no recovery phrase to derive accounts from")]
+    NotHdWallet,
+    #[error("Not a valid private key or recovery phrase")]
+    InvalidImport,
+    #[error("Too many failed attempts, retry in {retry_after_secs}s")]
+    LockedOut { retry_after_secs: u64 },
+    #[error("Client is not authorized for this request")]
+    Unauthorized,
+}
+
+impl KeyManagerError {
+    /// Lockout error for `retry_after`, rounded up to whole seconds
+    pub fn locked_out(retry_after: std::time::Duration) -> Self {
+        let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        KeyManagerError::LockedOut { retry_after_secs }
+    }
+}
+
+impl From<ChannelError> for KeyManagerError {
+    fn from(error: ChannelError) -> Self {
+        match error {
+            // Framing failures mean the same thing with or without encryption
+            ChannelError::Framing(e) => KeyManagerError::FramingError(e),
+            other => KeyManagerError::SecureChannel(other),
+        }
+    }
+}
+
+/// Argon2 variant used for key derivation
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfAlgorithm {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+/// Key derivation parameters, recorded in every config so they can be raised later
+///
+/// The default matches `Argon2::default()`, which is what configs created before the
+/// parameters were recorded were derived with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    pub version: u32,
+    /// Memory cost in KiB
+    pub m_cost: u32,
+    /// Number of passes
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            version: argon2::Version::V0x13 as u32,
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Argon2 instance for these parameters, failing if they are out of range
+    pub fn argon2(&self) -> Result<Argon2<'static>, KeyManagerError> {
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2d => argon2::Algorithm::Argon2d,
+            KdfAlgorithm::Argon2i => argon2::Algorithm::Argon2i,
+            KdfAlgorithm::Argon2id => argon2::Algorithm::Argon2id,
+        };
+        let version = argon2::Version::try_from(self.version)
+            .map_err(|e| KeyManagerError::KeyGenerationError(e.to_string()))?;
+        let params = argon2::Params::new(self.m_cost, self.t_cost, self.parallelism, None)
+            .map_err(|e| KeyManagerError::KeyGenerationError(e.to_string()))?;
+        Ok(Argon2::new(algorithm, version, params))
+    }
 }
 
 /// Configuration for encrypted keys
@@ -36,35 +159,63 @@ pub struct EncryptedKeyConfig {
     pub password_hash: String,
     pub salt1: String,
     pub salt2: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum EnclaveRequest {
-    SetupConfig { password: String },
-    VerifyAndDeriveKeys { password: String },
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum EnclaveResponse {
-    ConfigSetup { config: String },
-    Keys { key1: Vec<u8>, key2: Vec<u8> },
-    Error { message: String },
+    /// Parameters `salt1` and `salt2` are derived with
+    #[serde(default)]
+    pub kdf: KdfParams,
 }
 
 /// Manager for handling sensitive operations
+///
+/// The `*_for` methods are stateless and take the user's config explicitly, so a
+/// single manager can serve many users concurrently. The remaining methods keep a
+/// single config in memory for callers that manage one user at a time.
+///
+/// New configs are created with the manager's [`KdfParams`]; existing configs are always
+/// verified with the parameters they record.
 pub struct KeyManager {
     config: Mutex<Option<EncryptedKeyConfig>>,
+    params: KdfParams,
+    attempts: AttemptTracker,
 }
 
 impl KeyManager {
     pub fn new() -> Self {
         Self {
             config: Mutex::new(None),
+            params: KdfParams::default(),
+            attempts: AttemptTracker::default(),
         }
     }
 
-    pub async fn setup_config(&self, password: &str) -> Result<String, KeyManagerError> {
-        let password_hash = hash_password(password)?;
+    /// Creates a manager that derives new configs with `params`
+    pub fn with_params(params: KdfParams) -> Result<Self, KeyManagerError> {
+        params.argon2()?;
+        Ok(Self {
+            config: Mutex::new(None),
+            params,
+            attempts: AttemptTracker::default(),
+        })
+    }
+
+    /// Locks out password guesses against a config according to `policy`
+    pub fn with_lockout(mut self, policy: LockoutPolicy) -> Self {
+        self.attempts = AttemptTracker::new(policy);
+        self
+    }
+
+    pub fn params(&self) -> KdfParams {
+        self.params
+    }
+
+    /// Whether `config` was derived with this manager's parameters
+    pub fn is_current(&self, config: &EncryptedKeyConfig) -> bool {
+        config.kdf == self.params
+    }
+
+    /// Creates a fresh config for `password` without storing it
+    pub async fn create_config(&self, password: &SecretPassword) -> Result<EncryptedKeyConfig, KeyManagerError> {
+        let argon2 = self.params.argon2()?;
+        let password_hash = hash_password(&argon2, password.expose())?;
 
         let mut salt1 = [0u8; 16];
         let mut salt2 = [0u8; 16];
@@ -72,33 +223,43 @@ impl KeyManager {
         rng.fill_bytes(&mut salt1);
         rng.fill_bytes(&mut salt2);
 
-        let config = EncryptedKeyConfig {
+        Ok(EncryptedKeyConfig {
             password_hash,
-            salt1: hex::encode(&salt1),
-            salt2: hex::encode(&salt2),
-        };
+            salt1: hex::encode(salt1),
+            salt2: hex::encode(salt2),
+            kdf: self.params,
+        })
+    }
 
+    pub async fn setup_config(&self, password: &SecretPassword) -> Result<String, KeyManagerError> {
+        let config = self.create_config(password).await?;
         let config_json = serde_json::to_string_pretty(&config)?;
         *self.config.lock().unwrap() = Some(config);
         Ok(config_json)
     }
 
-    pub async fn verify_and_derive_keys(
+    /// Verifies `password` against `config` and derives its keys
+    ///
+    /// Failed attempts are counted per password hash, whichever way it is encoded; once the
+    /// lockout policy kicks in, further attempts fail with [`KeyManagerError::LockedOut`]
+    /// without being checked.
+    pub async fn verify_and_derive_keys_for(
         &self,
-        password: &str,
-    ) -> Result<([u8; 32], [u8; 32]), KeyManagerError> {
-        let config = {
-            let guard = self.config.lock().unwrap();
-            guard
-                .as_ref()
-                .ok_or(KeyManagerError::InvalidConfig)?
-                .clone()
-        };
+        config: &EncryptedKeyConfig,
+        password: &SecretPassword,
+    ) -> Result<(DerivedKey, DerivedKey), KeyManagerError> {
+        let argon2 = config.kdf.argon2()?;
+        let password = password.expose();
 
         // Verify password
-        if !verify_password(password, &config.password_hash) {
+        let lockout_key = lockout_key(&config.password_hash);
+        self.attempts
+            .begin(&lockout_key)
+            .map_err(KeyManagerError::locked_out)?;
+        if !verify_password(&argon2, password, &config.password_hash) {
             return Err(KeyManagerError::AuthenticationFailed);
         }
+        self.attempts.succeed(&lockout_key);
 
         // Decode salts
         let salt1 = hex::decode(&config.salt1)
@@ -107,12 +268,27 @@ impl KeyManager {
             .map_err(|e| KeyManagerError::KeyGenerationError(e.to_string()))?;
 
         // Derive keys
-        let key1 = derive_key(password, &salt1)?;
-        let key2 = derive_key(password, &salt2)?;
+        let key1 = derive_key(&argon2, password, &salt1)?;
+        let key2 = derive_key(&argon2, password, &salt2)?;
 
         Ok((key1, key2))
     }
 
+    pub async fn verify_and_derive_keys(
+        &self,
+        password: &SecretPassword,
+    ) -> Result<(DerivedKey, DerivedKey), KeyManagerError> {
+        let config = {
+            let guard = self.config.lock().unwrap();
+            guard
+                .as_ref()
+                .ok_or(KeyManagerError::InvalidConfig)?
+                .clone()
+        };
+
+        self.verify_and_derive_keys_for(&config, password).await
+    }
+
     pub fn set_config(&self, config: EncryptedKeyConfig) {
         let mut guard = self.config.lock().unwrap();
         *guard = Some(config);
@@ -120,10 +296,9 @@ impl KeyManager {
 }
 
 /// Hashes a password using Argon2
-fn hash_password(password: &str) -> Result<String, KeyManagerError> {
+fn hash_password(argon2: &Argon2, password: &str) -> Result<String, KeyManagerError> {
     let mut rng = thread_rng();
     let salt = SaltString::generate(&mut rng);
-    let argon2 = Argon2::default();
 
     Ok(argon2
         .hash_password(password.as_bytes(), &salt)
@@ -132,23 +307,37 @@ fn hash_password(password: &str) -> Result<String, KeyManagerError> {
 }
 
 /// Verifies a password against a stored hash
-fn verify_password(password: &str, stored_hash: &str) -> bool {
+fn verify_password(argon2: &Argon2, password: &str, stored_hash: &str) -> bool {
     let parsed_hash = match PasswordHash::new(stored_hash) {
         Ok(hash) => hash,
         Err(_) => return false,
     };
-    let argon2 = Argon2::default();
     argon2
         .verify_password(password.as_bytes(), &parsed_hash)
         .is_ok()
 }
 
+/// Names the salt and hash bytes of a PHC string, so re-encoding it (reordering its
+/// parameters, say) still counts against the same lockout
+fn lockout_key(stored_hash: &str) -> String {
+    let Ok(parsed) = PasswordHash::new(stored_hash) else {
+        // Can't be verified against either, so nothing is guessed through it
+        return stored_hash.to_string();
+    };
+    let mut salt_buf = [0u8; Salt::MAX_LENGTH];
+    let salt = parsed
+        .salt
+        .and_then(|salt| salt.decode_b64(&mut salt_buf).ok())
+        .unwrap_or_default();
+    let hash = parsed.hash.as_ref().map(|hash| hash.as_bytes()).unwrap_or_default();
+    format!("{}:{}", hex::encode(salt), hex::encode(hash))
+}
+
 /// Derives a key from a password and salt
-fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], KeyManagerError> {
-    let mut key = [0u8; 32];
-    let argon2 = Argon2::default();
+fn derive_key(argon2: &Argon2, password: &str, salt: &[u8]) -> Result<DerivedKey, KeyManagerError> {
+    let mut key = DerivedKey::from([0u8; 32]);
     argon2
-        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .hash_password_into(password.as_bytes(), salt, key.expose_mut())
         .map_err(|e| KeyManagerError::KeyGenerationError(e.to_string()))?;
     Ok(key)
 }
@@ -158,27 +347,207 @@ pub fn encrypt_chacha20(
     key: &[u8],
     plaintext: &[u8],
     nonce: &[u8; 12],
+) -> Result<Vec<u8>, KeyManagerError> {
+    encrypt_chacha20_with_aad(key, plaintext, nonce, &[])
+}
+
+/// Decrypts ciphertext using ChaCha20Poly1305
+pub fn decrypt_chacha20(
+    key: &[u8],
+    ciphertext: &[u8],
+    nonce: &[u8; 12],
+) -> Result<SecretBytes, KeyManagerError> {
+    decrypt_chacha20_with_aad(key, ciphertext, nonce, &[])
+}
+
+/// Encrypts plaintext using ChaCha20Poly1305, authenticating `aad` alongside it
+pub fn encrypt_chacha20_with_aad(
+    key: &[u8],
+    plaintext: &[u8],
+    nonce: &[u8; 12],
+    aad: &[u8],
 ) -> Result<Vec<u8>, KeyManagerError> {
     let cipher = ChaCha20Poly1305::new_from_slice(key)
         .map_err(|e| KeyManagerError::EncryptionError(e.to_string()))?;
     let nonce = Nonce::from_slice(nonce);
     let ciphertext = cipher
-        .encrypt(nonce, plaintext)
+        .encrypt(nonce, Payload { msg: plaintext, aad })
         .map_err(|e| KeyManagerError::EncryptionError(e.to_string()))?;
     Ok(ciphertext)
 }
 
-/// Decrypts ciphertext using ChaCha20Poly1305
-pub fn decrypt_chacha20(
+/// Decrypts ciphertext using ChaCha20Poly1305, failing unless `aad` matches what was sealed
+pub fn decrypt_chacha20_with_aad(
     key: &[u8],
     ciphertext: &[u8],
     nonce: &[u8; 12],
-) -> Result<Vec<u8>, KeyManagerError> {
+    aad: &[u8],
+) -> Result<SecretBytes, KeyManagerError> {
     let cipher = ChaCha20Poly1305::new_from_slice(key)
         .map_err(|e| KeyManagerError::DecryptionError(e.to_string()))?;
     let nonce = Nonce::from_slice(nonce);
 
     cipher
-        .decrypt(nonce, ciphertext)
+        .decrypt(nonce, Payload { msg: ciphertext, aad })
+        .map(SecretBytes::new)
         .map_err(|e| KeyManagerError::DecryptionError(e.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_keyed_derivation_is_independent_per_user() {
+        let key_manager = KeyManager::new();
+        let alice = key_manager.create_config(&"alice-password".into()).await.unwrap();
+        let bob = key_manager.create_config(&"bob-password".into()).await.unwrap();
+
+        let alice_keys = key_manager
+            .verify_and_derive_keys_for(&alice, &"alice-password".into())
+            .await
+            .unwrap();
+        let bob_keys = key_manager
+            .verify_and_derive_keys_for(&bob, &"bob-password".into())
+            .await
+            .unwrap();
+        assert_ne!(alice_keys, bob_keys);
+
+        // Creating configs must not touch the stored single-user config
+        assert!(matches!(
+            key_manager.verify_and_derive_keys(&"alice-password".into()).await,
+            Err(KeyManagerError::InvalidConfig)
+        ));
+
+        // Derivation is deterministic for the same config and password
+        let again = key_manager
+            .verify_and_derive_keys_for(&alice, &"alice-password".into())
+            .await
+            .unwrap();
+        assert_eq!(alice_keys, again);
+    }
+
+    #[tokio::test]
+    async fn test_configs_record_their_parameters() {
+        let params = KdfParams {
+            m_cost: 1024,
+            t_cost: 1,
+            ..KdfParams::default()
+        };
+        let cheap = KeyManager::with_params(params).unwrap();
+        let config = cheap.create_config(&"password".into()).await.unwrap();
+        assert_eq!(config.kdf, params);
+        assert!(config.password_hash.contains("m=1024,t=1"));
+
+        // Another manager still derives the same keys from the recorded parameters
+        let default = KeyManager::new();
+        assert!(!default.is_current(&config));
+        assert_eq!(
+            default.verify_and_derive_keys_for(&config, &"password".into()).await.unwrap(),
+            cheap.verify_and_derive_keys_for(&config, &"password".into()).await.unwrap()
+        );
+
+        let invalid = KdfParams {
+            parallelism: 0,
+            ..KdfParams::default()
+        };
+        assert!(KeyManager::with_params(invalid).is_err());
+    }
+
+    #[test]
+    fn test_configs_without_parameters_use_the_old_defaults() {
+        let config: EncryptedKeyConfig =
+            serde_json::from_str(r#"{"password_hash": "", "salt1": "", "salt2": ""}"#).unwrap();
+        assert_eq!(config.kdf, KdfParams::default());
+        assert!(KeyManager::new().is_current(&config));
+
+        // The recorded defaults must keep matching what `Argon2::default()` used to derive with
+        let mut from_params = [0u8; 32];
+        let mut from_default = [0u8; 32];
+        let argon2 = config.kdf.argon2().unwrap();
+        argon2.hash_password_into(b"pw", b"saltsaltsalt", &mut from_params).unwrap();
+        Argon2::default()
+            .hash_password_into(b"pw", b"saltsaltsalt", &mut from_default)
+            .unwrap();
+        assert_eq!(from_params, from_default);
+    }
+
+    #[test]
+    fn test_aad_must_match() {
+        let key = [5u8; 32];
+        let nonce = [1u8; 12];
+        let ciphertext = encrypt_chacha20_with_aad(&key, b"secret", &nonce, b"alice").unwrap();
+
+        assert_eq!(decrypt_chacha20_with_aad(&key, &ciphertext, &nonce, b"alice").unwrap().as_slice(), b"secret");
+        assert!(decrypt_chacha20_with_aad(&key, &ciphertext, &nonce, b"bob").is_err());
+        assert!(decrypt_chacha20(&key, &ciphertext, &nonce).is_err());
+
+        // Without associated data the variants agree with the plain functions
+        let plain = encrypt_chacha20(&key, b"secret", &nonce).unwrap();
+        assert_eq!(decrypt_chacha20_with_aad(&key, &plain, &nonce, &[]).unwrap().as_slice(), b"secret");
+    }
+
+    #[tokio::test]
+    async fn test_keyed_derivation_rejects_other_users_password() {
+        let key_manager = KeyManager::new();
+        let alice = key_manager.create_config(&"alice-password".into()).await.unwrap();
+
+        let result = key_manager
+            .verify_and_derive_keys_for(&alice, &"bob-password".into())
+            .await;
+        assert!(matches!(result, Err(KeyManagerError::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_failures_lock_out_the_config() {
+        let key_manager = KeyManager::new().with_lockout(LockoutPolicy {
+            free_attempts: 1,
+            base_delay: std::time::Duration::from_secs(60),
+            ..LockoutPolicy::default()
+        });
+        let config = key_manager.create_config(&"right".into()).await.unwrap();
+
+        for _ in 0..2 {
+            let result = key_manager.verify_and_derive_keys_for(&config, &"wrong".into()).await;
+            assert!(matches!(result, Err(KeyManagerError::AuthenticationFailed)));
+        }
+        // Even the right password is refused until the lockout ends
+        let result = key_manager.verify_and_derive_keys_for(&config, &"right".into()).await;
+        assert!(matches!(result, Err(KeyManagerError::LockedOut { retry_after_secs: 1..=60 })));
+
+        // Other configs are unaffected
+        let other = key_manager.create_config(&"other".into()).await.unwrap();
+        assert!(key_manager.verify_and_derive_keys_for(&other, &"other".into()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reencoded_hashes_share_a_lockout() {
+        let key_manager = KeyManager::new().with_lockout(LockoutPolicy {
+            free_attempts: 1,
+            base_delay: std::time::Duration::from_secs(60),
+            ..LockoutPolicy::default()
+        });
+        let config = key_manager.create_config(&"right".into()).await.unwrap();
+
+        // The same hash with its parameters in another order
+        let mut fields: Vec<String> = config.password_hash.split('$').map(str::to_string).collect();
+        let mut params: Vec<&str> = fields[3].split(',').collect();
+        params.reverse();
+        fields[3] = params.join(",");
+        let reencoded = EncryptedKeyConfig {
+            password_hash: fields.join("$"),
+            ..config.clone()
+        };
+        assert_ne!(reencoded.password_hash, config.password_hash);
+        assert!(key_manager.verify_and_derive_keys_for(&reencoded, &"right".into()).await.is_ok());
+
+        for config in [&config, &reencoded] {
+            let result = key_manager.verify_and_derive_keys_for(config, &"wrong".into()).await;
+            assert!(matches!(result, Err(KeyManagerError::AuthenticationFailed)));
+        }
+        for config in [&config, &reencoded] {
+            let result = key_manager.verify_and_derive_keys_for(config, &"right".into()).await;
+            assert!(matches!(result, Err(KeyManagerError::LockedOut { .. })));
+        }
+    }
+}
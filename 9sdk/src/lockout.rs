@@ -0,0 +1,180 @@
+//! Exponential lockout after repeated failed password attempts
+//!
+//! The enclave counts attempts per password hash, so a compromised bot can't guess any
+//! faster than the policy allows. The bot keeps its own per-user count in its database,
+//! which survives restarts and lets it tell users how long to wait.
+//!
+//! The bot's `login_attempts` table is the authoritative limit. The enclave's counts are
+//! only a backstop: they are kept in memory, so they reset when it restarts, and at most
+//! [`MAX_TRACKED_KEYS`] are kept, forgetting the least recently tried past that.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most keys an [`AttemptTracker`] counts at once
+pub const MAX_TRACKED_KEYS: usize = 100_000;
+
+/// How many failures are allowed and how long each one after that locks out for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Consecutive failures allowed before the first lockout
+    pub free_attempts: u32,
+    /// Lockout after the first failure past `free_attempts`; doubles with each one after
+    pub base_delay: Duration,
+    /// Longest a single lockout can last
+    pub max_delay: Duration,
+    /// Failures are forgotten after this long without another attempt
+    pub reset_after: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            free_attempts: 5,
+            base_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(60 * 60),
+            reset_after: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// How long to lock out for after `failures` consecutive failed attempts
+    pub fn delay_after(&self, failures: u32) -> Duration {
+        if failures <= self.free_attempts {
+            return Duration::ZERO;
+        }
+        let doublings = (failures - self.free_attempts - 1).min(31);
+        self.base_delay
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay)
+    }
+}
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    last_attempt: Instant,
+    locked_until: Instant,
+}
+
+/// In-memory attempt counts, keyed by whatever identifies the secret being guessed
+///
+/// Each attempt is charged up front and refunded on success, so guesses sent in
+/// parallel can't all slip in before the first failure is recorded.
+#[derive(Debug)]
+pub struct AttemptTracker {
+    policy: LockoutPolicy,
+    attempts: Mutex<HashMap<String, Attempts>>,
+    max_keys: usize,
+}
+
+impl AttemptTracker {
+    pub fn new(policy: LockoutPolicy) -> Self {
+        Self {
+            policy,
+            attempts: Mutex::new(HashMap::new()),
+            max_keys: MAX_TRACKED_KEYS,
+        }
+    }
+
+    pub fn policy(&self) -> LockoutPolicy {
+        self.policy
+    }
+
+    /// Counts an attempt against `key`, failing with the time left if it is locked out
+    pub fn begin(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        let reset_after = self.policy.reset_after;
+        attempts.retain(|_, entry| now.duration_since(entry.last_attempt) < reset_after);
+        if attempts.len() >= self.max_keys && !attempts.contains_key(key) {
+            let oldest = attempts
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_attempt)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                attempts.remove(&oldest);
+            }
+        }
+
+        let entry = attempts.entry(key.to_string()).or_insert(Attempts {
+            failures: 0,
+            last_attempt: now,
+            locked_until: now,
+        });
+        if entry.locked_until > now {
+            return Err(entry.locked_until - now);
+        }
+        entry.failures += 1;
+        entry.last_attempt = now;
+        entry.locked_until = now + self.policy.delay_after(entry.failures);
+        Ok(())
+    }
+
+    /// Clears the count for `key` after a successful attempt
+    pub fn succeed(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+}
+
+impl Default for AttemptTracker {
+    fn default() -> Self {
+        Self::new(LockoutPolicy::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            free_attempts: 2,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60),
+            reset_after: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn test_delay_doubles_up_to_the_maximum() {
+        let policy = policy();
+        let delays: Vec<u64> = (0..8).map(|failures| policy.delay_after(failures).as_secs()).collect();
+        assert_eq!(delays, [0, 0, 0, 10, 20, 40, 60, 60]);
+        assert_eq!(policy.delay_after(u32::MAX), policy.max_delay);
+    }
+
+    #[test]
+    fn test_tracker_locks_out_and_success_resets() {
+        let tracker = AttemptTracker::new(policy());
+        for _ in 0..3 {
+            tracker.begin("alice").unwrap();
+        }
+        let retry_after = tracker.begin("alice").unwrap_err();
+        assert!(retry_after > Duration::from_secs(9) && retry_after <= Duration::from_secs(10));
+
+        // Other keys are counted separately
+        tracker.begin("bob").unwrap();
+
+        tracker.succeed("alice");
+        tracker.begin("alice").unwrap();
+    }
+
+    #[test]
+    fn test_tracker_forgets_the_least_recently_tried_key_when_full() {
+        let mut tracker = AttemptTracker::new(policy());
+        tracker.max_keys = 2;
+        for _ in 0..3 {
+            tracker.begin("alice").unwrap();
+        }
+        tracker.begin("bob").unwrap();
+        assert!(tracker.begin("alice").is_err());
+
+        tracker.begin("carol").unwrap();
+        assert_eq!(tracker.attempts.lock().unwrap().len(), 2);
+        assert!(!tracker.attempts.lock().unwrap().contains_key("alice"));
+        tracker.begin("alice").unwrap();
+    }
+}
@@ -0,0 +1,552 @@
+//! Wire protocol shared by the bot and the enclave
+//!
+//! Every frame is a length-prefixed JSON envelope carrying the protocol version and
+//! a request id. A connection starts with a `Hello`/`Version` handshake, so bot and
+//! enclave builds can be upgraded independently and refuse incompatible peers.
+//!
+//! Version 2 adds `Attest`, which returns a Nitro attestation document.
+//! Version 3 adds wallet requests: the Ethereum key is generated, unsealed and used
+//! for signing inside the enclave, and the bot only ever sees the address.
+//! Version 4 lets `WalletUnlocked` carry a config re-derived with newer key derivation
+//! parameters.
+//! Version 5 adds `ChangePassword`, which moves a wallet to a config for a new password.
+//! Version 6 adds the `LockedOut` error code, sent once a config has seen too many
+//! failed password attempts; older peers are told `AuthenticationFailed` instead.
+//! Version 7 creates wallets from a BIP-39 recovery phrase, returned once in
+//! `WalletCreated`, and adds `SelectAccount` for signing with further BIP-44 accounts.
+//! Version 8 adds `ImportWallet`, which seals an existing private key or recovery phrase.
+//! Version 9 adds `StorageKey`, which hands out the key for sealing the bot's stored records.
+//! Version 10 covers a wallet's selected account with its MAC, so `AccountSelected`
+//! carries the MAC to store along with the new selection.
+//! Version 11 answers `StorageKey` only for clients whose static key the enclave trusts,
+//! refusing others with the `Unauthorized` error code; older peers are told
+//! `AuthenticationFailed` instead.
+
+use crate::attestation::AttestationError;
+use crate::{DerivedKey, EncryptedKeyConfig, KeyManagerError, SecretPassword, SecretPhrase};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Protocol version spoken by this build
+pub const PROTOCOL_VERSION: u16 = 11;
+/// Oldest protocol version this build still accepts
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// First protocol version that supports [`EnclaveRequest::Attest`]
+pub const ATTESTATION_PROTOCOL_VERSION: u16 = 2;
+/// First protocol version that supports the wallet requests
+pub const WALLET_PROTOCOL_VERSION: u16 = 3;
+/// First protocol version whose peers store the config returned by `WalletUnlocked`
+pub const KDF_UPGRADE_PROTOCOL_VERSION: u16 = 4;
+/// First protocol version that supports [`EnclaveRequest::ChangePassword`]
+pub const CHANGE_PASSWORD_PROTOCOL_VERSION: u16 = 5;
+/// First protocol version that understands [`ErrorCode::LockedOut`]
+pub const LOCKOUT_PROTOCOL_VERSION: u16 = 6;
+/// First protocol version whose peers store a wallet's sealed recovery phrase
+pub const HD_WALLET_PROTOCOL_VERSION: u16 = 7;
+/// First protocol version that supports [`EnclaveRequest::ImportWallet`]
+pub const IMPORT_WALLET_PROTOCOL_VERSION: u16 = 8;
+/// First protocol version that supports [`EnclaveRequest::StorageKey`]
+pub const STORAGE_KEY_PROTOCOL_VERSION: u16 = 9;
+/// First protocol version whose wallet MACs cover [`SealedWallet::account_index`]
+pub const ACCOUNT_MAC_PROTOCOL_VERSION: u16 = 10;
+/// First protocol version that understands [`ErrorCode::Unauthorized`]
+pub const CLIENT_AUTH_PROTOCOL_VERSION: u16 = 11;
+
+/// Machine-readable error category carried in [`EnclaveResponse::Error`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    AuthenticationFailed,
+    InvalidConfig,
+    KeyGeneration,
+    UnsupportedVersion,
+    HandshakeRequired,
+    MalformedRequest,
+    AttestationUnavailable,
+    WalletLocked,
+    SigningFailed,
+    IntegrityCheckFailed,
+    /// Too many failed password attempts; the next one is refused until the delay passes
+    LockedOut { retry_after_secs: u64 },
+    NotHdWallet,
+    InvalidImport,
+    /// The request needs a client identity the enclave trusts
+    Unauthorized,
+    Internal,
+}
+
+/// Ethereum wallet whose private key is sealed under the user's first derived key
+///
+/// Only the enclave can open it; the bot stores it as-is alongside the user's config.
+/// `mac` authenticates every other field under the second derived key, so a tampered
+/// record is refused on unlock. Wallets stored before it was introduced have none.
+///
+/// Current wallets hold a [`SealedBlob`](crate::SealedBlob) bound to a context naming
+/// their owner. Legacy wallets hold a bare ciphertext with its nonce stored alongside.
+/// Wallets created from a recovery phrase also hold the phrase, sealed the same way; their
+/// private key is that of the phrase's first BIP-44 account.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SealedWallet {
+    pub address: String,
+    pub public_key: String,
+    /// Hex sealed blob of the private key, or for legacy wallets the hex ChaCha20Poly1305
+    /// ciphertext of the hex-encoded private key
+    pub encrypted_private_key: String,
+    /// Hex 12-byte nonce of a legacy wallet's ciphertext; empty for sealed blobs
+    pub nonce: String,
+    /// Hex HMAC-SHA256 over the other fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    /// Hex sealed blob of the BIP-39 recovery phrase, for wallets created from one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_mnemonic: Option<String>,
+    /// BIP-44 account of the recovery phrase selected for signing; 0 is the sealed key
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub account_index: u32,
+}
+
+fn is_zero(index: &u32) -> bool {
+    *index == 0
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum EnclaveRequest {
+    Hello {
+        version: u16,
+        min_version: u16,
+    },
+    SetupConfig {
+        password: SecretPassword,
+    },
+    VerifyAndDeriveKeys {
+        config: EncryptedKeyConfig,
+        password: SecretPassword,
+    },
+    /// Asks for an attestation document over `nonce` and the enclave's channel key
+    Attest {
+        nonce: Vec<u8>,
+    },
+    /// Asks for the key the bot encrypts its stored records under for master key `key_id`
+    StorageKey {
+        key_id: String,
+    },
+    /// Generates a wallet and seals it under the key derived from `password`, bound to `context`
+    CreateWallet {
+        config: EncryptedKeyConfig,
+        password: SecretPassword,
+        context: String,
+    },
+    /// Seals an existing wallet like `CreateWallet` does a generated one
+    ///
+    /// `secret` is a hex private key or a BIP-39 recovery phrase, checked in the enclave.
+    ImportWallet {
+        config: EncryptedKeyConfig,
+        password: SecretPassword,
+        context: String,
+        secret: SecretPhrase,
+    },
+    /// Opens `wallet`, which must be bound to `context`, and keeps its signer in the
+    /// enclave until locked or expired
+    UnlockWallet {
+        config: EncryptedKeyConfig,
+        password: SecretPassword,
+        wallet: SealedWallet,
+        context: String,
+    },
+    LockWallet {
+        session: String,
+    },
+    /// Switches `session` to signing with account `index` of its wallet's recovery phrase,
+    /// derived at `m/44'/60'/0'/0/{index}`
+    SelectAccount {
+        session: String,
+        index: u32,
+    },
+    /// Checks `old_password` against `config` and reseals `wallet` under a fresh config
+    /// for `new_password`, keeping its key and address
+    ChangePassword {
+        config: EncryptedKeyConfig,
+        old_password: SecretPassword,
+        new_password: SecretPassword,
+        wallet: SealedWallet,
+        context: String,
+    },
+    /// Signs `message` as an EIP-191 personal message
+    SignMessage {
+        session: String,
+        message: Vec<u8>,
+    },
+    /// Signs an EIP-712 payload given as its JSON `TypedData`
+    SignTypedData {
+        session: String,
+        typed_data: String,
+    },
+    /// Signs an unsigned transaction given as its JSON `TypedTransaction`
+    SignTransaction {
+        session: String,
+        transaction: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum EnclaveResponse {
+    Version { version: u16 },
+    ConfigSetup { config: String },
+    Keys { key1: DerivedKey, key2: DerivedKey },
+    Attestation { document: Vec<u8> },
+    StorageKey { key: DerivedKey },
+    /// `mnemonic` is the wallet's recovery phrase, which is never handed out again
+    WalletCreated {
+        wallet: SealedWallet,
+        #[serde(default)]
+        mnemonic: Option<SecretPhrase>,
+    },
+    /// `upgraded` is set when a wallet in an older format was resealed and should be stored
+    ///
+    /// `config` is set when the user's config was re-derived with the enclave's current
+    /// key derivation parameters; `upgraded` is then sealed under its keys, and the two
+    /// must be stored together.
+    WalletUnlocked {
+        session: String,
+        address: String,
+        upgraded: Option<SealedWallet>,
+        #[serde(default)]
+        config: Option<EncryptedKeyConfig>,
+    },
+    WalletLocked,
+    /// `mac` is the wallet's MAC over the new selection, sent from version 10 on
+    AccountSelected {
+        address: String,
+        #[serde(default)]
+        mac: Option<String>,
+    },
+    /// Config for the new password and the wallet resealed under it, to be stored together
+    PasswordChanged {
+        config: EncryptedKeyConfig,
+        wallet: SealedWallet,
+    },
+    Signature { signature: Vec<u8> },
+    /// EIP-2718 encoding of the signed transaction, ready for `eth_sendRawTransaction`
+    SignedTransaction { raw: Vec<u8> },
+    Error { code: ErrorCode, message: String },
+}
+
+/// Frame sent from the bot to the enclave
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestEnvelope {
+    pub version: u16,
+    pub request_id: u64,
+    pub request: EnclaveRequest,
+}
+
+/// Frame sent from the enclave back to the bot, echoing the request id
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseEnvelope {
+    pub version: u16,
+    pub request_id: u64,
+    pub response: EnclaveResponse,
+}
+
+/// Shows which request was made without any credentials, so requests are safe to log
+///
+/// Passwords, key configs, session tokens and the payloads being signed are left out.
+impl fmt::Debug for EnclaveRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hello { version, min_version } => f
+                .debug_struct("Hello")
+                .field("version", version)
+                .field("min_version", min_version)
+                .finish(),
+            Self::SetupConfig { .. } => f.debug_struct("SetupConfig").finish_non_exhaustive(),
+            Self::VerifyAndDeriveKeys { .. } => f.debug_struct("VerifyAndDeriveKeys").finish_non_exhaustive(),
+            Self::Attest { nonce } => f.debug_struct("Attest").field("nonce", nonce).finish(),
+            Self::StorageKey { key_id } => f.debug_struct("StorageKey").field("key_id", key_id).finish(),
+            Self::CreateWallet { context, .. } => f
+                .debug_struct("CreateWallet")
+                .field("context", context)
+                .finish_non_exhaustive(),
+            Self::ImportWallet { context, .. } => f
+                .debug_struct("ImportWallet")
+                .field("context", context)
+                .finish_non_exhaustive(),
+            Self::UnlockWallet { wallet, context, .. } => f
+                .debug_struct("UnlockWallet")
+                .field("address", &wallet.address)
+                .field("context", context)
+                .finish_non_exhaustive(),
+            Self::LockWallet { .. } => f.debug_struct("LockWallet").finish_non_exhaustive(),
+            Self::SelectAccount { index, .. } => f
+                .debug_struct("SelectAccount")
+                .field("index", index)
+                .finish_non_exhaustive(),
+            Self::ChangePassword { wallet, context, .. } => f
+                .debug_struct("ChangePassword")
+                .field("address", &wallet.address)
+                .field("context", context)
+                .finish_non_exhaustive(),
+            Self::SignMessage { .. } => f.debug_struct("SignMessage").finish_non_exhaustive(),
+            Self::SignTypedData { .. } => f.debug_struct("SignTypedData").finish_non_exhaustive(),
+            Self::SignTransaction { .. } => f.debug_struct("SignTransaction").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl EnclaveRequest {
+    /// Handshake request advertising this build's supported versions
+    pub fn hello() -> Self {
+        Self::Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Oldest protocol version in which this request exists
+    ///
+    /// The enclave refuses requests newer than the version a connection negotiated.
+    pub fn min_version(&self) -> u16 {
+        match self {
+            Self::Hello { .. } | Self::SetupConfig { .. } | Self::VerifyAndDeriveKeys { .. } => MIN_PROTOCOL_VERSION,
+            Self::Attest { .. } => ATTESTATION_PROTOCOL_VERSION,
+            Self::CreateWallet { .. }
+            | Self::UnlockWallet { .. }
+            | Self::LockWallet { .. }
+            | Self::SignMessage { .. }
+            | Self::SignTypedData { .. }
+            | Self::SignTransaction { .. } => WALLET_PROTOCOL_VERSION,
+            Self::ChangePassword { .. } => CHANGE_PASSWORD_PROTOCOL_VERSION,
+            Self::SelectAccount { .. } => HD_WALLET_PROTOCOL_VERSION,
+            Self::ImportWallet { .. } => IMPORT_WALLET_PROTOCOL_VERSION,
+            Self::StorageKey { .. } => STORAGE_KEY_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Whether the enclave can safely see this request twice
+    ///
+    /// Only these are resent when a pooled connection turns out to be dead, since the
+    /// first attempt may have been carried out before the connection dropped.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Self::Hello { .. }
+                | Self::VerifyAndDeriveKeys { .. }
+                | Self::StorageKey { .. }
+                | Self::LockWallet { .. }
+        )
+    }
+}
+
+impl EnclaveResponse {
+    /// Builds an error response from a key manager error
+    pub fn error(error: &KeyManagerError) -> Self {
+        Self::Error {
+            code: ErrorCode::from(error),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<&KeyManagerError> for ErrorCode {
+    fn from(error: &KeyManagerError) -> Self {
+        match error {
+            KeyManagerError::AuthenticationFailed => Self::AuthenticationFailed,
+            KeyManagerError::InvalidConfig => Self::InvalidConfig,
+            KeyManagerError::KeyGenerationError(_) => Self::KeyGeneration,
+            KeyManagerError::SerializationError(_) => Self::MalformedRequest,
+            KeyManagerError::IncompatibleVersion { .. } => Self::UnsupportedVersion,
+            KeyManagerError::AttestationFailed(_) => Self::AttestationUnavailable,
+            KeyManagerError::WalletLocked => Self::WalletLocked,
+            KeyManagerError::SigningError(_) => Self::SigningFailed,
+            KeyManagerError::IntegrityCheckFailed => Self::IntegrityCheckFailed,
+            KeyManagerError::LockedOut { retry_after_secs } => Self::LockedOut {
+                retry_after_secs: *retry_after_secs,
+            },
+            KeyManagerError::NotHdWallet => Self::NotHdWallet,
+            KeyManagerError::InvalidImport => Self::InvalidImport,
+            KeyManagerError::Unauthorized => Self::Unauthorized,
+            _ => Self::Internal,
+        }
+    }
+}
+
+impl ErrorCode {
+    /// Converts a remote error back into the local error type
+    pub fn into_error(self, message: String) -> KeyManagerError {
+        match self {
+            Self::AuthenticationFailed => KeyManagerError::AuthenticationFailed,
+            Self::InvalidConfig => KeyManagerError::InvalidConfig,
+            Self::KeyGeneration => KeyManagerError::KeyGenerationError(message),
+            Self::UnsupportedVersion | Self::HandshakeRequired | Self::MalformedRequest => {
+                KeyManagerError::ProtocolError(message)
+            }
+            Self::AttestationUnavailable => {
+                KeyManagerError::AttestationFailed(AttestationError::Unavailable(message))
+            }
+            Self::WalletLocked => KeyManagerError::WalletLocked,
+            Self::SigningFailed => KeyManagerError::SigningError(message),
+            Self::IntegrityCheckFailed => KeyManagerError::IntegrityCheckFailed,
+            Self::LockedOut { retry_after_secs } => KeyManagerError::LockedOut { retry_after_secs },
+            Self::NotHdWallet => KeyManagerError::NotHdWallet,
+            Self::InvalidImport => KeyManagerError::InvalidImport,
+            Self::Unauthorized => KeyManagerError::Unauthorized,
+            Self::Internal => KeyManagerError::EnclaveError(message),
+        }
+    }
+}
+
+/// Picks the highest version both sides support, if any
+pub fn negotiate_version(peer_version: u16, peer_min_version: u16) -> Option<u16> {
+    let version = peer_version.min(PROTOCOL_VERSION);
+    if version >= peer_min_version.max(MIN_PROTOCOL_VERSION) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_same_version() {
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_newer_peer_falls_back() {
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 5, MIN_PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_rejects_incompatible_peers() {
+        // Peer requires a version newer than we speak
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 2, PROTOCOL_VERSION + 1), None);
+        // Peer only speaks a version older than we accept
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1, 0), None);
+    }
+
+    #[test]
+    fn test_error_code_roundtrip() {
+        let error = KeyManagerError::AuthenticationFailed;
+        let response = EnclaveResponse::error(&error);
+        match response {
+            EnclaveResponse::Error { code, message } => {
+                assert_eq!(code, ErrorCode::AuthenticationFailed);
+                assert!(matches!(
+                    code.into_error(message),
+                    KeyManagerError::AuthenticationFailed
+                ));
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let error = KeyManagerError::SocketError("boom".into());
+        assert_eq!(ErrorCode::from(&error), ErrorCode::Internal);
+
+        let error = KeyManagerError::LockedOut { retry_after_secs: 30 };
+        let code = ErrorCode::from(&error);
+        let code: ErrorCode = serde_json::from_str(&serde_json::to_string(&code).unwrap()).unwrap();
+        assert!(matches!(
+            code.into_error(error.to_string()),
+            KeyManagerError::LockedOut { retry_after_secs: 30 }
+        ));
+    }
+
+    #[test]
+    fn test_secrets_are_redacted_in_debug() {
+        let request = EnclaveRequest::SetupConfig {
+            password: "hunter2".into(),
+        };
+        assert!(!format!("{:?}", request).contains("hunter2"));
+
+        let config = EncryptedKeyConfig {
+            password_hash: "$argon2id$hash".to_string(),
+            salt1: "5a17".to_string(),
+            salt2: "5a18".to_string(),
+            kdf: Default::default(),
+        };
+        let wallet = SealedWallet {
+            address: "0xabc".to_string(),
+            public_key: "04".to_string(),
+            encrypted_private_key: "c1f3e2".to_string(),
+            nonce: String::new(),
+            mac: Some("3ac".to_string()),
+            encrypted_mnemonic: Some("3e3".to_string()),
+            account_index: 0,
+        };
+        let unlock = EnclaveRequest::UnlockWallet {
+            config: config.clone(),
+            password: "hunter2".into(),
+            wallet: wallet.clone(),
+            context: "user:1".to_string(),
+        };
+        assert_eq!(
+            format!("{:?}", unlock),
+            r#"UnlockWallet { address: "0xabc", context: "user:1", .. }"#
+        );
+
+        let requests = [
+            unlock,
+            EnclaveRequest::VerifyAndDeriveKeys {
+                config: config.clone(),
+                password: "hunter2".into(),
+            },
+            EnclaveRequest::ImportWallet {
+                config: config.clone(),
+                password: "hunter2".into(),
+                context: "user:1".to_string(),
+                secret: "0x5ec2e7".into(),
+            },
+            EnclaveRequest::ChangePassword {
+                config,
+                old_password: "hunter2".into(),
+                new_password: "hunter3".into(),
+                wallet,
+                context: "user:1".to_string(),
+            },
+            EnclaveRequest::SignMessage {
+                session: "5e5510n".to_string(),
+                message: b"hello".to_vec(),
+            },
+            EnclaveRequest::SelectAccount {
+                session: "5e5510n".to_string(),
+                index: 1,
+            },
+        ];
+        for request in requests {
+            let debug = format!("{:?}", request);
+            for secret in ["hunter", "argon2", "5a17", "c1f3e2", "3ac", "3e3", "5e5510n", "104", "5ec2e7"] {
+                assert!(!debug.contains(secret), "{} leaks {}", debug, secret);
+            }
+        }
+
+        let response = EnclaveResponse::Keys {
+            key1: [0xaa; 32].into(),
+            key2: [0xbb; 32].into(),
+        };
+        let debug = format!("{:?}", response);
+        assert!(!debug.contains("170") && !debug.contains("187"));
+    }
+
+    #[test]
+    fn test_envelope_serialization() {
+        let envelope = RequestEnvelope {
+            version: PROTOCOL_VERSION,
+            request_id: 7,
+            request: EnclaveRequest::hello(),
+        };
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["version"], PROTOCOL_VERSION);
+        assert_eq!(json["request_id"], 7);
+        assert_eq!(json["request"]["Hello"]["version"], PROTOCOL_VERSION);
+
+        let decoded: RequestEnvelope = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.request_id, 7);
+    }
+}
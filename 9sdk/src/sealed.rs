@@ -0,0 +1,194 @@
+//! Self-describing encrypted blobs bound to a context
+//!
+//! A sealed blob is laid out as
+//!
+//! ```text
+//! version (1) | algorithm (1) | nonce (12) | context length (u16 BE) | context | ciphertext
+//! ```
+//!
+//! Everything before the ciphertext is passed to the AEAD as associated data, so neither
+//! the header nor the context can be altered without failing decryption. Opening also
+//! requires the caller to name the context it expects, which stops a blob sealed for one
+//! owner from being accepted for another.
+
+use crate::{SecretBytes, decrypt_chacha20_with_aad, encrypt_chacha20_with_aad};
+use rand::{RngCore, thread_rng};
+use thiserror::Error;
+
+/// Layout version written by this build
+pub const SEALED_BLOB_VERSION: u8 = 1;
+
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = 2 + NONCE_SIZE + 2;
+
+#[derive(Error, Debug)]
+pub enum SealedBlobError {
+    #[error("Unsupported sealed blob version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unsupported sealed blob algorithm {0}")]
+    UnsupportedAlgorithm(u8),
+    #[error("Malformed sealed blob")]
+    Malformed,
+    #[error("Context too long: {0} bytes")]
+    ContextTooLong(usize),
+    #[error("Sealed blob belongs to a different context")]
+    ContextMismatch,
+    #[error("Encryption failed")]
+    Encryption,
+    #[error("Decryption failed")]
+    Decryption,
+}
+
+/// AEAD used for the ciphertext
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Algorithm {
+    ChaCha20Poly1305 = 1,
+}
+
+impl TryFrom<u8> for Algorithm {
+    type Error = SealedBlobError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            1 => Ok(Self::ChaCha20Poly1305),
+            other => Err(SealedBlobError::UnsupportedAlgorithm(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedBlob {
+    pub version: u8,
+    pub algorithm: Algorithm,
+    pub nonce: [u8; NONCE_SIZE],
+    pub context: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl SealedBlob {
+    /// Encrypts `plaintext` under `key` with a fresh nonce, bound to `context`
+    pub fn seal(key: &[u8], plaintext: &[u8], context: &[u8]) -> Result<Self, SealedBlobError> {
+        if context.len() > u16::MAX as usize {
+            return Err(SealedBlobError::ContextTooLong(context.len()));
+        }
+        let mut nonce = [0u8; NONCE_SIZE];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let mut blob = Self {
+            version: SEALED_BLOB_VERSION,
+            algorithm: Algorithm::ChaCha20Poly1305,
+            nonce,
+            context: context.to_vec(),
+            ciphertext: Vec::new(),
+        };
+        blob.ciphertext = encrypt_chacha20_with_aad(key, plaintext, &nonce, &blob.header())
+            .map_err(|_| SealedBlobError::Encryption)?;
+        Ok(blob)
+    }
+
+    /// Decrypts the blob if it was sealed for `context` under `key`
+    pub fn open(&self, key: &[u8], context: &[u8]) -> Result<SecretBytes, SealedBlobError> {
+        if self.context != context {
+            return Err(SealedBlobError::ContextMismatch);
+        }
+        decrypt_chacha20_with_aad(key, &self.ciphertext, &self.nonce, &self.header())
+            .map_err(|_| SealedBlobError::Decryption)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header();
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SealedBlobError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(SealedBlobError::Malformed);
+        }
+        let version = bytes[0];
+        if version != SEALED_BLOB_VERSION {
+            return Err(SealedBlobError::UnsupportedVersion(version));
+        }
+        let algorithm = Algorithm::try_from(bytes[1])?;
+        let nonce = bytes[2..2 + NONCE_SIZE].try_into().expect("slice has nonce length");
+        let context_len = u16::from_be_bytes([bytes[HEADER_SIZE - 2], bytes[HEADER_SIZE - 1]]) as usize;
+        let rest = &bytes[HEADER_SIZE..];
+        if rest.len() < context_len {
+            return Err(SealedBlobError::Malformed);
+        }
+        let (context, ciphertext) = rest.split_at(context_len);
+
+        Ok(Self {
+            version,
+            algorithm,
+            nonce,
+            context: context.to_vec(),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+
+    /// Serialized header, which doubles as the associated data
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE + self.context.len());
+        header.push(self.version);
+        header.push(self.algorithm as u8);
+        header.extend_from_slice(&self.nonce);
+        header.extend_from_slice(&(self.context.len() as u16).to_be_bytes());
+        header.extend_from_slice(&self.context);
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [3; 32];
+
+    #[test]
+    fn test_roundtrip_through_bytes() {
+        let blob = SealedBlob::seal(&KEY, b"secret", b"user:1").unwrap();
+        let decoded = SealedBlob::from_bytes(&blob.to_bytes()).unwrap();
+        assert_eq!(decoded, blob);
+        assert_eq!(decoded.open(&KEY, b"user:1").unwrap().as_slice(), b"secret");
+    }
+
+    #[test]
+    fn test_context_is_enforced() {
+        let blob = SealedBlob::seal(&KEY, b"secret", b"user:1").unwrap();
+        assert!(matches!(blob.open(&KEY, b"user:2"), Err(SealedBlobError::ContextMismatch)));
+
+        // Rewriting the stored context is caught by the AEAD
+        let mut relabeled = blob.clone();
+        relabeled.context = b"user:2".to_vec();
+        assert!(matches!(relabeled.open(&KEY, b"user:2"), Err(SealedBlobError::Decryption)));
+
+        assert!(matches!(blob.open(&[4; 32], b"user:1"), Err(SealedBlobError::Decryption)));
+    }
+
+    #[test]
+    fn test_malformed_headers_are_rejected() {
+        let bytes = SealedBlob::seal(&KEY, b"secret", b"user:1").unwrap().to_bytes();
+
+        assert!(matches!(SealedBlob::from_bytes(&bytes[..4]), Err(SealedBlobError::Malformed)));
+
+        let mut version = bytes.clone();
+        version[0] = 9;
+        assert!(matches!(
+            SealedBlob::from_bytes(&version),
+            Err(SealedBlobError::UnsupportedVersion(9))
+        ));
+
+        let mut algorithm = bytes.clone();
+        algorithm[1] = 9;
+        assert!(matches!(
+            SealedBlob::from_bytes(&algorithm),
+            Err(SealedBlobError::UnsupportedAlgorithm(9))
+        ));
+
+        let mut context_len = bytes.clone();
+        context_len[HEADER_SIZE - 2] = 0xff;
+        assert!(matches!(SealedBlob::from_bytes(&context_len), Err(SealedBlobError::Malformed)));
+    }
+}
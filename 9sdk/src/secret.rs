@@ -0,0 +1,162 @@
+//! Secret-bearing types that are wiped from memory when dropped
+//!
+//! Passwords and derived keys pass through several layers, each of which may copy them.
+//! Wrapping them here means every copy is zeroized on drop and none of them can end up in
+//! a log line through `Debug`.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Decrypted secret bytes, such as an unsealed private key
+pub type SecretBytes = Zeroizing<Vec<u8>>;
+
+/// User password
+///
+/// Serializes as a plain string, so it can stand in for `String` on the wire.
+#[derive(Clone, Zeroize, ZeroizeOnDrop, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretPassword(String);
+
+impl SecretPassword {
+    pub fn new(password: impl Into<String>) -> Self {
+        Self(password.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretPassword {
+    fn from(password: String) -> Self {
+        Self(password)
+    }
+}
+
+impl From<&str> for SecretPassword {
+    fn from(password: &str) -> Self {
+        Self(password.to_string())
+    }
+}
+
+impl fmt::Debug for SecretPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretPassword([REDACTED])")
+    }
+}
+
+/// BIP-39 recovery phrase
+///
+/// Serializes as a plain string. Shown to the user once, when their wallet is created.
+#[derive(Clone, Zeroize, ZeroizeOnDrop, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretPhrase(String);
+
+impl SecretPhrase {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretPhrase {
+    fn from(phrase: String) -> Self {
+        Self(phrase)
+    }
+}
+
+impl From<&str> for SecretPhrase {
+    fn from(phrase: &str) -> Self {
+        Self(phrase.to_string())
+    }
+}
+
+impl fmt::Debug for SecretPhrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretPhrase([REDACTED])")
+    }
+}
+
+/// 256-bit key derived from a password
+///
+/// Serializes as a byte sequence, matching the `Vec<u8>` it replaces on the wire.
+#[derive(Clone, Zeroize, ZeroizeOnDrop, PartialEq, Eq)]
+pub struct DerivedKey([u8; 32]);
+
+impl DerivedKey {
+    pub fn expose(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub(crate) fn expose_mut(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
+
+impl From<[u8; 32]> for DerivedKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for DerivedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DerivedKey([REDACTED])")
+    }
+}
+
+impl Serialize for DerivedKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.as_slice().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DerivedKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Zeroizing::new(Vec::<u8>::deserialize(deserializer)?);
+        let key = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| serde::de::Error::invalid_length(bytes.len(), &"32 bytes"))?;
+        Ok(Self(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let password = SecretPassword::from("hunter2");
+        let phrase = SecretPhrase::from("abandon ability able");
+        let key = DerivedKey::from([0xab; 32]);
+        assert!(!format!("{:?}", password).contains("hunter2"));
+        assert!(!format!("{:?}", phrase).contains("abandon"));
+        assert!(!format!("{:?}", key).contains("171"));
+        assert!(!format!("{:?}", key).to_lowercase().contains("ab"));
+    }
+
+    #[test]
+    fn test_wire_format_matches_plain_types() {
+        let password = SecretPassword::from("hunter2");
+        assert_eq!(serde_json::to_string(&password).unwrap(), r#""hunter2""#);
+        let decoded: SecretPassword = serde_json::from_str(r#""hunter2""#).unwrap();
+        assert_eq!(decoded.expose(), "hunter2");
+
+        let key = DerivedKey::from([7; 32]);
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(json, serde_json::to_string(&vec![7u8; 32]).unwrap());
+        assert_eq!(serde_json::from_str::<DerivedKey>(&json).unwrap(), key);
+        assert!(serde_json::from_str::<DerivedKey>("[1, 2, 3]").is_err());
+    }
+
+    #[test]
+    fn test_zeroize_wipes_contents() {
+        let mut password = SecretPassword::from("hunter2");
+        password.zeroize();
+        assert_eq!(password.expose(), "");
+
+        let mut key = DerivedKey::from([7; 32]);
+        key.zeroize();
+        assert_eq!(key.expose(), &[0; 32]);
+    }
+}
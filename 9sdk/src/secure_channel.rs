@@ -0,0 +1,654 @@
+//! Authenticated encryption between the bot and the enclave
+//!
+//! The handshake follows the Noise `NK` pattern: the client knows the enclave's static
+//! X25519 key in advance and sends an ephemeral key; the enclave answers with its own
+//! ephemeral key and a confirmation tag. Session keys come from HKDF-SHA256 over both
+//! Diffie-Hellman results, so only the holder of the pinned static key can complete
+//! the handshake. Every frame afterwards is sealed with ChaCha20Poly1305 using a
+//! per-direction key and a counter nonce, which also rejects replayed or reordered frames.
+//!
+//! Handshake version 2 adds the client's own static key, as in Noise `IK`: it is sent with
+//! the ephemeral key and mixed into the session keys through two more Diffie-Hellman
+//! results, so only its holder can send a frame the enclave will open. The enclave can
+//! then grant requests to the clients it trusts.
+//!
+//! The handshake and cipher state are I/O-free; [`SecureChannel`] drives them over a
+//! framed [`TransportStream`].
+
+use crate::DerivedKey;
+use crate::framing::{FrameCodec, FramingError};
+use crate::transport::TransportStream;
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use hkdf::Hkdf;
+use rand::thread_rng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::pin::Pin;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Version byte leading the first handshake message
+pub const HANDSHAKE_VERSION: u8 = 1;
+/// Version byte of a first handshake message carrying the client's static key
+pub const AUTHENTICATED_HANDSHAKE_VERSION: u8 = 2;
+
+const PROTOCOL_NAME: &[u8] = b"nine-sdk-secure-channel-v1";
+const STORAGE_KEY_SALT: &[u8] = b"nine-sdk-storage-key-v1";
+const KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum ChannelError {
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+    #[error("Frame failed authentication")]
+    Decryption,
+    #[error("Nonce space exhausted; the channel must be re-established")]
+    NonceExhausted,
+    #[error("Peer closed the channel")]
+    Closed,
+    #[error("Framing error: {0}")]
+    Framing(#[from] FramingError),
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+}
+
+/// The enclave's long-term X25519 key pair
+pub struct EnclaveIdentity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl EnclaveIdentity {
+    /// Generates a fresh identity
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(thread_rng()))
+    }
+
+    /// Restores an identity from its 32-byte secret
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        Self::from_secret(StaticSecret::from(bytes))
+    }
+
+    /// Restores an identity from a hex-encoded secret
+    pub fn from_hex(secret: &str) -> Result<Self, ChannelError> {
+        Ok(Self::from_bytes(decode_key(secret)?))
+    }
+
+    /// Public half to pin in clients
+    pub fn public_key(&self) -> EnclavePublicKey {
+        EnclavePublicKey(self.public.to_bytes())
+    }
+
+    /// Key for encrypting the bot's stored records under master key `key_id`
+    ///
+    /// The key follows from the identity secret, so it survives enclave restarts without
+    /// the bot keeping it anywhere; a new `key_id` gives an unrelated key to rotate to.
+    pub fn derive_storage_key(&self, key_id: &str) -> DerivedKey {
+        let hkdf = Hkdf::<Sha256>::new(Some(STORAGE_KEY_SALT), self.secret.as_bytes());
+        let mut key = DerivedKey::from([0u8; KEY_SIZE]);
+        hkdf.expand(key_id.as_bytes(), key.expose_mut())
+            .expect("32 bytes is a valid HKDF output length");
+        key
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+}
+
+impl fmt::Debug for EnclaveIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnclaveIdentity")
+            .field("public", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The enclave's static public key as pinned by clients
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EnclavePublicKey([u8; KEY_SIZE]);
+
+impl EnclavePublicKey {
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn from_hex(key: &str) -> Result<Self, ChannelError> {
+        Ok(Self(decode_key(key)?))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for EnclavePublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EnclavePublicKey({})", self.to_hex())
+    }
+}
+
+/// A client's long-term X25519 key pair, which the enclave can be told to trust
+#[derive(Clone)]
+pub struct ClientIdentity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl ClientIdentity {
+    /// Generates a fresh identity
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(thread_rng()))
+    }
+
+    /// Restores an identity from a hex-encoded secret
+    pub fn from_hex(secret: &str) -> Result<Self, ChannelError> {
+        Ok(Self::from_secret(StaticSecret::from(decode_key(secret)?)))
+    }
+
+    /// Public half to list in the enclave's trusted clients
+    pub fn public_key(&self) -> ClientPublicKey {
+        ClientPublicKey(self.public.to_bytes())
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+}
+
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("public", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A client's static public key, as proven in an authenticated handshake
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ClientPublicKey([u8; KEY_SIZE]);
+
+impl ClientPublicKey {
+    pub fn from_hex(key: &str) -> Result<Self, ChannelError> {
+        Ok(Self(decode_key(key)?))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+impl fmt::Debug for ClientPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClientPublicKey({})", self.to_hex())
+    }
+}
+
+/// Client side of the handshake, waiting for the enclave's reply
+pub struct ClientHandshake {
+    ephemeral: StaticSecret,
+    ephemeral_public: PublicKey,
+    server_static: PublicKey,
+    identity: Option<ClientIdentity>,
+}
+
+impl ClientHandshake {
+    /// Starts a handshake with the enclave owning `server_key`, returning the first message
+    pub fn start(server_key: &EnclavePublicKey) -> (Self, Vec<u8>) {
+        Self::start_with(server_key, None)
+    }
+
+    /// Starts a handshake that also proves `identity` to the enclave
+    pub fn start_authenticated(server_key: &EnclavePublicKey, identity: &ClientIdentity) -> (Self, Vec<u8>) {
+        Self::start_with(server_key, Some(identity.clone()))
+    }
+
+    fn start_with(server_key: &EnclavePublicKey, identity: Option<ClientIdentity>) -> (Self, Vec<u8>) {
+        let ephemeral = StaticSecret::random_from_rng(thread_rng());
+        let ephemeral_public = PublicKey::from(&ephemeral);
+
+        let mut message = Vec::with_capacity(1 + 2 * KEY_SIZE);
+        match &identity {
+            Some(identity) => {
+                message.push(AUTHENTICATED_HANDSHAKE_VERSION);
+                message.extend_from_slice(ephemeral_public.as_bytes());
+                message.extend_from_slice(identity.public.as_bytes());
+            }
+            None => {
+                message.push(HANDSHAKE_VERSION);
+                message.extend_from_slice(ephemeral_public.as_bytes());
+            }
+        }
+
+        let handshake = Self {
+            ephemeral,
+            ephemeral_public,
+            server_static: PublicKey::from(server_key.0),
+            identity,
+        };
+        (handshake, message)
+    }
+
+    /// Checks the enclave's reply and derives the session ciphers
+    pub fn finish(self, reply: &[u8]) -> Result<CipherPair, ChannelError> {
+        if reply.len() != KEY_SIZE + TAG_SIZE {
+            return Err(ChannelError::Handshake("malformed reply".to_string()));
+        }
+        let server_ephemeral = PublicKey::from(to_key(&reply[..KEY_SIZE]));
+
+        let mut shared = vec![
+            self.ephemeral.diffie_hellman(&self.server_static),
+            self.ephemeral.diffie_hellman(&server_ephemeral),
+        ];
+        if let Some(identity) = &self.identity {
+            shared.push(identity.secret.diffie_hellman(&self.server_static));
+            shared.push(identity.secret.diffie_hellman(&server_ephemeral));
+        }
+        if !shared.iter().all(|dh| dh.was_contributory()) {
+            return Err(ChannelError::Handshake("low-order public key".to_string()));
+        }
+
+        let client_static = self.identity.as_ref().map(|identity| &identity.public);
+        let transcript = transcript_hash(&self.server_static, &self.ephemeral_public, client_static, &server_ephemeral);
+        let (client_to_server, server_to_client) = derive_keys(&transcript, &shared);
+        let mut ciphers = CipherPair {
+            send: CipherState::new(client_to_server),
+            recv: CipherState::new(server_to_client),
+        };
+
+        // Only the holder of the pinned static key could have produced this tag
+        ciphers
+            .recv
+            .open(&reply[KEY_SIZE..], &transcript)
+            .map_err(|_| ChannelError::Handshake("enclave failed to prove its identity".to_string()))?;
+        Ok(ciphers)
+    }
+}
+
+/// Answers a client's first handshake message, returning the reply, the session ciphers
+/// and the client's static key if it sent one
+///
+/// The client has only proven it holds that key once a frame from it opens, since frames
+/// can't be sealed without it.
+pub fn respond(
+    identity: &EnclaveIdentity,
+    message: &[u8],
+) -> Result<(Vec<u8>, CipherPair, Option<ClientPublicKey>), ChannelError> {
+    let (client_ephemeral, client_static) = match message.split_first() {
+        Some((&HANDSHAKE_VERSION, key)) if key.len() == KEY_SIZE => (to_key(key), None),
+        Some((&AUTHENTICATED_HANDSHAKE_VERSION, keys)) if keys.len() == 2 * KEY_SIZE => {
+            (to_key(&keys[..KEY_SIZE]), Some(PublicKey::from(to_key(&keys[KEY_SIZE..]))))
+        }
+        Some((&HANDSHAKE_VERSION | &AUTHENTICATED_HANDSHAKE_VERSION, _)) | None => {
+            return Err(ChannelError::Handshake("malformed hello".to_string()));
+        }
+        Some((version, _)) => {
+            return Err(ChannelError::Handshake(format!("unsupported handshake version {}", version)));
+        }
+    };
+    let client_ephemeral = PublicKey::from(client_ephemeral);
+
+    let ephemeral = StaticSecret::random_from_rng(thread_rng());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+
+    let mut shared = vec![
+        identity.secret.diffie_hellman(&client_ephemeral),
+        ephemeral.diffie_hellman(&client_ephemeral),
+    ];
+    if let Some(client_static) = &client_static {
+        shared.push(identity.secret.diffie_hellman(client_static));
+        shared.push(ephemeral.diffie_hellman(client_static));
+    }
+    if !shared.iter().all(|dh| dh.was_contributory()) {
+        return Err(ChannelError::Handshake("low-order public key".to_string()));
+    }
+
+    let transcript = transcript_hash(&identity.public, &client_ephemeral, client_static.as_ref(), &ephemeral_public);
+    let (client_to_server, server_to_client) = derive_keys(&transcript, &shared);
+    let mut ciphers = CipherPair {
+        send: CipherState::new(server_to_client),
+        recv: CipherState::new(client_to_server),
+    };
+
+    let mut reply = ephemeral_public.as_bytes().to_vec();
+    reply.extend(ciphers.send.seal(&[], &transcript)?);
+    let client_key = client_static.map(|key| ClientPublicKey(key.to_bytes()));
+    Ok((reply, ciphers, client_key))
+}
+
+/// Session ciphers for both directions of a channel
+pub struct CipherPair {
+    send: CipherState,
+    recv: CipherState,
+}
+
+impl CipherPair {
+    /// Encrypts the next outgoing frame
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        self.send.seal(plaintext, &[])
+    }
+
+    /// Decrypts the next incoming frame
+    pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        self.recv.open(ciphertext, &[])
+    }
+}
+
+/// One direction's key and nonce counter
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl CipherState {
+    fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<Nonce, ChannelError> {
+        let counter = self.counter;
+        self.counter = counter.checked_add(1).ok_or(ChannelError::NonceExhausted)?;
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        Ok(*Nonce::from_slice(&nonce))
+    }
+
+    fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| ChannelError::Decryption)
+    }
+
+    fn open(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(&nonce, Payload { msg: ciphertext, aad })
+            .map_err(|_| ChannelError::Decryption)
+    }
+}
+
+/// Encrypted, framed channel over a transport stream
+pub struct SecureChannel {
+    stream: Pin<Box<dyn TransportStream>>,
+    codec: FrameCodec,
+    ciphers: CipherPair,
+    client_key: Option<ClientPublicKey>,
+}
+
+impl SecureChannel {
+    /// Performs the client side of the handshake against the pinned enclave key
+    pub async fn connect(
+        stream: Pin<Box<dyn TransportStream>>,
+        codec: FrameCodec,
+        server_key: &EnclavePublicKey,
+    ) -> Result<Self, ChannelError> {
+        Self::finish_connect(stream, codec, ClientHandshake::start(server_key)).await
+    }
+
+    /// Performs the client side of the handshake, also proving `identity` to the enclave
+    pub async fn connect_authenticated(
+        stream: Pin<Box<dyn TransportStream>>,
+        codec: FrameCodec,
+        server_key: &EnclavePublicKey,
+        identity: &ClientIdentity,
+    ) -> Result<Self, ChannelError> {
+        Self::finish_connect(stream, codec, ClientHandshake::start_authenticated(server_key, identity)).await
+    }
+
+    async fn finish_connect(
+        mut stream: Pin<Box<dyn TransportStream>>,
+        codec: FrameCodec,
+        (handshake, hello): (ClientHandshake, Vec<u8>),
+    ) -> Result<Self, ChannelError> {
+        codec.write_frame(&mut stream, &hello).await?;
+        let reply = codec.read_frame(&mut stream).await?.ok_or(ChannelError::Closed)?;
+        let ciphers = handshake.finish(&reply)?;
+        Ok(Self { stream, codec, ciphers, client_key: None })
+    }
+
+    /// Performs the enclave side of the handshake
+    pub async fn accept(
+        mut stream: Pin<Box<dyn TransportStream>>,
+        codec: FrameCodec,
+        identity: &EnclaveIdentity,
+    ) -> Result<Self, ChannelError> {
+        let hello = codec.read_frame(&mut stream).await?.ok_or(ChannelError::Closed)?;
+        let (reply, ciphers, client_key) = respond(identity, &hello)?;
+        codec.write_frame(&mut stream, &reply).await?;
+        Ok(Self { stream, codec, ciphers, client_key })
+    }
+
+    /// On the enclave side, the static key the client authenticated with, if any
+    ///
+    /// Every frame [`recv`](Self::recv) returns was sealed by its holder.
+    pub fn client_key(&self) -> Option<&ClientPublicKey> {
+        self.client_key.as_ref()
+    }
+
+    /// Encrypts and sends one frame
+    pub async fn send(&mut self, plaintext: &[u8]) -> Result<(), ChannelError> {
+        let frame = self.ciphers.seal(plaintext)?;
+        self.codec.write_frame(&mut self.stream, &frame).await?;
+        Ok(())
+    }
+
+    /// Receives and decrypts one frame, returning `None` if the peer closed the stream
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, ChannelError> {
+        match self.codec.read_frame(&mut self.stream).await? {
+            Some(frame) => self.ciphers.open(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn transcript_hash(
+    server_static: &PublicKey,
+    client_ephemeral: &PublicKey,
+    client_static: Option<&PublicKey>,
+    server_ephemeral: &PublicKey,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(PROTOCOL_NAME);
+    hasher.update(server_static.as_bytes());
+    hasher.update(client_ephemeral.as_bytes());
+    if let Some(client_static) = client_static {
+        hasher.update(client_static.as_bytes());
+    }
+    hasher.update(server_ephemeral.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Returns the client-to-server and server-to-client keys from the Diffie-Hellman
+/// results, in the order both sides computed them
+fn derive_keys(transcript: &[u8; 32], shared: &[SharedSecret]) -> ([u8; KEY_SIZE], [u8; KEY_SIZE]) {
+    let ikm = Zeroizing::new(shared.iter().flat_map(|dh| *dh.as_bytes()).collect::<Vec<u8>>());
+    let hkdf = Hkdf::<Sha256>::new(Some(transcript), &ikm);
+
+    let mut client_to_server = [0u8; KEY_SIZE];
+    let mut server_to_client = [0u8; KEY_SIZE];
+    hkdf.expand(b"client-to-server", &mut client_to_server)
+        .expect("32 bytes is a valid HKDF output length");
+    hkdf.expand(b"server-to-client", &mut server_to_client)
+        .expect("32 bytes is a valid HKDF output length");
+    (client_to_server, server_to_client)
+}
+
+fn to_key(bytes: &[u8]) -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(bytes);
+    key
+}
+
+fn decode_key(key: &str) -> Result<[u8; KEY_SIZE], ChannelError> {
+    let bytes = hex::decode(key.trim()).map_err(|e| ChannelError::InvalidKey(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|_| ChannelError::InvalidKey("expected 32 bytes".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn handshake_pair(identity: &EnclaveIdentity) -> (CipherPair, CipherPair) {
+        let (client, hello) = ClientHandshake::start(&identity.public_key());
+        let (reply, server, client_key) = respond(identity, &hello).unwrap();
+        assert_eq!(client_key, None);
+        (client.finish(&reply).unwrap(), server)
+    }
+
+    #[test]
+    fn test_frames_roundtrip_in_both_directions() {
+        let identity = EnclaveIdentity::generate();
+        let (mut client, mut server) = handshake_pair(&identity);
+
+        let request = client.seal(b"request").unwrap();
+        assert_ne!(&request[..7], b"request");
+        assert_eq!(server.open(&request).unwrap(), b"request");
+
+        let response = server.seal(b"response").unwrap();
+        assert_eq!(client.open(&response).unwrap(), b"response");
+    }
+
+    #[test]
+    fn test_wrong_pinned_key_fails_handshake() {
+        let enclave = EnclaveIdentity::generate();
+        let impostor = EnclaveIdentity::generate();
+
+        // The client pins the real enclave but talks to an impostor
+        let (client, hello) = ClientHandshake::start(&enclave.public_key());
+        let (reply, _, _) = respond(&impostor, &hello).unwrap();
+        assert!(matches!(client.finish(&reply), Err(ChannelError::Handshake(_))));
+    }
+
+    #[test]
+    fn test_client_identity_is_proven_by_its_frames() {
+        let enclave = EnclaveIdentity::generate();
+        let client_identity = ClientIdentity::generate();
+
+        let (client, hello) = ClientHandshake::start_authenticated(&enclave.public_key(), &client_identity);
+        let (reply, mut server, client_key) = respond(&enclave, &hello).unwrap();
+        assert_eq!(client_key, Some(client_identity.public_key()));
+        let mut client = client.finish(&reply).unwrap();
+        assert_eq!(server.open(&client.seal(b"request").unwrap()).unwrap(), b"request");
+
+        // Claiming someone else's key gets a reply, but one the claimant can't open, so it
+        // never gets as far as sending a frame
+        let impostor = ClientIdentity {
+            secret: ClientIdentity::generate().secret,
+            public: client_identity.public,
+        };
+        let (client, hello) = ClientHandshake::start_authenticated(&enclave.public_key(), &impostor);
+        let (reply, _, client_key) = respond(&enclave, &hello).unwrap();
+        assert_eq!(client_key, Some(client_identity.public_key()));
+        assert!(matches!(client.finish(&reply), Err(ChannelError::Handshake(_))));
+    }
+
+    #[test]
+    fn test_tampered_replayed_and_reflected_frames_are_rejected() {
+        let identity = EnclaveIdentity::generate();
+        let (mut client, mut server) = handshake_pair(&identity);
+
+        let mut frame = client.seal(b"secret").unwrap();
+        frame[0] ^= 1;
+        assert!(matches!(server.open(&frame), Err(ChannelError::Decryption)));
+
+        let (mut client, mut server) = handshake_pair(&identity);
+        let frame = client.seal(b"secret").unwrap();
+        server.open(&frame).unwrap();
+        assert!(matches!(server.open(&frame), Err(ChannelError::Decryption)));
+
+        // A client frame sent back to the client doesn't decrypt under the other direction's key
+        let (mut client, _) = handshake_pair(&identity);
+        let frame = client.seal(b"secret").unwrap();
+        assert!(matches!(client.open(&frame), Err(ChannelError::Decryption)));
+    }
+
+    #[test]
+    fn test_malformed_handshake_messages() {
+        let identity = EnclaveIdentity::generate();
+        assert!(respond(&identity, &[]).is_err());
+        assert!(respond(&identity, &[HANDSHAKE_VERSION, 1, 2, 3]).is_err());
+
+        let mut hello = vec![AUTHENTICATED_HANDSHAKE_VERSION + 1];
+        hello.extend_from_slice(&[9; KEY_SIZE]);
+        assert!(respond(&identity, &hello).is_err());
+
+        // An authenticated hello must carry both keys, neither of them low-order
+        let mut hello = vec![AUTHENTICATED_HANDSHAKE_VERSION];
+        hello.extend_from_slice(&[9; KEY_SIZE]);
+        assert!(respond(&identity, &hello).is_err());
+        hello.extend_from_slice(&[0; KEY_SIZE]);
+        assert!(respond(&identity, &hello).is_err());
+
+        // The all-zero point would make the shared secret predictable
+        let mut hello = vec![HANDSHAKE_VERSION];
+        hello.extend_from_slice(&[0; KEY_SIZE]);
+        assert!(respond(&identity, &hello).is_err());
+    }
+
+    #[test]
+    fn test_key_hex_roundtrip() {
+        let secret = [7u8; KEY_SIZE];
+        let identity = EnclaveIdentity::from_hex(&hex::encode(secret)).unwrap();
+        let public = EnclavePublicKey::from_hex(&identity.public_key().to_hex()).unwrap();
+        assert_eq!(public, EnclaveIdentity::from_bytes(secret).public_key());
+
+        assert!(EnclavePublicKey::from_hex("abcd").is_err());
+        assert!(EnclavePublicKey::from_hex("not hex").is_err());
+        assert!(!format!("{:?}", identity).contains(&hex::encode(secret)));
+    }
+
+    #[test]
+    fn test_storage_keys_are_stable_per_identity_and_key_id() {
+        let identity = EnclaveIdentity::from_bytes([7u8; KEY_SIZE]);
+        let key = identity.derive_storage_key("2026-01");
+        assert_eq!(key, EnclaveIdentity::from_bytes([7u8; KEY_SIZE]).derive_storage_key("2026-01"));
+        assert_ne!(key, identity.derive_storage_key("2026-02"));
+        assert_ne!(key, EnclaveIdentity::generate().derive_storage_key("2026-01"));
+    }
+
+    #[tokio::test]
+    async fn test_secure_channel_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let identity = EnclaveIdentity::generate();
+        let server_key = identity.public_key();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut channel = SecureChannel::accept(Box::pin(stream), FrameCodec::default(), &identity)
+                .await
+                .unwrap();
+            let request = channel.recv().await.unwrap().unwrap();
+            channel.send(&request).await.unwrap();
+            assert!(channel.recv().await.unwrap().is_none());
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut channel = SecureChannel::connect(Box::pin(stream), FrameCodec::default(), &server_key)
+            .await
+            .unwrap();
+        channel.send(b"echo").await.unwrap();
+        assert_eq!(channel.recv().await.unwrap().unwrap(), b"echo");
+        drop(channel);
+
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+//! Helpers for tests of crates built on the SDK, enabled by the `test-util` feature
+
+use std::sync::{Mutex, Once};
+
+/// Keeps every log line so tests can check what would have been written
+struct CaptureLogger(Mutex<Vec<String>>);
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGS: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+
+/// Returns everything logged so far, installing the capturing logger on first use
+///
+/// Call it once before the code under test, since lines logged before that are lost.
+pub fn captured_logs() -> Vec<String> {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&LOGS).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    LOGS.0.lock().unwrap().clone()
+}
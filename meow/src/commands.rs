@@ -32,4 +32,12 @@ pub enum CommandLoggedIn {
     LogOut,
     /// Print Keys
     PrintKeys,
+    /// Change Password
+    ChangePassword,
+    /// Switch Account
+    Account { index: u32 },
+    /// Export Data
+    ExportData,
+    /// Delete Account
+    DeleteAccount,
 }
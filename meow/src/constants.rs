@@ -1 +1,5 @@
 pub static MAN_PAGE: &str = include_str!("../assets/purr.1");
+
+/// Shown when the signing enclave can't be reached; the user can simply try again
+pub static ENCLAVE_UNAVAILABLE_MESSAGE: &str =
+    "⚠️ Signing service unavailable, please retry in a moment.";
@@ -1,5 +1,6 @@
 use crate::processors::callback_processor::process_callback;
-use crate::services::user_config_store::UserConfigStore;
+use crate::services::config_store::ConfigStore;
+use nine_sdk::EnclaveClient;
 use std::error::Error;
 use std::sync::Arc;
 use teloxide::prelude::*;
@@ -8,7 +9,8 @@ use teloxide::types::CallbackQuery;
 pub async fn callback_handler(
     bot: Bot,
     q: CallbackQuery,
-    config_store: Arc<UserConfigStore>,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     log::info!("callback_handler called! q: {:?}", q);
 
@@ -25,7 +27,7 @@ pub async fn callback_handler(
     }
 
     // Process the callback
-    let result = process_callback(bot, q, config_store).await;
+    let result = process_callback(bot, q, config_store, enclave_client).await;
 
     // Log the result
     match &result {
@@ -79,11 +81,11 @@ mod tests {
     fn test_callback_handler_signature() {
         // Verify that the function exists and has the correct signature
         fn _check_signature(
-            _handler: fn(Bot, CallbackQuery, Arc<UserConfigStore>) -> 
+            _handler: fn(Bot, CallbackQuery, Arc<dyn ConfigStore>, Arc<EnclaveClient>) -> 
                 std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send>>
         ) {}
         
-        _check_signature(|bot, query, store| Box::pin(callback_handler(bot, query, store)));
+        _check_signature(|bot, query, store, enclave| Box::pin(callback_handler(bot, query, store, enclave)));
     }
     
     #[test]
@@ -1,5 +1,6 @@
 use crate::processors::message_processor::process_message;
-use crate::services::user_config_store::UserConfigStore;
+use crate::services::config_store::ConfigStore;
+use nine_sdk::EnclaveClient;
 use std::error::Error;
 use std::sync::Arc;
 use teloxide::prelude::*;
@@ -8,10 +9,11 @@ use teloxide::types::Message;
 pub async fn message_handler(
     bot: Bot,
     msg: Message,
-    config_store: Arc<UserConfigStore>,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let me = bot.get_me().await?;
-    process_message(bot, msg, me, config_store).await
+    process_message(bot, msg, me, config_store, enclave_client).await
 }
 
 #[cfg(test)]
@@ -28,12 +30,12 @@ mod tests {
         
         // Verify that the function exists and has the correct signature
         fn _check_signature(
-            _handler: fn(Bot, Message, Arc<UserConfigStore>) -> 
+            _handler: fn(Bot, Message, Arc<dyn ConfigStore>, Arc<EnclaveClient>) -> 
                 std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send>>
         ) {}
         
         // This won't compile if the signature is wrong
-        _check_signature(|bot, msg, store| Box::pin(message_handler(bot, msg, store)));
+        _check_signature(|bot, msg, store, enclave| Box::pin(message_handler(bot, msg, store, enclave)));
     }
     
     #[test]
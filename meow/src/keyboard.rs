@@ -1,7 +1,12 @@
 use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
 
 pub fn logged_out_operations() -> InlineKeyboardMarkup {
-    let operations = [("Sign Up", "Sign Up"), ("Log In", "Log In"), ("FAQ", "FAQ")];
+    let operations = [
+        ("Sign Up", "Sign Up"),
+        ("Import Wallet", "Import Wallet"),
+        ("Log In", "Log In"),
+        ("FAQ", "FAQ"),
+    ];
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = vec![];
 
     for row in operations.chunks(3) {
@@ -69,14 +74,16 @@ mod tests {
         let keyboard = logged_out_operations();
         
         // Test that we have the expected structure
-        assert_eq!(keyboard.inline_keyboard.len(), 1, "Should have 1 row");
-        assert_eq!(keyboard.inline_keyboard[0].len(), 3, "Should have 3 buttons");
+        assert_eq!(keyboard.inline_keyboard.len(), 2, "Should have 2 rows");
+        assert_eq!(keyboard.inline_keyboard[0].len(), 3, "First row should have 3 buttons");
+        assert_eq!(keyboard.inline_keyboard[1].len(), 1, "Second row should have 1 button");
         
         // Verify button texts
-        let row = &keyboard.inline_keyboard[0];
-        assert_eq!(row[0].text, "Sign Up");
-        assert_eq!(row[1].text, "Log In");
-        assert_eq!(row[2].text, "FAQ");
+        let row1 = &keyboard.inline_keyboard[0];
+        assert_eq!(row1[0].text, "Sign Up");
+        assert_eq!(row1[1].text, "Import Wallet");
+        assert_eq!(row1[2].text, "Log In");
+        assert_eq!(keyboard.inline_keyboard[1][0].text, "FAQ");
     }
 
     #[test]
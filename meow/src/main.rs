@@ -1,6 +1,6 @@
 use std::error::Error;
 use teloxide::{prelude::*, utils::command::BotCommands};
-use nine_sdk::Transport;
+use nine_sdk::{AttestationVerifier, ClientConfig, ClientIdentity, EnclaveClient, EnclavePublicKey, Transport};
 mod keyboard;
 mod commands;
 mod constants;
@@ -8,7 +8,11 @@ mod handlers;
 mod models;
 mod processors;
 mod services;
+#[cfg(test)]
+mod test_util;
 use std::sync::Arc;
+use services::config_store::{is_sqlite_path, open_config_store};
+use services::master_key::{MasterKeyError, MasterKeys};
 use services::user_config_store::UserConfigStore;
 use teloxide::Bot;
 use teloxide::dispatching::{Dispatcher, UpdateFilterExt};
@@ -18,9 +22,20 @@ use handlers::callback_handler;
 
 // Constants
 const DEFAULT_DATABASE_PATH: &str = "purrbot.sqlite";
+const DATABASE_URL_ENV_VAR: &str = "DATABASE_URL";
+const MASTER_KEY_ENV_VAR: &str = "MASTER_KEY";
+const MASTER_KEY_FILE_ENV_VAR: &str = "MASTER_KEY_FILE";
+const MASTER_KEY_IDS_ENV_VAR: &str = "MASTER_KEY_IDS";
+const ROTATE_MASTER_KEY_COMMAND: &str = "rotate-master-key";
+const ROTATION_BATCH_SIZE: usize = 100;
 const DEFAULT_TCP_ADDRESS: &str = "127.0.0.1:5005";
 const ENCLAVE_MODE_ENV_VAR: &str = "ENCLAVE_MODE";
 const ENCLAVE_MODE_VALUE: &str = "enclave";
+const ENCLAVE_SOCKET_ENV_VAR: &str = "ENCLAVE_SOCKET";
+const ENCLAVE_PUBLIC_KEY_ENV_VAR: &str = "ENCLAVE_PUBLIC_KEY";
+const ENCLAVE_ROOT_CERT_ENV_VAR: &str = "ENCLAVE_ROOT_CERT";
+const BOT_STATIC_KEY_ENV_VAR: &str = "BOT_STATIC_KEY";
+const ATTESTED_PCR_COUNT: usize = 3;
 
 // Helper functions
 fn is_enclave_mode() -> bool {
@@ -34,6 +49,48 @@ fn create_tcp_transport(address: &str) -> Transport {
     }
 }
 
+fn create_unix_transport(path: &str) -> Transport {
+    Transport::Unix(path.into())
+}
+
+fn parse_enclave_public_key(hex: &str) -> EnclavePublicKey {
+    match EnclavePublicKey::from_hex(hex) {
+        Ok(key) => key,
+        Err(_) => panic!("Invalid {}", ENCLAVE_PUBLIC_KEY_ENV_VAR),
+    }
+}
+
+/// Pins the attestation root (PEM or DER) and the expected PCR0..PCR2 hex values
+fn create_attestation_verifier(root_cert: &[u8], pcrs: &[String]) -> Result<AttestationVerifier, Box<dyn Error>> {
+    let verifier = if root_cert.starts_with(b"-----BEGIN") {
+        AttestationVerifier::from_pem(std::str::from_utf8(root_cert)?)?
+    } else {
+        AttestationVerifier::new(root_cert.to_vec())
+    };
+    pcrs.iter().enumerate().try_fold(verifier, |verifier, (index, pcr)| {
+        let value = hex::decode(pcr).map_err(|e| format!("Invalid ENCLAVE_PCR{}: {}", index, e))?;
+        Ok(verifier.expect_pcr(index, value))
+    })
+}
+
+/// Loads the master keys for encrypting stored configs, current key first
+///
+/// `MASTER_KEY` and the file named by `MASTER_KEY_FILE` hold `id:hexkey` entries;
+/// `MASTER_KEY_IDS` lists key ids whose keys the enclave derives.
+async fn load_master_keys(enclave_client: &EnclaveClient) -> Result<Option<MasterKeys>, MasterKeyError> {
+    if let Ok(entries) = std::env::var(MASTER_KEY_ENV_VAR) {
+        return MasterKeys::parse(&entries).map(Some);
+    }
+    if let Ok(path) = std::env::var(MASTER_KEY_FILE_ENV_VAR) {
+        return MasterKeys::from_file(path).map(Some);
+    }
+    if let Ok(key_ids) = std::env::var(MASTER_KEY_IDS_ENV_VAR) {
+        let key_ids: Vec<&str> = key_ids.split(',').map(str::trim).filter(|id| !id.is_empty()).collect();
+        return MasterKeys::from_enclave(enclave_client, &key_ids).await.map(Some);
+    }
+    Ok(None)
+}
+
 fn create_default_transport() -> Transport {
     create_tcp_transport(DEFAULT_TCP_ADDRESS)
 }
@@ -56,10 +113,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
     log::info!("PurrBot is purring...");
 
-    // Initialize the SQLite user config store
-    let config_store = Arc::new(UserConfigStore::new(DEFAULT_DATABASE_PATH)?);
-    // Make it available globally if needed, or pass to handlers
-
     // Determine transport based on environment
     let transport = if std::env::var("USE_VSOCK").as_deref() == Ok("true") {
         // Parent instance always has CID 3 in Nitro Enclaves
@@ -84,11 +137,60 @@ async fn main() -> Result<(), Box<dyn Error>> {
             log::error!("vsock feature not enabled!");
             return Err("vsock feature not enabled".into());
         }
+    } else if let Ok(path) = std::env::var(ENCLAVE_SOCKET_ENV_VAR) {
+        log::info!("Using Unix socket transport to enclave: {}", path);
+        create_unix_transport(&path)
     } else if is_enclave_mode() {
         create_enclave_transport()
     } else {
         create_default_transport()
     };
+    // The enclave's static key is pinned so the channel can't be intercepted
+    let enclave_key = std::env::var(ENCLAVE_PUBLIC_KEY_ENV_VAR)
+        .map(|hex| parse_enclave_public_key(&hex))
+        .map_err(|_| format!("{} must be set to the enclave's public key", ENCLAVE_PUBLIC_KEY_ENV_VAR))?;
+
+    let mut client_config = ClientConfig::default();
+    if let Ok(path) = std::env::var(ENCLAVE_ROOT_CERT_ENV_VAR) {
+        let root_cert = std::fs::read(&path)?;
+        let pcrs = (0..ATTESTED_PCR_COUNT)
+            .map(|index| {
+                std::env::var(format!("ENCLAVE_PCR{}", index))
+                    .map_err(|_| format!("ENCLAVE_PCR{} must be set when attestation is enabled", index))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        client_config.attestation = Some(create_attestation_verifier(&root_cert, &pcrs)?);
+        log::info!("Enclave attestation enabled");
+    } else {
+        log::warn!("{} not set, the enclave image will not be attested", ENCLAVE_ROOT_CERT_ENV_VAR);
+    }
+    // The enclave hands storage keys only to bots whose public key it trusts
+    if let Ok(secret) = std::env::var(BOT_STATIC_KEY_ENV_VAR) {
+        let identity = ClientIdentity::from_hex(&secret).map_err(|e| format!("Invalid {}: {}", BOT_STATIC_KEY_ENV_VAR, e))?;
+        log::info!("Bot public key: {}", identity.public_key().to_hex());
+        client_config.identity = Some(identity);
+    } else {
+        log::warn!("{} not set, the enclave will refuse to derive MASTER_KEY_IDS", BOT_STATIC_KEY_ENV_VAR);
+    }
+    let enclave_client = Arc::new(EnclaveClient::with_config(transport, enclave_key, client_config));
+
+    // Replicas sharing a PostgreSQL DATABASE_URL share accounts; otherwise a local SQLite file
+    let database_url = std::env::var(DATABASE_URL_ENV_VAR).unwrap_or_else(|_| DEFAULT_DATABASE_PATH.to_string());
+    let master_keys = load_master_keys(&enclave_client).await?;
+    if std::env::args().nth(1).as_deref() == Some(ROTATE_MASTER_KEY_COMMAND) {
+        let master_keys = master_keys.ok_or("rotate-master-key needs a master key")?;
+        if !is_sqlite_path(&database_url) {
+            return Err("rotate-master-key only applies to the SQLite config store".into());
+        }
+        let store = UserConfigStore::new(&database_url)?.with_master_keys(master_keys);
+        let rotated = store.rotate_master_key(ROTATION_BATCH_SIZE).await?;
+        log::info!("Master key rotation finished, {} configs resealed", rotated);
+        return Ok(());
+    }
+    if master_keys.is_none() && is_sqlite_path(&database_url) {
+        log::warn!("No master key configured, configs will be stored unencrypted");
+    }
+    let config_store = open_config_store(&database_url, master_keys).await?;
 
     let bot = Bot::from_env();
 
@@ -100,16 +202,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let handler = dptree::entry()
         .branch(Update::filter_message().branch(dptree::endpoint({
             let config_store = Arc::clone(&config_store);
+            let enclave_client = Arc::clone(&enclave_client);
             move |bot, msg| {
                 let config_store = Arc::clone(&config_store);
-                async move { handlers::message_handler(bot, msg, config_store).await }
+                let enclave_client = Arc::clone(&enclave_client);
+                async move { handlers::message_handler(bot, msg, config_store, enclave_client).await }
             }
         })))
         .branch(Update::filter_callback_query().branch(dptree::endpoint({
             let config_store = Arc::clone(&config_store);
+            let enclave_client = Arc::clone(&enclave_client);
             move |bot, q| {
                 let config_store = Arc::clone(&config_store);
-                async move { callback_handler(bot, q, config_store).await }
+                let enclave_client = Arc::clone(&enclave_client);
+                async move { callback_handler(bot, q, config_store, enclave_client).await }
             }
         })));
     //.branch(Update::filter_inline_query().branch(dptree::endpoint(inline_query_handler)));
@@ -132,6 +238,10 @@ mod tests {
         assert_eq!(DEFAULT_TCP_ADDRESS, "127.0.0.1:5005");
         assert_eq!(ENCLAVE_MODE_ENV_VAR, "ENCLAVE_MODE");
         assert_eq!(ENCLAVE_MODE_VALUE, "enclave");
+        assert_eq!(ENCLAVE_SOCKET_ENV_VAR, "ENCLAVE_SOCKET");
+        assert_eq!(ENCLAVE_PUBLIC_KEY_ENV_VAR, "ENCLAVE_PUBLIC_KEY");
+        assert_eq!(ENCLAVE_ROOT_CERT_ENV_VAR, "ENCLAVE_ROOT_CERT");
+        assert_eq!(BOT_STATIC_KEY_ENV_VAR, "BOT_STATIC_KEY");
     }
     
     #[test]
@@ -183,6 +293,41 @@ mod tests {
         create_tcp_transport("invalid:address:format");
     }
     
+    #[test]
+    fn test_create_unix_transport() {
+        let transport = create_unix_transport("/run/enclave/enclave.sock");
+        match transport {
+            Transport::Unix(path) => {
+                assert_eq!(path, std::path::Path::new("/run/enclave/enclave.sock"));
+            }
+            _ => panic!("Expected Unix transport"),
+        }
+    }
+    
+    #[test]
+    fn test_parse_enclave_public_key() {
+        let hex = "11".repeat(32);
+        assert_eq!(parse_enclave_public_key(&hex).to_hex(), hex);
+    }
+    
+    #[test]
+    #[should_panic(expected = "Invalid ENCLAVE_PUBLIC_KEY")]
+    fn test_parse_enclave_public_key_invalid() {
+        parse_enclave_public_key("not-a-key");
+    }
+    
+    #[test]
+    fn test_create_attestation_verifier() {
+        let pcrs = vec!["00".repeat(48), "11".repeat(48), "22".repeat(48)];
+        assert!(create_attestation_verifier(b"der bytes", &pcrs).is_ok());
+        
+        let pcrs = vec!["not hex".to_string()];
+        let error = create_attestation_verifier(b"der bytes", &pcrs).unwrap_err();
+        assert!(error.to_string().contains("ENCLAVE_PCR0"));
+        
+        assert!(create_attestation_verifier(b"-----BEGIN CERTIFICATE-----\ngarbage", &[]).is_err());
+    }
+    
     #[test]
     fn test_create_default_transport() {
         let transport = create_default_transport();
@@ -237,10 +382,13 @@ mod tests {
         }
     }
     
-    #[test]
-    fn test_create_config_store() {
-        let config_store = UserConfigStore::new(DEFAULT_DATABASE_PATH).unwrap();
-        assert_eq!(config_store.get_database_path(), DEFAULT_DATABASE_PATH);
+    #[tokio::test]
+    async fn test_create_config_store() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join(DEFAULT_DATABASE_PATH);
+        let config_store = open_config_store(db_path.to_str().unwrap(), None).await.unwrap();
+        assert!(!config_store.config_exists("alice").await.unwrap());
+        assert!(db_path.exists());
     }
     
     #[test]
@@ -3,7 +3,8 @@ use crate::commands::CommandLoggedIn;
 use crate::constants::MAN_PAGE;
 use crate::models::{log_in_state, password_handler::PasswordHandler};
 use crate::processors::message_processor::{CHAT_MESSAGE_IDS, logout, print_keys};
-use crate::services::user_config_store::UserConfigStore;
+use crate::services::config_store::ConfigStore;
+use nine_sdk::EnclaveClient;
 use std::sync::Arc;
 use teloxide::prelude::ResponseResult;
 use teloxide::prelude::*;
@@ -22,6 +23,7 @@ pub enum Button {
     // Logged out buttons
     LogIn,
     SignUp,
+    Import,
     Faq,
     // Unknown button
     UnRecognized,
@@ -43,6 +45,7 @@ impl Button {
             match input {
                 "Log In" => Self::LogIn,
                 "Sign Up" => Self::SignUp,
+                "Import Wallet" => Self::Import,
                 "FAQ" => Self::Faq,
                 _ => Self::UnRecognized,
             }
@@ -54,7 +57,8 @@ impl Button {
         &self,
         bot: Bot,
         chat_id: ChatId,
-        config_store: Arc<UserConfigStore>,
+        config_store: Arc<dyn ConfigStore>,
+        enclave_client: Arc<EnclaveClient>,
         is_logged_in: bool,
     ) -> ResponseResult<()> {
         log::debug!("Executing Button: {:?}", self);
@@ -69,7 +73,8 @@ impl Button {
             // Logged out buttons
             Button::Faq => handle_faq_button(bot, chat_id).await,
             Button::LogIn => handle_login_button(bot, chat_id).await,
-            Button::SignUp => handle_signup_button(bot, chat_id, config_store).await,
+            Button::SignUp => handle_signup_button(bot, chat_id, config_store, enclave_client).await,
+            Button::Import => handle_import_button(bot, chat_id, config_store).await,
             Button::UnRecognized => handle_unrecognized_button(bot, chat_id, is_logged_in).await,
         }
     }
@@ -194,6 +199,9 @@ async fn handle_login_button(bot: Bot, chat_id: ChatId) -> ResponseResult<()> {
         .reply_markup(logged_out_operations())
         .await?;
     store_message_id(chat_id, message.id).await;
+
+    let mut states = log_in_state::USER_STATES.lock().await;
+    states.insert(chat_id.0, log_in_state::AwaitingState::AwaitingLoginPassword);
     log::debug!("LogIn button execution completed");
     Ok(())
 }
@@ -202,7 +210,8 @@ async fn handle_login_button(bot: Bot, chat_id: ChatId) -> ResponseResult<()> {
 async fn handle_signup_button(
     bot: Bot,
     chat_id: ChatId,
-    config_store: Arc<UserConfigStore>,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
 ) -> ResponseResult<()> {
     log::debug!("Executing SignUp button");
     let message = bot
@@ -211,7 +220,7 @@ async fn handle_signup_button(
         .await?;
     store_message_id(chat_id, message.id).await;
 
-    if let Err(e) = PasswordHandler::new(config_store.clone()) {
+    if let Err(e) = PasswordHandler::new(config_store.clone(), enclave_client.clone()) {
         log::error!("Failed to create password handler: {}", e);
         let error_message = bot
             .send_message(chat_id, "Failed to initialize password handler")
@@ -230,6 +239,43 @@ async fn handle_signup_button(
     Ok(())
 }
 
+/// Helper function to handle Import button
+async fn handle_import_button(bot: Bot, chat_id: ChatId, config_store: Arc<dyn ConfigStore>) -> ResponseResult<()> {
+    log::debug!("Executing Import button");
+    // Importing would otherwise replace the wallet the user already has
+    let exists = match config_store.config_exists(&chat_id.0.to_string()).await {
+        Ok(exists) => exists,
+        Err(e) => {
+            log::error!("Failed to look up config for chat_id={}: {}", chat_id, e);
+            true
+        }
+    };
+    if exists {
+        let message = bot
+            .send_message(chat_id, "❌ You already have an account. Log in to use it.")
+            .reply_markup(logged_out_operations())
+            .await?;
+        store_message_id(chat_id, message.id).await;
+        return Ok(());
+    }
+
+    let message = bot
+        .send_message(
+            chat_id,
+            "Send the private key (hex) or recovery phrase of the wallet to import.\n\
+             Your message will be deleted from the chat right away.",
+        )
+        .reply_markup(logged_out_operations())
+        .await?;
+    store_message_id(chat_id, message.id).await;
+
+    log_in_state::PENDING_IMPORTS.lock().await.remove(&chat_id.0);
+    let mut states = log_in_state::USER_STATES.lock().await;
+    states.insert(chat_id.0, log_in_state::AwaitingState::AwaitingImportSecret);
+    log::debug!("Import button execution completed");
+    Ok(())
+}
+
 /// Helper function to handle unrecognized button
 async fn handle_unrecognized_button(
     bot: Bot,
@@ -253,5 +299,5 @@ async fn handle_unrecognized_button(
 /// Helper function to store message ID
 async fn store_message_id(chat_id: ChatId, message_id: MessageId) {
     let mut chat_message_ids = CHAT_MESSAGE_IDS.lock().await;
-    chat_message_ids.insert(chat_id, vec![message_id]);
+    chat_message_ids.entry(chat_id).or_default().push(message_id);
 }
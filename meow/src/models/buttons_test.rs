@@ -89,6 +89,7 @@ fn test_button_from_str_logged_in() {
 fn test_button_from_str_logged_out() {
     assert!(matches!(Button::from_str("Log In", false), Button::LogIn));
     assert!(matches!(Button::from_str("Sign Up", false), Button::SignUp));
+    assert!(matches!(Button::from_str("Import Wallet", false), Button::Import));
     assert!(matches!(Button::from_str("FAQ", false), Button::Faq));
     assert!(matches!(Button::from_str("Invalid", false), Button::UnRecognized));
 }
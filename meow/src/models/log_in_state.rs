@@ -1,3 +1,4 @@
+use nine_sdk::{SecretPassword, SecretPhrase};
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use tokio::sync::Mutex;
@@ -7,7 +8,76 @@ pub enum AwaitingState {
     None,
     AwaitingSignUpPassword,
     AwaitingLoginPassword,
+    AwaitingCurrentPassword,
+    AwaitingNewPassword,
+    /// The recovery phrase is on screen until the user confirms they have saved it
+    AwaitingMnemonicBackup,
+    AwaitingImportSecret,
+    AwaitingImportPassword,
+    /// `/deleteaccount` was sent; the password confirms it
+    AwaitingDeleteConfirmation,
+}
+
+impl AwaitingState {
+    /// Whether the next message from the user is a password, private key or recovery phrase
+    pub fn expects_secret(self) -> bool {
+        matches!(
+            self,
+            AwaitingState::AwaitingSignUpPassword
+                | AwaitingState::AwaitingLoginPassword
+                | AwaitingState::AwaitingCurrentPassword
+                | AwaitingState::AwaitingNewPassword
+                | AwaitingState::AwaitingImportSecret
+                | AwaitingState::AwaitingImportPassword
+                | AwaitingState::AwaitingDeleteConfirmation
+        )
+    }
+}
+
+/// Logs an incoming message, leaving out its text when it is a secret
+pub fn log_incoming_message(chat_id: i64, state: AwaitingState, text: &str) {
+    if state.expects_secret() {
+        log::info!("Processing message: [REDACTED] from chat_id={} ({:?})", chat_id, state);
+    } else {
+        log::info!("Processing message: '{}' from chat_id={}", text, chat_id);
+    }
 }
 
 pub static USER_STATES: Lazy<Mutex<HashMap<i64, AwaitingState>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Current password sent during `/changepassword`, held until the new one arrives
+pub static PENDING_PASSWORD_CHANGES: Lazy<Mutex<HashMap<i64, SecretPassword>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Private key or recovery phrase sent to import a wallet, held until the password arrives
+pub static PENDING_IMPORTS: Lazy<Mutex<HashMap<i64, SecretPhrase>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nine_sdk::test_util::captured_logs;
+
+    #[test]
+    fn test_passwords_are_not_logged() {
+        captured_logs();
+        for state in [
+            AwaitingState::AwaitingSignUpPassword,
+            AwaitingState::AwaitingLoginPassword,
+            AwaitingState::AwaitingCurrentPassword,
+            AwaitingState::AwaitingNewPassword,
+            AwaitingState::AwaitingImportSecret,
+            AwaitingState::AwaitingImportPassword,
+            AwaitingState::AwaitingDeleteConfirmation,
+        ] {
+            log_incoming_message(7, state, "password-leak-canary");
+        }
+        log_incoming_message(7, AwaitingState::None, "/help");
+
+        let logs = captured_logs();
+        assert!(logs.iter().all(|line| !line.contains("password-leak-canary")));
+        assert_eq!(logs.iter().filter(|line| line.contains("[REDACTED] from chat_id=7")).count(), 7);
+        assert!(logs.iter().any(|line| line.contains("'/help' from chat_id=7")));
+    }
+}
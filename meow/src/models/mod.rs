@@ -9,5 +9,5 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-pub static PASSWORD_HANDLERS: Lazy<Arc<Mutex<HashMap<i64, Option<PasswordHandler>>>>> =
+pub static PASSWORD_HANDLERS: Lazy<Arc<Mutex<HashMap<i64, Option<Arc<PasswordHandler>>>>>> =
     Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
@@ -1,233 +1,925 @@
-use crate::services::user_config_store::{UserConfigStore, UserConfigStoreError};
-use nine_sdk::{EncryptedKeyConfig, KeyManager};
+use crate::services::config_store::{ConfigStore, ConfigStoreError, LoginAttempt};
+use nine_sdk::{EnclaveClient, EncryptedKeyConfig, LockoutPolicy, SealedWallet, SecretPassword, SecretPhrase};
 use serde_json;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::Mutex;
-use alloy_signer_local::{PrivateKeySigner, LocalSigner};
-use alloy_primitives::{Address, B256};
 use serde::{Deserialize, Serialize};
-use rand;
+
+/// Record layout written by this build
+///
+/// Version 0 records hold a bare ciphertext; version 1 records hold a sealed blob bound to
+/// the owning user and this version, and replace version 0 records on login.
+pub const WALLET_SCHEMA_VERSION: u32 = 1;
+
+/// Context a user's wallet is sealed for, so a record copied to another user won't open
+pub fn wallet_context(user_id: &str, schema_version: u32) -> String {
+    format!("meow/wallet/v{}/user/{}", schema_version, user_id)
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserWalletConfig {
+    #[serde(default)]
+    pub schema_version: u32,
     pub encrypted_key_config: EncryptedKeyConfig,
     pub encrypted_ethereum_private_key: String,
     pub ethereum_public_key: String,
     pub ethereum_address: String,
-    pub nonce: String, // For encryption
+    pub nonce: String, // For encryption; empty once sealed as a blob
+    /// MAC under the user's second derived key over the wallet fields, checked in the enclave
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    /// Recovery phrase the wallet was created from, sealed like its private key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_mnemonic: Option<String>,
+    /// BIP-44 account of the recovery phrase the user signs with; 0 is the sealed key.
+    /// Covered by `mac`, so it is only changed along with it.
+    #[serde(default)]
+    pub account_index: u32,
+}
+
+impl UserWalletConfig {
+    fn new(encrypted_key_config: EncryptedKeyConfig, wallet: SealedWallet) -> Self {
+        Self {
+            schema_version: WALLET_SCHEMA_VERSION,
+            encrypted_key_config,
+            encrypted_ethereum_private_key: wallet.encrypted_private_key,
+            ethereum_public_key: wallet.public_key,
+            ethereum_address: wallet.address,
+            nonce: wallet.nonce,
+            mac: wallet.mac,
+            encrypted_mnemonic: wallet.encrypted_mnemonic,
+            account_index: 0,
+        }
+    }
+
+    /// Replacement record for a wallet resealed by the enclave, keeping the selected account
+    fn resealed(&self, encrypted_key_config: EncryptedKeyConfig, wallet: SealedWallet) -> Self {
+        Self {
+            account_index: self.account_index,
+            ..Self::new(encrypted_key_config, wallet)
+        }
+    }
+
+    /// The sealed wallet as the enclave expects it back
+    fn sealed_wallet(&self) -> SealedWallet {
+        SealedWallet {
+            address: self.ethereum_address.clone(),
+            public_key: self.ethereum_public_key.clone(),
+            encrypted_private_key: self.encrypted_ethereum_private_key.clone(),
+            nonce: self.nonce.clone(),
+            mac: self.mac.clone(),
+            encrypted_mnemonic: self.encrypted_mnemonic.clone(),
+            account_index: self.account_index,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum PasswordError {
     #[error("Key manager error: {0}")]
     KeyManagerError(#[from] nine_sdk::KeyManagerError),
-    #[error("User config store error: {0}")]
-    UserConfigStore(#[from] UserConfigStoreError),
+    #[error("Config store error: {0}")]
+    ConfigStore(#[from] ConfigStoreError),
     #[error("Serialization error: {0}")]
     Serde(#[from] serde_json::Error),
-    #[error("Encryption error: {0}")]
-    EncryptionError(String),
-    #[error("Wallet generation error: {0}")]
-    WalletError(String),
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Account was modified concurrently, please try again")]
+    ConcurrentUpdate,
+    #[error("An account already exists, log in instead")]
+    AccountExists,
+    #[error("Too many failed attempts, try again in {}", wait_time(.retry_after))]
+    LockedOut { retry_after: Duration },
+}
+
+/// Rounds a lockout up to the largest whole unit, e.g. "3 minutes"
+fn wait_time(retry_after: &Duration) -> String {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let (count, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs.div_ceil(60), "minute"),
+        _ => (secs.div_ceil(3600), "hour"),
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// How long the user must wait before trying their password again, if `error` is a lockout
+pub fn locked_out_for(error: &(dyn std::error::Error + 'static)) -> Option<Duration> {
+    match error.downcast_ref::<PasswordError>() {
+        Some(PasswordError::LockedOut { retry_after }) => Some(*retry_after),
+        _ => None,
+    }
+}
+
+/// Whether `error` means the signing enclave couldn't be reached, so the user should retry
+pub fn is_enclave_unavailable(error: &(dyn std::error::Error + 'static)) -> bool {
+    let key_manager_error = match error.downcast_ref::<PasswordError>() {
+        Some(PasswordError::KeyManagerError(e)) => Some(e),
+        _ => error.downcast_ref::<nine_sdk::KeyManagerError>(),
+    };
+    matches!(key_manager_error, Some(nine_sdk::KeyManagerError::Unavailable(_)))
+}
+
+/// A wallet unlocked inside the enclave; only its address is known to the bot
+struct WalletSession {
+    session: String,
+    address: String,
 }
 
 pub struct PasswordHandler {
-    key_manager: Arc<Mutex<KeyManager>>,
-    config_store: Arc<UserConfigStore>,
-    ethereum_wallet: Arc<Mutex<Option<PrivateKeySigner>>>,
+    enclave_client: Arc<EnclaveClient>,
+    config_store: Arc<dyn ConfigStore>,
+    wallet: Arc<Mutex<Option<WalletSession>>>,
+    lockout: LockoutPolicy,
 }
 
 impl PasswordHandler {
     pub fn new(
-        config_store: Arc<UserConfigStore>,
+        config_store: Arc<dyn ConfigStore>,
+        enclave_client: Arc<EnclaveClient>,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let key_manager = KeyManager::new();
         Ok(Self {
-            key_manager: Arc::new(Mutex::new(key_manager)),
+            enclave_client,
             config_store,
-            ethereum_wallet: Arc::new(Mutex::new(None)),
+            wallet: Arc::new(Mutex::new(None)),
+            lockout: LockoutPolicy::default(),
         })
     }
 
+    /// Creates the user's account and wallet and logs them in
+    ///
+    /// Returns the wallet's recovery phrase, to be shown to the user once; it can't be
+    /// retrieved later. Enclaves that predate recovery phrases return none.
     pub async fn sign_up(
         &self,
         user_id: &str,
-        password: &str,
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        // Generate encryption keys
-        let mut key_manager = self.key_manager.lock().await;
-        let config_json = key_manager.setup_config(password).await.map_err(|e| {
-            Box::new(PasswordError::KeyManagerError(e)) as Box<dyn std::error::Error + Send + Sync>
-        })?;
-        
+        password: &SecretPassword,
+    ) -> Result<Option<SecretPhrase>, Box<dyn std::error::Error + Send + Sync>> {
+        // Generate encryption keys inside the enclave
+        let config_json = self.enclave_client.setup_config(password).await.map_err(PasswordError::from)?;
         let encrypted_key_config: EncryptedKeyConfig = serde_json::from_str(&config_json)?;
-        
-        // Derive encryption keys to encrypt the Ethereum private key
-        let (key1, _key2) = key_manager.verify_and_derive_keys(password).await.map_err(|e| {
-            Box::new(PasswordError::KeyManagerError(e)) as Box<dyn std::error::Error + Send + Sync>
-        })?;
-        
-        // Generate Ethereum wallet
-        let wallet = PrivateKeySigner::random();
-        let ethereum_private_key = hex::encode(wallet.to_bytes());
-        let ethereum_public_key = hex::encode(wallet.address().as_slice());
-        let ethereum_address = format!("{:?}", wallet.address());
-        
-        // Store wallet in memory
-        {
-            let mut eth_wallet = self.ethereum_wallet.lock().await;
-            *eth_wallet = Some(wallet);
-        }
-        
-        // Encrypt the private key using ChaCha20Poly1305
-        let mut nonce = [0u8; 12];
-        rand::Rng::fill(&mut rand::thread_rng(), &mut nonce);
-        
-        let encrypted_private_key = nine_sdk::encrypt_chacha20(
-            &key1,
-            ethereum_private_key.as_bytes(),
-            &nonce
-        ).map_err(|e| {
-            Box::new(PasswordError::EncryptionError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>
-        })?;
-        
-        // Create wallet config
-        let wallet_config = UserWalletConfig {
-            encrypted_key_config,
-            encrypted_ethereum_private_key: hex::encode(&encrypted_private_key),
-            ethereum_public_key,
-            ethereum_address,
-            nonce: hex::encode(&nonce),
-        };
-        
+
+        // The enclave generates the Ethereum wallet and hands back only its sealed form
+        let created = self
+            .enclave_client
+            .create_wallet(&encrypted_key_config, password, &wallet_context(user_id, WALLET_SCHEMA_VERSION))
+            .await
+            .map_err(PasswordError::from)?;
+        self.save_new_wallet(user_id, password, UserWalletConfig::new(encrypted_key_config, created.wallet))
+            .await?;
+        Ok(created.mnemonic)
+    }
+
+    /// Creates the user's account around an existing wallet and logs them in
+    ///
+    /// `secret` is a hex private key or a recovery phrase; the enclave checks it and seals
+    /// it exactly like a generated wallet.
+    pub async fn import_wallet(
+        &self,
+        user_id: &str,
+        password: &SecretPassword,
+        secret: &SecretPhrase,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let config_json = self.enclave_client.setup_config(password).await.map_err(PasswordError::from)?;
+        let encrypted_key_config: EncryptedKeyConfig = serde_json::from_str(&config_json)?;
+
+        let wallet = self
+            .enclave_client
+            .import_wallet(&encrypted_key_config, password, &wallet_context(user_id, WALLET_SCHEMA_VERSION), secret)
+            .await
+            .map_err(PasswordError::from)?;
+        self.save_new_wallet(user_id, password, UserWalletConfig::new(encrypted_key_config, wallet))
+            .await
+    }
+
+    /// Stores a newly sealed wallet as the user's record and unlocks it
+    ///
+    /// Fails without touching the stored record if the user already has one, so their
+    /// existing wallet can't be replaced.
+    async fn save_new_wallet(
+        &self,
+        user_id: &str,
+        password: &SecretPassword,
+        wallet_config: UserWalletConfig,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let wallet_config_json = serde_json::to_string_pretty(&wallet_config)?;
-        
+
         // Persist config JSON to DB
-        self.config_store
-            .insert_or_update_config(user_id, &wallet_config_json)
-            .await?;
-        
-        Ok(wallet_config_json)
+        if !self.config_store.insert_config(user_id, &wallet_config_json).await? {
+            return Err(Box::new(PasswordError::AccountExists));
+        }
+
+        self.unlock(user_id, &wallet_config, password).await?;
+        Ok(())
     }
 
     pub async fn login(
         &self,
         user_id: &str,
-        password: &str,
+        password: &SecretPassword,
     ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
         // Load config from DB
         let config_json: String = self.config_store.get_config(user_id).await?;
         let wallet_config: UserWalletConfig = serde_json::from_str(&config_json)?;
-        
-        let mut key_manager = self.key_manager.lock().await;
-        // Set config in KeyManager
-        key_manager.set_config(wallet_config.encrypted_key_config.clone());
-        
-        // Attempt to verify and derive keys
-        match key_manager.verify_and_derive_keys(password).await {
-            Ok((key1, _key2)) => {
-                // Decrypt the Ethereum private key
-                let nonce_bytes = hex::decode(&wallet_config.nonce)
-                    .map_err(|e| Box::new(PasswordError::EncryptionError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>)?;
-                let mut nonce = [0u8; 12];
-                nonce.copy_from_slice(&nonce_bytes[..12]);
-                
-                let encrypted_key_bytes = hex::decode(&wallet_config.encrypted_ethereum_private_key)
-                    .map_err(|e| Box::new(PasswordError::EncryptionError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>)?;
-                
-                let decrypted_key = nine_sdk::decrypt_chacha20(
-                    &key1,
-                    &encrypted_key_bytes,
-                    &nonce
-                ).map_err(|e| {
-                    Box::new(PasswordError::EncryptionError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>
-                })?;
-                
-                let private_key_hex = String::from_utf8(decrypted_key)
-                    .map_err(|e| Box::new(PasswordError::EncryptionError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>)?;
-                
-                let private_key_bytes = hex::decode(&private_key_hex)
-                    .map_err(|e| Box::new(PasswordError::EncryptionError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>)?;
-                
-                // Convert to B256 for alloy
-                let mut key_array = [0u8; 32];
-                key_array.copy_from_slice(&private_key_bytes[..32]);
-                let b256_key = B256::from(key_array);
-                
-                // Reconstruct the wallet from the private key
-                let wallet = PrivateKeySigner::from_bytes(&b256_key)
-                    .map_err(|e| Box::new(PasswordError::WalletError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>)?;
-                
-                // Store wallet in memory
-                {
-                    let mut eth_wallet = self.ethereum_wallet.lock().await;
-                    *eth_wallet = Some(wallet);
-                }
-                
-                Ok(true)
+
+        let retry_after = self.begin_attempt(user_id).await?;
+        let unlocked = self.unlock(user_id, &wallet_config, password).await;
+        let Some(upgraded) = self.count_attempt(user_id, retry_after, unlocked).await? else {
+            return Ok(false);
+        };
+
+        // Records from older schema versions or key derivation parameters are replaced with
+        // what the enclave resealed
+        if let Some(wallet_config) = upgraded {
+            let wallet_config_json = serde_json::to_string_pretty(&wallet_config)?;
+            self.config_store
+                .insert_or_update_config(user_id, &wallet_config_json)
+                .await?;
+        }
+        Ok(true)
+    }
+
+    /// Moves the user's wallet to a config for `new_password`, keeping its address
+    ///
+    /// Returns `false` if `old_password` is wrong; wrong guesses count towards the same
+    /// lockout as logins. The record is only replaced if it hasn't changed since it was
+    /// read, so a concurrent login upgrade can't be lost. An open session is reopened under
+    /// the new record, so accounts selected later are authenticated with its keys.
+    pub async fn change_password(
+        &self,
+        user_id: &str,
+        old_password: &SecretPassword,
+        new_password: &SecretPassword,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let config_json = self.config_store.get_config(user_id).await?;
+        let wallet_config: UserWalletConfig = serde_json::from_str(&config_json)?;
+        if wallet_config.schema_version > WALLET_SCHEMA_VERSION {
+            return Err(Box::new(PasswordError::KeyManagerError(nine_sdk::KeyManagerError::InvalidConfig)));
+        }
+
+        let retry_after = self.begin_attempt(user_id).await?;
+        let changed = self
+            .enclave_client
+            .change_password(
+                &wallet_config.encrypted_key_config,
+                old_password,
+                new_password,
+                &wallet_config.sealed_wallet(),
+                &wallet_context(user_id, WALLET_SCHEMA_VERSION),
+            )
+            .await;
+        let Some((config, wallet)) = self.count_attempt(user_id, retry_after, changed).await? else {
+            return Ok(false);
+        };
+
+        let new_config = wallet_config.resealed(config, wallet);
+        let new_config_json = serde_json::to_string_pretty(&new_config)?;
+        if !self
+            .config_store
+            .replace_config(user_id, &config_json, &new_config_json)
+            .await?
+        {
+            return Err(Box::new(PasswordError::ConcurrentUpdate));
+        }
+
+        if self.wallet.lock().await.is_some() {
+            if let Err(e) = self.unlock(user_id, &new_config, new_password).await {
+                // The password has changed all the same; the user just logs in again
+                log::warn!("Failed to reopen wallet for user {} under its new password: {}", user_id, e);
+                self.logout().await;
             }
-            Err(e) => {
-                if matches!(e, nine_sdk::KeyManagerError::AuthenticationFailed) {
-                    Ok(false)
+        }
+        Ok(true)
+    }
+
+    /// Deletes everything stored about the user once `password` is confirmed, and locks
+    /// their wallet
+    ///
+    /// Returns `false` if the password is wrong; wrong guesses count towards the same
+    /// lockout as logins. Without a backup of the recovery phrase the wallet is lost for good.
+    pub async fn delete_account(
+        &self,
+        user_id: &str,
+        password: &SecretPassword,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let config_json = self.config_store.get_config(user_id).await?;
+        let wallet_config: UserWalletConfig = serde_json::from_str(&config_json)?;
+
+        if wallet_config.schema_version > WALLET_SCHEMA_VERSION {
+            return Err(nine_sdk::KeyManagerError::InvalidConfig.into());
+        }
+
+        // Unlocking checks the password without any keys leaving the enclave
+        let retry_after = self.begin_attempt(user_id).await?;
+        let context = wallet_context(user_id, WALLET_SCHEMA_VERSION);
+        let unlocked = self
+            .enclave_client
+            .unlock_wallet(&wallet_config.encrypted_key_config, password, &wallet_config.sealed_wallet(), &context)
+            .await;
+        let Some(unlocked) = self.count_attempt(user_id, retry_after, unlocked).await? else {
+            return Ok(false);
+        };
+        if let Err(e) = self.enclave_client.lock_wallet(&unlocked.session).await {
+            log::warn!("Failed to lock the wallet opened to delete user {}: {}", user_id, e);
+        }
+        self.config_store.delete_user(user_id).await?;
+        self.logout().await;
+        Ok(true)
+    }
+
+    /// Charges a password attempt before it is checked, returning how long a wrong
+    /// password will lock the user out for
+    async fn begin_attempt(&self, user_id: &str) -> Result<Duration, PasswordError> {
+        match self.config_store.begin_login_attempt(user_id, &self.lockout).await? {
+            LoginAttempt::Charged { retry_after } => Ok(retry_after),
+            LoginAttempt::LockedOut { retry_after } => Err(PasswordError::LockedOut { retry_after }),
+        }
+    }
+
+    /// Settles an attempt charged by [`begin_attempt`](Self::begin_attempt) with the
+    /// outcome of its password check
+    ///
+    /// Returns `None` for a wrong password, or a lockout error if it was one too many.
+    /// A lockout enforced by the enclave is passed on as is. Attempts that fail for any
+    /// other reason stay charged, as the enclave's own do.
+    async fn count_attempt<T>(
+        &self,
+        user_id: &str,
+        retry_after: Duration,
+        result: Result<T, nine_sdk::KeyManagerError>,
+    ) -> Result<Option<T>, PasswordError> {
+        match result {
+            Ok(value) => {
+                self.config_store.clear_failed_logins(user_id).await?;
+                Ok(Some(value))
+            }
+            Err(nine_sdk::KeyManagerError::AuthenticationFailed) => {
+                if retry_after.is_zero() {
+                    Ok(None)
                 } else {
-                    Err(Box::new(PasswordError::KeyManagerError(e)))
+                    Err(PasswordError::LockedOut { retry_after })
                 }
             }
+            Err(nine_sdk::KeyManagerError::LockedOut { retry_after_secs }) => Err(PasswordError::LockedOut {
+                retry_after: Duration::from_secs(retry_after_secs),
+            }),
+            Err(e) => Err(PasswordError::KeyManagerError(e)),
         }
     }
 
-    pub async fn get_private_key(
+    /// Unlocks the user's wallet inside the enclave, replacing any previous session, and
+    /// returns the record to store in its place if it needs upgrading
+    async fn unlock(
         &self,
-    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
-        let eth_wallet = self.ethereum_wallet.lock().await;
-        if let Some(wallet) = eth_wallet.as_ref() {
-            Ok(Some(wallet.to_bytes().to_vec()))
-        } else {
-            Ok(None)
+        user_id: &str,
+        wallet_config: &UserWalletConfig,
+        password: &SecretPassword,
+    ) -> Result<Option<UserWalletConfig>, nine_sdk::KeyManagerError> {
+        if wallet_config.schema_version > WALLET_SCHEMA_VERSION {
+            return Err(nine_sdk::KeyManagerError::InvalidConfig);
         }
+        // Older records are resealed for the current version, so that is the context to expect
+        let context = wallet_context(user_id, WALLET_SCHEMA_VERSION);
+        let unlocked = self
+            .enclave_client
+            .unlock_wallet(&wallet_config.encrypted_key_config, password, &wallet_config.sealed_wallet(), &context)
+            .await?;
+        let mut session = WalletSession {
+            session: unlocked.session,
+            address: unlocked.address,
+        };
+        if wallet_config.account_index != 0 {
+            match self.enclave_client.select_account(&session.session, wallet_config.account_index).await {
+                Ok(selected) => session.address = selected.address,
+                Err(e) => {
+                    self.lock_session(&session).await;
+                    return Err(e);
+                }
+            }
+        }
+        let previous = self.wallet.lock().await.replace(session);
+        if let Some(previous) = previous {
+            self.lock_session(&previous).await;
+        }
+        Ok(unlocked.upgraded.map(|wallet| {
+            let config = unlocked
+                .config
+                .unwrap_or_else(|| wallet_config.encrypted_key_config.clone());
+            wallet_config.resealed(config, wallet)
+        }))
     }
 
-    pub async fn get_public_key(
+    /// Switches signing to account `index` of the user's recovery phrase and keeps it
+    /// selected for future logins, returning the account's address
+    ///
+    /// Enclaves that can't authenticate the selection only switch the current session.
+    pub async fn select_account(
         &self,
-    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
-        let eth_wallet = self.ethereum_wallet.lock().await;
-        if let Some(wallet) = eth_wallet.as_ref() {
-            Ok(Some(wallet.address().as_slice().to_vec()))
-        } else {
-            Ok(None)
+        user_id: &str,
+        index: u32,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let session = self.session().await?;
+        let selected = self
+            .enclave_client
+            .select_account(&session, index)
+            .await
+            .map_err(PasswordError::from)?;
+        if let Some(wallet) = self.wallet.lock().await.as_mut() {
+            wallet.address = selected.address.clone();
+        }
+        let Some(mac) = selected.mac else {
+            return Ok(selected.address);
+        };
+
+        let config_json = self.config_store.get_config(user_id).await?;
+        let mut wallet_config: UserWalletConfig = serde_json::from_str(&config_json)?;
+        wallet_config.account_index = index;
+        wallet_config.mac = Some(mac);
+        let new_config_json = serde_json::to_string_pretty(&wallet_config)?;
+        if !self
+            .config_store
+            .replace_config(user_id, &config_json, &new_config_json)
+            .await?
+        {
+            return Err(Box::new(PasswordError::ConcurrentUpdate));
         }
+        Ok(selected.address)
+    }
+
+    /// Locks the wallet inside the enclave so it can no longer sign
+    pub async fn logout(&self) {
+        if let Some(wallet) = self.wallet.lock().await.take() {
+            self.lock_session(&wallet).await;
+        }
+    }
+
+    async fn lock_session(&self, wallet: &WalletSession) {
+        // An unreachable enclave drops the session itself once it expires
+        if let Err(e) = self.enclave_client.lock_wallet(&wallet.session).await {
+            log::warn!("Failed to lock wallet {}: {}", wallet.address, e);
+        }
+    }
+
+    /// Checksummed address of the unlocked wallet, if logged in
+    pub async fn get_address(&self) -> Option<String> {
+        self.wallet.lock().await.as_ref().map(|wallet| wallet.address.clone())
+    }
+
+    async fn session(&self) -> Result<String, PasswordError> {
+        let wallet = self.wallet.lock().await;
+        wallet.as_ref().map(|wallet| wallet.session.clone()).ok_or(PasswordError::NotLoggedIn)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::NamedTempFile;
+    use nine_sdk::{ClientConfig, EnclaveIdentity, KeyManager, Transport};
+    use crate::services::memory_config_store::MemoryConfigStore;
+    use crate::test_util::{spawn_enclave, spawn_enclave_with};
+    use tokio::net::TcpListener;
     
     #[tokio::test]
     async fn test_ethereum_key_generation_and_recovery() {
-        // Create a temporary database
-        let temp_file = NamedTempFile::new().unwrap();
-        let db_path = temp_file.path().to_str().unwrap();
-        
         // Create user config store
-        let config_store = Arc::new(UserConfigStore::new(db_path).unwrap());
+        let config_store = Arc::new(MemoryConfigStore::default());
         
         // Create password handler
-        let handler = PasswordHandler::new(config_store.clone()).unwrap();
+        let handler = PasswordHandler::new(config_store.clone(), spawn_enclave().await).unwrap();
         
         // Test user credentials
         let user_id = "test_user_123";
-        let password = "strong_password_123!";
+        let password = SecretPassword::from("strong_password_123!");
         
         // Sign up (generates Ethereum wallet)
-        let config_json = handler.sign_up(user_id, password).await.unwrap();
+        let mnemonic = handler.sign_up(user_id, &password).await.unwrap().unwrap();
+        assert_eq!(mnemonic.expose().split_whitespace().count(), 12);
         
         // Parse the config to verify it has the expected fields
+        let config_json = config_store.get_config(user_id).await.unwrap();
         let wallet_config: UserWalletConfig = serde_json::from_str(&config_json).unwrap();
         assert!(!wallet_config.encrypted_ethereum_private_key.is_empty());
         assert!(!wallet_config.ethereum_public_key.is_empty());
         assert!(!wallet_config.ethereum_address.is_empty());
+        assert!(wallet_config.encrypted_mnemonic.is_some());
+        assert!(!config_json.contains(mnemonic.expose()));
+        assert_eq!(handler.get_address().await, Some(wallet_config.ethereum_address));
+    }
+    
+    #[tokio::test]
+    async fn test_imported_key_is_sealed_like_a_generated_one() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let password = SecretPassword::from("import_password");
+        // Second Hardhat development account
+        let secret = SecretPhrase::from("0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d");
+        
+        let handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        let error = handler.import_wallet("alice", &password, &"0x1234".into()).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<PasswordError>(),
+            Some(PasswordError::KeyManagerError(nine_sdk::KeyManagerError::InvalidImport))
+        ));
+        assert!(!config_store.config_exists("alice").await.unwrap());
+        
+        handler.import_wallet("alice", &password, &secret).await.unwrap();
+        let address = Some("0x70997970C51812dc3A010C7d01b50e0d17dc79C8".to_string());
+        assert_eq!(handler.get_address().await, address);
+        
+        let config_json = config_store.get_config("alice").await.unwrap();
+        assert!(!config_json.contains(&secret.expose()[2..]));
+        let wallet_config: UserWalletConfig = serde_json::from_str(&config_json).unwrap();
+        assert!(wallet_config.mac.is_some());
+        
+        let login_handler = PasswordHandler::new(config_store.clone(), enclave_client).unwrap();
+        assert!(login_handler.login("alice", &password).await.unwrap());
+        assert_eq!(login_handler.get_address().await, address);
+        
+        // Neither another import nor a sign-up replaces the existing wallet
+        let other = SecretPhrase::from("0x5de4111afa1a4b94908f83103eb1f1706367c2e68ca870fc3fb9a804cdab365a");
+        let error = login_handler.import_wallet("alice", &password, &other).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<PasswordError>(), Some(PasswordError::AccountExists)));
+        let error = login_handler.sign_up("alice", &password).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<PasswordError>(), Some(PasswordError::AccountExists)));
+        assert_eq!(config_store.get_config("alice").await.unwrap(), config_json);
+        assert_eq!(login_handler.get_address().await, address);
+    }
+    
+    #[tokio::test]
+    async fn test_selected_account_is_kept_across_logins() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let password = SecretPassword::from("account_password");
+        
+        let handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        handler.sign_up("alice", &password).await.unwrap();
+        let first = handler.get_address().await.unwrap();
+        
+        let second = handler.select_account("alice", 1).await.unwrap();
+        assert_ne!(second, first);
+        assert_eq!(handler.get_address().await, Some(second.clone()));
+        
+        let login_handler = PasswordHandler::new(config_store.clone(), enclave_client).unwrap();
+        assert!(login_handler.login("alice", &password).await.unwrap());
+        assert_eq!(login_handler.get_address().await, Some(second));
+        
+        assert_eq!(login_handler.select_account("alice", 0).await.unwrap(), first);
+        
+        // The selection is covered by the wallet's MAC, so it can't be changed in the database
+        login_handler.select_account("alice", 1).await.unwrap();
+        let wallet_config: UserWalletConfig =
+            serde_json::from_str(&config_store.get_config("alice").await.unwrap()).unwrap();
+        assert_eq!(wallet_config.account_index, 1);
+        let tampered = UserWalletConfig {
+            account_index: 2,
+            ..wallet_config
+        };
+        config_store
+            .insert_or_update_config("alice", &serde_json::to_string(&tampered).unwrap())
+            .await
+            .unwrap();
+        let error = login_handler.login("alice", &password).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<PasswordError>(),
+            Some(PasswordError::KeyManagerError(nine_sdk::KeyManagerError::IntegrityCheckFailed))
+        ));
+    }
+    
+    #[tokio::test]
+    async fn test_login_through_enclave() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        
+        let user_id = "test_user_456";
+        let password = SecretPassword::from("another_password_456!");
+        
+        let signup_handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        signup_handler.sign_up(user_id, &password).await.unwrap();
+        let signed_up_address = signup_handler.get_address().await;
+        
+        // A fresh handler must unlock the same wallet via the enclave
+        let login_handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        assert!(!login_handler.login(user_id, &"wrong_password".into()).await.unwrap());
+        assert!(login_handler.get_address().await.is_none());
+        assert!(login_handler.session().await.is_err());
+        
+        assert!(login_handler.login(user_id, &password).await.unwrap());
+        assert_eq!(login_handler.get_address().await, signed_up_address);
+        let session = login_handler.session().await.unwrap();
+        assert_eq!(enclave_client.sign_message(&session, b"hello").await.unwrap().len(), 65);
+    }
+    
+    #[tokio::test]
+    async fn test_tampered_config_is_refused_on_login() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        
+        handler.sign_up("alice", &"alice_password".into()).await.unwrap();
+        handler.sign_up("mallory", &"mallory_password".into()).await.unwrap();
+        let alice: UserWalletConfig =
+            serde_json::from_str(&config_store.get_config("alice").await.unwrap()).unwrap();
+        let mallory: UserWalletConfig =
+            serde_json::from_str(&config_store.get_config("mallory").await.unwrap()).unwrap();
+        assert!(alice.mac.is_some());
+        
+        // Swapping in another user's address is caught before anything is shown
+        let tampered = UserWalletConfig {
+            ethereum_address: mallory.ethereum_address.clone(),
+            ..alice.clone()
+        };
+        config_store
+            .insert_or_update_config("alice", &serde_json::to_string(&tampered).unwrap())
+            .await
+            .unwrap();
+        let error = handler.login("alice", &"alice_password".into()).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<PasswordError>(),
+            Some(PasswordError::KeyManagerError(nine_sdk::KeyManagerError::IntegrityCheckFailed))
+        ));
+        
+        // A current record can't pass for one from before the MAC by dropping it
+        let stripped = UserWalletConfig { mac: None, ..alice.clone() };
+        config_store
+            .insert_or_update_config("alice", &serde_json::to_string(&stripped).unwrap())
+            .await
+            .unwrap();
+        let error = handler.login("alice", &"alice_password".into()).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<PasswordError>(),
+            Some(PasswordError::KeyManagerError(nine_sdk::KeyManagerError::IntegrityCheckFailed))
+        ));
+    }
+    
+    #[tokio::test]
+    async fn test_outdated_kdf_parameters_are_upgraded_on_login() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let cheap = nine_sdk::KdfParams {
+            m_cost: 1024,
+            t_cost: 1,
+            ..Default::default()
+        };
+        let old_enclave = spawn_enclave_with(KeyManager::with_params(cheap).unwrap()).await;
+        let handler = PasswordHandler::new(config_store.clone(), old_enclave).unwrap();
+        handler.sign_up("alice", &"alice_password".into()).await.unwrap();
+        let address = handler.get_address().await;
+        
+        // After the enclave raises its parameters, the next login re-derives the config
+        let handler = PasswordHandler::new(config_store.clone(), spawn_enclave().await).unwrap();
+        assert!(handler.login("alice", &"alice_password".into()).await.unwrap());
+        assert_eq!(handler.get_address().await, address);
+        let upgraded: UserWalletConfig =
+            serde_json::from_str(&config_store.get_config("alice").await.unwrap()).unwrap();
+        assert_eq!(upgraded.encrypted_key_config.kdf, nine_sdk::KdfParams::default());
+        
+        assert!(!handler.login("alice", &"wrong_password".into()).await.unwrap());
+        assert!(handler.login("alice", &"alice_password".into()).await.unwrap());
+        assert_eq!(handler.get_address().await, address);
+    }
+    
+    #[tokio::test]
+    async fn test_change_password_keeps_the_wallet() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        handler.sign_up("alice", &"old_password".into()).await.unwrap();
+        let address = handler.get_address().await;
+        
+        let new_password = SecretPassword::from("new_password");
+        assert!(!handler.change_password("alice", &"wrong_password".into(), &new_password).await.unwrap());
+        assert!(handler.change_password("alice", &"old_password".into(), &new_password).await.unwrap());
+        // The user stays logged in, and accounts selected now are kept under the new password
+        let session = handler.session().await.unwrap();
+        assert_eq!(enclave_client.sign_message(&session, b"hello").await.unwrap().len(), 65);
+        let second = handler.select_account("alice", 1).await.unwrap();
+        
+        let login_handler = PasswordHandler::new(config_store.clone(), enclave_client).unwrap();
+        assert!(!login_handler.login("alice", &"old_password".into()).await.unwrap());
+        assert!(login_handler.login("alice", &"new_password".into()).await.unwrap());
+        assert_eq!(login_handler.get_address().await, Some(second));
+        assert_eq!(login_handler.select_account("alice", 0).await.unwrap(), address.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_delete_account_needs_the_password() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let bob = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        bob.sign_up("bob", &"password".into()).await.unwrap();
+        let handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        handler.sign_up("alice", &"password".into()).await.unwrap();
+        let session = handler.session().await.unwrap();
+        
+        assert!(!handler.delete_account("alice", &"wrong".into()).await.unwrap());
+        assert!(config_store.config_exists("alice").await.unwrap());
+        assert!(config_store.failed_logins("alice").await.unwrap().is_some());
+        
+        assert!(handler.delete_account("alice", &"password".into()).await.unwrap());
+        assert!(!config_store.config_exists("alice").await.unwrap());
+        assert!(config_store.failed_logins("alice").await.unwrap().is_none());
+        assert!(handler.get_address().await.is_none());
+        assert!(matches!(
+            enclave_client.sign_message(&session, b"hi").await,
+            Err(nine_sdk::KeyManagerError::WalletLocked)
+        ));
+        assert!(config_store.config_exists("bob").await.unwrap());
+        let bob_session = bob.session().await.unwrap();
+        assert_eq!(enclave_client.sign_message(&bob_session, b"hi").await.unwrap().len(), 65);
+    }
+    
+    #[tokio::test]
+    async fn test_delete_account_works_without_logging_in() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let signup = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        signup.sign_up("alice", &"password".into()).await.unwrap();
+        
+        // The password is checked by opening the wallet, which is locked again straight away
+        let handler = PasswordHandler::new(config_store.clone(), enclave_client).unwrap();
+        assert!(handler.delete_account("alice", &"password".into()).await.unwrap());
+        assert!(!config_store.config_exists("alice").await.unwrap());
+        assert!(handler.get_address().await.is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_repeated_wrong_passwords_lock_the_user_out() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let policy = LockoutPolicy {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(120),
+            ..LockoutPolicy::default()
+        };
+        let mut handler = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        handler.lockout = policy;
+        handler.sign_up("alice", &"password".into()).await.unwrap();
+        
+        assert!(!handler.login("alice", &"wrong".into()).await.unwrap());
+        let error = handler.login("alice", &"wrong".into()).await.unwrap_err();
+        assert_eq!(locked_out_for(error.as_ref()), Some(Duration::from_secs(120)));
+        assert_eq!(error.to_string(), "Too many failed attempts, try again in 2 minutes");
+        
+        // The lockout is kept in the database, so a fresh handler honours it too
+        let handler = PasswordHandler::new(config_store, enclave_client).unwrap();
+        let error = handler.login("alice", &"password".into()).await.unwrap_err();
+        assert!(locked_out_for(error.as_ref()).is_some());
+        let error = handler
+            .change_password("alice", &"password".into(), &"new_password".into())
+            .await
+            .unwrap_err();
+        assert!(locked_out_for(error.as_ref()).is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_parallel_guesses_are_charged_before_checking() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        let mut handler = PasswordHandler::new(config_store.clone(), enclave_client).unwrap();
+        handler.lockout = LockoutPolicy {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(120),
+            ..LockoutPolicy::default()
+        };
+        handler.sign_up("alice", &"password".into()).await.unwrap();
+        let handler = Arc::new(handler);
+        
+        // Only the guesses charged before the lockout reach the enclave
+        let guesses: Vec<_> = (0..8)
+            .map(|_| {
+                let handler = Arc::clone(&handler);
+                tokio::spawn(async move { handler.login("alice", &"wrong".into()).await.map_err(|e| e.to_string()) })
+            })
+            .collect();
+        let mut checked = 0;
+        for guess in guesses {
+            if guess.await.unwrap() == Ok(false) {
+                checked += 1;
+            }
+        }
+        assert_eq!(checked, 1);
+        assert_eq!(config_store.failed_logins("alice").await.unwrap().unwrap().failures, 2);
+    }
+    
+    #[tokio::test]
+    async fn test_enclave_lockout_is_passed_on() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave_with(KeyManager::new().with_lockout(LockoutPolicy {
+            free_attempts: 0,
+            ..LockoutPolicy::default()
+        }))
+        .await;
+        let handler = PasswordHandler::new(config_store, enclave_client).unwrap();
+        handler.sign_up("alice", &"password".into()).await.unwrap();
+        
+        // The bot would allow more guesses, but the enclave refuses them
+        assert!(!handler.login("alice", &"wrong".into()).await.unwrap());
+        let error = handler.login("alice", &"password".into()).await.unwrap_err();
+        assert!(locked_out_for(error.as_ref()).unwrap() > Duration::ZERO);
+    }
+    
+    #[test]
+    fn test_wait_time_is_rounded_up() {
+        assert_eq!(wait_time(&Duration::from_millis(500)), "1 second");
+        assert_eq!(wait_time(&Duration::from_secs(45)), "45 seconds");
+        assert_eq!(wait_time(&Duration::from_secs(61)), "2 minutes");
+        assert_eq!(wait_time(&Duration::from_secs(3600)), "1 hour");
+    }
+    
+    #[tokio::test]
+    async fn test_config_copied_to_another_user_does_not_open() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let handler = PasswordHandler::new(config_store.clone(), spawn_enclave().await).unwrap();
+        
+        handler.sign_up("alice", &"alice_password".into()).await.unwrap();
+        let alice = config_store.get_config("alice").await.unwrap();
+        config_store.insert_or_update_config("bob", &alice).await.unwrap();
+        
+        // Even with Alice's password, her wallet is bound to her user id
+        let error = handler.login("bob", &"alice_password".into()).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<PasswordError>(),
+            Some(PasswordError::KeyManagerError(nine_sdk::KeyManagerError::IntegrityCheckFailed))
+        ));
+        assert!(handler.login("alice", &"alice_password".into()).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_logout_locks_wallet_in_enclave() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        
+        let handler = PasswordHandler::new(config_store, enclave_client.clone()).unwrap();
+        handler.sign_up("user", &"password".into()).await.unwrap();
+        let session = handler.session().await.unwrap();
+        assert!(enclave_client.sign_message(&session, b"hi").await.is_ok());
+        
+        handler.logout().await;
+        assert!(handler.get_address().await.is_none());
+        assert!(matches!(
+            enclave_client.sign_message(&session, b"hi").await,
+            Err(nine_sdk::KeyManagerError::WalletLocked)
+        ));
+    }
+    
+    #[tokio::test]
+    async fn test_concurrent_users_do_not_cross_talk() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        let enclave_client = spawn_enclave().await;
+        
+        let alice = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        let bob = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        let alice_password = SecretPassword::from("alice_password");
+        let bob_password = SecretPassword::from("bob_password");
+        
+        // Interleave both sign-ups through the same enclave client
+        let (alice_signup, bob_signup) = tokio::join!(
+            alice.sign_up("alice", &alice_password),
+            bob.sign_up("bob", &bob_password),
+        );
+        alice_signup.unwrap();
+        bob_signup.unwrap();
+        
+        let alice_login = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        let bob_login = PasswordHandler::new(config_store.clone(), enclave_client.clone()).unwrap();
+        let (alice_ok, bob_ok) = tokio::join!(
+            alice_login.login("alice", &alice_password),
+            bob_login.login("bob", &bob_password),
+        );
+        assert!(alice_ok.unwrap());
+        assert!(bob_ok.unwrap());
+        
+        assert_eq!(alice_login.get_address().await, alice.get_address().await);
+        assert_eq!(bob_login.get_address().await, bob.get_address().await);
+        assert_ne!(alice.get_address().await, bob.get_address().await);
+        
+        // Each password only unlocks its own account
+        assert!(!alice_login.login("alice", &bob_password).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_unreachable_enclave_is_reported_as_unavailable() {
+        let config_store = Arc::new(MemoryConfigStore::default());
+        
+        // Nothing listens on this address any more
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let config = ClientConfig {
+            request_timeout: std::time::Duration::from_millis(200),
+            ..ClientConfig::default()
+        };
+        let enclave_key = EnclaveIdentity::generate().public_key();
+        let enclave_client = Arc::new(EnclaveClient::with_config(Transport::Tcp(addr), enclave_key, config));
+        
+        let handler = PasswordHandler::new(config_store, enclave_client).unwrap();
+        let error = handler.sign_up("user", &"password".into()).await.unwrap_err();
+        assert!(is_enclave_unavailable(error.as_ref()));
+        
+        // Other failures are not mistaken for an outage
+        let error: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(PasswordError::NotLoggedIn);
+        assert!(!is_enclave_unavailable(error.as_ref()));
     }
 }
@@ -1,7 +1,8 @@
 use crate::models::PASSWORD_HANDLERS;
 use crate::models::buttons::Button;
 use crate::processors::message_processor::delete_all_messages;
-use crate::services::user_config_store::UserConfigStore;
+use crate::services::config_store::ConfigStore;
+use nine_sdk::EnclaveClient;
 use std::error::Error;
 use std::sync::Arc;
 use teloxide::prelude::*;
@@ -10,7 +11,8 @@ use teloxide::types::MaybeInaccessibleMessage;
 pub async fn process_callback(
     bot: Bot,
     q: CallbackQuery,
-    config_store: Arc<UserConfigStore>,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     log::debug!("Processing callback query: {:?}", q);
     if let Some(data) = q.data.as_deref() {
@@ -35,7 +37,7 @@ pub async fn process_callback(
                     );
                     let button = Button::from_str(data, is_logged_in);
                     button
-                        .execute(bot, msg.chat.id, config_store, is_logged_in)
+                        .execute(bot, msg.chat.id, config_store, enclave_client, is_logged_in)
                         .await?;
                 }
                 MaybeInaccessibleMessage::Inaccessible(_) => {
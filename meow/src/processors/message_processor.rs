@@ -1,12 +1,15 @@
 use crate::keyboard::{logged_in_operations, logged_out_operations};
 use crate::commands::{CommandLoggedIn, CommandLoggedOut};
-use crate::models::{PASSWORD_HANDLERS, log_in_state, password_handler::PasswordHandler};
-use hex;
+use crate::constants::ENCLAVE_UNAVAILABLE_MESSAGE;
+use crate::models::{PASSWORD_HANDLERS, log_in_state, password_handler::{self, PasswordHandler}};
+use crate::services::config_store::ConfigStore;
+use nine_sdk::{EnclaveClient, SecretPassword, SecretPhrase};
 use std::error::Error;
+use std::sync::Arc;
 use teloxide::{
     payloads::SendMessageSetters,
     prelude::*,
-    types::{BotCommandScope, Me, MessageId},
+    types::{BotCommandScope, InputFile, Me, MessageId},
     utils::command::BotCommands,
 };
 
@@ -15,10 +18,17 @@ use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use tokio::sync::Mutex;
 
+/// Name of the file `/exportdata` sends
+const EXPORT_FILE_NAME: &str = "purrbot-data.json";
+
 /// Global state to track message IDs per chat
 pub static CHAT_MESSAGE_IDS: Lazy<Mutex<HashMap<ChatId, Vec<MessageId>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
+/// Messages showing a recovery phrase, kept apart so button presses can't untrack them
+static RECOVERY_PHRASE_MESSAGES: Lazy<Mutex<HashMap<ChatId, MessageId>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
 /// Deletes all messages for a given chat
 /// 
 /// # Arguments
@@ -69,7 +79,9 @@ async fn delete_messages_for_chat(
     Ok(())
 }
 
-/// Prints the user's keys if they are logged in
+/// Prints the user's wallet address if they are logged in
+/// 
+/// The private key never leaves the enclave, so there is nothing else to show.
 /// 
 /// # Arguments
 /// * `chat_id` - The chat ID to print keys for
@@ -82,60 +94,36 @@ pub async fn print_keys(chat_id: ChatId, bot: &Bot) -> Result<(), Box<dyn Error
     delete_all_messages(chat_id, bot).await?;
 
     let handler = PASSWORD_HANDLERS.lock().await;
-    let keys_result = get_user_keys(&handler, chat_id).await;
-    
-    match keys_result {
-        Ok((private_key, public_key)) => {
-            send_keys_message(bot, chat_id, private_key, public_key).await?;
-        }
-        Err(_) => {
-            send_no_keys_message(bot, chat_id).await?;
-        }
+    match get_user_address(&handler, chat_id).await {
+        Some(address) => send_address_message(bot, chat_id, &address).await?,
+        None => send_no_keys_message(bot, chat_id).await?,
     }
 
     log::info!("print_keys completed for chat_id={}", chat_id);
     Ok(())
 }
 
-/// Helper function to get user keys from handler
-async fn get_user_keys(
-    handler: &tokio::sync::MutexGuard<'_, HashMap<i64, Option<PasswordHandler>>>,
+/// Helper function to get the user's wallet address from handler
+async fn get_user_address(
+    handler: &tokio::sync::MutexGuard<'_, HashMap<i64, Option<Arc<PasswordHandler>>>>,
     chat_id: ChatId,
-) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>), Box<dyn Error + Send + Sync>> {
-    if let Some(Some(handler)) = handler.get(&chat_id.0) {
-        let priv_key = handler.get_private_key().await?;
-        let pub_key = handler.get_public_key().await?;
-        Ok((priv_key, pub_key))
-    } else {
-        Ok((None, None))
+) -> Option<String> {
+    match handler.get(&chat_id.0) {
+        Some(Some(handler)) => handler.get_address().await,
+        _ => None,
     }
 }
 
-/// Helper function to send keys message
-async fn send_keys_message(
+/// Helper function to send address message
+async fn send_address_message(
     bot: &Bot,
     chat_id: ChatId,
-    private_key: Option<Vec<u8>>,
-    public_key: Option<Vec<u8>>,
+    address: &str,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    match (private_key, public_key) {
-        (Some(private_key), Some(public_key)) => {
-            let msg = bot
-                .send_message(
-                    chat_id,
-                    format!(
-                        "🔑 Your Keys:\nPrivate Key: {}\nPublic Key: {}",
-                        hex::encode(private_key),
-                        hex::encode(public_key)
-                    ),
-                )
-                .await?;
-            store_message_id(chat_id, msg.id).await;
-        }
-        _ => {
-            send_no_keys_message(bot, chat_id).await?;
-        }
-    }
+    let msg = bot
+        .send_message(chat_id, format!("🔑 Your Wallet:\nAddress: {}", address))
+        .await?;
+    store_message_id(chat_id, msg.id).await;
     Ok(())
 }
 
@@ -151,7 +139,7 @@ async fn send_no_keys_message(bot: &Bot, chat_id: ChatId) -> Result<(), Box<dyn
 /// Helper function to store message ID
 async fn store_message_id(chat_id: ChatId, message_id: MessageId) {
     let mut chat_message_ids = CHAT_MESSAGE_IDS.lock().await;
-    chat_message_ids.insert(chat_id, vec![message_id]);
+    chat_message_ids.entry(chat_id).or_default().push(message_id);
 }
 
 /// Logs out a user and cleans up their state
@@ -184,6 +172,11 @@ async fn is_user_logged_in(chat_id: ChatId) -> bool {
     handlers.get(&chat_id.0).and_then(|h| h.as_ref()).is_some()
 }
 
+/// Helper function to get a logged-in user's handler, so the map isn't locked while it is used
+async fn logged_in_handler(chat_id: ChatId) -> Option<Arc<PasswordHandler>> {
+    PASSWORD_HANDLERS.lock().await.get(&chat_id.0).cloned().flatten()
+}
+
 /// Helper function to handle logout when user is not logged in
 async fn handle_not_logged_in_logout(
     bot: &Bot,
@@ -206,9 +199,13 @@ async fn handle_not_logged_in_logout(
 async fn cleanup_user_state(chat_id: ChatId) {
     let mut states = log_in_state::USER_STATES.lock().await;
     states.insert(chat_id.0, log_in_state::AwaitingState::None);
+    log_in_state::PENDING_PASSWORD_CHANGES.lock().await.remove(&chat_id.0);
+    log_in_state::PENDING_IMPORTS.lock().await.remove(&chat_id.0);
     
-    let mut handler = PASSWORD_HANDLERS.lock().await;
-    handler.remove(&chat_id.0);
+    let handler = PASSWORD_HANDLERS.lock().await.remove(&chat_id.0);
+    if let Some(Some(handler)) = handler {
+        handler.logout().await;
+    }
     
     log::info!("User state cleaned up for chat_id={}", chat_id);
 }
@@ -261,6 +258,7 @@ async fn cleanup_messages(chat_id: ChatId, bot: &Bot) -> Result<(), Box<dyn Erro
 /// * `msg` - The message to process
 /// * `me` - Bot information
 /// * `config_store` - User configuration store
+/// * `enclave_client` - Client for the key-derivation enclave
 /// 
 /// # Returns
 /// * `Result<(), Box<dyn Error + Send + Sync>>` - Result indicating success or failure
@@ -268,22 +266,485 @@ pub async fn process_message(
     bot: Bot,
     msg: Message,
     me: Me,
-    config_store: std::sync::Arc<crate::services::user_config_store::UserConfigStore>,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     if let Some(text) = msg.text() {
-        log::info!(
-            "Processing message: '{}' from chat_id={}",
-            text,
-            msg.chat.id
-        );
+        let state = current_state(msg.chat.id).await;
+        log_in_state::log_incoming_message(msg.chat.id.0, state, text);
 
         if text.trim().to_lowercase() == "/logout" {
             return handle_logout_command(bot, msg).await;
         }
+        if text.trim().to_lowercase() == "/changepassword" {
+            return handle_change_password_command(&bot, &msg).await;
+        }
+        if text.split_whitespace().next().unwrap_or_default().to_lowercase() == "/account" {
+            let index = match CommandLoggedIn::parse(text.trim(), me.username()) {
+                Ok(CommandLoggedIn::Account { index }) => Some(index),
+                _ => None,
+            };
+            return handle_account_command(&bot, &msg, index).await;
+        }
+        if text.trim().to_lowercase() == "/exportdata" {
+            return handle_export_data_command(&bot, &msg, config_store).await;
+        }
+        if text.trim().to_lowercase() == "/deleteaccount" {
+            return handle_delete_account_command(&bot, &msg).await;
+        }
+
+        match state {
+            log_in_state::AwaitingState::AwaitingSignUpPassword => {
+                return handle_signup_password(&bot, &msg, &text.into(), config_store, enclave_client).await;
+            }
+            log_in_state::AwaitingState::AwaitingLoginPassword => {
+                return handle_login_password(&bot, &msg, &text.into(), config_store, enclave_client).await;
+            }
+            log_in_state::AwaitingState::AwaitingCurrentPassword => {
+                return handle_current_password(&bot, &msg, text.into()).await;
+            }
+            log_in_state::AwaitingState::AwaitingNewPassword => {
+                return handle_new_password(&bot, &msg, &text.into()).await;
+            }
+            log_in_state::AwaitingState::AwaitingMnemonicBackup => {
+                return handle_mnemonic_backup(&bot, &msg).await;
+            }
+            log_in_state::AwaitingState::AwaitingImportSecret => {
+                return handle_import_secret(&bot, &msg, text.into()).await;
+            }
+            log_in_state::AwaitingState::AwaitingImportPassword => {
+                return handle_import_password(&bot, &msg, &text.into(), config_store, enclave_client).await;
+            }
+            log_in_state::AwaitingState::AwaitingDeleteConfirmation => {
+                return handle_delete_confirmation(&bot, &msg, &text.into()).await;
+            }
+            log_in_state::AwaitingState::None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to read the conversation state for a chat
+async fn current_state(chat_id: ChatId) -> log_in_state::AwaitingState {
+    let states = log_in_state::USER_STATES.lock().await;
+    states
+        .get(&chat_id.0)
+        .copied()
+        .unwrap_or(log_in_state::AwaitingState::None)
+}
+
+/// Helper function to set the conversation state for a chat
+async fn set_state(chat_id: ChatId, state: log_in_state::AwaitingState) {
+    let mut states = log_in_state::USER_STATES.lock().await;
+    states.insert(chat_id.0, state);
+}
+
+/// Helper function to turn a sign-up or login failure into a reply
+///
+/// Enclave outages get a retry hint and lockouts say how long to wait; the conversation
+/// state is left unchanged so the user can simply send their password again.
+fn failure_message(action: &str, error: &(dyn Error + 'static)) -> String {
+    if password_handler::is_enclave_unavailable(error) {
+        ENCLAVE_UNAVAILABLE_MESSAGE.to_string()
+    } else if password_handler::locked_out_for(error).is_some() {
+        format!("🔒 {}", error)
+    } else {
+        format!("{}: {}", action, error)
+    }
+}
+
+/// Helper function to reply to a password message and track both for deletion
+async fn reply_to_password(
+    bot: &Bot,
+    msg: &Message,
+    text: String,
+    is_logged_in: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let message = bot
+        .send_message(msg.chat.id, text)
+        .reply_markup(if is_logged_in {
+            logged_in_operations()
+        } else {
+            logged_out_operations()
+        })
+        .await?;
+    let mut chat_message_ids = CHAT_MESSAGE_IDS.lock().await;
+    chat_message_ids.entry(msg.chat.id).or_default().extend([msg.id, message.id]);
+    Ok(())
+}
+
+/// Helper function to create an account from the password the user just sent
+async fn handle_signup_password(
+    bot: &Bot,
+    msg: &Message,
+    password: &SecretPassword,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let handler = PasswordHandler::new(config_store, enclave_client)?;
+    let user_id = msg.chat.id.0.to_string();
+
+    let signed_up = handler.sign_up(&user_id, password).await;
+    // The user logs in again once the account exists, so the wallet isn't kept open meanwhile
+    handler.logout().await;
+    let reply = match signed_up {
+        Ok(Some(mnemonic)) => {
+            log::info!("User {} created an account", msg.chat.id.0);
+            set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingMnemonicBackup).await;
+            return send_recovery_phrase(bot, msg, &mnemonic).await;
+        }
+        Ok(None) => {
+            log::info!("User {} created an account", msg.chat.id.0);
+            set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingLoginPassword).await;
+            "Account created successfully! 🎉\nNow enter your password again to log in.".to_string()
+        }
+        Err(e) => {
+            log::error!("Failed to create account for user {}: {}", msg.chat.id.0, e);
+            failure_message("Failed to create account", e.as_ref())
+        }
+    };
+    reply_to_password(bot, msg, reply, false).await
+}
+
+/// Helper function to show a new wallet's recovery phrase, the only time it is shown
+async fn send_recovery_phrase(
+    bot: &Bot,
+    msg: &Message,
+    mnemonic: &SecretPhrase,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let text = format!(
+        "Account created successfully! 🎉\n\n📝 Your recovery phrase:\n\n{}\n\n\
+         Write it down and keep it offline; it restores your wallet if you lose your password. \
+         It will not be shown again.\n\nSend any message once you have saved it.",
+        mnemonic.expose()
+    );
+    let message = bot.send_message(msg.chat.id, text).await?;
+    RECOVERY_PHRASE_MESSAGES.lock().await.insert(msg.chat.id, message.id);
+    let mut chat_message_ids = CHAT_MESSAGE_IDS.lock().await;
+    chat_message_ids.entry(msg.chat.id).or_default().push(msg.id);
+    Ok(())
+}
+
+/// Helper function to remove the recovery phrase once the user has saved it
+async fn handle_mnemonic_backup(bot: &Bot, msg: &Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let phrase_message = RECOVERY_PHRASE_MESSAGES.lock().await.remove(&msg.chat.id);
+    if let Some(message_id) = phrase_message {
+        if let Err(e) = bot.delete_message(msg.chat.id, message_id).await {
+            log::warn!("Failed to delete recovery phrase for chat_id={}: {}", msg.chat.id, e);
+        }
+    }
+    delete_all_messages(msg.chat.id, bot).await?;
+    set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingLoginPassword).await;
+    reply_to_password(
+        bot,
+        msg,
+        "Recovery phrase removed from the chat. 🔒\nNow enter your password again to log in.".to_string(),
+        false,
+    )
+    .await
+}
+
+/// Helper function to take the private key or recovery phrase to import
+///
+/// The message holding it is deleted before anything else; the secret is held until the
+/// user sends the password for their new account.
+async fn handle_import_secret(
+    bot: &Bot,
+    msg: &Message,
+    secret: SecretPhrase,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    CHAT_MESSAGE_IDS.lock().await.entry(msg.chat.id).or_default().push(msg.id);
+    delete_all_messages(msg.chat.id, bot).await?;
+    {
+        let mut pending = log_in_state::PENDING_IMPORTS.lock().await;
+        pending.insert(msg.chat.id.0, secret);
+    }
+    set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingImportPassword).await;
+
+    let message = bot
+        .send_message(msg.chat.id, "🗑 Your key or phrase was removed from the chat.\nNow choose your password:")
+        .await?;
+    store_message_id(msg.chat.id, message.id).await;
+    Ok(())
+}
+
+/// Helper function to create an account around the wallet being imported
+///
+/// Whatever the outcome, the held secret is dropped; the user starts over with the
+/// Import Wallet button.
+async fn handle_import_password(
+    bot: &Bot,
+    msg: &Message,
+    password: &SecretPassword,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let secret = log_in_state::PENDING_IMPORTS.lock().await.remove(&msg.chat.id.0);
+    set_state(msg.chat.id, log_in_state::AwaitingState::None).await;
+    let Some(secret) = secret else {
+        return reply_to_password(bot, msg, "❌ Nothing to import, please start over.".to_string(), false).await;
+    };
+
+    let handler = PasswordHandler::new(config_store, enclave_client)?;
+    let user_id = msg.chat.id.0.to_string();
+
+    let imported = handler.import_wallet(&user_id, password, &secret).await;
+    // As after sign-up, the user logs in again with their new password
+    handler.logout().await;
+    let reply = match imported {
+        Ok(()) => {
+            log::info!("User {} imported a wallet", msg.chat.id.0);
+            set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingLoginPassword).await;
+            "Wallet imported successfully! 🎉\nNow enter your password again to log in.".to_string()
+        }
+        Err(e) => {
+            log::error!("Failed to import wallet for user {}: {}", msg.chat.id.0, e);
+            failure_message("Failed to import wallet", e.as_ref())
+        }
+    };
+    reply_to_password(bot, msg, reply, false).await
+}
+
+/// Helper function to log in with the password the user just sent
+async fn handle_login_password(
+    bot: &Bot,
+    msg: &Message,
+    password: &SecretPassword,
+    config_store: Arc<dyn ConfigStore>,
+    enclave_client: Arc<EnclaveClient>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let handler = PasswordHandler::new(config_store, enclave_client)?;
+    let user_id = msg.chat.id.0.to_string();
+
+    match handler.login(&user_id, password).await {
+        Ok(true) => {
+            let previous = PASSWORD_HANDLERS.lock().await.insert(msg.chat.id.0, Some(Arc::new(handler)));
+            // Logging in again replaces the session, so the old one is locked
+            if let Some(Some(previous)) = previous {
+                previous.logout().await;
+            }
+            set_state(msg.chat.id, log_in_state::AwaitingState::None).await;
+            if let Err(e) = bot
+                .set_my_commands(CommandLoggedIn::bot_commands())
+                .scope(BotCommandScope::Chat {
+                    chat_id: msg.chat.id.into(),
+                })
+                .await
+            {
+                log::warn!("Failed to set commands for chat_id={}: {}", msg.chat.id, e);
+            }
+            log::info!("User {} logged in successfully", msg.chat.id.0);
+            reply_to_password(bot, msg, "Logged in successfully! 🎉".to_string(), true).await
+        }
+        Ok(false) => {
+            log::warn!("User {} failed to log in", msg.chat.id.0);
+            reply_to_password(bot, msg, "Invalid password! ❌".to_string(), false).await
+        }
+        Err(e) => {
+            log::error!("Failed to log in user {}: {}", msg.chat.id.0, e);
+            reply_to_password(bot, msg, failure_message("Login failed", e.as_ref()), false).await
+        }
+    }
+}
+
+/// Helper function to start the change-password conversation for a logged-in user
+async fn handle_change_password_command(bot: &Bot, msg: &Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !is_user_logged_in(msg.chat.id).await {
+        return reply_to_password(bot, msg, "❌ You are not logged in!".to_string(), false).await;
+    }
+    set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingCurrentPassword).await;
+    reply_to_password(bot, msg, "Enter your current password:".to_string(), true).await
+}
+
+/// Helper function to hold the current password until the new one is sent
+async fn handle_current_password(
+    bot: &Bot,
+    msg: &Message,
+    password: SecretPassword,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    {
+        let mut pending = log_in_state::PENDING_PASSWORD_CHANGES.lock().await;
+        pending.insert(msg.chat.id.0, password);
+    }
+    set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingNewPassword).await;
+    reply_to_password(bot, msg, "Enter your new password:".to_string(), true).await
+}
+
+/// Helper function to re-wrap the user's wallet under the new password they just sent
+///
+/// Whatever the outcome, the conversation ends and the held current password is dropped;
+/// the user starts over with `/changepassword`.
+async fn handle_new_password(
+    bot: &Bot,
+    msg: &Message,
+    new_password: &SecretPassword,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current_password = log_in_state::PENDING_PASSWORD_CHANGES.lock().await.remove(&msg.chat.id.0);
+    set_state(msg.chat.id, log_in_state::AwaitingState::None).await;
+    let (Some(current_password), Some(handler)) = (current_password, logged_in_handler(msg.chat.id).await) else {
+        return reply_to_password(bot, msg, "❌ You are not logged in!".to_string(), false).await;
+    };
+
+    // The logged-in handler reopens its session under the new password, so the wallet's
+    // MAC keeps matching the stored record when the user later switches accounts
+    let user_id = msg.chat.id.0.to_string();
+
+    let reply = match handler.change_password(&user_id, &current_password, new_password).await {
+        Ok(true) => {
+            log::info!("User {} changed their password", msg.chat.id.0);
+            "Password changed successfully! 🔐".to_string()
+        }
+        Ok(false) => {
+            log::warn!("User {} failed to change their password", msg.chat.id.0);
+            "Current password is incorrect! ❌".to_string()
+        }
+        Err(e) => {
+            log::error!("Failed to change password for user {}: {}", msg.chat.id.0, e);
+            failure_message("Failed to change password", e.as_ref())
+        }
+    };
+    reply_to_password(bot, msg, reply, true).await
+}
+
+/// Helper function to switch the account a logged-in user signs with, e.g. `/account 1`
+///
+/// `index` is `None` when the command's argument isn't an account number.
+async fn handle_account_command(
+    bot: &Bot,
+    msg: &Message,
+    index: Option<u32>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(index) = index else {
+        return reply_to_password(bot, msg, "Usage: /account <number>, e.g. /account 1".to_string(), true).await;
+    };
+    let user_id = msg.chat.id.0.to_string();
+    let result = match logged_in_handler(msg.chat.id).await {
+        Some(handler) => Some(handler.select_account(&user_id, index).await),
+        None => None,
+    };
+
+    let reply = match result {
+        Some(Ok(address)) => {
+            log::info!("User {} switched to account {}", msg.chat.id.0, index);
+            format!("🔑 Switched to account {}\nAddress: {}", index, address)
+        }
+        Some(Err(e)) => {
+            log::error!("Failed to switch account for user {}: {}", msg.chat.id.0, e);
+            failure_message("Failed to switch account", e.as_ref())
+        }
+        None => return reply_to_password(bot, msg, "❌ You are not logged in!".to_string(), false).await,
+    };
+    reply_to_password(bot, msg, reply, true).await
+}
+
+/// Helper function to send a logged-in user everything stored about them as a JSON file
+async fn handle_export_data_command(
+    bot: &Bot,
+    msg: &Message,
+    config_store: Arc<dyn ConfigStore>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !is_user_logged_in(msg.chat.id).await {
+        return reply_to_password(bot, msg, "❌ You are not logged in!".to_string(), false).await;
+    }
+    let export = match config_store.export_user(&msg.chat.id.0.to_string()).await {
+        Ok(export) => export,
+        Err(e) => {
+            log::error!("Failed to export data for user {}: {}", msg.chat.id.0, e);
+            return reply_to_password(bot, msg, format!("Failed to export data: {}", e), true).await;
+        }
+    };
+
+    let document = InputFile::memory(serde_json::to_vec_pretty(&export)?).file_name(EXPORT_FILE_NAME);
+    let message = bot
+        .send_document(msg.chat.id, document)
+        .caption("📦 Everything stored about you. Save it now, it is removed from the chat with your other messages.")
+        .reply_markup(logged_in_operations())
+        .await?;
+    log::info!("User {} exported their data", msg.chat.id.0);
+    let mut chat_message_ids = CHAT_MESSAGE_IDS.lock().await;
+    chat_message_ids.entry(msg.chat.id).or_default().extend([msg.id, message.id]);
+    Ok(())
+}
+
+/// Helper function to start the account deletion conversation for a logged-in user
+async fn handle_delete_account_command(bot: &Bot, msg: &Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !is_user_logged_in(msg.chat.id).await {
+        return reply_to_password(bot, msg, "❌ You are not logged in!".to_string(), false).await;
     }
+    set_state(msg.chat.id, log_in_state::AwaitingState::AwaitingDeleteConfirmation).await;
+    reply_to_password(
+        bot,
+        msg,
+        "⚠️ This deletes your account and everything stored about you. Your wallet can only be \
+         restored from its recovery phrase afterwards.\nEnter your password to confirm:"
+            .to_string(),
+        true,
+    )
+    .await
+}
+
+/// Helper function to delete the account once the user has confirmed with their password
+///
+/// Whatever the outcome, the conversation ends; the user starts over with `/deleteaccount`.
+async fn handle_delete_confirmation(
+    bot: &Bot,
+    msg: &Message,
+    password: &SecretPassword,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    set_state(msg.chat.id, log_in_state::AwaitingState::None).await;
+    let user_id = msg.chat.id.0.to_string();
+    let result = match logged_in_handler(msg.chat.id).await {
+        Some(handler) => Some(handler.delete_account(&user_id, password).await),
+        None => None,
+    };
+
+    let reply = match result {
+        Some(Ok(true)) => {
+            log::info!("User {} deleted their account", msg.chat.id.0);
+            return finish_account_deletion(bot, msg).await;
+        }
+        Some(Ok(false)) => {
+            log::warn!("User {} failed to confirm deleting their account", msg.chat.id.0);
+            "Password is incorrect, your account was not deleted! ❌".to_string()
+        }
+        Some(Err(e)) => {
+            log::error!("Failed to delete account for user {}: {}", msg.chat.id.0, e);
+            failure_message("Failed to delete account", e.as_ref())
+        }
+        None => return reply_to_password(bot, msg, "❌ You are not logged in!".to_string(), false).await,
+    };
+    reply_to_password(bot, msg, reply, true).await
+}
+
+/// Helper function to clear the chat and every trace of a deleted account kept in memory
+///
+/// The confirmation isn't tracked, so nothing about the user is left behind.
+async fn finish_account_deletion(bot: &Bot, msg: &Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+    purge_user_state(msg.chat.id).await;
+    CHAT_MESSAGE_IDS.lock().await.entry(msg.chat.id).or_default().push(msg.id);
+    delete_all_messages(msg.chat.id, bot).await?;
+    // Failures are logged there, and the account is gone either way
+    update_bot_commands(bot, msg.chat.id).await.ok();
+
+    bot.send_message(msg.chat.id, "🗑 Your account and all its data have been deleted.")
+        .reply_markup(logged_out_operations())
+        .await?;
     Ok(())
 }
 
+/// Helper function to drop everything held in memory for a chat
+async fn purge_user_state(chat_id: ChatId) {
+    log_in_state::USER_STATES.lock().await.remove(&chat_id.0);
+    log_in_state::PENDING_PASSWORD_CHANGES.lock().await.remove(&chat_id.0);
+    log_in_state::PENDING_IMPORTS.lock().await.remove(&chat_id.0);
+    RECOVERY_PHRASE_MESSAGES.lock().await.remove(&chat_id);
+    let handler = PASSWORD_HANDLERS.lock().await.remove(&chat_id.0);
+    if let Some(Some(handler)) = handler {
+        handler.logout().await;
+    }
+    log::info!("User state purged for chat_id={}", chat_id);
+}
+
 /// Helper function to handle logout command
 async fn handle_logout_command(bot: Bot, msg: Message) -> Result<(), Box<dyn Error + Send + Sync>> {
     log::info!(
@@ -298,9 +759,116 @@ async fn handle_logout_command(bot: Bot, msg: Message) -> Result<(), Box<dyn Err
             .await?;
         
         let mut chat_message_ids = CHAT_MESSAGE_IDS.lock().await;
-        chat_message_ids.insert(msg.chat.id, vec![msg.id, message.id]);
+        chat_message_ids.entry(msg.chat.id).or_default().extend([msg.id, message.id]);
         return Ok(());
     }
     
     logout(msg.chat.id, &bot).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::memory_config_store::MemoryConfigStore;
+    use crate::test_util::{FakeTelegram, spawn_enclave};
+
+    /// A private chat with the bot, backed by a fake Bot API and an in-process enclave
+    struct TestChat {
+        id: ChatId,
+        telegram: FakeTelegram,
+        config_store: Arc<dyn ConfigStore>,
+        enclave_client: Arc<EnclaveClient>,
+    }
+
+    impl TestChat {
+        async fn new(id: i64) -> Self {
+            Self {
+                id: ChatId(id),
+                telegram: FakeTelegram::start().await,
+                config_store: Arc::new(MemoryConfigStore::default()),
+                enclave_client: spawn_enclave().await,
+            }
+        }
+
+        async fn send(&self, text: &str) {
+            let msg = self.telegram.message(self.id.0, text);
+            process_message(
+                self.telegram.bot.clone(),
+                msg,
+                self.telegram.me(),
+                Arc::clone(&self.config_store),
+                Arc::clone(&self.enclave_client),
+            )
+            .await
+            .unwrap();
+        }
+
+        fn last_reply(&self) -> String {
+            self.telegram.sent(self.id.0).pop().unwrap_or_default()
+        }
+
+        async fn sign_up_and_log_in(&self, password: &str) {
+            set_state(self.id, log_in_state::AwaitingState::AwaitingSignUpPassword).await;
+            self.send(password).await;
+            self.send("saved it").await;
+            self.send(password).await;
+            assert_eq!(self.last_reply(), "Logged in successfully! 🎉");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_account_selected_after_a_password_change_survives_login() {
+        let chat = TestChat::new(1001).await;
+        chat.sign_up_and_log_in("password").await;
+
+        chat.send("/changepassword").await;
+        chat.send("password").await;
+        chat.send("new_password").await;
+        assert_eq!(chat.last_reply(), "Password changed successfully! 🔐");
+        chat.send("/account 1").await;
+        let switched = chat.last_reply();
+        assert!(switched.starts_with("🔑 Switched to account 1"), "{}", switched);
+
+        // The selection was stored with a MAC under the new password's keys
+        chat.send("/logout").await;
+        set_state(chat.id, log_in_state::AwaitingState::AwaitingLoginPassword).await;
+        chat.send("new_password").await;
+        assert_eq!(chat.last_reply(), "Logged in successfully! 🎉");
+        let address = logged_in_handler(chat.id).await.unwrap().get_address().await.unwrap();
+        assert!(switched.ends_with(&address));
+    }
+
+    #[tokio::test]
+    async fn test_only_the_account_command_itself_switches_accounts() {
+        let chat = TestChat::new(1002).await;
+        chat.sign_up_and_log_in("password").await;
+        let replies = chat.telegram.sent(chat.id.0).len();
+
+        chat.send("/accounts 1").await;
+        chat.send("/accountfoo").await;
+        assert_eq!(chat.telegram.sent(chat.id.0).len(), replies);
+
+        chat.send("/account").await;
+        assert_eq!(chat.last_reply(), "Usage: /account <number>, e.g. /account 1");
+        chat.send("/account 1").await;
+        assert!(chat.last_reply().starts_with("🔑 Switched to account 1"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_account_needs_the_password() {
+        let chat = TestChat::new(1003).await;
+        chat.sign_up_and_log_in("password").await;
+        let user_id = chat.id.0.to_string();
+
+        chat.send("/deleteaccount").await;
+        chat.send("wrong").await;
+        assert_eq!(chat.last_reply(), "Password is incorrect, your account was not deleted! ❌");
+        assert!(chat.config_store.config_exists(&user_id).await.unwrap());
+
+        chat.send("/deleteaccount").await;
+        chat.send("password").await;
+        assert_eq!(chat.last_reply(), "🗑 Your account and all its data have been deleted.");
+        assert!(!chat.config_store.config_exists(&user_id).await.unwrap());
+        assert!(logged_in_handler(chat.id).await.is_none());
+    }
+}
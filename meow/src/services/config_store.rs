@@ -0,0 +1,385 @@
+//! Storage for users' wallet configs and failed logins, behind a backend-agnostic trait
+//!
+//! SQLite suits a single bot; PostgreSQL lets several bot replicas share one database.
+//! The in-memory store keeps unit tests free of files and servers.
+
+use super::master_key::{MasterKeyError, MasterKeys};
+use super::memory_config_store::MemoryConfigStore;
+use super::postgres_config_store::PostgresConfigStore;
+use super::user_config_store::UserConfigStore;
+use async_trait::async_trait;
+use nine_sdk::LockoutPolicy;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigStoreError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("SQLite connection pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+    #[error("Database worker failed: {0}")]
+    Worker(#[from] tokio::task::JoinError),
+    #[error("PostgreSQL error: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("Master key error: {0}")]
+    MasterKey(#[from] MasterKeyError),
+    #[error("Serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("User config not found")]
+    NotFound,
+    #[error("Database schema version {found} is newer than this build supports ({supported})")]
+    UnsupportedSchema { found: u32, supported: u32 },
+}
+
+/// A user's stored record, with the fields mirrored out of its JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserConfig {
+    pub user_id: String,
+    pub config_json: String, // Serialized UserWalletConfig
+    /// Wallet address, if the config has one
+    pub address: Option<String>,
+    /// `schema_version` of the stored config, 0 for configs from before it was recorded
+    pub schema_version: u32,
+    /// Unix seconds; records from before timestamps were tracked show when they were migrated
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// Where users' configs and failed logins are kept
+///
+/// Every method is a single atomic step, so replicas sharing a backend can't interleave
+/// inside one.
+#[async_trait]
+pub trait ConfigStore: Send + Sync {
+    /// Stores or updates a user's configuration
+    async fn insert_or_update_config(&self, user_id: &str, config_json: &str) -> Result<(), ConfigStoreError>;
+
+    /// Stores a new user's configuration
+    ///
+    /// Returns `false`, leaving the record untouched, if the user already has one.
+    async fn insert_config(&self, user_id: &str, config_json: &str) -> Result<bool, ConfigStoreError>;
+
+    /// Replaces a user's configuration only if it is still `expected_json`
+    ///
+    /// Returns `false`, leaving the record untouched, if it was changed or removed since
+    /// `expected_json` was read.
+    async fn replace_config(
+        &self,
+        user_id: &str,
+        expected_json: &str,
+        config_json: &str,
+    ) -> Result<bool, ConfigStoreError>;
+
+    /// Retrieves a user's whole record, including its address and timestamps
+    async fn get_user_config(&self, user_id: &str) -> Result<UserConfig, ConfigStoreError>;
+
+    /// Retrieves a user's configuration
+    async fn get_config(&self, user_id: &str) -> Result<String, ConfigStoreError> {
+        Ok(self.get_user_config(user_id).await?.config_json)
+    }
+
+    /// Checks if a user configuration exists
+    async fn config_exists(&self, user_id: &str) -> Result<bool, ConfigStoreError> {
+        match self.get_config(user_id).await {
+            Ok(_) => Ok(true),
+            Err(ConfigStoreError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Charges a password attempt before it is checked, unless the user is locked out
+    ///
+    /// The attempt counts as a failure until [`clear_failed_logins`](Self::clear_failed_logins)
+    /// is called for a right password, so guesses sent in parallel can't all get past the
+    /// lockout before the first failure is recorded. Earlier failures are forgotten once
+    /// `policy.reset_after` has passed since the last one.
+    async fn begin_login_attempt(&self, user_id: &str, policy: &LockoutPolicy) -> Result<LoginAttempt, ConfigStoreError>;
+
+    /// Forgets the user's failed attempts after a successful login
+    async fn clear_failed_logins(&self, user_id: &str) -> Result<(), ConfigStoreError>;
+
+    /// Failed password attempts currently recorded for the user, if any
+    async fn failed_logins(&self, user_id: &str) -> Result<Option<LoginAttempts>, ConfigStoreError>;
+
+    /// Removes everything stored about the user, returning whether they had a config
+    ///
+    /// Anything new that is kept per user must be removed here too, in the same transaction,
+    /// and added to [`UserExport`].
+    async fn delete_user(&self, user_id: &str) -> Result<bool, ConfigStoreError>;
+
+    /// Everything stored about the user, for `/exportdata`
+    async fn export_user(&self, user_id: &str) -> Result<UserExport, ConfigStoreError> {
+        let config = match self.get_user_config(user_id).await {
+            Ok(config) => Some(config),
+            Err(ConfigStoreError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(UserExport {
+            user_id: user_id.to_string(),
+            config,
+            failed_logins: self.failed_logins(user_id).await?,
+        })
+    }
+}
+
+/// Everything a store keeps about one user
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    pub user_id: String,
+    pub config: Option<UserConfig>,
+    pub failed_logins: Option<LoginAttempts>,
+}
+
+/// Opens the store `database_url` names: a `postgres://` URL, `memory:`, or a SQLite file path
+///
+/// Only the SQLite store encrypts configs, so `master_keys` are refused for the others
+/// rather than silently going unused.
+pub async fn open_config_store(
+    database_url: &str,
+    master_keys: Option<MasterKeys>,
+) -> Result<Arc<dyn ConfigStore>, ConfigStoreError> {
+    if master_keys.is_some() && !is_sqlite_path(database_url) {
+        return Err(MasterKeyError::Unsupported.into());
+    }
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        log::info!("Using PostgreSQL config store");
+        Ok(Arc::new(PostgresConfigStore::connect(database_url).await?))
+    } else if database_url == "memory:" {
+        log::warn!("Using in-memory config store, accounts will not survive a restart");
+        Ok(Arc::new(MemoryConfigStore::default()))
+    } else {
+        log::info!("Using SQLite config store at {}", database_url);
+        let store = UserConfigStore::new(database_url)?;
+        Ok(Arc::new(match master_keys {
+            Some(master_keys) => store.with_master_keys(master_keys),
+            None => store,
+        }))
+    }
+}
+
+/// Whether `database_url` names a SQLite file rather than another backend
+pub fn is_sqlite_path(database_url: &str) -> bool {
+    !(database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") || database_url == "memory:")
+}
+
+/// Fields of a stored config that are kept in columns of their own
+///
+/// Configs the store doesn't recognise are still stored, just without them.
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct ConfigColumns {
+    #[serde(default, rename = "ethereum_address")]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub schema_version: u32,
+}
+
+impl ConfigColumns {
+    pub fn parse(config_json: &str) -> Self {
+        serde_json::from_str(config_json).unwrap_or_default()
+    }
+}
+
+/// Outcome of [`ConfigStore::begin_login_attempt`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginAttempt {
+    /// The attempt may go ahead; if the password is wrong, the user is locked out for
+    /// `retry_after`
+    Charged { retry_after: Duration },
+    /// The user is locked out for `retry_after`, and nothing was charged
+    LockedOut { retry_after: Duration },
+}
+
+impl LoginAttempt {
+    /// Outcome of charging an attempt at `now` against the user's `previous` attempts,
+    /// and the attempts to store if it was charged
+    pub fn charge(previous: Option<LoginAttempts>, now: u64, policy: &LockoutPolicy) -> (Self, Option<LoginAttempts>) {
+        if let Some(retry_after) = previous.and_then(|attempts| attempts.lockout(now)) {
+            return (Self::LockedOut { retry_after }, None);
+        }
+        let attempts = LoginAttempts::after_failure(previous, now, policy);
+        (Self::charged(&attempts, now), Some(attempts))
+    }
+
+    /// An attempt charged as `attempts` at `now`
+    pub fn charged(attempts: &LoginAttempts, now: u64) -> Self {
+        Self::Charged {
+            retry_after: attempts.lockout(now).unwrap_or_default(),
+        }
+    }
+}
+
+/// Failed password attempts recorded for a user, with times in Unix seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LoginAttempts {
+    pub failures: u32,
+    pub last_failure: u64,
+    pub locked_until: u64,
+}
+
+impl LoginAttempts {
+    /// Attempts after one more failure at `now`, starting over if the last one has expired
+    pub fn after_failure(previous: Option<Self>, now: u64, policy: &LockoutPolicy) -> Self {
+        let failures = match previous {
+            Some(attempts) if now.saturating_sub(attempts.last_failure) < policy.reset_after.as_secs() => {
+                attempts.failures
+            }
+            _ => 0,
+        };
+        let failures = failures.saturating_add(1);
+        Self {
+            failures,
+            last_failure: now,
+            locked_until: now + policy.delay_after(failures).as_secs(),
+        }
+    }
+
+    /// Time left at `now` before another attempt is allowed, if any
+    pub fn lockout(&self, now: u64) -> Option<Duration> {
+        (self.locked_until > now).then(|| Duration::from_secs(self.locked_until - now))
+    }
+}
+
+pub(super) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Behaviour every backend must share, run against each one by its own tests
+#[cfg(test)]
+pub(super) mod conformance {
+    use super::*;
+
+    const CONFIG_JSON: &str = r#"{"schema_version":1,"ethereum_address":"0x1111111111111111111111111111111111111111"}"#;
+    const UPDATED_CONFIG_JSON: &str = r#"{"schema_version":1,"ethereum_address":"0x2222222222222222222222222222222222222222"}"#;
+
+    pub async fn check_configs(store: &dyn ConfigStore) {
+        assert!(matches!(store.get_config("alice").await, Err(ConfigStoreError::NotFound)));
+        assert!(!store.config_exists("alice").await.unwrap());
+
+        store.insert_or_update_config("alice", CONFIG_JSON).await.unwrap();
+        assert_eq!(store.get_config("alice").await.unwrap(), CONFIG_JSON);
+        assert!(store.config_exists("alice").await.unwrap());
+        let inserted = store.get_user_config("alice").await.unwrap();
+
+        // An existing record is never overwritten by a new one
+        assert!(!store.insert_config("alice", UPDATED_CONFIG_JSON).await.unwrap());
+        assert_eq!(store.get_config("alice").await.unwrap(), CONFIG_JSON);
+        assert_eq!(inserted.address.as_deref(), Some("0x1111111111111111111111111111111111111111"));
+        assert_eq!(inserted.schema_version, 1);
+        assert_eq!(inserted.created_at, inserted.updated_at);
+
+        // Only a record that is still as it was read gets replaced
+        assert!(!store.replace_config("alice", UPDATED_CONFIG_JSON, "{}").await.unwrap());
+        assert!(!store.replace_config("bob", CONFIG_JSON, "{}").await.unwrap());
+        assert!(store.replace_config("alice", CONFIG_JSON, UPDATED_CONFIG_JSON).await.unwrap());
+        let replaced = store.get_user_config("alice").await.unwrap();
+        assert_eq!(replaced.config_json, UPDATED_CONFIG_JSON);
+        assert_eq!(replaced.address.as_deref(), Some("0x2222222222222222222222222222222222222222"));
+        assert_eq!(replaced.created_at, inserted.created_at);
+
+        store.insert_or_update_config("alice", "not json").await.unwrap();
+        let unparsed = store.get_user_config("alice").await.unwrap();
+        assert_eq!((unparsed.address, unparsed.schema_version), (None, 0));
+        assert!(!store.config_exists("bob").await.unwrap());
+        assert!(store.insert_config("bob", UPDATED_CONFIG_JSON).await.unwrap());
+        assert_eq!(store.get_config("bob").await.unwrap(), UPDATED_CONFIG_JSON);
+    }
+
+    pub async fn check_failed_logins(store: &dyn ConfigStore) {
+        let policy = LockoutPolicy {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(60),
+            ..LockoutPolicy::default()
+        };
+        let charged = |secs| LoginAttempt::Charged {
+            retry_after: Duration::from_secs(secs),
+        };
+        assert_eq!(store.begin_login_attempt("alice", &policy).await.unwrap(), charged(0));
+        assert_eq!(store.begin_login_attempt("alice", &policy).await.unwrap(), charged(60));
+        match store.begin_login_attempt("alice", &policy).await.unwrap() {
+            LoginAttempt::LockedOut { retry_after } => {
+                assert!(retry_after > Duration::from_secs(58) && retry_after <= Duration::from_secs(60));
+            }
+            other => panic!("charged while locked out: {:?}", other),
+        }
+        // Attempts refused during a lockout aren't counted
+        assert_eq!(store.failed_logins("alice").await.unwrap().unwrap().failures, 2);
+        assert_eq!(store.begin_login_attempt("bob", &policy).await.unwrap(), charged(0));
+
+        store.clear_failed_logins("alice").await.unwrap();
+        assert_eq!(store.failed_logins("alice").await.unwrap(), None);
+        assert_eq!(store.begin_login_attempt("alice", &policy).await.unwrap(), charged(0));
+
+        // Bob's earlier failure has expired under this policy, so he starts over
+        let forgetful = LockoutPolicy {
+            reset_after: Duration::ZERO,
+            ..policy
+        };
+        assert_eq!(store.begin_login_attempt("bob", &forgetful).await.unwrap(), charged(0));
+        assert_eq!(store.failed_logins("bob").await.unwrap().unwrap().failures, 1);
+    }
+
+    pub async fn check_delete_user(store: &dyn ConfigStore) {
+        store.insert_or_update_config("carol", CONFIG_JSON).await.unwrap();
+        store.insert_or_update_config("dave", UPDATED_CONFIG_JSON).await.unwrap();
+        store.begin_login_attempt("carol", &LockoutPolicy::default()).await.unwrap();
+        store.begin_login_attempt("dave", &LockoutPolicy::default()).await.unwrap();
+
+        let export = store.export_user("carol").await.unwrap();
+        assert_eq!(export.config.unwrap().config_json, CONFIG_JSON);
+        assert_eq!(export.failed_logins.unwrap().failures, 1);
+
+        assert!(store.delete_user("carol").await.unwrap());
+        assert!(!store.config_exists("carol").await.unwrap());
+        let export = store.export_user("carol").await.unwrap();
+        assert!(export.config.is_none() && export.failed_logins.is_none());
+        assert!(!store.delete_user("carol").await.unwrap());
+
+        // Other users are untouched
+        assert_eq!(store.get_config("dave").await.unwrap(), UPDATED_CONFIG_JSON);
+        assert!(store.failed_logins("dave").await.unwrap().is_some());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures_expire_after_the_reset_period() {
+        let policy = LockoutPolicy {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(60),
+            reset_after: Duration::from_secs(3600),
+            ..LockoutPolicy::default()
+        };
+        let first = LoginAttempts::after_failure(None, 1000, &policy);
+        assert_eq!(first, LoginAttempts { failures: 1, last_failure: 1000, locked_until: 1000 });
+        assert_eq!(first.lockout(1000), None);
+
+        let second = LoginAttempts::after_failure(Some(first), 1010, &policy);
+        assert_eq!(second.failures, 2);
+        assert_eq!(second.lockout(1030), Some(Duration::from_secs(40)));
+
+        let later = LoginAttempts::after_failure(Some(second), 1010 + 3600, &policy);
+        assert_eq!(later.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_open_config_store_picks_the_backend() {
+        let store = open_config_store("memory:", None).await.unwrap();
+        conformance::check_configs(store.as_ref()).await;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let store = open_config_store(db_path.to_str().unwrap(), None).await.unwrap();
+        store.insert_or_update_config("alice", "{}").await.unwrap();
+        assert!(db_path.exists());
+
+        assert!(open_config_store("postgres://nobody@127.0.0.1:1/none", None).await.is_err());
+    }
+}
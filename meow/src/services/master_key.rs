@@ -0,0 +1,159 @@
+//! Server master keys that stored configs are encrypted under
+//!
+//! Keys are named by an id stored next to each record, so records sealed under an older
+//! key can still be read while they are re-encrypted under the current one.
+
+use nine_sdk::{DerivedKey, EnclaveClient, KeyManagerError, SealedBlob, SealedBlobError, SecretBytes};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Hex-encoded length of a 256-bit key
+const KEY_HEX_LEN: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MasterKeyError {
+    #[error("Invalid master key entry: {0}")]
+    InvalidEntry(String),
+    #[error("No master keys given")]
+    Empty,
+    #[error("Config is encrypted under unknown master key {0:?}")]
+    UnknownKeyId(String),
+    #[error("Config is encrypted but no master key is configured")]
+    NotConfigured,
+    #[error("Only the SQLite config store encrypts configs")]
+    Unsupported,
+    #[error("Sealed config error: {0}")]
+    Sealed(#[from] SealedBlobError),
+    #[error("Failed to read master key file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to fetch master key from the enclave: {0}")]
+    Enclave(#[from] KeyManagerError),
+}
+
+/// The current master key, which new records are sealed under, and any older ones
+pub struct MasterKeys {
+    current: String,
+    keys: HashMap<String, DerivedKey>,
+}
+
+impl MasterKeys {
+    pub fn new(key_id: impl Into<String>, key: DerivedKey) -> Self {
+        let current = key_id.into();
+        let keys = HashMap::from([(current.clone(), key)]);
+        Self { current, keys }
+    }
+
+    /// Adds an older key that records may still be sealed under
+    pub fn with_previous(mut self, key_id: impl Into<String>, key: DerivedKey) -> Self {
+        self.keys.entry(key_id.into()).or_insert(key);
+        self
+    }
+
+    /// Parses `id:hexkey` entries separated by commas or newlines, current key first
+    pub fn parse(entries: &str) -> Result<Self, MasterKeyError> {
+        let mut parsed = entries
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+            .map(parse_entry);
+        let (current_id, current_key) = parsed.next().ok_or(MasterKeyError::Empty)??;
+        parsed.try_fold(Self::new(current_id, current_key), |keys, entry| {
+            let (key_id, key) = entry?;
+            Ok(keys.with_previous(key_id, key))
+        })
+    }
+
+    /// Reads entries in the format [`parse`](Self::parse) takes from `path`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, MasterKeyError> {
+        let entries = SecretBytes::new(std::fs::read(path)?);
+        let entries = std::str::from_utf8(&entries)
+            .map_err(|_| MasterKeyError::InvalidEntry("file is not UTF-8".to_string()))?;
+        Self::parse(entries)
+    }
+
+    /// Fetches the keys for `key_ids` from the enclave, current key first
+    pub async fn from_enclave(client: &EnclaveClient, key_ids: &[&str]) -> Result<Self, MasterKeyError> {
+        let (current_id, previous_ids) = key_ids.split_first().ok_or(MasterKeyError::Empty)?;
+        let mut keys = Self::new(*current_id, client.storage_key(current_id).await?);
+        for key_id in previous_ids {
+            keys = keys.with_previous(*key_id, client.storage_key(key_id).await?);
+        }
+        Ok(keys)
+    }
+
+    /// Id of the key new records are sealed under
+    pub fn current_id(&self) -> &str {
+        &self.current
+    }
+
+    /// Encrypts `config_json` under the current key, bound to `user_id`, as hex
+    pub fn seal(&self, user_id: &str, config_json: &str) -> Result<String, MasterKeyError> {
+        let blob = SealedBlob::seal(self.keys[&self.current].expose(), config_json.as_bytes(), user_id.as_bytes())?;
+        Ok(hex::encode(blob.to_bytes()))
+    }
+
+    /// Decrypts a config sealed by [`seal`](Self::seal) under `key_id` for `user_id`
+    pub fn open(&self, key_id: &str, user_id: &str, sealed: &str) -> Result<String, MasterKeyError> {
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| MasterKeyError::UnknownKeyId(key_id.to_string()))?;
+        let bytes = hex::decode(sealed).map_err(|_| SealedBlobError::Malformed)?;
+        let plaintext = SealedBlob::from_bytes(&bytes)?.open(key.expose(), user_id.as_bytes())?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| MasterKeyError::Sealed(SealedBlobError::Malformed))
+    }
+}
+
+fn parse_entry(entry: &str) -> Result<(String, DerivedKey), MasterKeyError> {
+    let Some((key_id, key_hex)) = entry.split_once(':') else {
+        return Err(MasterKeyError::InvalidEntry("expected id:hexkey".to_string()));
+    };
+    let invalid = || MasterKeyError::InvalidEntry(format!("key {:?}", key_id));
+    if key_id.is_empty() || key_hex.len() != KEY_HEX_LEN {
+        return Err(invalid());
+    }
+    let key = SecretBytes::new(hex::decode(key_hex).map_err(|_| invalid())?);
+    let key = <[u8; 32]>::try_from(key.as_slice()).map_err(|_| invalid())?;
+    Ok((key_id.to_string(), DerivedKey::from(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const KEY_2: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+
+    #[test]
+    fn test_parse_takes_the_first_key_as_current() {
+        let keys = MasterKeys::parse(&format!("# rotated 2026-10\nk2:{}\nk1:{}\n", KEY_2, KEY_1)).unwrap();
+        assert_eq!(keys.current_id(), "k2");
+
+        let old = MasterKeys::parse(&format!("k1:{}", KEY_1)).unwrap();
+        let sealed = old.seal("alice", "{}").unwrap();
+        assert_eq!(keys.open("k1", "alice", &sealed).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_invalid_entries_are_refused_without_echoing_keys() {
+        assert!(matches!(MasterKeys::parse(" \n"), Err(MasterKeyError::Empty)));
+        for entries in [KEY_1.to_string(), format!(":{}", KEY_1), "k1:abcd".to_string(), format!("k1:{}", "zz".repeat(32))] {
+            let err = MasterKeys::parse(&entries).err().unwrap();
+            assert!(matches!(err, MasterKeyError::InvalidEntry(_)), "{}", entries);
+            assert!(!err.to_string().contains("1111"));
+        }
+    }
+
+    #[test]
+    fn test_sealed_configs_open_only_for_their_owner_and_key() {
+        let keys = MasterKeys::parse(&format!("k1:{}", KEY_1)).unwrap();
+        let sealed = keys.seal("alice", r#"{"a":1}"#).unwrap();
+        assert!(!sealed.contains("\"a\""));
+        assert_eq!(keys.open("k1", "alice", &sealed).unwrap(), r#"{"a":1}"#);
+
+        assert!(matches!(keys.open("k1", "bob", &sealed), Err(MasterKeyError::Sealed(SealedBlobError::ContextMismatch))));
+        assert!(matches!(keys.open("k0", "alice", &sealed), Err(MasterKeyError::UnknownKeyId(_))));
+        let other = MasterKeys::parse(&format!("k1:{}", KEY_2)).unwrap();
+        assert!(matches!(other.open("k1", "alice", &sealed), Err(MasterKeyError::Sealed(SealedBlobError::Decryption))));
+    }
+}
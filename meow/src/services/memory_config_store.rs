@@ -0,0 +1,117 @@
+use super::config_store::{ConfigColumns, ConfigStore, ConfigStoreError, LoginAttempt, LoginAttempts, UserConfig, unix_now};
+use async_trait::async_trait;
+use nine_sdk::LockoutPolicy;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+
+/// Config store that lives only as long as the process, for tests and throwaway bots
+#[derive(Default)]
+pub struct MemoryConfigStore {
+    configs: Mutex<HashMap<String, UserConfig>>,
+    login_attempts: Mutex<HashMap<String, LoginAttempts>>,
+}
+
+#[async_trait]
+impl ConfigStore for MemoryConfigStore {
+    async fn insert_or_update_config(&self, user_id: &str, config_json: &str) -> Result<(), ConfigStoreError> {
+        let columns = ConfigColumns::parse(config_json);
+        let now = unix_now();
+        let mut configs = self.configs.lock().unwrap();
+        let created_at = configs.get(user_id).map_or(now, |config| config.created_at);
+        configs.insert(
+            user_id.to_string(),
+            UserConfig {
+                user_id: user_id.to_string(),
+                config_json: config_json.to_string(),
+                address: columns.address,
+                schema_version: columns.schema_version,
+                created_at,
+                updated_at: now,
+            },
+        );
+        Ok(())
+    }
+
+    async fn insert_config(&self, user_id: &str, config_json: &str) -> Result<bool, ConfigStoreError> {
+        let columns = ConfigColumns::parse(config_json);
+        let now = unix_now();
+        let mut configs = self.configs.lock().unwrap();
+        let Entry::Vacant(entry) = configs.entry(user_id.to_string()) else {
+            return Ok(false);
+        };
+        entry.insert(UserConfig {
+            user_id: user_id.to_string(),
+            config_json: config_json.to_string(),
+            address: columns.address,
+            schema_version: columns.schema_version,
+            created_at: now,
+            updated_at: now,
+        });
+        Ok(true)
+    }
+
+    async fn replace_config(
+        &self,
+        user_id: &str,
+        expected_json: &str,
+        config_json: &str,
+    ) -> Result<bool, ConfigStoreError> {
+        let mut configs = self.configs.lock().unwrap();
+        let Some(config) = configs.get_mut(user_id).filter(|config| config.config_json == expected_json) else {
+            return Ok(false);
+        };
+        let columns = ConfigColumns::parse(config_json);
+        config.config_json = config_json.to_string();
+        config.address = columns.address;
+        config.schema_version = columns.schema_version;
+        config.updated_at = unix_now();
+        Ok(true)
+    }
+
+    async fn get_user_config(&self, user_id: &str) -> Result<UserConfig, ConfigStoreError> {
+        self.configs
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .cloned()
+            .ok_or(ConfigStoreError::NotFound)
+    }
+
+    async fn begin_login_attempt(&self, user_id: &str, policy: &LockoutPolicy) -> Result<LoginAttempt, ConfigStoreError> {
+        let mut attempts = self.login_attempts.lock().unwrap();
+        let (attempt, charged) = LoginAttempt::charge(attempts.get(user_id).copied(), unix_now(), policy);
+        if let Some(charged) = charged {
+            attempts.insert(user_id.to_string(), charged);
+        }
+        Ok(attempt)
+    }
+
+    async fn clear_failed_logins(&self, user_id: &str) -> Result<(), ConfigStoreError> {
+        self.login_attempts.lock().unwrap().remove(user_id);
+        Ok(())
+    }
+
+    async fn failed_logins(&self, user_id: &str) -> Result<Option<LoginAttempts>, ConfigStoreError> {
+        Ok(self.login_attempts.lock().unwrap().get(user_id).copied())
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<bool, ConfigStoreError> {
+        let mut configs = self.configs.lock().unwrap();
+        self.login_attempts.lock().unwrap().remove(user_id);
+        Ok(configs.remove(user_id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::config_store::conformance;
+
+    #[tokio::test]
+    async fn test_memory_store_conformance() {
+        conformance::check_configs(&MemoryConfigStore::default()).await;
+        conformance::check_failed_logins(&MemoryConfigStore::default()).await;
+        conformance::check_delete_user(&MemoryConfigStore::default()).await;
+    }
+}
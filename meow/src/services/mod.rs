@@ -1,2 +1,6 @@
 // Service layer for external integrations and business logic
+pub mod config_store;
+pub mod master_key;
+pub mod memory_config_store;
+pub mod postgres_config_store;
 pub mod user_config_store;
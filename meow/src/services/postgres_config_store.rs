@@ -0,0 +1,395 @@
+use super::config_store::{ConfigColumns, ConfigStore, ConfigStoreError, LoginAttempt, LoginAttempts, UserConfig, unix_now};
+use async_trait::async_trait;
+use nine_sdk::LockoutPolicy;
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, Config, NoTls, Row};
+
+/// Schema migrations in order; `config_store_schema` records how many have been applied
+///
+/// As with the SQLite store, released migrations must never change.
+const MIGRATIONS: &[&str] = &["CREATE TABLE user_configs (
+        user_id TEXT PRIMARY KEY,
+        config_json TEXT NOT NULL,
+        address TEXT,
+        schema_version BIGINT NOT NULL,
+        created_at BIGINT NOT NULL,
+        updated_at BIGINT NOT NULL
+    );
+    CREATE TABLE login_attempts (
+        user_id TEXT PRIMARY KEY,
+        failures BIGINT NOT NULL,
+        last_failure BIGINT NOT NULL,
+        locked_until BIGINT NOT NULL
+    );"];
+
+/// Schema version of a database with every migration applied
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Serialises migrations between replicas starting at the same time
+const MIGRATION_LOCK_ID: i64 = 0x6d65_6f77_636f_6e66;
+
+const CREATE_SCHEMA_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS config_store_schema (version INTEGER NOT NULL)";
+const SELECT_SCHEMA_VERSION_SQL: &str = "SELECT version FROM config_store_schema";
+const INSERT_OR_UPDATE_SQL: &str = "INSERT INTO user_configs
+    (user_id, config_json, address, schema_version, created_at, updated_at)
+    VALUES ($1, $2, $3, $4, $5, $5)
+    ON CONFLICT(user_id) DO UPDATE SET config_json=excluded.config_json, address=excluded.address,
+        schema_version=excluded.schema_version, updated_at=excluded.updated_at";
+const INSERT_SQL: &str = "INSERT INTO user_configs
+    (user_id, config_json, address, schema_version, created_at, updated_at)
+    VALUES ($1, $2, $3, $4, $5, $5)
+    ON CONFLICT(user_id) DO NOTHING";
+const REPLACE_IF_UNCHANGED_SQL: &str = "UPDATE user_configs
+    SET config_json = $3, address = $4, schema_version = $5, updated_at = $6
+    WHERE user_id = $1 AND config_json = $2";
+const CONFIG_EXISTS_SQL: &str = "SELECT EXISTS(SELECT 1 FROM user_configs WHERE user_id = $1)";
+const SELECT_USER_CONFIG_SQL: &str = "SELECT user_id, config_json, address, schema_version, created_at, updated_at
+    FROM user_configs WHERE user_id = $1";
+const SELECT_LOGIN_ATTEMPTS_SQL: &str =
+    "SELECT failures, last_failure, locked_until FROM login_attempts WHERE user_id = $1";
+/// Charges a failure at `$2` unless the user is locked out, following `LoginAttempts::after_failure`
+/// for a policy of `$3` seconds to reset, `$4` free attempts and `$5`/`$6` seconds of base and
+/// maximum delay; `$7` is the delay after a first failure. Returns nothing while locked out.
+const CHARGE_LOGIN_ATTEMPT_SQL: &str = "INSERT INTO login_attempts AS a (user_id, failures, last_failure, locked_until)
+    VALUES ($1, 1, $2::BIGINT, $2::BIGINT + $7::BIGINT)
+    ON CONFLICT(user_id) DO UPDATE SET
+        failures = CASE WHEN $2 - a.last_failure < $3::BIGINT THEN a.failures + 1 ELSE 1 END,
+        last_failure = $2,
+        locked_until = $2 + CASE
+            WHEN (CASE WHEN $2 - a.last_failure < $3 THEN a.failures + 1 ELSE 1 END) <= $4::BIGINT THEN 0
+            ELSE LEAST(
+                $5::BIGINT << LEAST((CASE WHEN $2 - a.last_failure < $3 THEN a.failures + 1 ELSE 1 END) - $4 - 1, 31)::INTEGER,
+                $6::BIGINT
+            )
+        END
+    WHERE a.locked_until <= $2
+    RETURNING failures, last_failure, locked_until";
+const DELETE_LOGIN_ATTEMPTS_SQL: &str = "DELETE FROM login_attempts WHERE user_id = $1";
+const DELETE_CONFIG_SQL: &str = "DELETE FROM user_configs WHERE user_id = $1";
+
+/// Config store in a PostgreSQL database, which several bot replicas can share
+///
+/// Connections are made without TLS, so the database should be reached over a private
+/// network or a local socket.
+pub struct PostgresConfigStore {
+    client: Mutex<Client>,
+}
+
+impl PostgresConfigStore {
+    /// Connects to the database at `url` and brings its schema up to date
+    pub async fn connect(url: &str) -> Result<Self, ConfigStoreError> {
+        Self::connect_with(url.parse()?).await
+    }
+
+    pub async fn connect_with(config: Config) -> Result<Self, ConfigStoreError> {
+        let (mut client, connection) = config.connect(NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::error!("PostgreSQL connection closed: {}", e);
+            }
+        });
+        migrate(&mut client).await?;
+        Ok(Self { client: Mutex::new(client) })
+    }
+}
+
+/// Brings the database up to [`SCHEMA_VERSION`] in one transaction
+async fn migrate(client: &mut Client) -> Result<(), ConfigStoreError> {
+    let transaction = client.transaction().await?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_ID]).await?;
+    transaction.execute(CREATE_SCHEMA_TABLE_SQL, &[]).await?;
+    let version = match transaction.query_opt(SELECT_SCHEMA_VERSION_SQL, &[]).await? {
+        Some(row) => row.get::<_, i32>(0) as u32,
+        None => {
+            transaction.execute("INSERT INTO config_store_schema VALUES (0)", &[]).await?;
+            0
+        }
+    };
+    if version > SCHEMA_VERSION {
+        return Err(ConfigStoreError::UnsupportedSchema {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        transaction.batch_execute(migration).await?;
+    }
+    transaction
+        .execute("UPDATE config_store_schema SET version = $1", &[&(SCHEMA_VERSION as i32)])
+        .await?;
+    transaction.commit().await?;
+    if version < SCHEMA_VERSION {
+        log::info!("Migrated PostgreSQL config store to schema version {}", SCHEMA_VERSION);
+    }
+    Ok(())
+}
+
+fn user_config_from_row(row: &Row) -> UserConfig {
+    UserConfig {
+        user_id: row.get(0),
+        config_json: row.get(1),
+        address: row.get(2),
+        schema_version: row.get::<_, i64>(3) as u32,
+        created_at: row.get::<_, i64>(4) as u64,
+        updated_at: row.get::<_, i64>(5) as u64,
+    }
+}
+
+fn login_attempts_from_row(row: &Row) -> LoginAttempts {
+    LoginAttempts {
+        failures: row.get::<_, i64>(0) as u32,
+        last_failure: row.get::<_, i64>(1) as u64,
+        locked_until: row.get::<_, i64>(2) as u64,
+    }
+}
+
+#[async_trait]
+impl ConfigStore for PostgresConfigStore {
+    async fn insert_or_update_config(&self, user_id: &str, config_json: &str) -> Result<(), ConfigStoreError> {
+        let columns = ConfigColumns::parse(config_json);
+        self.client
+            .lock()
+            .await
+            .execute(
+                INSERT_OR_UPDATE_SQL,
+                &[
+                    &user_id,
+                    &config_json,
+                    &columns.address,
+                    &i64::from(columns.schema_version),
+                    &(unix_now() as i64),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_config(&self, user_id: &str, config_json: &str) -> Result<bool, ConfigStoreError> {
+        let columns = ConfigColumns::parse(config_json);
+        let inserted = self
+            .client
+            .lock()
+            .await
+            .execute(
+                INSERT_SQL,
+                &[
+                    &user_id,
+                    &config_json,
+                    &columns.address,
+                    &i64::from(columns.schema_version),
+                    &(unix_now() as i64),
+                ],
+            )
+            .await?;
+        Ok(inserted == 1)
+    }
+
+    async fn replace_config(
+        &self,
+        user_id: &str,
+        expected_json: &str,
+        config_json: &str,
+    ) -> Result<bool, ConfigStoreError> {
+        let columns = ConfigColumns::parse(config_json);
+        let updated = self
+            .client
+            .lock()
+            .await
+            .execute(
+                REPLACE_IF_UNCHANGED_SQL,
+                &[
+                    &user_id,
+                    &expected_json,
+                    &config_json,
+                    &columns.address,
+                    &i64::from(columns.schema_version),
+                    &(unix_now() as i64),
+                ],
+            )
+            .await?;
+        Ok(updated == 1)
+    }
+
+    async fn config_exists(&self, user_id: &str) -> Result<bool, ConfigStoreError> {
+        let row = self.client.lock().await.query_one(CONFIG_EXISTS_SQL, &[&user_id]).await?;
+        Ok(row.get(0))
+    }
+
+    async fn get_user_config(&self, user_id: &str) -> Result<UserConfig, ConfigStoreError> {
+        self.client
+            .lock()
+            .await
+            .query_opt(SELECT_USER_CONFIG_SQL, &[&user_id])
+            .await?
+            .map(|row| user_config_from_row(&row))
+            .ok_or(ConfigStoreError::NotFound)
+    }
+
+    async fn begin_login_attempt(&self, user_id: &str, policy: &LockoutPolicy) -> Result<LoginAttempt, ConfigStoreError> {
+        // The check and the charge are one statement, which replicas charging at once queue
+        // on; the transaction keeps the row locked until a lockout has been read back
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await?;
+        let now = unix_now();
+        let charged = transaction
+            .query_opt(
+                CHARGE_LOGIN_ATTEMPT_SQL,
+                &[
+                    &user_id,
+                    &(now as i64),
+                    &(policy.reset_after.as_secs() as i64),
+                    &i64::from(policy.free_attempts),
+                    &(policy.base_delay.as_secs() as i64),
+                    &(policy.max_delay.as_secs() as i64),
+                    &(policy.delay_after(1).as_secs() as i64),
+                ],
+            )
+            .await?;
+        let attempt = match charged {
+            Some(row) => LoginAttempt::charged(&login_attempts_from_row(&row), now),
+            None => {
+                let row = transaction.query_opt(SELECT_LOGIN_ATTEMPTS_SQL, &[&user_id]).await?;
+                LoginAttempt::LockedOut {
+                    retry_after: row
+                        .and_then(|row| login_attempts_from_row(&row).lockout(now))
+                        .unwrap_or_default(),
+                }
+            }
+        };
+        transaction.commit().await?;
+        Ok(attempt)
+    }
+
+    async fn clear_failed_logins(&self, user_id: &str) -> Result<(), ConfigStoreError> {
+        self.client.lock().await.execute(DELETE_LOGIN_ATTEMPTS_SQL, &[&user_id]).await?;
+        Ok(())
+    }
+
+    async fn failed_logins(&self, user_id: &str) -> Result<Option<LoginAttempts>, ConfigStoreError> {
+        let row = self.client.lock().await.query_opt(SELECT_LOGIN_ATTEMPTS_SQL, &[&user_id]).await?;
+        Ok(row.map(|row| login_attempts_from_row(&row)))
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<bool, ConfigStoreError> {
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await?;
+        let deleted = transaction.execute(DELETE_CONFIG_SQL, &[&user_id]).await?;
+        transaction.execute(DELETE_LOGIN_ATTEMPTS_SQL, &[&user_id]).await?;
+        transaction.commit().await?;
+        Ok(deleted == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::config_store::conformance;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// Server to run against, e.g. `postgres://postgres@localhost/postgres`; tests are
+    /// skipped without one
+    const TEST_DATABASE_URL_ENV_VAR: &str = "TEST_DATABASE_URL";
+
+    /// Config for a fresh, empty schema on the test server
+    async fn test_database() -> Option<Config> {
+        let Ok(url) = std::env::var(TEST_DATABASE_URL_ENV_VAR) else {
+            eprintln!("{} not set, skipping PostgreSQL test", TEST_DATABASE_URL_ENV_VAR);
+            return None;
+        };
+        let mut config: Config = url.parse().unwrap();
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let schema = format!("meow_test_{}_{}", std::process::id(), nanos);
+        let (client, connection) = config.connect(NoTls).await.unwrap();
+        tokio::spawn(connection);
+        client.batch_execute(&format!("CREATE SCHEMA {}", schema)).await.unwrap();
+        config.options(format!("-c search_path={}", schema));
+        Some(config)
+    }
+
+    #[tokio::test]
+    async fn test_postgres_store_conformance() {
+        let Some(config) = test_database().await else { return };
+        let store = PostgresConfigStore::connect_with(config).await.unwrap();
+        conformance::check_configs(&store).await;
+        conformance::check_failed_logins(&store).await;
+        conformance::check_delete_user(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_replicas_share_configs_and_lockouts() {
+        let Some(config) = test_database().await else { return };
+        let first = Arc::new(PostgresConfigStore::connect_with(config.clone()).await.unwrap());
+        let second = Arc::new(PostgresConfigStore::connect_with(config).await.unwrap());
+
+        first.insert_or_update_config("alice", "{}").await.unwrap();
+        assert!(second.replace_config("alice", "{}", "[]").await.unwrap());
+        assert!(!first.replace_config("alice", "{}", "{}").await.unwrap());
+        assert_eq!(first.get_config("alice").await.unwrap(), "[]");
+
+        // Of the attempts both replicas make at once, only one gets past the lockout
+        let policy = LockoutPolicy {
+            free_attempts: 0,
+            base_delay: Duration::from_secs(60),
+            ..LockoutPolicy::default()
+        };
+        let attempts = (0..8).map(|i| {
+            let store = if i % 2 == 0 { Arc::clone(&first) } else { Arc::clone(&second) };
+            tokio::spawn(async move { store.begin_login_attempt("alice", &policy).await.unwrap() })
+        });
+        let mut charged = 0;
+        for attempt in attempts {
+            if let LoginAttempt::Charged { retry_after } = attempt.await.unwrap() {
+                assert_eq!(retry_after, Duration::from_secs(60));
+                charged += 1;
+            }
+        }
+        assert_eq!(charged, 1);
+        assert_eq!(second.failed_logins("alice").await.unwrap().unwrap().failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_lockout_delays_follow_the_policy() {
+        let Some(config) = test_database().await else { return };
+        let store = PostgresConfigStore::connect_with(config).await.unwrap();
+        let policy = LockoutPolicy {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(25),
+            ..LockoutPolicy::default()
+        };
+
+        for failures in 1..=6 {
+            let retry_after = policy.delay_after(failures);
+            assert_eq!(
+                store.begin_login_attempt("alice", &policy).await.unwrap(),
+                LoginAttempt::Charged { retry_after }
+            );
+            // Let the lockout run out without forgetting the failures
+            let client = store.client.lock().await;
+            client.execute("UPDATE login_attempts SET locked_until = 0", &[]).await.unwrap();
+        }
+        assert_eq!(store.failed_logins("alice").await.unwrap().unwrap().failures, 6);
+    }
+
+    #[tokio::test]
+    async fn test_newer_schema_is_refused() {
+        let Some(config) = test_database().await else { return };
+        let store = PostgresConfigStore::connect_with(config.clone()).await.unwrap();
+        store
+            .client
+            .lock()
+            .await
+            .execute("UPDATE config_store_schema SET version = $1", &[&(SCHEMA_VERSION as i32 + 1)])
+            .await
+            .unwrap();
+
+        let result = PostgresConfigStore::connect_with(config).await;
+        assert!(matches!(
+            result,
+            Err(ConfigStoreError::UnsupportedSchema { found, supported })
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+    }
+}
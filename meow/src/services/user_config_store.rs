@@ -1,120 +1,545 @@
-use rusqlite::{Connection, OptionalExtension, params};
-use serde::{Deserialize, Serialize};
+use super::config_store::{ConfigColumns, ConfigStore, ConfigStoreError, LoginAttempt, LoginAttempts, UserConfig, unix_now};
+use super::master_key::{MasterKeyError, MasterKeys};
+use async_trait::async_trait;
+use nine_sdk::LockoutPolicy;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension, Row, Transaction, TransactionBehavior, params};
 use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 
 // Constants
+/// Connections kept open, so reads needn't queue behind one another
+const POOL_SIZE: u32 = 8;
+/// How long a query waits for another connection's write to finish before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Prepared statements each connection keeps for reuse
+const STATEMENT_CACHE_CAPACITY: usize = 32;
 const TABLE_NAME: &str = "user_configs";
 const CREATE_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS user_configs (
     user_id TEXT PRIMARY KEY,
     config_json TEXT NOT NULL
 )";
-const INSERT_OR_UPDATE_SQL: &str = "INSERT INTO user_configs (user_id, config_json) VALUES (?1, ?2)
-    ON CONFLICT(user_id) DO UPDATE SET config_json=excluded.config_json";
-const SELECT_CONFIG_SQL: &str = "SELECT config_json FROM user_configs WHERE user_id = ?1";
-
-#[derive(Debug, thiserror::Error)]
-pub enum UserConfigStoreError {
-    #[error("SQLite error: {0}")]
-    Sqlite(#[from] rusqlite::Error),
-    #[error("Serialization error: {0}")]
-    Serde(#[from] serde_json::Error),
-    #[error("User config not found")]
-    NotFound,
-}
+const ADD_CONFIG_COLUMNS_SQL: &str = "ALTER TABLE user_configs ADD COLUMN address TEXT;
+    ALTER TABLE user_configs ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE user_configs ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE user_configs ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;";
+const ADD_KEY_ID_COLUMN_SQL: &str = "ALTER TABLE user_configs ADD COLUMN key_id TEXT";
+const INSERT_OR_UPDATE_SQL: &str = "INSERT INTO user_configs
+    (user_id, config_json, key_id, address, schema_version, created_at, updated_at)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+    ON CONFLICT(user_id) DO UPDATE SET config_json=excluded.config_json, key_id=excluded.key_id,
+        address=excluded.address, schema_version=excluded.schema_version, updated_at=excluded.updated_at";
+const INSERT_SQL: &str = "INSERT INTO user_configs
+    (user_id, config_json, key_id, address, schema_version, created_at, updated_at)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+    ON CONFLICT(user_id) DO NOTHING";
+const UPDATE_CONFIG_SQL: &str = "UPDATE user_configs
+    SET config_json = ?2, key_id = ?3, address = ?4, schema_version = ?5, updated_at = ?6
+    WHERE user_id = ?1";
+const CONFIG_EXISTS_SQL: &str = "SELECT EXISTS(SELECT 1 FROM user_configs WHERE user_id = ?1)";
+const SELECT_CONFIG_SQL: &str = "SELECT config_json, key_id FROM user_configs WHERE user_id = ?1";
+const SELECT_USER_CONFIG_SQL: &str = "SELECT user_id, config_json, address, schema_version, created_at, updated_at,
+    key_id FROM user_configs WHERE user_id = ?1";
+const SELECT_UNROTATED_CONFIGS_SQL: &str = "SELECT user_id, config_json, key_id FROM user_configs
+    WHERE key_id IS NULL OR key_id != ?1 LIMIT ?2";
+const RESEAL_CONFIG_SQL: &str = "UPDATE user_configs SET config_json = ?2, key_id = ?3, address = NULL
+    WHERE user_id = ?1";
+const SELECT_ALL_CONFIGS_SQL: &str = "SELECT user_id, config_json FROM user_configs";
+const BACKFILL_CONFIG_COLUMNS_SQL: &str = "UPDATE user_configs
+    SET address = ?2, schema_version = ?3, created_at = ?4, updated_at = ?4 WHERE user_id = ?1";
+const CREATE_LOGIN_ATTEMPTS_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS login_attempts (
+    user_id TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    last_failure INTEGER NOT NULL,
+    locked_until INTEGER NOT NULL
+)";
+const SELECT_LOGIN_ATTEMPTS_SQL: &str =
+    "SELECT failures, last_failure, locked_until FROM login_attempts WHERE user_id = ?1";
+/// Charges a failure at `?2` unless the user is locked out, following `LoginAttempts::after_failure`
+/// for a policy of `?3` seconds to reset, `?4` free attempts and `?5`/`?6` seconds of base and
+/// maximum delay; `?7` is the delay after a first failure. Returns nothing while locked out.
+const CHARGE_LOGIN_ATTEMPT_SQL: &str = "INSERT INTO login_attempts (user_id, failures, last_failure, locked_until)
+    VALUES (?1, 1, ?2, ?2 + ?7)
+    ON CONFLICT(user_id) DO UPDATE SET
+        failures = CASE WHEN ?2 - last_failure < ?3 THEN failures + 1 ELSE 1 END,
+        last_failure = ?2,
+        locked_until = ?2 + CASE
+            WHEN (CASE WHEN ?2 - last_failure < ?3 THEN failures + 1 ELSE 1 END) <= ?4 THEN 0
+            ELSE min(?5 << min((CASE WHEN ?2 - last_failure < ?3 THEN failures + 1 ELSE 1 END) - ?4 - 1, 31), ?6)
+        END
+    WHERE locked_until <= ?2
+    RETURNING failures, last_failure, locked_until";
+const DELETE_LOGIN_ATTEMPTS_SQL: &str = "DELETE FROM login_attempts WHERE user_id = ?1";
+const DELETE_CONFIG_SQL: &str = "DELETE FROM user_configs WHERE user_id = ?1";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UserConfig {
-    pub user_id: String,
-    pub config_json: String, // Serialized EncryptedKeyConfig
-}
+/// A step from one schema version to the next
+type Migration = fn(&Transaction) -> Result<(), rusqlite::Error>;
 
-/// Thread-safe store for user configuration data
+/// Schema migrations in order; a database's `PRAGMA user_version` counts those applied
+///
+/// Released migrations must never change, since databases already past them won't rerun
+/// them; schema changes go in a new one at the end.
+const MIGRATIONS: &[Migration] = &[create_base_tables, add_config_columns, add_key_id_column];
+
+/// Schema version of a database with every migration applied
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Config store in a local SQLite database
+///
+/// Queries run on pooled connections on tokio's blocking threads, so a slow disk holds up
+/// only the queries waiting on it rather than the async runtime. The database is in WAL
+/// mode, letting reads proceed while a write is in progress.
+///
+/// With master keys set, configs are written sealed under the current key and bound to
+/// their user, and the address column is left empty. Rows written in the clear or under an
+/// older key stay readable until [`rotate_master_key`](Self::rotate_master_key) reseals them.
 pub struct UserConfigStore {
-    connection: Arc<Mutex<Connection>>,
+    pool: Pool<SqliteConnectionManager>,
+    master_keys: Option<Arc<MasterKeys>>,
 }
 
 impl UserConfigStore {
     /// Creates a new UserConfigStore with the given database path
-    pub fn new<P: AsRef<Path>>(database_path: P) -> Result<Self, UserConfigStoreError> {
-        let connection = open_database(database_path)?;
+    pub fn new<P: AsRef<Path>>(database_path: P) -> Result<Self, ConfigStoreError> {
+        let connection = open_database(&database_path)?;
         initialize_database_schema(&connection)?;
-        
-        Ok(Self {
-            connection: Arc::new(Mutex::new(connection)),
+
+        let manager = SqliteConnectionManager::file(database_path).with_init(configure_connection);
+        let pool = Pool::builder().max_size(POOL_SIZE).build(manager)?;
+        Ok(Self { pool, master_keys: None })
+    }
+
+    /// Encrypts configs written from now on under `master_keys`
+    pub fn with_master_keys(mut self, master_keys: MasterKeys) -> Self {
+        self.master_keys = Some(Arc::new(master_keys));
+        self
+    }
+
+    /// Reseals every config not yet under the current master key, `batch_size` rows per
+    /// transaction, and returns how many were resealed
+    ///
+    /// Rows in the clear are encrypted for the first time. Each batch commits on its own,
+    /// so the bot can keep serving while a large table is rotated, and an interrupted
+    /// rotation picks up where it stopped.
+    pub async fn rotate_master_key(&self, batch_size: usize) -> Result<usize, ConfigStoreError> {
+        let master_keys = self.master_keys.clone().ok_or(MasterKeyError::NotConfigured)?;
+        let mut rotated = 0;
+        loop {
+            let batch_keys = Arc::clone(&master_keys);
+            let resealed = self.run(move |connection| reseal_batch(connection, &batch_keys, batch_size)).await?;
+            if resealed == 0 {
+                break;
+            }
+            rotated += resealed;
+            log::info!("Resealed {} configs under master key {:?}", rotated, master_keys.current_id());
+        }
+        Ok(rotated)
+    }
+
+    /// Runs `query` on a pooled connection on the blocking thread pool
+    async fn run<T, F>(&self, query: F) -> Result<T, ConfigStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, ConfigStoreError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || query(&mut *pool.get()?)).await?
+    }
+}
+
+#[async_trait]
+impl ConfigStore for UserConfigStore {
+    async fn insert_or_update_config(
+        &self,
+        user_id: &str,
+        config_json: &str,
+    ) -> Result<(), ConfigStoreError> {
+        let (user_id, config_json) = (user_id.to_string(), config_json.to_string());
+        let master_keys = self.master_keys.clone();
+        self.run(move |connection| {
+            execute_insert_or_update(connection, master_keys.as_deref(), &user_id, &config_json)
+        })
+        .await
+    }
+
+    async fn insert_config(&self, user_id: &str, config_json: &str) -> Result<bool, ConfigStoreError> {
+        let (user_id, config_json) = (user_id.to_string(), config_json.to_string());
+        let master_keys = self.master_keys.clone();
+        self.run(move |connection| {
+            let stored = StoredConfig::seal(master_keys.as_deref(), &user_id, &config_json)?;
+            let inserted = connection.prepare_cached(INSERT_SQL)?.execute(params![
+                user_id,
+                stored.config_json,
+                stored.key_id,
+                stored.address,
+                stored.schema_version,
+                unix_now()
+            ])?;
+            Ok(inserted == 1)
         })
+        .await
     }
 
-    /// Stores or updates a user's configuration
-    pub async fn insert_or_update_config(
+    async fn replace_config(
         &self,
         user_id: &str,
+        expected_json: &str,
         config_json: &str,
-    ) -> Result<(), UserConfigStoreError> {
-        let connection = self.connection.lock().await;
-        execute_insert_or_update(&*connection, user_id, config_json)?;
-        Ok(())
+    ) -> Result<bool, ConfigStoreError> {
+        let params = (user_id.to_string(), expected_json.to_string(), config_json.to_string());
+        let master_keys = self.master_keys.clone();
+        self.run(move |connection| {
+            let (user_id, expected_json, config_json) = params;
+            // Sealed configs differ on every write, so the comparison is made on the plaintext
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            match query_user_config(&transaction, master_keys.as_deref(), &user_id) {
+                Ok(current) if current == expected_json => {}
+                Ok(_) | Err(ConfigStoreError::NotFound) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+            let stored = StoredConfig::seal(master_keys.as_deref(), &user_id, &config_json)?;
+            transaction.prepare_cached(UPDATE_CONFIG_SQL)?.execute(params![
+                user_id,
+                stored.config_json,
+                stored.key_id,
+                stored.address,
+                stored.schema_version,
+                unix_now()
+            ])?;
+            transaction.commit()?;
+            Ok(true)
+        })
+        .await
     }
 
-    /// Retrieves a user's configuration
-    pub async fn get_config(&self, user_id: &str) -> Result<String, UserConfigStoreError> {
-        let connection = self.connection.lock().await;
-        query_user_config(&*connection, user_id)
+    async fn get_config(&self, user_id: &str) -> Result<String, ConfigStoreError> {
+        let user_id = user_id.to_string();
+        let master_keys = self.master_keys.clone();
+        self.run(move |connection| query_user_config(connection, master_keys.as_deref(), &user_id))
+            .await
     }
-    
-    /// Checks if a user configuration exists
-    pub async fn config_exists(&self, user_id: &str) -> Result<bool, UserConfigStoreError> {
-        match self.get_config(user_id).await {
-            Ok(_) => Ok(true),
-            Err(UserConfigStoreError::NotFound) => Ok(false),
-            Err(e) => Err(e),
-        }
+
+    /// Checks for the row without opening its config, which may be sealed
+    async fn config_exists(&self, user_id: &str) -> Result<bool, ConfigStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |connection| {
+            Ok(connection.prepare_cached(CONFIG_EXISTS_SQL)?.query_row(params![user_id], |row| row.get(0))?)
+        })
+        .await
+    }
+
+    async fn get_user_config(&self, user_id: &str) -> Result<UserConfig, ConfigStoreError> {
+        let user_id = user_id.to_string();
+        let master_keys = self.master_keys.clone();
+        self.run(move |connection| {
+            let (mut config, key_id) = connection
+                .prepare_cached(SELECT_USER_CONFIG_SQL)?
+                .query_row(params![user_id], user_config_from_row)
+                .optional()?
+                .ok_or(ConfigStoreError::NotFound)?;
+            config.config_json = open_config(master_keys.as_deref(), &user_id, config.config_json, key_id)?;
+            if config.address.is_none() {
+                config.address = ConfigColumns::parse(&config.config_json).address;
+            }
+            Ok(config)
+        })
+        .await
+    }
+
+    async fn begin_login_attempt(
+        &self,
+        user_id: &str,
+        policy: &LockoutPolicy,
+    ) -> Result<LoginAttempt, ConfigStoreError> {
+        let (user_id, policy) = (user_id.to_string(), *policy);
+        self.run(move |connection| {
+            // The check and the charge are one statement; the transaction only keeps the
+            // lockout from being cleared before it is read back
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let now = unix_now();
+            let charged = transaction
+                .prepare_cached(CHARGE_LOGIN_ATTEMPT_SQL)?
+                .query_row(
+                    params![
+                        user_id,
+                        now,
+                        policy.reset_after.as_secs(),
+                        policy.free_attempts,
+                        policy.base_delay.as_secs(),
+                        policy.max_delay.as_secs(),
+                        policy.delay_after(1).as_secs()
+                    ],
+                    login_attempts_from_row,
+                )
+                .optional()?;
+            let attempt = match charged {
+                Some(attempts) => LoginAttempt::charged(&attempts, now),
+                None => LoginAttempt::LockedOut {
+                    retry_after: query_login_attempts(&transaction, &user_id)?
+                        .and_then(|attempts| attempts.lockout(now))
+                        .unwrap_or_default(),
+                },
+            };
+            transaction.commit()?;
+            Ok(attempt)
+        })
+        .await
+    }
+
+    async fn clear_failed_logins(&self, user_id: &str) -> Result<(), ConfigStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |connection| {
+            connection.prepare_cached(DELETE_LOGIN_ATTEMPTS_SQL)?.execute(params![user_id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn failed_logins(&self, user_id: &str) -> Result<Option<LoginAttempts>, ConfigStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |connection| Ok(query_login_attempts(connection, &user_id)?))
+            .await
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<bool, ConfigStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |connection| {
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let deleted = transaction.prepare_cached(DELETE_CONFIG_SQL)?.execute(params![user_id])?;
+            transaction.prepare_cached(DELETE_LOGIN_ATTEMPTS_SQL)?.execute(params![user_id])?;
+            transaction.commit()?;
+            Ok(deleted == 1)
+        })
+        .await
     }
 }
 
 // Database operation helpers
 
 fn open_database<P: AsRef<Path>>(path: P) -> Result<Connection, rusqlite::Error> {
-    Connection::open(path)
+    let mut connection = Connection::open(path)?;
+    configure_connection(&mut connection)?;
+    Ok(connection)
+}
+
+/// Settings every connection needs; WAL mode is kept by the database file itself
+///
+/// Deleted rows are overwritten with zeros, so a deleted account doesn't linger in free pages.
+fn configure_connection(connection: &mut Connection) -> Result<(), rusqlite::Error> {
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    connection.pragma_update(None, "secure_delete", true)?;
+    connection.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+    Ok(())
+}
+
+/// Brings the database up to [`SCHEMA_VERSION`], one migration per transaction
+///
+/// Databases written by a newer build are refused rather than used with a schema this
+/// build doesn't know.
+fn initialize_database_schema(connection: &Connection) -> Result<(), ConfigStoreError> {
+    let version = schema_version(connection)?;
+    if version > SCHEMA_VERSION {
+        return Err(ConfigStoreError::UnsupportedSchema {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = connection.unchecked_transaction()?;
+        migration(&transaction)?;
+        transaction.pragma_update(None, "user_version", applied as u32 + 1)?;
+        transaction.commit()?;
+        log::info!("Migrated user config database to schema version {}", applied + 1);
+    }
+    Ok(())
+}
+
+fn schema_version(connection: &Connection) -> Result<u32, rusqlite::Error> {
+    connection.pragma_query_value(None, "user_version", |row| row.get(0))
 }
 
-fn initialize_database_schema(connection: &Connection) -> Result<(), rusqlite::Error> {
-    connection.execute(CREATE_TABLE_SQL, [])?;
+/// Version 1: the tables as they were before versioning, which older databases already have
+fn create_base_tables(transaction: &Transaction) -> Result<(), rusqlite::Error> {
+    transaction.execute(CREATE_TABLE_SQL, [])?;
+    transaction.execute(CREATE_LOGIN_ATTEMPTS_TABLE_SQL, [])?;
     Ok(())
 }
 
+/// Version 2: address, schema version and timestamps in columns, filled in from existing configs
+fn add_config_columns(transaction: &Transaction) -> Result<(), rusqlite::Error> {
+    transaction.execute_batch(ADD_CONFIG_COLUMNS_SQL)?;
+
+    let configs = transaction
+        .prepare(SELECT_ALL_CONFIGS_SQL)?
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let now = unix_now();
+    for (user_id, config_json) in configs {
+        let columns = ConfigColumns::parse(&config_json);
+        transaction.execute(
+            BACKFILL_CONFIG_COLUMNS_SQL,
+            params![user_id, columns.address, columns.schema_version, now],
+        )?;
+    }
+    Ok(())
+}
+
+/// Version 3: the id of the master key a config is sealed under, or NULL for one in the clear
+fn add_key_id_column(transaction: &Transaction) -> Result<(), rusqlite::Error> {
+    transaction.execute(ADD_KEY_ID_COLUMN_SQL, [])?;
+    Ok(())
+}
+
+/// A config as written to its row
+struct StoredConfig {
+    config_json: String,
+    key_id: Option<String>,
+    address: Option<String>,
+    schema_version: u32,
+}
+
+impl StoredConfig {
+    /// Seals `config_json` under the current master key, if there is one
+    ///
+    /// The address is only mirrored into its column for configs kept in the clear.
+    fn seal(master_keys: Option<&MasterKeys>, user_id: &str, config_json: &str) -> Result<Self, MasterKeyError> {
+        let columns = ConfigColumns::parse(config_json);
+        Ok(match master_keys {
+            Some(master_keys) => Self {
+                config_json: master_keys.seal(user_id, config_json)?,
+                key_id: Some(master_keys.current_id().to_string()),
+                address: None,
+                schema_version: columns.schema_version,
+            },
+            None => Self {
+                config_json: config_json.to_string(),
+                key_id: None,
+                address: columns.address,
+                schema_version: columns.schema_version,
+            },
+        })
+    }
+}
+
+/// Plaintext of a stored config, which is sealed under `key_id` if that is set
+fn open_config(
+    master_keys: Option<&MasterKeys>,
+    user_id: &str,
+    stored: String,
+    key_id: Option<String>,
+) -> Result<String, MasterKeyError> {
+    match (key_id, master_keys) {
+        (None, _) => Ok(stored),
+        (Some(key_id), Some(master_keys)) => master_keys.open(&key_id, user_id, &stored),
+        (Some(_), None) => Err(MasterKeyError::NotConfigured),
+    }
+}
+
 fn execute_insert_or_update(
     connection: &Connection,
+    master_keys: Option<&MasterKeys>,
     user_id: &str,
     config_json: &str,
-) -> Result<(), rusqlite::Error> {
-    connection.execute(INSERT_OR_UPDATE_SQL, params![user_id, config_json])?;
+) -> Result<(), ConfigStoreError> {
+    let stored = StoredConfig::seal(master_keys, user_id, config_json)?;
+    connection.prepare_cached(INSERT_OR_UPDATE_SQL)?.execute(params![
+        user_id,
+        stored.config_json,
+        stored.key_id,
+        stored.address,
+        stored.schema_version,
+        unix_now()
+    ])?;
     Ok(())
 }
 
+/// Reads a row along with the id of the key its config is sealed under
+fn user_config_from_row(row: &Row) -> Result<(UserConfig, Option<String>), rusqlite::Error> {
+    let config = UserConfig {
+        user_id: row.get(0)?,
+        config_json: row.get(1)?,
+        address: row.get(2)?,
+        schema_version: row.get(3)?,
+        created_at: row.get(4)?,
+        updated_at: row.get(5)?,
+    };
+    Ok((config, row.get(6)?))
+}
+
 fn query_user_config(
     connection: &Connection,
+    master_keys: Option<&MasterKeys>,
     user_id: &str,
-) -> Result<String, UserConfigStoreError> {
-    let config_json: Option<String> = connection
-        .query_row(SELECT_CONFIG_SQL, params![user_id], |row| row.get(0))
-        .optional()?;
+) -> Result<String, ConfigStoreError> {
+    let (config_json, key_id): (String, Option<String>) = connection
+        .prepare_cached(SELECT_CONFIG_SQL)?
+        .query_row(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?
+        .ok_or(ConfigStoreError::NotFound)?;
     
-    config_json.ok_or(UserConfigStoreError::NotFound)
+    Ok(open_config(master_keys, user_id, config_json, key_id)?)
+}
+
+/// Reseals up to `batch_size` configs not under the current master key in one transaction
+fn reseal_batch(
+    connection: &mut Connection,
+    master_keys: &MasterKeys,
+    batch_size: usize,
+) -> Result<usize, ConfigStoreError> {
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let rows = transaction
+        .prepare(SELECT_UNROTATED_CONFIGS_SQL)?
+        .query_map(params![master_keys.current_id(), batch_size], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (user_id, stored, key_id) in &rows {
+        let config_json = open_config(Some(master_keys), user_id, stored.clone(), key_id.clone())?;
+        let resealed = master_keys.seal(user_id, &config_json)?;
+        transaction.execute(RESEAL_CONFIG_SQL, params![user_id, resealed, master_keys.current_id()])?;
+    }
+    transaction.commit()?;
+    Ok(rows.len())
+}
+
+fn query_login_attempts(
+    connection: &Connection,
+    user_id: &str,
+) -> Result<Option<LoginAttempts>, rusqlite::Error> {
+    connection
+        .prepare_cached(SELECT_LOGIN_ATTEMPTS_SQL)?
+        .query_row(params![user_id], login_attempts_from_row)
+        .optional()
+}
+
+fn login_attempts_from_row(row: &Row) -> Result<LoginAttempts, rusqlite::Error> {
+    Ok(LoginAttempts {
+        failures: row.get(0)?,
+        last_failure: row.get(1)?,
+        locked_until: row.get(2)?,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
     use tempfile::TempDir;
     
     // Test constants
     const TEST_USER_ID: &str = "test_user_123";
-    const TEST_CONFIG_JSON: &str = r#"{"password_hash":"test_hash","primary_key_salt":"test_salt1","secondary_key_salt":"test_salt2"}"#;
+    // Stored configs are serialized `UserWalletConfig`s
+    const TEST_CONFIG_JSON: &str = r#"{"schema_version":1,"encrypted_key_config":{"password_hash":"test_hash","salt1":"test_salt1","salt2":"test_salt2"},"encrypted_ethereum_private_key":"c1f3e2","ethereum_public_key":"04aa","ethereum_address":"0x1111111111111111111111111111111111111111","nonce":""}"#;
     const TEST_USER_ID_2: &str = "test_user_456";
-    const TEST_CONFIG_JSON_2: &str = r#"{"password_hash":"test_hash2","primary_key_salt":"test_salt3","secondary_key_salt":"test_salt4"}"#;
-    const UPDATED_CONFIG_JSON: &str = r#"{"password_hash":"updated_hash","primary_key_salt":"updated_salt1","secondary_key_salt":"updated_salt2"}"#;
+    const TEST_CONFIG_JSON_2: &str = r#"{"schema_version":1,"encrypted_key_config":{"password_hash":"test_hash2","salt1":"test_salt3","salt2":"test_salt4"},"encrypted_ethereum_private_key":"c1f3e3","ethereum_public_key":"04bb","ethereum_address":"0x2222222222222222222222222222222222222222","nonce":""}"#;
+    const UPDATED_CONFIG_JSON: &str = r#"{"schema_version":1,"encrypted_key_config":{"password_hash":"updated_hash","salt1":"updated_salt1","salt2":"updated_salt2"},"encrypted_ethereum_private_key":"c1f3e4","ethereum_public_key":"04cc","ethereum_address":"0x3333333333333333333333333333333333333333","nonce":""}"#;
+    // A config from before `schema_version` was recorded, with a bare encrypted key
+    const LEGACY_CONFIG_JSON: &str = r#"{"encrypted_key_config":{"password_hash":"legacy_hash","salt1":"legacy_salt1","salt2":"legacy_salt2"},"encrypted_ethereum_private_key":"c1f3e5","ethereum_public_key":"04dd","ethereum_address":"0x4444444444444444444444444444444444444444","nonce":"0011"}"#;
     const EMPTY_CONFIG_JSON: &str = "{}";
     const SPECIAL_CHARS_USER_ID: &str = "user-with-special-chars!@#$%^&*()";
     const VERY_LONG_USER_ID: &str = "very_long_user_id_with_many_characters_that_exceeds_normal_length_expectations_but_should_still_work_correctly";
@@ -130,17 +555,17 @@ mod tests {
     #[test]
     fn test_user_config_store_error_display() {
         // Test NotFound error
-        let err = UserConfigStoreError::NotFound;
+        let err = ConfigStoreError::NotFound;
         assert_eq!(err.to_string(), "User config not found");
         
         // Test SQLite error conversion
         let sqlite_err = rusqlite::Error::InvalidPath("test path".into());
-        let err = UserConfigStoreError::Sqlite(sqlite_err);
+        let err = ConfigStoreError::Sqlite(sqlite_err);
         assert!(err.to_string().contains("SQLite error"));
         
         // Test Serde error conversion
         let serde_err = serde_json::from_str::<String>("invalid json").unwrap_err();
-        let err = UserConfigStoreError::Serde(serde_err);
+        let err = ConfigStoreError::Serde(serde_err);
         assert!(err.to_string().contains("Serialization error"));
     }
     
@@ -149,6 +574,10 @@ mod tests {
         let config = UserConfig {
             user_id: TEST_USER_ID.to_string(),
             config_json: TEST_CONFIG_JSON.to_string(),
+            address: None,
+            schema_version: 1,
+            created_at: 0,
+            updated_at: 0,
         };
         
         assert_eq!(config.user_id, TEST_USER_ID);
@@ -179,7 +608,265 @@ mod tests {
         assert!(CREATE_TABLE_SQL.contains("config_json TEXT NOT NULL"));
         assert!(INSERT_OR_UPDATE_SQL.contains("INSERT INTO user_configs"));
         assert!(INSERT_OR_UPDATE_SQL.contains("ON CONFLICT(user_id) DO UPDATE"));
-        assert!(SELECT_CONFIG_SQL.contains("SELECT config_json FROM user_configs"));
+        assert!(SELECT_CONFIG_SQL.contains("SELECT config_json, key_id FROM user_configs"));
+    }
+    
+    #[test]
+    fn test_fixtures_match_the_stored_config_format() {
+        use crate::models::password_handler::UserWalletConfig;
+        for config_json in [TEST_CONFIG_JSON, TEST_CONFIG_JSON_2, UPDATED_CONFIG_JSON, LEGACY_CONFIG_JSON] {
+            serde_json::from_str::<UserWalletConfig>(config_json).unwrap();
+        }
+    }
+    
+    /// Creates a database as the store left it before schema versioning
+    fn unversioned_database(db_path: &Path, with_login_attempts: bool) {
+        let conn = Connection::open(db_path).unwrap();
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS user_configs (user_id TEXT PRIMARY KEY, config_json TEXT NOT NULL)",
+            [],
+        )
+        .unwrap();
+        if with_login_attempts {
+            conn.execute(CREATE_LOGIN_ATTEMPTS_TABLE_SQL, []).unwrap();
+            conn.execute("INSERT INTO login_attempts VALUES ('locked', 9, 1, 4102444800)", [])
+                .unwrap();
+        }
+        for (user_id, config_json) in [
+            (TEST_USER_ID, TEST_CONFIG_JSON),
+            (TEST_USER_ID_2, LEGACY_CONFIG_JSON),
+            ("not_json", "not json"),
+        ] {
+            conn.execute(
+                "INSERT INTO user_configs (user_id, config_json) VALUES (?1, ?2)",
+                params![user_id, config_json],
+            )
+            .unwrap();
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_unversioned_databases_are_upgraded() {
+        for with_login_attempts in [false, true] {
+            let temp_dir = TempDir::new().unwrap();
+            let db_path = temp_dir.path().join("legacy.db");
+            unversioned_database(&db_path, with_login_attempts);
+            
+            let before = unix_now();
+            let store = UserConfigStore::new(&db_path).unwrap();
+            assert_eq!(schema_version(&store.pool.get().unwrap()).unwrap(), SCHEMA_VERSION);
+            
+            // Configs are untouched, with their columns filled in from the JSON
+            let config = store.get_user_config(TEST_USER_ID).await.unwrap();
+            assert_eq!(config.config_json, TEST_CONFIG_JSON);
+            assert_eq!(config.address.as_deref(), Some("0x1111111111111111111111111111111111111111"));
+            assert_eq!(config.schema_version, 1);
+            assert!(config.created_at >= before && config.updated_at == config.created_at);
+            
+            let legacy = store.get_user_config(TEST_USER_ID_2).await.unwrap();
+            assert_eq!(legacy.address.as_deref(), Some("0x4444444444444444444444444444444444444444"));
+            assert_eq!(legacy.schema_version, 0);
+            
+            let unparsed = store.get_user_config("not_json").await.unwrap();
+            assert_eq!((unparsed.config_json.as_str(), unparsed.address), ("not json", None));
+            
+            // Lockouts recorded before the upgrade still hold
+            let attempt = store.begin_login_attempt("locked", &LockoutPolicy::default()).await.unwrap();
+            assert_eq!(matches!(attempt, LoginAttempt::LockedOut { .. }), with_login_attempts);
+            store.begin_login_attempt(TEST_USER_ID, &LockoutPolicy::default()).await.unwrap();
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_each_version_upgrades_to_the_latest() {
+        for version in 0..=SCHEMA_VERSION {
+            let temp_dir = TempDir::new().unwrap();
+            let db_path = temp_dir.path().join("test.db");
+            {
+                let conn = Connection::open(&db_path).unwrap();
+                for (applied, migration) in MIGRATIONS.iter().enumerate().take(version as usize) {
+                    let transaction = conn.unchecked_transaction().unwrap();
+                    migration(&transaction).unwrap();
+                    transaction.pragma_update(None, "user_version", applied as u32 + 1).unwrap();
+                    transaction.commit().unwrap();
+                }
+                if version == SCHEMA_VERSION {
+                    execute_insert_or_update(&conn, None, TEST_USER_ID, TEST_CONFIG_JSON).unwrap();
+                } else if version >= 1 {
+                    conn.execute(
+                        "INSERT INTO user_configs (user_id, config_json) VALUES (?1, ?2)",
+                        params![TEST_USER_ID, TEST_CONFIG_JSON],
+                    )
+                    .unwrap();
+                }
+            }
+            
+            let store = UserConfigStore::new(&db_path).unwrap();
+            assert_eq!(schema_version(&store.pool.get().unwrap()).unwrap(), SCHEMA_VERSION);
+            if version >= 1 {
+                let config = store.get_user_config(TEST_USER_ID).await.unwrap();
+                assert_eq!(config.address.as_deref(), Some("0x1111111111111111111111111111111111111111"));
+            }
+            store.insert_or_update_config(TEST_USER_ID_2, TEST_CONFIG_JSON_2).await.unwrap();
+            drop(store);
+            
+            // Reopening an up-to-date database changes nothing
+            let store = UserConfigStore::new(&db_path).unwrap();
+            assert_eq!(store.get_config(TEST_USER_ID_2).await.unwrap(), TEST_CONFIG_JSON_2);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_newer_schema_is_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        drop(UserConfigStore::new(&db_path).unwrap());
+        Connection::open(&db_path)
+            .unwrap()
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        
+        let result = UserConfigStore::new(&db_path);
+        assert!(matches!(
+            result,
+            Err(ConfigStoreError::UnsupportedSchema { found, supported })
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+    }
+    
+    #[tokio::test]
+    async fn test_columns_follow_config_updates() {
+        let (store, _temp_dir) = create_test_store().await;
+        store.insert_or_update_config(TEST_USER_ID, LEGACY_CONFIG_JSON).await.unwrap();
+        let inserted = store.get_user_config(TEST_USER_ID).await.unwrap();
+        assert_eq!(inserted.schema_version, 0);
+        assert_eq!(inserted.created_at, inserted.updated_at);
+        
+        // Backdate the record so the update is visible in its timestamps
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute("UPDATE user_configs SET created_at = 1, updated_at = 1", [])
+            .unwrap();
+        
+        assert!(store.replace_config(TEST_USER_ID, LEGACY_CONFIG_JSON, TEST_CONFIG_JSON).await.unwrap());
+        let replaced = store.get_user_config(TEST_USER_ID).await.unwrap();
+        assert_eq!(replaced.address.as_deref(), Some("0x1111111111111111111111111111111111111111"));
+        assert_eq!(replaced.schema_version, 1);
+        assert_eq!(replaced.created_at, 1);
+        assert!(replaced.updated_at >= inserted.updated_at);
+        
+        store.insert_or_update_config(TEST_USER_ID, UPDATED_CONFIG_JSON).await.unwrap();
+        let updated = store.get_user_config(TEST_USER_ID).await.unwrap();
+        assert_eq!(updated.address.as_deref(), Some("0x3333333333333333333333333333333333333333"));
+        assert_eq!(updated.created_at, 1);
+        
+        assert!(matches!(store.get_user_config(TEST_USER_ID_2).await, Err(ConfigStoreError::NotFound)));
+    }
+    
+    fn master_keys(entries: &[(&str, u8)]) -> MasterKeys {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|(key_id, byte)| format!("{}:{}", key_id, hex::encode([*byte; 32])))
+            .collect();
+        MasterKeys::parse(&entries.join(",")).unwrap()
+    }
+    
+    fn stored_row(store: &UserConfigStore, user_id: &str) -> (String, Option<String>, Option<String>) {
+        store
+            .pool
+            .get()
+            .unwrap()
+            .query_row(
+                "SELECT config_json, key_id, address FROM user_configs WHERE user_id = ?1",
+                params![user_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_configs_are_encrypted_at_rest() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let store = UserConfigStore::new(&db_path).unwrap().with_master_keys(master_keys(&[("k1", 1)]));
+        store.insert_or_update_config(TEST_USER_ID, TEST_CONFIG_JSON).await.unwrap();
+        
+        let (stored, key_id, address) = stored_row(&store, TEST_USER_ID);
+        assert!(!stored.contains("ethereum_address") && !stored.contains("0x1111"));
+        assert_eq!((key_id.as_deref(), address), (Some("k1"), None));
+        
+        // Reads see the plaintext, with the address taken from it
+        assert_eq!(store.get_config(TEST_USER_ID).await.unwrap(), TEST_CONFIG_JSON);
+        let config = store.get_user_config(TEST_USER_ID).await.unwrap();
+        assert_eq!(config.address.as_deref(), Some("0x1111111111111111111111111111111111111111"));
+        assert_eq!(config.schema_version, 1);
+        
+        // Replacing compares plaintexts, since every seal of a config differs
+        assert!(!store.replace_config(TEST_USER_ID, TEST_CONFIG_JSON_2, UPDATED_CONFIG_JSON).await.unwrap());
+        assert!(store.replace_config(TEST_USER_ID, TEST_CONFIG_JSON, UPDATED_CONFIG_JSON).await.unwrap());
+        assert_eq!(store.get_config(TEST_USER_ID).await.unwrap(), UPDATED_CONFIG_JSON);
+        assert_ne!(stored_row(&store, TEST_USER_ID).0, stored);
+        
+        // A sealed config copied onto another user's row doesn't open
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute(
+                "INSERT INTO user_configs (user_id, config_json, key_id) SELECT ?2, config_json, key_id
+                    FROM user_configs WHERE user_id = ?1",
+                params![TEST_USER_ID, TEST_USER_ID_2],
+            )
+            .unwrap();
+        assert!(matches!(store.get_config(TEST_USER_ID_2).await, Err(ConfigStoreError::MasterKey(_))));
+        drop(store);
+        
+        let without_keys = UserConfigStore::new(&db_path).unwrap();
+        assert!(matches!(
+            without_keys.get_config(TEST_USER_ID).await,
+            Err(ConfigStoreError::MasterKey(MasterKeyError::NotConfigured))
+        ));
+        // Whether a user has a config can be told without opening it
+        assert!(without_keys.config_exists(TEST_USER_ID).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_rotate_master_key_reseals_in_batches() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let plain = UserConfigStore::new(&db_path).unwrap();
+        assert!(matches!(
+            plain.rotate_master_key(10).await,
+            Err(ConfigStoreError::MasterKey(MasterKeyError::NotConfigured))
+        ));
+        for i in 0..5 {
+            plain.insert_or_update_config(&format!("plain_{}", i), TEST_CONFIG_JSON).await.unwrap();
+        }
+        drop(plain);
+        let old = UserConfigStore::new(&db_path).unwrap().with_master_keys(master_keys(&[("k1", 1)]));
+        old.insert_or_update_config(TEST_USER_ID, TEST_CONFIG_JSON).await.unwrap();
+        old.insert_or_update_config(TEST_USER_ID_2, TEST_CONFIG_JSON_2).await.unwrap();
+        drop(old);
+        
+        let store = UserConfigStore::new(&db_path)
+            .unwrap()
+            .with_master_keys(master_keys(&[("k2", 2), ("k1", 1)]));
+        assert_eq!(store.rotate_master_key(2).await.unwrap(), 7);
+        assert_eq!(store.rotate_master_key(2).await.unwrap(), 0);
+        drop(store);
+        
+        // Every row is now readable with the new key alone
+        let store = UserConfigStore::new(&db_path).unwrap().with_master_keys(master_keys(&[("k2", 2)]));
+        for i in 0..5 {
+            let user_id = format!("plain_{}", i);
+            assert_eq!(stored_row(&store, &user_id).1.as_deref(), Some("k2"));
+            assert_eq!(store.get_config(&user_id).await.unwrap(), TEST_CONFIG_JSON);
+        }
+        assert_eq!(store.get_config(TEST_USER_ID_2).await.unwrap(), TEST_CONFIG_JSON_2);
+        let (_, key_id, address) = stored_row(&store, TEST_USER_ID);
+        assert_eq!((key_id.as_deref(), address), (Some("k2"), None));
     }
     
     #[tokio::test]
@@ -215,7 +902,7 @@ mod tests {
         
         // Try to get non-existent config
         let result = store.get_config("non_existent_user").await;
-        assert!(matches!(result, Err(UserConfigStoreError::NotFound)));
+        assert!(matches!(result, Err(ConfigStoreError::NotFound)));
     }
     
     #[tokio::test]
@@ -233,6 +920,87 @@ mod tests {
         assert_eq!(retrieved_config, UPDATED_CONFIG_JSON);
     }
     
+    #[tokio::test]
+    async fn test_replace_config_only_if_unchanged() {
+        let (store, _temp_dir) = create_test_store().await;
+        store.insert_or_update_config(TEST_USER_ID, TEST_CONFIG_JSON).await.unwrap();
+        
+        // A record changed since it was read is left alone
+        assert!(!store.replace_config(TEST_USER_ID, TEST_CONFIG_JSON_2, UPDATED_CONFIG_JSON).await.unwrap());
+        assert_eq!(store.get_config(TEST_USER_ID).await.unwrap(), TEST_CONFIG_JSON);
+        assert!(!store.replace_config(TEST_USER_ID_2, TEST_CONFIG_JSON, UPDATED_CONFIG_JSON).await.unwrap());
+        
+        assert!(store.replace_config(TEST_USER_ID, TEST_CONFIG_JSON, UPDATED_CONFIG_JSON).await.unwrap());
+        assert_eq!(store.get_config(TEST_USER_ID).await.unwrap(), UPDATED_CONFIG_JSON);
+    }
+    
+    #[tokio::test]
+    async fn test_failed_logins_persist_across_reopening() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let policy = LockoutPolicy {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(60),
+            ..LockoutPolicy::default()
+        };
+        
+        let store = UserConfigStore::new(&db_path).unwrap();
+        store.begin_login_attempt(TEST_USER_ID, &policy).await.unwrap();
+        store.begin_login_attempt(TEST_USER_ID, &policy).await.unwrap();
+        drop(store);
+        
+        // A restart doesn't lift the lockout
+        let store = UserConfigStore::new(&db_path).unwrap();
+        let LoginAttempt::LockedOut { retry_after } = store.begin_login_attempt(TEST_USER_ID, &policy).await.unwrap() else {
+            panic!("lockout lost on reopening");
+        };
+        assert!(retry_after > Duration::from_secs(58) && retry_after <= Duration::from_secs(60));
+        assert_eq!(store.failed_logins(TEST_USER_ID_2).await.unwrap(), None);
+    }
+    
+    #[tokio::test]
+    async fn test_lockout_delays_follow_the_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = UserConfigStore::new(temp_dir.path().join("test.db")).unwrap();
+        let policy = LockoutPolicy {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(25),
+            ..LockoutPolicy::default()
+        };
+        
+        for failures in 1..=6 {
+            let retry_after = policy.delay_after(failures);
+            assert_eq!(
+                store.begin_login_attempt(TEST_USER_ID, &policy).await.unwrap(),
+                LoginAttempt::Charged { retry_after }
+            );
+            // Let the lockout run out without forgetting the failures
+            store.pool.get().unwrap().execute("UPDATE login_attempts SET locked_until = 0", []).unwrap();
+        }
+        assert_eq!(store.failed_logins(TEST_USER_ID).await.unwrap().unwrap().failures, 6);
+    }
+    
+    #[tokio::test]
+    async fn test_deleted_users_do_not_linger_in_the_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let store = UserConfigStore::new(&db_path).unwrap();
+        crate::services::config_store::conformance::check_delete_user(&store).await;
+        
+        store.insert_or_update_config(TEST_USER_ID, TEST_CONFIG_JSON).await.unwrap();
+        assert!(store.delete_user(TEST_USER_ID).await.unwrap());
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+            .unwrap();
+        let file = std::fs::read(&db_path).unwrap();
+        assert!(!file.windows(TEST_USER_ID.len()).any(|window| window == TEST_USER_ID.as_bytes()));
+        assert!(!file.windows(6).any(|window| window == b"0x1111"));
+    }
+    
     #[tokio::test]
     async fn test_multiple_users() {
         let (store, _temp_dir) = create_test_store().await;
@@ -327,6 +1095,56 @@ mod tests {
         }
     }
     
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_logins_do_not_wait_behind_a_slow_write() {
+        const WRITE_LOCK_HELD: Duration = Duration::from_millis(600);
+        let (store, temp_dir) = create_test_store().await;
+        let store = Arc::new(store);
+        for i in 0..POOL_SIZE {
+            store.insert_or_update_config(&format!("user_{}", i), TEST_CONFIG_JSON).await.unwrap();
+        }
+        
+        // Another writer holds the database's write lock, as a slow disk flush would
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let db_path = temp_dir.path().join("test.db");
+        let writer = std::thread::spawn(move || {
+            let conn = Connection::open(db_path).unwrap();
+            conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+            locked_tx.send(()).unwrap();
+            std::thread::sleep(WRITE_LOCK_HELD);
+            conn.execute_batch("COMMIT").unwrap();
+        });
+        locked_rx.recv().unwrap();
+        
+        // A failed login has to wait for the lock...
+        let started = Instant::now();
+        let failed_login = tokio::spawn({
+            let store = Arc::clone(&store);
+            async move { store.begin_login_attempt(TEST_USER_ID, &LockoutPolicy::default()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        
+        // ...while other users' logins read their configs and lockouts without waiting for it
+        let logins = (0..POOL_SIZE - 1).map(|i| {
+            let store = Arc::clone(&store);
+            tokio::spawn(async move {
+                let user_id = format!("user_{}", i);
+                store.get_config(&user_id).await.unwrap();
+                store.failed_logins(&user_id).await.unwrap()
+            })
+        });
+        for login in logins.collect::<Vec<_>>() {
+            assert_eq!(login.await.unwrap(), None);
+        }
+        let logins_took = started.elapsed();
+        
+        failed_login.await.unwrap().unwrap();
+        let write_took = started.elapsed();
+        writer.join().unwrap();
+        assert!(write_took >= WRITE_LOCK_HELD / 2, "write finished in {:?}", write_took);
+        assert!(logins_took < WRITE_LOCK_HELD / 2, "logins took {:?}", logins_took);
+    }
+    
     #[tokio::test]
     async fn test_large_config_json() {
         let (store, _temp_dir) = create_test_store().await;
@@ -360,15 +1178,15 @@ mod tests {
         assert!(initialize_database_schema(&conn).is_ok());
         
         // Test execute_insert_or_update
-        assert!(execute_insert_or_update(&conn, TEST_USER_ID, TEST_CONFIG_JSON).is_ok());
+        assert!(execute_insert_or_update(&conn, None, TEST_USER_ID, TEST_CONFIG_JSON).is_ok());
         
         // Test query_user_config
-        let result = query_user_config(&conn, TEST_USER_ID).unwrap();
+        let result = query_user_config(&conn, None, TEST_USER_ID).unwrap();
         assert_eq!(result, TEST_CONFIG_JSON);
         
         // Test query non-existent user
-        let result = query_user_config(&conn, "non_existent");
-        assert!(matches!(result, Err(UserConfigStoreError::NotFound)));
+        let result = query_user_config(&conn, None, "non_existent");
+        assert!(matches!(result, Err(ConfigStoreError::NotFound)));
     }
     
     #[tokio::test]
@@ -0,0 +1,165 @@
+//! Helpers for tests that need an enclave or the Telegram Bot API
+
+use nine_sdk::{EnclaveClient, EnclaveIdentity, FrameCodec, KeyManager, Transport};
+use nine_sdk_enclave::wallet::WalletStore;
+use serde_json::{Value, json};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use teloxide::types::{Me, Message};
+use teloxide::Bot;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Spawns an in-process enclave on an ephemeral port
+pub async fn spawn_enclave() -> Arc<EnclaveClient> {
+    spawn_enclave_with(KeyManager::new()).await
+}
+
+pub async fn spawn_enclave_with(key_manager: KeyManager) -> Arc<EnclaveClient> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let key_manager = Arc::new(key_manager);
+    let wallets = Arc::new(WalletStore::default());
+    let identity = Arc::new(EnclaveIdentity::generate());
+    let enclave_key = identity.public_key();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let key_manager = Arc::clone(&key_manager);
+            tokio::spawn(nine_sdk_enclave::handle_connection(
+                Box::pin(stream),
+                key_manager,
+                Arc::clone(&wallets),
+                Arc::clone(&identity),
+                Arc::new([]),
+                None,
+                FrameCodec::default(),
+            ));
+        }
+    });
+
+    Arc::new(EnclaveClient::new(Transport::Tcp(addr), enclave_key))
+}
+
+/// Stand-in for the Telegram Bot API that accepts every call and keeps the texts sent
+pub struct FakeTelegram {
+    pub bot: Bot,
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_message_id: AtomicI32,
+    sent: Mutex<Vec<(i64, String)>>,
+}
+
+impl FakeTelegram {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let state = Arc::new(State::default());
+        let server_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, Arc::clone(&server_state)));
+            }
+        });
+
+        let bot = Bot::new("0:test").set_api_url(url.parse().unwrap());
+        Self { bot, state }
+    }
+
+    /// The bot's own account, as `getMe` would return it
+    pub fn me(&self) -> Me {
+        serde_json::from_value(json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Meow",
+            "username": "meow_bot",
+            "can_join_groups": false,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+        }))
+        .unwrap()
+    }
+
+    /// A text message from the user of private chat `chat_id`
+    pub fn message(&self, chat_id: i64, text: &str) -> Message {
+        serde_json::from_value(message_json(self.state.next_id(), chat_id, text)).unwrap()
+    }
+
+    /// Texts the bot has sent to `chat_id` so far
+    pub fn sent(&self, chat_id: i64) -> Vec<String> {
+        let sent = self.state.sent.lock().unwrap();
+        sent.iter().filter(|(chat, _)| *chat == chat_id).map(|(_, text)| text.clone()).collect()
+    }
+}
+
+impl State {
+    fn next_id(&self) -> i32 {
+        self.next_message_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Result of Bot API `method` called with `params`
+    fn answer(&self, method: &str, params: &Value) -> Value {
+        match method {
+            "sendmessage" | "senddocument" => {
+                let chat_id = params["chat_id"].as_i64().unwrap_or_default();
+                let text = params["text"].as_str().unwrap_or_default();
+                self.sent.lock().unwrap().push((chat_id, text.to_string()));
+                message_json(self.next_id(), chat_id, text)
+            }
+            _ => Value::Bool(true),
+        }
+    }
+}
+
+fn message_json(message_id: i32, chat_id: i64, text: &str) -> Value {
+    json!({
+        "message_id": message_id,
+        "date": 0,
+        "chat": { "id": chat_id, "type": "private", "first_name": "Test" },
+        "from": { "id": chat_id, "is_bot": false, "first_name": "Test" },
+        "text": text,
+    })
+}
+
+/// Answers HTTP/1.1 requests on one connection until the client closes it
+async fn serve(stream: TcpStream, state: Arc<State>) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await? == 0 {
+            return Ok(());
+        }
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            stream.read_line(&mut header).await?;
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or_default();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await?;
+
+        // Requests go to /bot<token>/<Method>, which Telegram matches case-insensitively
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let method = path.rsplit('/').next().unwrap_or_default().to_lowercase();
+        let params = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        let response = json!({ "ok": true, "result": state.answer(&method, &params) }).to_string();
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            response.len()
+        );
+        stream.get_mut().write_all(head.as_bytes()).await?;
+        stream.get_mut().write_all(response.as_bytes()).await?;
+    }
+}
@@ -1,16 +1,16 @@
 use serde_json::json;
-use std::io::{Read, Write};
 use std::process::{Command, Child};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::time::{sleep, Duration};
-use nine_sdk::{KeyManager, Transport};
+use nine_sdk::protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use nine_sdk::{EnclaveIdentity, FrameCodec, SecureChannel};
 
 // Constants for better maintainability
 const ENCLAVE_ADDRESS: &str = "127.0.0.1:5005";
 const ENCLAVE_STARTUP_DELAY: Duration = Duration::from_secs(1);
 const TEST_PASSWORD: &str = "test_password";
-const MESSAGE_LENGTH_SIZE: usize = 4;
+// Fixed test identity so the client can pin the enclave's public key
+const ENCLAVE_STATIC_KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
 
 #[tokio::test]
 #[ignore = "Requires nine-sdk-enclave binary from another package"]
@@ -30,6 +30,10 @@ async fn run_enclave_test() -> Result<(), Box<dyn std::error::Error>> {
     
     let mut connection = connect_to_enclave().await?;
     
+    send_request(&mut connection, &create_hello_request()).await?;
+    let response = read_enclave_response(&mut connection).await?;
+    verify_version_response(&response)?;
+    
     send_setup_config_request(&mut connection, TEST_PASSWORD).await?;
     
     let response = read_enclave_response(&mut connection).await?;
@@ -45,6 +49,7 @@ fn start_enclave_process() -> Result<Child, Box<dyn std::error::Error>> {
     Command::new("cargo")
         .args(["run", "--bin", "nine-sdk-enclave"])
         .env("RUST_LOG", "debug")
+        .env("ENCLAVE_STATIC_KEY", ENCLAVE_STATIC_KEY)
         .spawn()
         .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
 }
@@ -59,34 +64,53 @@ fn cleanup_enclave_process(process: &mut Child) -> Result<(), Box<dyn std::error
 
 // Network communication functions
 
-async fn connect_to_enclave() -> Result<TcpStream, Box<dyn std::error::Error>> {
-    TcpStream::connect(ENCLAVE_ADDRESS)
-        .await
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+async fn connect_to_enclave() -> Result<SecureChannel, Box<dyn std::error::Error>> {
+    let stream = TcpStream::connect(ENCLAVE_ADDRESS).await?;
+    let enclave_key = EnclaveIdentity::from_hex(ENCLAVE_STATIC_KEY)?.public_key();
+    let channel = SecureChannel::connect(Box::pin(stream), FrameCodec::default(), &enclave_key).await?;
+    Ok(channel)
 }
 
 async fn send_setup_config_request(
-    stream: &mut TcpStream,
+    channel: &mut SecureChannel,
     password: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let request = create_setup_config_request(password);
-    send_request(stream, &request).await
+    send_request(channel, &request).await
+}
+
+fn create_hello_request() -> serde_json::Value {
+    json!({
+        "version": PROTOCOL_VERSION,
+        "request_id": 1,
+        "request": {
+            "Hello": {
+                "version": PROTOCOL_VERSION,
+                "min_version": MIN_PROTOCOL_VERSION
+            }
+        }
+    })
 }
 
 fn create_setup_config_request(password: &str) -> serde_json::Value {
     json!({
-        "SetupConfig": {
-            "password": password
+        "version": PROTOCOL_VERSION,
+        "request_id": 2,
+        "request": {
+            "SetupConfig": {
+                "password": password
+            }
         }
     })
 }
 
 async fn send_request(
-    stream: &mut TcpStream,
+    channel: &mut SecureChannel,
     request: &serde_json::Value,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let request_bytes = serialize_request(request)?;
-    write_message_with_length(stream, &request_bytes).await
+    channel.send(&request_bytes).await?;
+    Ok(())
 }
 
 fn serialize_request(request: &serde_json::Value) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -94,51 +118,18 @@ fn serialize_request(request: &serde_json::Value) -> Result<Vec<u8>, Box<dyn std
         .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
 }
 
-async fn write_message_with_length(
-    stream: &mut TcpStream,
-    data: &[u8],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let length_bytes = encode_message_length(data.len());
-    
-    stream.write_all(&length_bytes).await?;
-    stream.write_all(data).await?;
-    
-    Ok(())
-}
-
-fn encode_message_length(length: usize) -> [u8; MESSAGE_LENGTH_SIZE] {
-    (length as u32).to_be_bytes()
-}
-
 // Response handling functions
 
 async fn read_enclave_response(
-    stream: &mut TcpStream,
+    channel: &mut SecureChannel,
 ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-    let message_length = read_message_length(stream).await?;
-    let message_body = read_message_body(stream, message_length).await?;
+    let message_body = channel
+        .recv()
+        .await?
+        .ok_or("Enclave closed the connection")?;
     deserialize_response(&message_body)
 }
 
-async fn read_message_length(stream: &mut TcpStream) -> Result<usize, Box<dyn std::error::Error>> {
-    let mut length_buffer = [0u8; MESSAGE_LENGTH_SIZE];
-    stream.read_exact(&mut length_buffer).await?;
-    Ok(decode_message_length(&length_buffer))
-}
-
-fn decode_message_length(buffer: &[u8; MESSAGE_LENGTH_SIZE]) -> usize {
-    u32::from_be_bytes(*buffer) as usize
-}
-
-async fn read_message_body(
-    stream: &mut TcpStream,
-    length: usize,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut buffer = vec![0u8; length];
-    stream.read_exact(&mut buffer).await?;
-    Ok(buffer)
-}
-
 fn deserialize_response(data: &[u8]) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
     serde_json::from_slice(data)
         .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
@@ -146,8 +137,16 @@ fn deserialize_response(data: &[u8]) -> Result<serde_json::Value, Box<dyn std::e
 
 // Verification functions
 
+fn verify_version_response(response: &serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+    if response["response"]["Version"]["version"] == PROTOCOL_VERSION {
+        Ok(())
+    } else {
+        Err("Expected Version response".into())
+    }
+}
+
 fn verify_config_setup_response(response: &serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
-    if response.get("ConfigSetup").is_some() {
+    if response["request_id"] == 2 && response["response"].get("ConfigSetup").is_some() {
         Ok(())
     } else {
         Err("Expected ConfigSetup response".into())